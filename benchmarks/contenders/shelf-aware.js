@@ -21,23 +21,23 @@ export class DotShelfBench extends BenchmarkEnvironment {
   testDeltaSize(firstValues, secondValues) {
     const sizes = {};
 
-    const shelf1 = new Shelf(firstValues, 1);
-    const shelf2 = new Shelf(secondValues, 2);
+    const shelf1 = new Shelf(firstValues, 1n);
+    const shelf2 = new Shelf(secondValues, 2n);
     let sv = shelf1.getStateVector();
     let delta = shelf2.getStateDelta(sv);
     sizes["Random Merge Update"] = delta.byteLength;
 
     // With only a single element changed
-    const shelf1Updated = new Shelf(firstValues, 1);
-    shelf1Updated.set(["test"], "delta");
+    const shelf1Updated = new Shelf(firstValues, 1n);
+    shelf1Updated.set(["test"], "delta", 1n);
     sv = shelf1.getStateVector();
     delta = shelf1Updated.getStateDelta(sv);
     sizes["Single Change Update"] = delta.byteLength;
 
     // Complete deletion
-    const deletionShelf = new Shelf({ contents: firstValues }, 1);
-    const deletionShelfCopy = new Shelf({ contents: firstValues }, 1);
-    deletionShelf.set(["contents"], {}, 1);
+    const deletionShelf = new Shelf({ contents: firstValues }, 1n);
+    const deletionShelfCopy = new Shelf({ contents: firstValues }, 1n);
+    deletionShelf.set(["contents"], {}, 1n);
     sv = deletionShelfCopy.getStateVector();
     delta = deletionShelf.getStateDelta(sv);
     sizes["Complete Deletion Update"] = delta.byteLength;
@@ -45,28 +45,28 @@ export class DotShelfBench extends BenchmarkEnvironment {
   }
 
   testSizeAfterDeletion(values) {
-    const deletionShelf = new Shelf({ contents: values }, 1);
-    deletionShelf.set(["contents"], {}, 1);
+    const deletionShelf = new Shelf({ contents: values }, 1n);
+    deletionShelf.set(["contents"], {}, 1n);
     return {
       "Complete Deletion": shelfSizeOf(deletionShelf),
     };
   }
 
   testCRDTSize(values) {
-    const shelfCRDT = new Shelf(values);
+    const shelfCRDT = new Shelf(values, 0n);
 
     return shelfSizeOf(shelfCRDT);
   }
 
   testNAdditions() {
-    let crdt = new Shelf({ base: 1 }, 1);
+    let crdt = new Shelf({ base: 1 }, 1n);
 
     let fuzzer = new Fuzzer(this.config.nAdditions.fuzzerConfig);
     let content = fuzzer.generateContent();
 
     return () => {
       for (let [key, val] of Object.entries(content)) {
-        crdt.set([key], val, 1);
+        crdt.set([key], val, 1n);
       }
     };
   }
@@ -96,22 +96,22 @@ export class ShelfAwarenessBench extends BenchmarkEnvironment {
   testDeltaSize(firstValues, secondValues) {
     const sizes = {};
 
-    const shelf1 = new shelf.Awareness(firstValues, 1);
-    const shelf2 = new shelf.Awareness(secondValues, 2);
+    const shelf1 = new shelf.Awareness(firstValues, 1n);
+    const shelf2 = new shelf.Awareness(secondValues, 2n);
     let sv = shelf1.getStateVector();
     let delta = shelf2.getStateDelta(sv);
     sizes["Random Merge Update"] = delta.byteLength;
 
     // With only a single element changed
-    const shelf1Updated = new shelf.Awareness(firstValues, 1);
+    const shelf1Updated = new shelf.Awareness(firstValues, 1n);
     shelf1Updated.set(["test"], "delta");
     sv = shelf1.getStateVector();
     delta = shelf1Updated.getStateDelta(sv);
     sizes["Single Change Update"] = delta.byteLength;
 
     // Complete deletion
-    const deletionShelf = new shelf.Awareness({ contents: firstValues }, 1);
-    const deletionShelfCopy = new shelf.Awareness({ contents: firstValues }, 1);
+    const deletionShelf = new shelf.Awareness({ contents: firstValues }, 1n);
+    const deletionShelfCopy = new shelf.Awareness({ contents: firstValues }, 1n);
     deletionShelf.set(["contents"], {});
     sv = deletionShelfCopy.getStateVector();
     delta = deletionShelf.getStateDelta(sv);
@@ -120,18 +120,18 @@ export class ShelfAwarenessBench extends BenchmarkEnvironment {
   }
 
   testSizeAfterDeletion(values) {
-    const awareness = new shelf.Awareness({ contents: values }, 1);
+    const awareness = new shelf.Awareness({ contents: values }, 1n);
     awareness.set(["contents"], {});
     return { "Complete Deletion": shelfSizeOf(awareness) };
   }
 
   testCRDTSize(values) {
-    const awareness = new shelf.Awareness(values);
+    const awareness = new shelf.Awareness(values, 0n);
     return shelfSizeOf(awareness); // TODO update
   }
 
   testNAdditions() {
-    let crdt = new shelf.Awareness({ base: 1 }, 1);
+    let crdt = new shelf.Awareness({ base: 1 }, 1n);
 
     let fuzzer = new Fuzzer(this.config.nAdditions.fuzzerConfig);
     let content = fuzzer.generateContent();
@@ -146,8 +146,8 @@ export class ShelfAwarenessBench extends BenchmarkEnvironment {
   testMerge() {
     let smallFuzzer = new Fuzzer(this.config.merges.smallFuzzer);
     let largeFuzzer = new Fuzzer(this.config.merges.largeFuzzer);
-    let first = new shelf.Awareness(smallFuzzer.generateContent());
-    let second = new shelf.Awareness(largeFuzzer.generateContent());
+    let first = new shelf.Awareness(smallFuzzer.generateContent(), 1n);
+    let second = new shelf.Awareness(largeFuzzer.generateContent(), 2n);
 
     return () => {
       let sv = first.getStateVector();
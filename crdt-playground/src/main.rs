@@ -153,8 +153,7 @@ fn model(app: &App) -> Model {
     let id = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
-        .as_micros()
-        % 255;
+        .as_micros() as u64;
     let id: String = id.to_string();
     let cursor_pos = MouseCursor { x: 0.0, y: 0.0 };
     let mut shared_state = Doc::default();
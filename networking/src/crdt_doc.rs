@@ -1,6 +1,6 @@
 // use crate::traits::Mergeable;
 // use bincode;
-// use networking::Multicast;
+// use networking::{ClientId, Multicast};
 // use rand::Rng;
 // use serde::{Deserialize, Serialize};
 // use std::{collections::HashMap, ops::Deref};
@@ -67,7 +67,11 @@
 //     }
 
 //     pub fn apply_updates(&mut self) -> Result<(), String> {
-//         while let Some(message) = self.communicator.try_recv() {
+//         while let Some(message) = self
+//             .communicator
+//             .try_recv()
+//             .map_err(|err| format!("Could not receive updates: {err:?}"))?
+//         {
 //             match message {
 //                 DocMessage::<CRDT>::StateVector { clocks, sender }
 //                     if self.communicator.id != sender =>
@@ -140,10 +144,10 @@
 // enum DocMessage<T: DeltaCRDT> {
 //     StateVector {
 //         clocks: HashMap<String, T::StateVector>,
-//         sender: u8,
+//         sender: ClientId,
 //     },
 //     Delta {
 //         diff: HashMap<String, T::Delta>,
-//         recipient: u8,
+//         recipient: ClientId,
 //     },
 // }
@@ -2,10 +2,11 @@
 
 extern crate nanomsg;
 
-use nanomsg::{Error, Protocol, Socket};
+use nanomsg::{Endpoint, Error, Protocol, Socket};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use std::sync::Once;
 use std::thread;
 
 use std::io::Write;
@@ -14,44 +15,82 @@ const CLIENT_DEVICE_URL: &'static str = "ipc:///tmp/thesis_multicast_client.ipc"
 const SERVER_DEVICE_URL: &'static str = "ipc:///tmp/thesis_multicast_server.ipc";
 const TOPIC: &'static str = "topic";
 
+/// The device forwarding messages between all `Multicast`s lives for the rest of the process, so it is only
+/// started once.
+static DEVICE: Once = Once::new();
+
+/// Identifies a node on the network. Matches the client ids that shelf clocks record, so a node can use its
+/// network id for its edits.
+pub type ClientId = u64;
+
+/// Why a `try_recv` failed. An empty inbox is not an error, `try_recv` returns `Ok(None)` for it.
+#[derive(Debug)]
+pub enum RecvError {
+    /// The socket could not be read, e.g. because it is no longer usable.
+    Socket(Error),
+    /// A message arrived but could not be decoded as the requested type.
+    Decode(bincode::Error),
+}
+
 pub struct Multicast {
-    pub id: u8,
-    write_socket: Socket,
-    read_socket: Socket,
+    pub id: ClientId,
+    sender: Sender,
+    receiver: Receiver,
 }
 
 impl Multicast {
-    pub fn new(id: u8) -> Self {
+    pub fn new(id: ClientId) -> Self {
         let mut write_socket = Socket::new(Protocol::Pub).unwrap();
-        write_socket.connect(SERVER_DEVICE_URL).unwrap();
+        let write_endpoint = write_socket.connect(SERVER_DEVICE_URL).unwrap();
 
         let mut read_socket = Socket::new(Protocol::Sub).unwrap();
         read_socket.subscribe(TOPIC.as_bytes()).unwrap();
-        read_socket.connect(CLIENT_DEVICE_URL).unwrap();
-        thread::spawn(Self::connect_sockets);
+        let read_endpoint = read_socket.connect(CLIENT_DEVICE_URL).unwrap();
+        DEVICE.call_once(|| {
+            thread::spawn(Self::connect_sockets);
+        });
         Multicast {
             id,
-            write_socket,
-            read_socket,
+            sender: Sender {
+                id,
+                write_socket,
+                endpoint: Some(write_endpoint),
+            },
+            receiver: Receiver {
+                id,
+                read_socket,
+                endpoint: Some(read_endpoint),
+            },
         }
     }
 
-    pub fn try_recv<Message: Serialize + DeserializeOwned>(&mut self) -> Option<Message> {
-        let mut msg = Vec::new();
-        self.read_socket
-            .nb_read_to_end(&mut msg)
-            .ok()
-            .and_then(|_| bincode::deserialize::<Message>(&msg[TOPIC.len()..]).ok())
+    pub fn try_recv<Message: Serialize + DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<Message>, RecvError> {
+        self.receiver.try_recv()
+    }
+
+    pub fn drain<Message: Serialize + DeserializeOwned>(
+        &mut self,
+    ) -> Result<Vec<Message>, RecvError> {
+        self.receiver.drain()
     }
 
     pub fn send<Message: Serialize + DeserializeOwned>(&mut self, message: Message) {
-        let mut msg = Vec::new();
-        msg.clear();
-        msg.extend_from_slice(TOPIC.as_bytes());
-        // Lib0 Serde
-        let message = bincode::serialize(&message).unwrap();
-        msg.extend_from_slice(&message);
-        self.write_socket.write_all(&msg).unwrap();
+        self.sender.send(message)
+    }
+
+    /// Splits into halves that each own one socket, so that sending and receiving can happen on different threads.
+    pub fn split(self) -> (Sender, Receiver) {
+        (self.sender, self.receiver)
+    }
+
+    /// Disconnects both sockets, reporting errors that dropping the `Multicast` would ignore.
+    /// Closing a socket waits for queued messages to be sent, up to the socket's linger time.
+    pub fn close(self) -> Result<(), Error> {
+        let (sender, receiver) = self.split();
+        let sent = sender.close();
+        receiver.close().and(sent)
     }
 
     fn connect_sockets() -> Result<(), Error> {
@@ -67,6 +106,95 @@ impl Multicast {
     }
 }
 
+/// The sending half of a `Multicast`. Disconnects from the device when dropped.
+pub struct Sender {
+    pub id: ClientId,
+    write_socket: Socket,
+    endpoint: Option<Endpoint>,
+}
+
+impl Sender {
+    pub fn close(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        match self.endpoint.take() {
+            Some(mut endpoint) => endpoint.shutdown(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn send<Message: Serialize + DeserializeOwned>(&mut self, message: Message) {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(TOPIC.as_bytes());
+        // Lib0 Serde
+        let message = bincode::serialize(&message).unwrap();
+        msg.extend_from_slice(&message);
+        self.write_socket.write_all(&msg).unwrap();
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The receiving half of a `Multicast`. Disconnects from the device when dropped.
+pub struct Receiver {
+    pub id: ClientId,
+    read_socket: Socket,
+    endpoint: Option<Endpoint>,
+}
+
+impl Receiver {
+    pub fn close(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        match self.endpoint.take() {
+            Some(mut endpoint) => endpoint.shutdown(),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads the next message without blocking. Returns `Ok(None)` if none is waiting.
+    pub fn try_recv<Message: Serialize + DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<Message>, RecvError> {
+        let mut msg = Vec::new();
+        match self.read_socket.nb_read_to_end(&mut msg) {
+            Ok(_) => {}
+            Err(Error::TryAgain) => return Ok(None),
+            Err(err) => return Err(RecvError::Socket(err)),
+        }
+        bincode::deserialize::<Message>(&msg[TOPIC.len()..])
+            .map(Some)
+            .map_err(RecvError::Decode)
+    }
+
+    /// Reads every message that is waiting without blocking, in the order they arrived, so that they can be
+    /// inspected or processed in a different order. Stops at the first error, discarding the messages read
+    /// before it; use `try_recv` to handle errors message by message.
+    pub fn drain<Message: Serialize + DeserializeOwned>(
+        &mut self,
+    ) -> Result<Vec<Message>, RecvError> {
+        let mut messages = Vec::new();
+        while let Some(message) = self.try_recv()? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
 // #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 // pub struct Message {
 //     pub data: Vec<u8>,
@@ -82,9 +210,10 @@ mod test {
         time::Duration,
     };
 
+    use nanomsg::{Protocol, Socket};
     use serde::{Deserialize, Serialize};
 
-    use crate::Multicast;
+    use crate::{Multicast, Receiver, RecvError};
     #[derive(Serialize, Deserialize, Clone)]
     struct Message(isize);
 
@@ -95,14 +224,109 @@ mod test {
         let data = Message(1);
         com1.send(data.clone());
         thread::sleep(Duration::from_millis(1000));
-        if let Some(res) = com2.try_recv::<Message>() {
+        if let Some(res) = com2.try_recv::<Message>().unwrap() {
             assert_eq!(res.0, data.0);
         } else {
             panic!("Did not find data");
         }
         sleep(Duration::from_millis(2000));
-        if let Some(res) = com1.try_recv::<Message>() {
+        if let Some(res) = com1.try_recv::<Message>().unwrap() {
             panic!("Should not have received data: {:?}", res.0);
         }
     }
+
+    #[test]
+    fn test_split_across_threads() {
+        let (mut sender, _) = Multicast::new(1).split();
+        let (_, mut receiver) = Multicast::new(2).split();
+        let data = Message(2);
+
+        let reader = thread::spawn(move || {
+            for _ in 0..30 {
+                if let Some(res) = receiver.try_recv::<Message>().unwrap() {
+                    return Some(res.0);
+                }
+                sleep(Duration::from_millis(100));
+            }
+            None
+        });
+        let writer = thread::spawn(move || {
+            sleep(Duration::from_millis(1000));
+            sender.send(data);
+        });
+
+        writer.join().unwrap();
+        assert_eq!(reader.join().unwrap(), Some(2), "Did not find data");
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut com1 = Multicast::new(1);
+        let mut com2 = Multicast::new(2);
+        for i in 100..105 {
+            com1.send(Message(i));
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let received: Vec<isize> = com2
+            .drain::<Message>()
+            .unwrap()
+            .into_iter()
+            .map(|message| message.0)
+            // Other tests send through the same device at the same time
+            .filter(|i| *i >= 100)
+            .collect();
+        assert_eq!(received, [100, 101, 102, 103, 104]);
+        assert!(com2.drain::<Message>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_node_ids_beyond_u8() {
+        let com = Multicast::new(300);
+        assert_eq!(com.id, 300);
+        let (sender, receiver) = Multicast::new(u64::MAX).split();
+        assert_eq!((sender.id, receiver.id), (u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn test_recv_surfaces_socket_errors() {
+        let (_, mut receiver) = Multicast::new(1).split();
+        assert!(matches!(receiver.try_recv::<Message>(), Ok(None)));
+
+        // Publishers cannot receive, so reading fails instead of finding an empty inbox
+        let mut receiver = Receiver {
+            id: 2,
+            read_socket: Socket::new(Protocol::Pub).unwrap(),
+            endpoint: None,
+        };
+        assert!(matches!(
+            receiver.try_recv::<Message>(),
+            Err(RecvError::Socket(_))
+        ));
+    }
+
+    #[test]
+    fn test_shutdown_disconnects_endpoint() {
+        let (mut sender, _) = Multicast::new(1).split();
+        let (_, mut connected) = Multicast::new(2).split();
+        let (_, mut disconnected) = Multicast::new(3).split();
+        // `close` and `Drop` disconnect through `shutdown`, which leaves the socket itself open
+        disconnected.shutdown().unwrap();
+        assert!(disconnected.endpoint.is_none());
+        sleep(Duration::from_millis(1000));
+
+        sender.send(Message(200));
+        sleep(Duration::from_millis(1000));
+        let received = |receiver: &mut Receiver| {
+            receiver
+                .drain::<Message>()
+                .unwrap()
+                .into_iter()
+                // Other tests send through the same device at the same time
+                .any(|message| message.0 == 200)
+        };
+        assert!(received(&mut connected));
+        assert!(!received(&mut disconnected));
+        // Dropping after `close` shuts down again, which has nothing left to do
+        assert!(disconnected.shutdown().is_ok());
+    }
 }
@@ -31,7 +31,7 @@ fn main() {
         thread::sleep(Duration::from_millis(100));
         message.clear();
 
-        if let Some(message) = com2.try_recv::<Message>() {
+        if let Some(message) = com2.try_recv::<Message>().unwrap() {
             println!("{}, {:?}", message.text, message.other);
         }
     }
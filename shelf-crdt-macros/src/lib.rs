@@ -3,11 +3,81 @@ use proc_macro_roids::DeriveInputExt;
 use quote::quote;
 
 use syn::{
-    parse_macro_input, parse_quote, punctuated::Punctuated, token::Comma, DeriveInput, Field,
-    Ident, Type,
+    parse_macro_input, parse_quote, punctuated::Punctuated, token::Comma, Attribute, DeriveInput,
+    Field, Ident, Lit, LitStr, Meta, MetaNameValue, NestedMeta, Type,
 };
 
-#[proc_macro_derive(CRDT)]
+/// Options of a field, set with `#[crdt(..)]`.
+#[derive(Default)]
+struct FieldOptions {
+    /// `#[crdt(counter)]`
+    counter: bool,
+    /// `#[crdt(rename = "name")]`
+    rename: Option<LitStr>,
+}
+
+fn field_options(field: &Field) -> FieldOptions {
+    let mut options = FieldOptions::default();
+    let nested = field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("crdt"))
+        .flat_map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) => list.nested,
+            _ => panic!("Expected #[crdt(..)]"),
+        });
+    for option in nested {
+        match option {
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("counter") => {
+                options.counter = true
+            }
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                path,
+                lit: Lit::Str(name),
+                ..
+            })) if path.is_ident("rename") => options.rename = Some(name),
+            _ => panic!("Expected #[crdt(counter)] or #[crdt(rename = \"name\")]"),
+        }
+    }
+    options
+}
+
+/// Whether a field is marked with `#[crdt(counter)]`.
+fn is_counter(field: &Field) -> bool {
+    field_options(field).counter
+}
+
+/// The attributes of the generated state vector and delta field for `field`: its own attributes without the
+/// `#[crdt(..)]` ones, which are only valid on the struct deriving `CRDT`, and a serde rename if one was requested.
+fn generated_field_attrs(field: &Field) -> Vec<Attribute> {
+    let mut attrs = without_crdt_attrs(&field.attrs);
+    if let Some(name) = field_options(field).rename {
+        attrs.push(parse_quote!(#[serde(rename = #name)]));
+    }
+    attrs
+}
+
+/// Drops the `#[crdt(..)]` attributes, which are only valid on the struct deriving `CRDT`.
+fn without_crdt_attrs(attrs: &[Attribute]) -> Vec<Attribute> {
+    attrs
+        .iter()
+        .filter(|attr| !attr.path.is_ident("crdt"))
+        .cloned()
+        .collect()
+}
+
+/// Derives a CRDT backend where every field is a last writer wins register.
+/// Each register keeps the clock and client id of its last write. Writes with equal clocks are concurrent, so the
+/// one by the higher client id wins, whatever the values are. Since values are never compared, register fields
+/// only need to be `Clone` and serializable, so newtype wrappers like `struct Email(String)` need no ordering.
+///
+/// Integer fields marked with `#[crdt(counter)]` are grow-only counters instead: each client's increments are
+/// tracked separately so that merging replicas sums them. Decreasing a counter field is ignored.
+///
+/// `#[crdt(rename = "favNum")]` names the field differently in the serialized state vector and delta, e.g. to match
+/// a JS client. Options can be combined, as in `#[crdt(counter, rename = "likeCount")]`. A `#[serde(rename)]` on the
+/// field is carried over to the state vector and delta as well.
+#[proc_macro_derive(CRDT, attributes(crdt))]
 pub fn derive_crdt(input: TokenStream) -> TokenStream {
     let mut ast = parse_macro_input!(input as DeriveInput);
     ast.append_derives(parse_quote!(Serialize, Deserialize, Clone));
@@ -19,11 +89,6 @@ pub fn derive_crdt(input: TokenStream) -> TokenStream {
     let crdt_name = format!("{struct_name}CRDT");
     let crdt_name = syn::Ident::new(&crdt_name, struct_name.span());
 
-    let imports = quote!(
-        use shelf_crdt::traits;
-        use serde;
-    );
-
     let fields = if let syn::Data::Struct(syn::DataStruct {
         fields: syn::Fields::Named(syn::FieldsNamed { ref named, .. }),
         ..
@@ -38,7 +103,14 @@ pub fn derive_crdt(input: TokenStream) -> TokenStream {
         .iter()
         .map(|field| {
             let mut sv_field = field.clone();
-            sv_field.ty = Type::Verbatim(quote!(usize));
+            let ty = &field.ty;
+            sv_field.attrs = generated_field_attrs(field);
+            // A counter's state vector holds the count of each client, a register's its clock and last writer
+            sv_field.ty = if is_counter(field) {
+                Type::Verbatim(quote!(std::collections::HashMap<shelf_crdt::clock::ClientId, #ty>))
+            } else {
+                Type::Verbatim(quote!((usize, shelf_crdt::clock::ClientId)))
+            };
             sv_field
         })
         .collect();
@@ -47,8 +119,13 @@ pub fn derive_crdt(input: TokenStream) -> TokenStream {
         .iter()
         .map(|field| {
             let mut delta_field = field.clone();
-            let ty = delta_field.ty;
-            delta_field.ty = Type::Verbatim(quote!(std::option::Option<(#ty, usize)>));
+            let ty = &field.ty;
+            delta_field.attrs = generated_field_attrs(field);
+            delta_field.ty = if is_counter(field) {
+                Type::Verbatim(quote!(std::option::Option<std::collections::HashMap<shelf_crdt::clock::ClientId, #ty>>))
+            } else {
+                Type::Verbatim(quote!(std::option::Option<(#ty, (usize, shelf_crdt::clock::ClientId))>))
+            };
             delta_field
         })
         .collect();
@@ -67,6 +144,7 @@ pub fn derive_crdt(input: TokenStream) -> TokenStream {
             struct #crdt_name {
                 state: #struct_name,
                 clocks: #state_vector_name,
+                client_id: shelf_crdt::clock::ClientId,
             }
         ),
     ];
@@ -83,34 +161,67 @@ pub fn derive_crdt(input: TokenStream) -> TokenStream {
         .iter()
         .map(|field| field.ident.as_ref().unwrap().clone())
         .collect();
+    let counters: Vec<bool> = fields.iter().map(is_counter).collect();
 
-    let merge_delta_components = props.iter().map(|field_name| {
-        let merge = quote! {
-            if let Some((val, time)) = other.#field_name {
-                match self.clocks.#field_name.cmp(&time) {
-                    std::cmp::Ordering::Less => {
-                        self.state.#field_name = val;
-                        self.clocks.#field_name = time;
+    let merge_delta_components = props.iter().zip(&counters).map(|(field_name, is_counter)| {
+        if *is_counter {
+            return quote! {
+                if let Some(counts) = other.#field_name {
+                    for (client_id, count) in counts {
+                        let local = self.clocks.#field_name.entry(client_id).or_default();
+                        if *local < count {
+                            self.state.#field_name += count - *local;
+                            *local = count;
+                        }
                     }
-                    std::cmp::Ordering::Equal if self.state.#field_name < val => {
-                        self.state.#field_name = val;
-                    }
-                    _ => (),
+                }
+            };
+        }
+        // Clocks are ordered first, and concurrent writes by the client id of the writer
+        quote! {
+            if let Some((val, write)) = other.#field_name {
+                if self.clocks.#field_name < write {
+                    self.state.#field_name = val;
+                    self.clocks.#field_name = write;
                 }
             }
-        };
-        merge
+        }
     });
     // TODO: This will force override, fix it later. Shouldn't be a problem for non overlapping users.
     // This should be fine if we consider a merge to override previous values.
-    let merge_data_components = props.iter().map(|prop| {
+    let merge_data_components = props.iter().zip(&counters).map(|(prop, is_counter)| {
+        if *is_counter {
+            // Local changes to a counter are increments by this client
+            return quote! {
+                if other.#prop > self.state.#prop {
+                    *self.clocks.#prop.entry(self.client_id).or_default() +=
+                        other.#prop - self.state.#prop;
+                    self.state.#prop = other.#prop;
+                }
+            };
+        }
         quote! {
-                self.clocks.#prop += 1;
+                self.clocks.#prop = (self.clocks.#prop.0 + 1, self.client_id);
                 self.state.#prop = other.#prop.clone();
         }
     });
 
-    let delta_components = props.iter().map(|name| {
+    let delta_components = props.iter().zip(&counters).map(|(name, is_counter)| {
+        if *is_counter {
+            return quote!(
+                let #name: std::collections::HashMap<_, _> = self
+                    .clocks
+                    .#name
+                    .iter()
+                    .filter(|(client_id, count)| match sv.#name.get(client_id) {
+                        Some(peer_count) => peer_count < count,
+                        None => true,
+                    })
+                    .map(|(client_id, count)| (*client_id, *count))
+                    .collect();
+                let #name = (!#name.is_empty()).then(|| #name);
+            );
+        }
         quote!(
             let #name = if self.clocks.#name >= sv.#name {
                 Some((self.state.#name.clone(), self.clocks.#name))
@@ -120,64 +231,83 @@ pub fn derive_crdt(input: TokenStream) -> TokenStream {
         )
     });
 
+    // The impls are scoped in an anonymous const, so that deriving several structs in one module does not import
+    // the same names twice.
     let expanded = quote! {
-        #imports
         #(#structs)*
 
-        impl traits::Mergeable<#delta_name> for #crdt_name {
-            fn merge(&mut self, other:  #delta_name) {
-                #(#merge_delta_components)*
+        const _: () = {
+            use shelf_crdt::traits;
+
+            impl traits::Mergeable<#delta_name> for #crdt_name {
+                fn merge(mut self, other:  #delta_name) -> Self {
+                    #(#merge_delta_components)*
+                    self
+                }
             }
-        }
 
-        impl traits::Mergeable<#struct_name> for #crdt_name {
-            fn merge(&mut self, other:  #struct_name) {
+            impl traits::Mergeable<#struct_name> for #crdt_name {
+                fn merge(mut self, other:  #struct_name) -> Self {
 
-                #(#merge_data_components)*
+                    #(#merge_data_components)*
+                    self
+                }
             }
-        }
 
-        impl std::ops::Deref for #crdt_name {
-            type Target = #struct_name;
+            impl #crdt_name {
+                /// Creates a CRDT for `state` that attributes local counter increments to `client_id`.
+                pub fn new_for_client(state: #struct_name, client_id: shelf_crdt::clock::ClientId) -> Self {
+                    #crdt_name {
+                        clocks: #state_vector_name::default(),
+                        state,
+                        client_id,
+                    }
+                }
+            }
 
-            fn deref(&self) -> &Self::Target {
-                &self.state
+            impl std::ops::Deref for #crdt_name {
+                type Target = #struct_name;
+
+                fn deref(&self) -> &Self::Target {
+                    &self.state
+                }
             }
-        }
 
 
-        impl traits::DeltaCRDT for #crdt_name {
-            type Delta = #delta_name;
-            type StateVector = #state_vector_name;
+            impl traits::DeltaCRDT for #crdt_name {
+                type Delta = #delta_name;
+                type StateVector = #state_vector_name;
 
-            fn get_state_vector(&self) -> Self::StateVector {
-                self.clocks.clone()
-            }
+                fn get_state_vector(&self) -> Self::StateVector {
+                    self.clocks.clone()
+                }
 
-            fn get_state_delta(&self, sv: &Self::StateVector) -> Option<Self::Delta> {
-                #(#delta_components)*
+                fn get_state_delta(&self, sv: &Self::StateVector) -> Option<Self::Delta> {
+                    #(#delta_components)*
 
-                Some(Self::Delta { #(#props,)* })
+                    Some(Self::Delta { #(#props,)* })
+                }
             }
-        }
 
-        impl Clone for #state_vector_name {
-            fn clone(&self) -> Self {
-                Self {
-                    #(#props : self.#props.clone(),)*
+            impl Clone for #state_vector_name {
+                fn clone(&self) -> Self {
+                    Self {
+                        #(#props : self.#props.clone(),)*
+                    }
                 }
             }
-        }
 
-        impl traits::CRDTBackend for #struct_name {
-            type Backend = #crdt_name;
-            fn new_crdt(&self) -> Self::Backend {
-                #crdt_name {
-                    clocks: #state_vector_name::default(), state: self.clone(),
+            impl traits::CRDTBackend for #struct_name {
+                type Backend = #crdt_name;
+                fn new_crdt(&self) -> Self::Backend {
+                    use std::hash::{BuildHasher, Hasher};
+                    let client_id = std::collections::hash_map::RandomState::new()
+                        .build_hasher()
+                        .finish();
+                    #crdt_name::new_for_client(self.clone(), client_id)
                 }
             }
-        }
-
+        };
     };
     expanded.into()
 }
@@ -1,5 +1,4 @@
 use serde::{Deserialize, Serialize};
-use shelf_crdt::adjacent_crdt::Doc;
 use shelf_crdt::traits::{CRDTBackend, DeltaCRDT, Mergeable};
 use shelf_crdt_macros::CRDT;
 
@@ -24,8 +23,43 @@ mod tests {
         name: String,
         fav_num: usize,
     }
+
+    #[derive(Clone, Default, Serialize, Deserialize, CRDT)]
+    struct Post {
+        title: String,
+        #[crdt(counter)]
+        likes: usize,
+    }
+    #[derive(Clone, Default, Serialize, Deserialize, CRDT)]
+    struct Profile {
+        #[crdt(rename = "favNum")]
+        fav_num: usize,
+        #[crdt(counter, rename = "visitCount")]
+        visit_count: usize,
+    }
+    /// A wrapper without `PartialOrd`
+    #[derive(Clone, Default, Serialize, Deserialize, PartialEq, Debug)]
+    struct Email(String);
+
+    #[derive(Clone, Default, Serialize, Deserialize, CRDT)]
+    struct Account {
+        email: Email,
+    }
     use super::*;
 
+    /// Sends each replica the changes it is missing from the other.
+    fn sync<C>(a: &mut C, b: &mut C)
+    where
+        C: DeltaCRDT + Mergeable<C::Delta> + Default,
+    {
+        let sv = a.get_state_vector();
+        let sv2 = b.get_state_vector();
+        let delta = a.get_state_delta(&sv2).unwrap();
+        let delta2 = b.get_state_delta(&sv).unwrap();
+        *b = std::mem::take(b).merge(delta);
+        *a = std::mem::take(a).merge(delta2);
+    }
+
     #[test]
     fn basic_use() {
         // Init data
@@ -42,12 +76,12 @@ mod tests {
         my_data.fav_num = 20;
 
         // Update the crdt
-        crdt.merge(my_data);
+        crdt = crdt.merge(my_data);
 
         // sync data
         let sv = crdt2.get_state_vector();
         let delta = crdt.get_state_delta(&sv).unwrap();
-        crdt2.merge(delta);
+        crdt2 = crdt2.merge(delta);
 
         assert_eq!(&crdt2.state.name, "Jason");
         assert_eq!(crdt2.state.fav_num, 20);
@@ -71,8 +105,8 @@ mod tests {
         data2.name = "Alfred".to_string();
 
         // Update the crdt
-        crdt.merge(my_data);
-        crdt2.merge(data2);
+        crdt = crdt.merge(my_data);
+        crdt2 = crdt2.merge(data2);
 
         // sync data
         let sv = crdt.get_state_vector();
@@ -81,28 +115,121 @@ mod tests {
         let delta = crdt.get_state_delta(&sv2).unwrap();
         let delta2 = crdt2.get_state_delta(&sv).unwrap();
 
-        crdt2.merge(delta);
-        crdt.merge(delta2);
+        crdt2 = crdt2.merge(delta);
+        crdt = crdt.merge(delta2);
 
         assert_eq!(&crdt2.state.name, &crdt.state.name,);
         assert_eq!(crdt2.state.fav_num, crdt.state.fav_num);
     }
     #[test]
-    fn test_doc() {
-        let mut doc = Doc::default();
-        let mut receiver = Doc::default();
-        let mut data = MyData {
-            name: "John".to_string(),
-            fav_num: 7,
+    fn concurrent_writes_resolve_by_client_id() {
+        /// Client 1 writes `first` and client 2 writes `second` concurrently, returns the name both agree on.
+        fn resolve(first: &str, second: &str) -> String {
+            let data = MyData {
+                name: "John".to_string(),
+                fav_num: 7,
+            };
+            let mut crdt = MyDataCRDT::new_for_client(data.clone(), 1);
+            let mut crdt2 = MyDataCRDT::new_for_client(data.clone(), 2);
+
+            // Both clients write once, so their clocks are equal
+            crdt = crdt.merge(MyData {
+                name: first.to_string(),
+                ..data.clone()
+            });
+            crdt2 = crdt2.merge(MyData {
+                name: second.to_string(),
+                ..data
+            });
+
+            sync(&mut crdt, &mut crdt2);
+            assert_eq!(crdt.state.name, crdt2.state.name);
+            crdt.state.name.clone()
+        }
+
+        // The higher client id wins, not the larger value
+        assert_eq!(resolve("Zeb", "Amy"), "Amy");
+        assert_eq!(resolve("Amy", "Zeb"), "Zeb");
+    }
+    #[test]
+    fn counter_sums_increments() {
+        let post = Post {
+            title: "Hello".to_string(),
+            likes: 2,
         };
-        let id = "test".to_string();
-        doc.register(id.clone(), data.clone());
-        receiver.register(id.clone(), data.clone());
+        let mut crdt = PostCRDT::new_for_client(post.clone(), 1);
+        let mut crdt2 = PostCRDT::new_for_client(post.clone(), 2);
+
+        // Each client likes the post concurrently
+        crdt = crdt.merge(Post {
+            likes: 5,
+            ..post.clone()
+        });
+        crdt2 = crdt2.merge(Post { likes: 4, ..post });
+
+        let sv = crdt.get_state_vector();
+        sync(&mut crdt, &mut crdt2);
 
-        data.name = "Jason".to_string();
-        data.fav_num = 20;
-        doc.update(&id, &data).unwrap();
+        assert_eq!(crdt.state.likes, 7);
+        assert_eq!(crdt2.state.likes, 7);
 
-        // receiver.apply_updates(&id).unwrap();
+        // Receiving the same increments again changes nothing
+        let delta2 = crdt2.get_state_delta(&sv).unwrap();
+        crdt = crdt.merge(delta2);
+        assert_eq!(crdt.state.likes, 7);
+    }
+    #[test]
+    fn renamed_fields() {
+        let profile = Profile {
+            fav_num: 7,
+            visit_count: 0,
+        };
+        let mut crdt = ProfileCRDT::new_for_client(profile.clone(), 1);
+        let crdt2 = ProfileCRDT::new_for_client(profile, 2);
+        crdt = crdt.merge(Profile {
+            fav_num: 20,
+            visit_count: 3,
+        });
+
+        let delta = crdt.get_state_delta(&crdt2.get_state_vector()).unwrap();
+        let delta = serde_json::to_value(&delta).unwrap();
+        assert_eq!(delta["favNum"][0], 20);
+        assert_eq!(delta["visitCount"]["1"], 3);
+        assert!(delta.get("fav_num").is_none());
+
+        let sv = serde_json::to_value(crdt.get_state_vector()).unwrap();
+        assert!(sv.get("favNum").is_some() && sv.get("visitCount").is_some());
+
+        // A delta written by a JS client uses the renamed fields as well
+        let delta: ProfileDelta = serde_json::from_value(delta).unwrap();
+        let crdt2 = crdt2.merge(delta);
+        assert_eq!(crdt2.state.fav_num, 20);
+        assert_eq!(crdt2.state.visit_count, 3);
+    }
+    #[test]
+    fn wrapper_fields_need_no_ordering() {
+        let account = Account {
+            email: Email("a@example.org".to_string()),
+        };
+        let mut crdt = AccountCRDT::new_for_client(account.clone(), 1);
+        let mut crdt2 = AccountCRDT::new_for_client(account, 2);
+        crdt = crdt.merge(Account {
+            email: Email("b@example.org".to_string()),
+        });
+
+        let delta = crdt.get_state_delta(&crdt2.get_state_vector()).unwrap();
+        crdt2 = crdt2.merge(delta);
+        assert_eq!(crdt2.state.email, Email("b@example.org".to_string()));
+
+        // Concurrent writes with equal clocks are where values would have to be ordered, they resolve by client id
+        crdt = crdt.merge(Account {
+            email: Email("z@example.org".to_string()),
+        });
+        crdt2 = crdt2.merge(Account {
+            email: Email("c@example.org".to_string()),
+        });
+        sync(&mut crdt, &mut crdt2);
+        assert_eq!(crdt.state.email, Email("c@example.org".to_string()));
+        assert_eq!(crdt2.state.email, Email("c@example.org".to_string()));
     }
 }
@@ -0,0 +1,218 @@
+//! Throughput of the core Shelf operations across shelf shapes.
+//!
+//! Run a single group with e.g. `cargo bench -p shelf-crdt --bench shelf -- merge`
+//! or a single shape with `cargo bench -p shelf-crdt --bench shelf -- merge/flat_10k`.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, SeedableRng};
+use serde_json::Value as JSON;
+use shelf_crdt::clock::{DotClock, DotClockGenerator, LamportTimestamp, LamportTimestampGenerator};
+use shelf_crdt::json::Value;
+use shelf_crdt::shelf_fuzzer::ShelfFuzzer;
+use shelf_crdt::traits::{DeltaCRDT, Mergeable};
+use shelf_crdt::wrap_crdt::{Awareness, Shelf};
+use std::ops::Range;
+
+type BenchShelf = Shelf<Value, LamportTimestamp, DotClock>;
+
+struct Shape {
+    name: &'static str,
+    depth_range: Range<usize>,
+    branch_range: Range<usize>,
+    value_range: Range<usize>,
+}
+
+fn shapes() -> Vec<Shape> {
+    vec![
+        Shape {
+            name: "flat_1k",
+            depth_range: 0..1,
+            branch_range: 0..1,
+            value_range: 1000..1001,
+        },
+        Shape {
+            name: "flat_10k",
+            depth_range: 0..1,
+            branch_range: 0..1,
+            value_range: 10000..10001,
+        },
+        Shape {
+            name: "deep",
+            depth_range: 5..6,
+            branch_range: 3..4,
+            value_range: 5..6,
+        },
+    ]
+}
+
+fn fuzzer(shape: &Shape, seed: u64) -> ShelfFuzzer {
+    ShelfFuzzer {
+        rng: StdRng::seed_from_u64(seed),
+        depth_range: shape.depth_range.clone(),
+        branch_range: shape.branch_range.clone(),
+        value_range: shape.value_range.clone(),
+    }
+}
+
+/// Two replicas of the same shape written by different clients.
+fn replicas(shape: &Shape) -> (BenchShelf, BenchShelf) {
+    let receiver = BenchShelf::try_from(fuzzer(shape, 1).generate_json_shelf(1)).unwrap();
+    let sender = BenchShelf::try_from(fuzzer(shape, 2).generate_json_shelf(2)).unwrap();
+    (receiver, sender)
+}
+
+fn values(shape: &Shape) -> JSON {
+    fuzzer(shape, 1).generate_json_values()
+}
+
+fn bench_merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge");
+    for shape in shapes() {
+        let (receiver, sender) = replicas(&shape);
+        group.bench_function(BenchmarkId::from_parameter(shape.name), |b| {
+            b.iter_batched(
+                || (receiver.clone(), sender.clone()),
+                |(receiver, sender)| receiver.merge(sender),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// A copy of `shelf` where every `nth` leaf was edited by another client, as if it had synced and moved ahead.
+fn edited(shelf: &BenchShelf, nth: usize) -> BenchShelf {
+    let mut leaves = 0;
+    shelf
+        .clone()
+        .convert_clocks(&mut |clock| clock, &mut |_, clock: DotClock| {
+            leaves += 1;
+            if leaves % nth == 0 {
+                DotClock {
+                    client_id: 2,
+                    clock: clock.clock + 1,
+                }
+            } else {
+                clock
+            }
+        })
+}
+
+/// Merges of deltas into a receiver. `behind` is a receiver that only lags the sender, so every entry in the
+/// delta replaces the receiver's in place, while `concurrent` merges replicas that both made edits.
+fn bench_merge_delta(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge_delta");
+    for shape in shapes() {
+        let (receiver, concurrent) = replicas(&shape);
+        let sender = edited(&receiver, 10);
+        let cases = [("behind", &sender), ("concurrent", &concurrent)];
+        for (case, sender) in cases {
+            let delta = sender.get_state_delta(&receiver.get_state_vector());
+            let delta = match delta {
+                Some(delta) => delta,
+                None => continue,
+            };
+            group.bench_function(BenchmarkId::new(case, shape.name), |b| {
+                b.iter_batched(
+                    || (receiver.clone(), delta.clone()),
+                    |(receiver, delta)| receiver.merge(delta),
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Merges of 10 deltas from peers that each edited different leaves. `merge_all` merges them in one by one, while
+/// `combined` first merges the deltas with each other, to check that doing so still does not pay off.
+fn bench_merge_all(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge_all");
+    for shape in shapes() {
+        let (receiver, _) = replicas(&shape);
+        let sv = receiver.get_state_vector();
+        let deltas: Vec<BenchShelf> = (0..10)
+            .filter_map(|i| edited(&receiver, 10 + i).get_state_delta(&sv))
+            .collect();
+        group.bench_function(BenchmarkId::new("merge_all", shape.name), |b| {
+            b.iter_batched(
+                || (receiver.clone(), deltas.clone()),
+                |(receiver, deltas)| receiver.merge_all(deltas),
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("combined", shape.name), |b| {
+            b.iter_batched(
+                || (receiver.clone(), deltas.clone()),
+                |(receiver, deltas)| match deltas.into_iter().reduce(Mergeable::merge) {
+                    Some(delta) => receiver.merge(delta),
+                    None => receiver,
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_state_vector(c: &mut Criterion) {
+    let mut group = c.benchmark_group("state_vector");
+    for shape in shapes() {
+        let (shelf, _) = replicas(&shape);
+        group.bench_function(BenchmarkId::from_parameter(shape.name), |b| {
+            b.iter(|| shelf.get_state_vector())
+        });
+    }
+    group.finish();
+}
+
+fn bench_state_delta(c: &mut Criterion) {
+    let mut group = c.benchmark_group("state_delta");
+    for shape in shapes() {
+        let (receiver, sender) = replicas(&shape);
+        let sv = receiver.get_state_vector();
+        group.bench_function(BenchmarkId::from_parameter(shape.name), |b| {
+            b.iter(|| sender.get_state_delta(&sv))
+        });
+    }
+    group.finish();
+}
+
+fn bench_from_json_values(c: &mut Criterion) {
+    let mut group = c.benchmark_group("from_json_values");
+    for shape in shapes() {
+        let json = values(&shape);
+        group.bench_function(BenchmarkId::new("shelf", shape.name), |b| {
+            b.iter_batched(
+                || json.clone(),
+                |json| {
+                    BenchShelf::from_json_values(
+                        json,
+                        &mut LamportTimestampGenerator,
+                        &mut DotClockGenerator::new(1),
+                    )
+                    .unwrap()
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("awareness", shape.name), |b| {
+            b.iter_batched(
+                || json.clone(),
+                |json| Awareness::from_json_values(json, 1).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_merge,
+    bench_merge_delta,
+    bench_merge_all,
+    bench_state_vector,
+    bench_state_delta,
+    bench_from_json_values
+);
+criterion_main!(benches);
@@ -4,16 +4,25 @@ use serde_json::{self, Value as JSON};
 
 use std::clone::Clone;
 use std::cmp::Ordering;
-use std::collections::hash_map::DefaultHasher;
 use std::fmt::Debug;
 use std::fmt::Display;
-use std::hash::{Hash, Hasher};
+use std::hash::Hash;
 
-use crate::traits::ClockGenerator;
+use crate::stable_hash::stable_hash;
+use crate::traits::{ClockGenerator, IdGenerator};
+
+/// Identifies the client, or node, that made an edit. The same type is used on every layer, from clocks to the
+/// network, so ids never get truncated on the way through.
+pub type ClientId = u64;
 
 // Gets the logical clock component of the clock
 pub trait LogicalClock {
     fn get_logical_clock(&self) -> usize;
+
+    // Gets the client that made the edit, for clocks that record it
+    fn get_client_id(&self) -> Option<ClientId> {
+        None
+    }
 }
 
 #[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Copy, Debug, Serialize, Deserialize)]
@@ -70,6 +79,16 @@ impl TryFrom<JSON> for LamportTimestamp {
     }
 }
 
+/// Picks client ids at random. Collisions are unlikely with 64 bits, use a coordinated `IdGenerator` where
+/// ids must be guaranteed unique.
+pub struct RandomIdGenerator;
+
+impl IdGenerator for RandomIdGenerator {
+    fn next_id(&mut self) -> ClientId {
+        rand::random()
+    }
+}
+
 pub struct LamportTimestampGenerator;
 
 impl ClockGenerator for LamportTimestampGenerator {
@@ -86,30 +105,47 @@ impl ClockGenerator for LamportTimestampGenerator {
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct DotClock {
-    pub client_id: usize,
+    pub client_id: ClientId,
     pub clock: usize,
 }
 
 impl DotClock {
-    pub fn new(client_id: usize) -> Self {
+    pub fn new(client_id: ClientId) -> Self {
         DotClock {
             client_id,
             clock: 0,
         }
     }
 
-    pub fn increment(&self, client_id: usize) -> Self {
+    pub fn increment(&self, client_id: ClientId) -> Self {
         DotClock {
             client_id,
             clock: self.clock + 1,
         }
     }
+
+    /// Whether both clocks mark the same edit, i.e. the same client at the same logical time.
+    /// This is what `==` checks, use it where the intent should be explicit.
+    pub fn same_event(&self, other: &Self) -> bool {
+        self == other
+    }
+
+    /// Whether both clocks are at the same logical time, regardless of which client made the edit.
+    /// Two such clocks from different clients are concurrent, so use this to find edits that need a tie-break
+    /// rather than to deduplicate them.
+    pub fn logical_eq(&self, other: &Self) -> bool {
+        self.clock == other.clock
+    }
 }
 
 impl LogicalClock for DotClock {
     fn get_logical_clock(&self) -> usize {
         self.clock
     }
+
+    fn get_client_id(&self) -> Option<ClientId> {
+        Some(self.client_id)
+    }
 }
 
 impl Default for DotClock {
@@ -177,8 +213,7 @@ impl TryFrom<JSON> for DotClock {
                     (JSON::Number(client_id), JSON::Number(clock)) => {
                         let client_id = client_id
                             .as_u64()
-                            .ok_or(format!("Could not parse client_id from {client_id}"))?
-                            as usize;
+                            .ok_or(format!("Could not parse client_id from {client_id}"))?;
                         let clock = clock
                             .as_u64()
                             .ok_or(format!("Could not parse clock from {clock}"))?
@@ -201,11 +236,11 @@ impl From<DotClock> for JSON {
 }
 
 pub struct DotClockGenerator {
-    client_id: usize,
+    client_id: ClientId,
 }
 
 impl DotClockGenerator {
-    pub fn new(client_id: usize) -> Self {
+    pub fn new(client_id: ClientId) -> Self {
         DotClockGenerator { client_id }
     }
 }
@@ -220,11 +255,7 @@ impl ClockGenerator for DotClockGenerator {
     }
 
     fn next_clock(&mut self, clock: Self::Clock) -> Self::Clock {
-        let DotClock { clock, .. } = clock;
-        DotClock {
-            client_id: self.client_id,
-            clock,
-        }
+        clock.increment(self.client_id)
     }
 }
 
@@ -237,19 +268,12 @@ pub struct SecureClock {
 
 impl SecureClock {
     pub fn new<T: Hash>(value: &T, clock: usize) -> Self {
-        let mut hasher = DefaultHasher::new(); // TODO use a different hasher
-        let pair = (clock, value);
-        pair.hash(&mut hasher);
-        let hash = hasher.finish();
+        let hash = stable_hash(&(clock, value));
         Self { clock, hash }
     }
 
     pub fn verify(&self, value: &impl Hash) -> bool {
-        let mut hasher = DefaultHasher::new(); // TODO use a different hasher
-        let pair = (self.clock, value);
-        pair.hash(&mut hasher);
-        let hash = hasher.finish();
-        self.hash == hash
+        self.hash == stable_hash(&(self.clock, value))
     }
 
     pub fn next(&self, value: &impl Hash) -> Self {
@@ -363,6 +387,13 @@ where
             ShelfClock::ValueClock(v) => v.get_logical_clock(),
         }
     }
+
+    fn get_client_id(&self) -> Option<ClientId> {
+        match &self {
+            ShelfClock::MapClock(m) => m.get_client_id(),
+            ShelfClock::ValueClock(v) => v.get_client_id(),
+        }
+    }
 }
 
 impl<'a, M, V> PartialEq for ShelfClock<'a, M, V>
@@ -394,6 +425,65 @@ where
     }
 }
 
+/// An owned `ShelfClock`, for keeping a clock around independently of the shelf it came from.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum OwnedShelfClock<M, V> {
+    MapClock(M),
+    ValueClock(V),
+}
+
+impl<M, V> OwnedShelfClock<M, V> {
+    pub fn as_shelf_clock(&self) -> ShelfClock<'_, M, V> {
+        match self {
+            OwnedShelfClock::MapClock(m) => ShelfClock::MapClock(m),
+            OwnedShelfClock::ValueClock(v) => ShelfClock::ValueClock(v),
+        }
+    }
+}
+
+impl<'a, M: Clone, V: Clone> From<ShelfClock<'a, M, V>> for OwnedShelfClock<M, V> {
+    fn from(clock: ShelfClock<'a, M, V>) -> Self {
+        match clock {
+            ShelfClock::MapClock(m) => OwnedShelfClock::MapClock(m.clone()),
+            ShelfClock::ValueClock(v) => OwnedShelfClock::ValueClock(v.clone()),
+        }
+    }
+}
+
+impl<M, V> LogicalClock for OwnedShelfClock<M, V>
+where
+    M: LogicalClock,
+    V: LogicalClock,
+{
+    fn get_logical_clock(&self) -> usize {
+        self.as_shelf_clock().get_logical_clock()
+    }
+
+    fn get_client_id(&self) -> Option<ClientId> {
+        self.as_shelf_clock().get_client_id()
+    }
+}
+
+impl<M, V> PartialEq for OwnedShelfClock<M, V>
+where
+    M: PartialEq + PartialEq<V>,
+    V: PartialEq + PartialEq<M>,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.as_shelf_clock() == other.as_shelf_clock()
+    }
+}
+
+impl<M, V> PartialOrd for OwnedShelfClock<M, V>
+where
+    M: PartialOrd + PartialOrd<V>,
+    V: PartialOrd + PartialOrd<M>,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.as_shelf_clock().partial_cmp(&other.as_shelf_clock())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -414,4 +504,67 @@ mod tests {
         // Inequality with different content
         assert_ne!(SecureClock::new(&2, 6), SecureClock::new(&1, 6));
     }
+
+    #[test]
+    fn test_dot_clock_equality() {
+        let a = DotClock {
+            client_id: 1,
+            clock: 3,
+        };
+        let b = DotClock {
+            client_id: 2,
+            clock: 3,
+        };
+        assert!(a.same_event(&a));
+        assert!(a.logical_eq(&a));
+
+        // Same logical time from different clients
+        assert!(!a.same_event(&b));
+        assert!(a.logical_eq(&b));
+        assert_eq!(a.partial_cmp(&b), None);
+
+        let later = a.increment(1);
+        assert!(!a.same_event(&later));
+        assert!(!a.logical_eq(&later));
+    }
+
+    #[test]
+    fn test_large_client_ids() {
+        for client_id in [300, u64::MAX] {
+            let clock = DotClock {
+                client_id,
+                clock: 1,
+            };
+            assert_eq!(clock.get_client_id(), Some(client_id));
+            assert_eq!(DotClock::try_from(JSON::from(clock)), Ok(clock));
+            let bytes = bincode::serialize(&clock).unwrap();
+            assert_eq!(bincode::deserialize::<DotClock>(&bytes).unwrap(), clock);
+        }
+    }
+
+    #[test]
+    fn test_owned_shelf_clock() {
+        let map_clock = LamportTimestamp(2);
+        let value_clock = DotClock {
+            client_id: 1,
+            clock: 3,
+        };
+        let owned_map: OwnedShelfClock<LamportTimestamp, DotClock> =
+            ShelfClock::MapClock(&map_clock).into();
+        let owned_value: OwnedShelfClock<LamportTimestamp, DotClock> =
+            ShelfClock::ValueClock(&value_clock).into();
+
+        assert_eq!(owned_map.get_logical_clock(), 2);
+        assert_eq!(owned_value.get_logical_clock(), 3);
+        assert!(owned_map < owned_value);
+        assert_eq!(owned_map, OwnedShelfClock::MapClock(LamportTimestamp(2)));
+        assert_ne!(owned_map, owned_value);
+
+        // Same logical time on clocks of different types cannot be ordered, like borrowed clocks
+        let concurrent = OwnedShelfClock::ValueClock(DotClock {
+            client_id: 1,
+            clock: 2,
+        });
+        assert_eq!(owned_map.partial_cmp(&concurrent), None);
+    }
 }
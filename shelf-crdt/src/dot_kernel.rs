@@ -1,6 +1,8 @@
 use std::cmp::Ordering;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
+
+use crate::clock::ClientId;
 struct ORSet;
 struct MVMap;
 
@@ -31,10 +33,8 @@ impl<T: PartialEq> DotKernel<T> {
                 entries.remove(&dot);
             });
 
-        DotKernel {
-            context: self.context.merge(other.context),
-            entries,
-        }
+        let (context, _) = self.context.merge(other.context);
+        DotKernel { context, entries }
     }
 
     fn insert(&mut self, client_id: ClientId, value: T) {
@@ -50,6 +50,12 @@ impl<T: PartialEq> DotKernel<T> {
 struct DotContext {
     integration_bound: VectorClock,
     detached_dots: HashSet<Dot>,
+    /// Bounds the detached dots for peers that deliver dots out of order or drop some. Compaction evicts once more
+    /// dots than this are detached, dropping the dots with the largest gap to the integration bound until this many
+    /// are left. `usize::MAX` never evicts.
+    max_detached: usize,
+    /// Once compaction evicts, detached dots with more missing dots before them than this are dropped as well.
+    max_gap: Option<Clock>,
 }
 
 impl DotContext {
@@ -67,8 +73,9 @@ impl DotContext {
         Dot { client_id, clock }
     }
 
-    /// Combines the information in two dot contexts.
-    fn merge(self, other: DotContext) -> Self {
+    /// Combines the information in two dot contexts, evicting with the limits of `self`.
+    /// Returns the merged context and how many detached dots were evicted.
+    fn merge(self, other: DotContext) -> (Self, usize) {
         let dots: HashSet<Dot> = self
             .detached_dots
             .union(&other.detached_dots)
@@ -78,16 +85,28 @@ impl DotContext {
         let merged_context = DotContext {
             integration_bound,
             detached_dots: dots,
+            max_detached: self.max_detached,
+            max_gap: self.max_gap,
         };
         merged_context.compact()
     }
 
-    /// Garbage collects detached dots based on the integration bound.
-    fn compact(self) -> Self {
-        let mut dot_list: Vec<&Dot> = self.detached_dots.iter().collect();
+    /// Garbage collects detached dots based on the integration bound, then evicts detached dots if more than
+    /// `max_detached` are left. Returns the context and how many dots were evicted.
+    ///
+    /// Evicting is not causally safe. The context forgets that it saw a dropped dot, so a merge with a peer that still
+    /// holds an entry for it adds the entry again, even if it was removed here. Once the gap before a dropped dot fills,
+    /// the integration bound covers it again, so its entry is treated as removed if it was never received.
+    fn compact(self) -> (Self, usize) {
+        let DotContext {
+            mut integration_bound,
+            detached_dots,
+            max_detached,
+            max_gap,
+        } = self;
+        let mut dot_list: Vec<&Dot> = detached_dots.iter().collect();
         dot_list.sort_by_key(|dot| dot.clock);
-        let mut integration_bound = self.integration_bound;
-        let dots = dot_list
+        let mut dots: Vec<Dot> = dot_list
             .into_iter()
             .filter(|dot| {
                 let is_touching_bound = integration_bound.integrate_dot(dot);
@@ -95,10 +114,21 @@ impl DotContext {
             })
             .cloned()
             .collect();
-        DotContext {
-            integration_bound,
-            detached_dots: dots,
+        let detached = dots.len();
+        if detached > max_detached {
+            if let Some(max_gap) = max_gap {
+                dots.retain(|dot| integration_bound.gap(dot) <= max_gap);
+            }
+            dots.sort_by_key(|dot| integration_bound.gap(dot));
+            dots.truncate(max_detached);
         }
+        let context = DotContext {
+            integration_bound,
+            detached_dots: dots.iter().cloned().collect(),
+            max_detached,
+            max_gap,
+        };
+        (context, detached - dots.len())
     }
 }
 
@@ -150,6 +180,14 @@ impl VectorClock {
             .unwrap_or(false)
     }
 
+    /// How many dots of the client that made `dot` are missing before it can be integrated.
+    fn gap(&self, dot: &Dot) -> Clock {
+        match self.clients.get(&dot.client_id) {
+            Some(clock) => dot.clock.saturating_sub(clock + 1),
+            None => dot.clock,
+        }
+    }
+
     fn merge(self, other: VectorClock) -> Self {
         let mut clients = self.clients;
         for (client_id, clock) in other.clients.into_iter() {
@@ -186,7 +224,6 @@ impl VectorClock {
     }
 }
 
-type ClientId = isize;
 type Clock = usize;
 
 #[cfg(test)]
@@ -200,4 +237,49 @@ mod tests {
     fn test_orset() {
         unimplemented!()
     }
+
+    #[test]
+    fn test_bounded_compaction() {
+        let dot = |clock| Dot {
+            client_id: 1,
+            clock,
+        };
+        let bound = |clock| VectorClock {
+            clients: HashMap::from([(1, clock)]),
+        };
+        let context = DotContext {
+            integration_bound: bound(0),
+            detached_dots: HashSet::new(),
+            max_detached: 10,
+            max_gap: Some(50),
+        };
+        // A flaky peer delivers later dots, but not 1:1. Below the threshold nothing is evicted.
+        let flaky = |dots: std::ops::Range<Clock>| DotContext {
+            integration_bound: VectorClock {
+                clients: HashMap::new(),
+            },
+            detached_dots: dots.map(dot).collect(),
+            max_detached: usize::MAX,
+            max_gap: None,
+        };
+        let (context, evicted) = context.merge(flaky(2..12));
+        assert_eq!((evicted, context.detached_dots.len()), (0, 10));
+
+        // Crossing the threshold evicts the dots furthest from the bound
+        let (context, evicted) = context.merge(flaky(12..102));
+        assert_eq!(evicted, 90);
+        assert_eq!(context.detached_dots.len(), 10);
+        assert!(context.contains(&dot(11)));
+        assert!(!context.contains(&dot(12)));
+
+        // The kept dots are integrated once the gap fills
+        let (context, _) = context.merge(DotContext {
+            integration_bound: bound(1),
+            detached_dots: HashSet::new(),
+            max_detached: usize::MAX,
+            max_gap: None,
+        });
+        assert!(context.detached_dots.is_empty());
+        assert!(context.integration_bound.contains(&dot(11)));
+    }
 }
@@ -0,0 +1,180 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::Write;
+
+/// Marks bytes as an encoded Shelf message.
+pub const MAGIC: [u8; 2] = *b"SH";
+/// Bumped whenever the binary layout of shelves, state vectors or clocks changes.
+pub const PROTOCOL_VERSION: u8 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+/// Serializes a state vector or delta with bincode, prefixed by a header holding the protocol version.
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    write_header(&mut bytes)?;
+    bincode::serialize_into(&mut bytes, value).map_err(|err| err.to_string())?;
+    Ok(bytes)
+}
+
+/// Writes the header that `encode` starts with, for encoders that stream the rest of a message.
+pub(crate) fn write_header<W: Write>(writer: &mut W) -> Result<(), String> {
+    writer.write_all(&MAGIC).map_err(|err| err.to_string())?;
+    writer
+        .write_all(&[PROTOCOL_VERSION])
+        .map_err(|err| err.to_string())
+}
+
+/// Deserializes bytes produced by `encode`, rejecting bytes from peers on a different protocol version
+/// instead of misreading them.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+        return Err(
+            "Missing protocol header. The peer may be running an incompatible version.".to_owned(),
+        );
+    }
+    let version = bytes[MAGIC.len()];
+    if version != PROTOCOL_VERSION {
+        return Err(format!(
+            "Incompatible protocol version: expected {PROTOCOL_VERSION}, got {version}"
+        ));
+    }
+    bincode::deserialize(&bytes[HEADER_LEN..]).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::{DotClock, LamportTimestamp, SecureClock};
+    use crate::json::Value;
+    use crate::state_vector::StateVector;
+    use crate::traits::DeltaCRDT;
+    use crate::wrap_crdt::Shelf;
+
+    type TestShelf = Shelf<Value, LamportTimestamp, DotClock>;
+
+    fn shelf() -> TestShelf {
+        json!([{"user": [{"name": ["a", [1, 1]]}, 0]}, 0])
+            .try_into()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let shelf = shelf();
+        let sv = shelf.get_state_vector();
+        let decoded: StateVector<LamportTimestamp, DotClock> =
+            decode(&encode(&sv).unwrap()).unwrap();
+        assert_eq!(decoded, sv);
+        let decoded: TestShelf = decode(&encode(&shelf).unwrap()).unwrap();
+        assert_eq!(decoded, shelf);
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let mut bytes = encode(&shelf()).unwrap();
+        bytes[MAGIC.len()] = PROTOCOL_VERSION + 1;
+        let err = decode::<TestShelf>(&bytes).err().unwrap();
+        assert!(err.contains("Incompatible protocol version"), "{err}");
+
+        // Version 1 peers cannot decode `Value::Object`
+        bytes[MAGIC.len()] = 1;
+        assert!(decode::<TestShelf>(&bytes).is_err());
+
+        // Bytes from a client that predates the header
+        let legacy = bincode::serialize(&shelf()).unwrap();
+        assert!(decode::<TestShelf>(&legacy).is_err());
+        assert!(decode::<TestShelf>(&[]).is_err());
+    }
+
+    /// Encodes `value` and checks its bytes after the header. Shelves and state vectors hold `HashMap`s, whose
+    /// order is not fixed, so golden values have at most one entry per map.
+    fn assert_golden<T: Serialize>(value: &T, expected: &[&[u8]]) {
+        let bytes = encode(value).unwrap();
+        assert_eq!(bytes[..HEADER_LEN], [b'S', b'H', 2]);
+        assert_eq!(bytes[HEADER_LEN..], expected.concat());
+    }
+
+    /// Pins the wire format. If one of these fails, deployed clients can no longer read what this version sends,
+    /// so bump `PROTOCOL_VERSION` and update the expected bytes.
+    #[test]
+    fn test_golden_bytes() {
+        // Enum variants are u32 indices, lengths and usizes are u64, all little endian
+        assert_golden(
+            &Value::String("a".to_owned()),
+            &[&[0, 0, 0, 0], &[1, 0, 0, 0, 0, 0, 0, 0], b"a"],
+        );
+        assert_golden(
+            &Value::Int(-2),
+            &[
+                &[1, 0, 0, 0],
+                &[0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ],
+        );
+        assert_golden(
+            &Value::Float(1.5),
+            &[&[2, 0, 0, 0], &[0x00, 0x00, 0xc0, 0x3f]],
+        );
+        assert_golden(&Value::Bool(true), &[&[3, 0, 0, 0], &[1]]);
+        assert_golden(
+            &Value::Array(vec![Value::Null]),
+            &[&[4, 0, 0, 0], &[1, 0, 0, 0, 0, 0, 0, 0], &[5, 0, 0, 0]],
+        );
+        assert_golden(&Value::Null, &[&[5, 0, 0, 0]]);
+        assert_golden(
+            &Value::Object([("a".to_owned(), Value::Null)].into()),
+            &[
+                &[6, 0, 0, 0],
+                &[1, 0, 0, 0, 0, 0, 0, 0],
+                &[1, 0, 0, 0, 0, 0, 0, 0],
+                b"a",
+                &[5, 0, 0, 0],
+            ],
+        );
+
+        assert_golden(&LamportTimestamp(3), &[&[3, 0, 0, 0, 0, 0, 0, 0]]);
+        assert_golden(
+            &DotClock {
+                client_id: 1,
+                clock: 2,
+            },
+            &[&[1, 0, 0, 0, 0, 0, 0, 0], &[2, 0, 0, 0, 0, 0, 0, 0]],
+        );
+        assert_golden(
+            &SecureClock {
+                clock: 2,
+                hash: 0x0102030405060708,
+            },
+            &[&[2, 0, 0, 0, 0, 0, 0, 0], &[8, 7, 6, 5, 4, 3, 2, 1]],
+        );
+
+        let shelf: TestShelf = json!([{"k": [7, [1, 2]]}, 1]).try_into().unwrap();
+        let key: &[u8] = &[1, 0, 0, 0, 0, 0, 0, 0, b'k'];
+        let dot: &[u8] = &[1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0];
+        let lamport: &[u8] = &[1, 0, 0, 0, 0, 0, 0, 0];
+        assert_golden(
+            &shelf,
+            &[
+                &[1, 0, 0, 0],             // Shelf::Map
+                &[1, 0, 0, 0, 0, 0, 0, 0], // One entry
+                key,
+                &[0, 0, 0, 0], // Shelf::Value
+                &[1, 0, 0, 0], // Value::Int
+                &[7, 0, 0, 0, 0, 0, 0, 0],
+                dot,
+                lamport,
+            ],
+        );
+        assert_golden(
+            &shelf.get_state_vector(),
+            &[
+                &[0, 0, 0, 0],             // StateVector::Node
+                &[1, 0, 0, 0, 0, 0, 0, 0], // One entry
+                key,
+                &[1, 0, 0, 0], // StateVector::Leaf
+                dot,
+                lamport,
+            ],
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::Arc;
+
+use crate::stable_hash::stable_hash;
+use crate::wrap_crdt::Shelf;
+
+/// A read-mostly copy of a Shelf whose identical subtrees share one allocation, e.g. the same default config
+/// stored under many keys. Shared subtrees are copied on write, so editing one place never changes another.
+///
+/// This is a prototype: it does not merge or produce deltas. Convert it back with `to_shelf` to sync.
+#[derive(Clone, PartialEq, Debug)]
+pub enum SharedShelf<T, MapClock, ValueClock = MapClock, K = String>
+where
+    K: Eq + Hash,
+{
+    Value {
+        value: T,
+        clock: ValueClock,
+    },
+    Map {
+        shelves: HashMap<K, Arc<SharedShelf<T, MapClock, ValueClock, K>>>,
+        clock: MapClock,
+    },
+}
+
+/// Subtrees seen so far, by content hash. Equal hashes are compared in full before sharing.
+type Interner<T, MapClock, ValueClock, K> =
+    HashMap<u64, Vec<Arc<SharedShelf<T, MapClock, ValueClock, K>>>>;
+
+impl<T, MapClock, ValueClock, K> SharedShelf<T, MapClock, ValueClock, K>
+where
+    T: PartialEq + PartialOrd + Clone + Serialize,
+    MapClock:
+        PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Clone + Serialize,
+    ValueClock:
+        PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + Clone + Serialize,
+    K: Eq + Hash + Clone,
+{
+    /// Copies `shelf`, sharing every subtree that is identical, clocks included, to one seen before.
+    pub fn from_shelf(shelf: &Shelf<T, MapClock, ValueClock, K>) -> Self {
+        let mut interner = HashMap::new();
+        let (root, _) = Self::intern(shelf, &mut interner);
+        Arc::try_unwrap(root).unwrap_or_else(|root| (*root).clone())
+    }
+
+    fn intern(
+        shelf: &Shelf<T, MapClock, ValueClock, K>,
+        interner: &mut Interner<T, MapClock, ValueClock, K>,
+    ) -> (Arc<Self>, u64) {
+        let (node, hash) = match shelf {
+            Shelf::Value { value, clock } => {
+                let bytes = bincode::serialize(&(value, clock))
+                    .expect("Shelf values and clocks must serialize");
+                let node = SharedShelf::Value {
+                    value: value.clone(),
+                    clock: clock.clone(),
+                };
+                (node, stable_hash(&(0u8, bytes)))
+            }
+            Shelf::Map { shelves, clock } => {
+                let mut entries = 0u64;
+                let shelves = shelves
+                    .iter()
+                    .map(|(k, child)| {
+                        let (child, child_hash) = Self::intern(child, interner);
+                        // Summing the entries keeps the hash independent of the map's iteration order
+                        entries = entries.wrapping_add(stable_hash(&(k, child_hash)));
+                        (k.clone(), child)
+                    })
+                    .collect();
+                let clock_bytes = bincode::serialize(clock).expect("Shelf clocks must serialize");
+                let node = SharedShelf::Map {
+                    shelves,
+                    clock: clock.clone(),
+                };
+                (node, stable_hash(&(1u8, clock_bytes, entries)))
+            }
+        };
+        let candidates = interner.entry(hash).or_default();
+        match candidates.iter().find(|candidate| ***candidate == node) {
+            Some(shared) => (shared.clone(), hash),
+            None => {
+                let node = Arc::new(node);
+                candidates.push(node.clone());
+                (node, hash)
+            }
+        }
+    }
+
+    /// Copies the shelf out into a regular Shelf, e.g. to merge it or compute a delta.
+    pub fn to_shelf(&self) -> Shelf<T, MapClock, ValueClock, K> {
+        match self {
+            SharedShelf::Value { value, clock } => Shelf::Value {
+                value: value.clone(),
+                clock: clock.clone(),
+            },
+            SharedShelf::Map { shelves, clock } => Shelf::Map {
+                shelves: shelves
+                    .iter()
+                    .map(|(k, child)| (k.clone(), child.to_shelf()))
+                    .collect(),
+                clock: clock.clone(),
+            },
+        }
+    }
+
+    pub fn get_path(&self, path: &[K]) -> Option<&Self> {
+        path.iter().try_fold(self, |shelf, key| match shelf {
+            SharedShelf::Map { shelves, .. } => shelves.get(key).map(|child| &**child),
+            SharedShelf::Value { .. } => None,
+        })
+    }
+
+    /// Mutable access to the subtree at `path`. Subtrees on the way that are shared are copied first, so the
+    /// edit only shows up under this path.
+    pub fn get_path_mut(&mut self, path: &[K]) -> Option<&mut Self> {
+        path.iter().try_fold(self, |shelf, key| match shelf {
+            SharedShelf::Map { shelves, .. } => shelves.get_mut(key).map(Arc::make_mut),
+            SharedShelf::Value { .. } => None,
+        })
+    }
+
+    /// The number of subtrees, counting shared ones once per place they appear.
+    pub fn node_count(&self) -> usize {
+        1 + match self {
+            SharedShelf::Map { shelves, .. } => {
+                shelves.values().map(|child| child.node_count()).sum()
+            }
+            SharedShelf::Value { .. } => 0,
+        }
+    }
+
+    /// The number of subtrees that have their own allocation, to measure what interning saved against `node_count`.
+    pub fn unique_node_count(&self) -> usize {
+        let mut seen = HashSet::new();
+        1 + self.count_unique(&mut seen)
+    }
+
+    fn count_unique(&self, seen: &mut HashSet<*const Self>) -> usize {
+        let mut count = 0;
+        if let SharedShelf::Map { shelves, .. } = self {
+            for child in shelves.values() {
+                if seen.insert(Arc::as_ptr(child)) {
+                    count += 1 + child.count_unique(seen);
+                }
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::{DotClock, LamportTimestamp};
+    use crate::json::Value;
+
+    type TestShelf = Shelf<Value, LamportTimestamp, DotClock>;
+    type TestSharedShelf = SharedShelf<Value, LamportTimestamp, DotClock>;
+
+    fn path(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn test_shares_duplicate_subtrees() {
+        let config: serde_json::Map<String, serde_json::Value> = (0..20)
+            .map(|i| (format!("option{i}"), json!([i, [1, 1]])))
+            .collect();
+        let users: serde_json::Map<String, serde_json::Value> = (0..100)
+            .map(|i| (format!("user{i}"), json!([config, 0])))
+            .collect();
+        let shelf: TestShelf = json!([users, 0]).try_into().unwrap();
+
+        let shared = TestSharedShelf::from_shelf(&shelf);
+        assert_eq!(shared.node_count(), 1 + 100 * 21);
+        // The root, one config map and its options
+        assert_eq!(shared.unique_node_count(), 1 + 1 + 20);
+        assert!(shared.to_shelf() == shelf);
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let shelf: TestShelf = json!([{
+            "a": [{"theme": ["dark", [1, 1]]}, 0],
+            "b": [{"theme": ["dark", [1, 1]]}, 0],
+            "c": [{"theme": ["dark", [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let mut shared = TestSharedShelf::from_shelf(&shelf);
+        assert_eq!(shared.unique_node_count(), 3);
+
+        if let Some(SharedShelf::Value { value, .. }) = shared.get_path_mut(&path(&["b", "theme"]))
+        {
+            *value = "light".into();
+        }
+        let theme = |shared: &TestSharedShelf, key| match shared.get_path(&path(&[key, "theme"])) {
+            Some(SharedShelf::Value { value, .. }) => value.clone(),
+            _ => panic!("Expected a theme under '{key}'"),
+        };
+        assert_eq!(theme(&shared, "a"), "dark".into());
+        assert_eq!(theme(&shared, "b"), "light".into());
+        assert_eq!(theme(&shared, "c"), "dark".into());
+        // "b" now has its own map and value
+        assert_eq!(shared.unique_node_count(), 5);
+    }
+}
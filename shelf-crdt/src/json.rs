@@ -3,30 +3,101 @@ use serde_json::{self, json, Value as JSON};
 
 use std::clone::Clone;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::fmt::Display;
 use std::hash::Hash;
 
-#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Default)]
 pub enum Value {
     String(String),
     Int(isize),
     Float(f32),
     Bool(bool),
     Array(Vec<Value>),
+    #[default]
     Null,
+    /// An object inside an array, e.g. one element of a list of objects. Objects outside of arrays are Shelf maps.
+    /// Keys are kept sorted, so objects with the same entries are equal, ordered and hashed alike whatever order
+    /// their keys were inserted in. Converting back to JSON writes the keys in sorted order.
+    Object(BTreeMap<String, Value>),
+}
+
+/// The type of a `Value`, to check it without matching on the content. Types are declared in the order that
+/// values of different types are compared in.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum ValueType {
+    Null,
+    Bool,
+    Float,
+    Int,
+    String,
+    Array,
+    Object,
+}
+
+impl ValueType {
+    /// A lowercase name for the type, e.g. for validation errors.
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::Null => "null",
+            ValueType::Bool => "bool",
+            ValueType::Float => "float",
+            ValueType::Int => "int",
+            ValueType::String => "string",
+            ValueType::Array => "array",
+            ValueType::Object => "object",
+        }
+    }
+}
+
+impl Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
 impl Value {
     #[inline(always)]
-    fn type_rank(value: &Value) -> u8 {
-        match value {
-            Value::Array(_) => 5,
-            Value::String(_) => 4,
-            Value::Int(_) => 3,
-            Value::Float(_) => 2,
-            Value::Bool(_) => 1,
-            Value::Null => 0,
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Object(_) => ValueType::Object,
+            Value::Array(_) => ValueType::Array,
+            Value::String(_) => ValueType::String,
+            Value::Int(_) => ValueType::Int,
+            Value::Float(_) => ValueType::Float,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Null => ValueType::Null,
+        }
+    }
+
+    /// The name of the value's type, see `ValueType::name`.
+    pub fn type_name(&self) -> &'static str {
+        self.value_type().name()
+    }
+
+    /// Returns the string slice if this is a `Value::String`.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns the numeric content of a `Value::Int` or `Value::Float` as an `f64`.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f as f64),
+            _ => None,
+        }
+    }
+
+    /// Returns the boolean if this is a `Value::Bool`.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
         }
     }
 }
@@ -40,6 +111,10 @@ impl Display for Value {
             Value::Bool(b) => format!("{}", b),
             Value::Array(a) => format!("{:?}", a),
             Value::Null => "null".to_string(),
+            Value::Object(o) => {
+                let entries: Vec<String> = o.iter().map(|(k, v)| format!("\"{k}\": {v}")).collect();
+                format!("{{{}}}", entries.join(", "))
+            }
         };
         write!(f, "{repr}")
     }
@@ -53,7 +128,7 @@ impl Debug for Value {
 
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match Value::type_rank(self).cmp(&Value::type_rank(other)) {
+        match self.value_type().cmp(&other.value_type()) {
             Ordering::Equal => match (self, other) {
                 (Value::Bool(b1), Value::Bool(b2)) => b1.partial_cmp(b2),
                 (Value::Int(v1), Value::Int(v2)) => v1.partial_cmp(v2),
@@ -61,6 +136,7 @@ impl PartialOrd for Value {
                 (Value::String(v1), Value::String(v2)) => v1.partial_cmp(v2),
                 (Value::Array(v1), Value::Array(v2)) => v1.partial_cmp(v2),
                 (Value::Null, Value::Null) => Some(Ordering::Equal),
+                (Value::Object(v1), Value::Object(v2)) => v1.partial_cmp(v2),
                 _ => unreachable!("If type ranks match, they must be the same type."),
             },
             ord => Some(ord),
@@ -68,30 +144,158 @@ impl PartialOrd for Value {
     }
 }
 
-impl TryFrom<JSON> for Value {
-    type Error = String;
-    fn try_from(json: JSON) -> Result<Self, Self::Error> {
+/// A `Value` with a total order, for use as a key in ordered collections like `BTreeMap` and `BTreeSet`.
+/// Values are ordered by type first (`Null < Bool < Float < Int < String < Array < Object`) like `Value`'s
+/// `PartialOrd`. Floats are ordered with `f32::total_cmp`, so `NaN` sorts above every other float and `-0.0` below
+/// `0.0`, arrays are compared element by element with the same order, and objects entry by entry in key order.
+#[derive(Clone, Debug)]
+pub struct OrderedValue(pub Value);
+
+impl OrderedValue {
+    fn total_cmp(this: &Value, other: &Value) -> Ordering {
+        match (this, other) {
+            (Value::Float(v1), Value::Float(v2)) => v1.total_cmp(v2),
+            (Value::Array(v1), Value::Array(v2)) => v1
+                .iter()
+                .zip(v2)
+                .map(|(a, b)| OrderedValue::total_cmp(a, b))
+                .find(|ord| ord.is_ne())
+                .unwrap_or_else(|| v1.len().cmp(&v2.len())),
+            (Value::Object(v1), Value::Object(v2)) => v1
+                .iter()
+                .zip(v2)
+                .map(|((k1, a), (k2, b))| k1.cmp(k2).then_with(|| OrderedValue::total_cmp(a, b)))
+                .find(|ord| ord.is_ne())
+                .unwrap_or_else(|| v1.len().cmp(&v2.len())),
+            (this, other) => this
+                .partial_cmp(other)
+                .expect("Only floats and containers of floats lack a total order"),
+        }
+    }
+}
+
+impl Ord for OrderedValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        OrderedValue::total_cmp(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for OrderedValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OrderedValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for OrderedValue {}
+
+impl From<Value> for OrderedValue {
+    fn from(value: Value) -> Self {
+        OrderedValue(value)
+    }
+}
+
+/// Bounds on the arrays accepted when parsing a `Value`, so untrusted JSON cannot exhaust memory or the stack.
+/// Objects inside arrays count as arrays of their entries.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ArrayLimits {
+    /// Maximum number of elements in a single array.
+    pub max_len: usize,
+    /// Maximum number of arrays nested inside each other.
+    pub max_depth: usize,
+}
+
+impl Default for ArrayLimits {
+    fn default() -> Self {
+        ArrayLimits {
+            max_len: 1 << 20,
+            max_depth: 64,
+        }
+    }
+}
+
+impl Value {
+    /// Parses `json`, rejecting arrays that exceed `limits`. Objects are only accepted inside arrays, since an
+    /// object in place of a value is read as a Shelf map.
+    pub fn from_json_with_limits(json: JSON, limits: &ArrayLimits) -> Result<Self, String> {
+        Value::parse_json(json, limits, 0)
+    }
+
+    fn parse_json(json: JSON, limits: &ArrayLimits, depth: usize) -> Result<Self, String> {
         match json {
             JSON::Bool(b) => Ok(Value::Bool(b)),
             JSON::Number(n) if n.is_i64() => Ok(Value::Int(n.as_i64().unwrap() as isize)),
             JSON::Number(n) if n.is_f64() => Ok(Value::Float(n.as_f64().unwrap() as f32)),
             JSON::String(s) => Ok(Value::String(s)),
             JSON::Array(a) => {
-                let array: Result<Vec<Value>, String> =
-                    a.into_iter().map(|json_val| json_val.try_into()).collect();
+                if depth >= limits.max_depth {
+                    return Err(format!(
+                        "Arrays are nested deeper than the limit of {}",
+                        limits.max_depth
+                    ));
+                }
+                if a.len() > limits.max_len {
+                    return Err(format!(
+                        "Array of {} elements exceeds the limit of {}",
+                        a.len(),
+                        limits.max_len
+                    ));
+                }
+                let array: Result<Vec<Value>, String> = a
+                    .into_iter()
+                    .map(|json_val| Value::parse_json(json_val, limits, depth + 1))
+                    .collect();
                 Ok(Value::Array(array?))
             }
             JSON::Null => Ok(Value::Null),
+            JSON::Object(_) if depth == 0 => {
+                Err("Objects outside of arrays are Shelf maps, not values.".to_string())
+            }
+            JSON::Object(o) => {
+                if depth >= limits.max_depth {
+                    return Err(format!(
+                        "Arrays are nested deeper than the limit of {}",
+                        limits.max_depth
+                    ));
+                }
+                if o.len() > limits.max_len {
+                    return Err(format!(
+                        "Object of {} entries exceeds the limit of {}",
+                        o.len(),
+                        limits.max_len
+                    ));
+                }
+                let object: Result<BTreeMap<String, Value>, String> = o
+                    .into_iter()
+                    .map(|(k, json_val)| Ok((k, Value::parse_json(json_val, limits, depth + 1)?)))
+                    .collect();
+                Ok(Value::Object(object?))
+            }
             _ => Err("Should not be building with null or object values.".to_string()),
         }
     }
 }
 
+impl TryFrom<JSON> for Value {
+    type Error = String;
+    fn try_from(json: JSON) -> Result<Self, Self::Error> {
+        Value::from_json_with_limits(json, &ArrayLimits::default())
+    }
+}
+
+/// JSON cannot represent `NaN` or infinite numbers, so non-finite floats are written as `null`, like
+/// `JSON.stringify` does in JavaScript. Reading the JSON back gives `Value::Null` in their place.
 impl From<Value> for JSON {
     fn from(value: Value) -> Self {
         match value {
             Value::String(s) => json!(s),
             Value::Int(i) => json!(i),
+            Value::Float(f) if !f.is_finite() => JSON::Null,
             Value::Float(f) => json!(f),
             Value::Bool(b) => json!(b),
             Value::Array(a) => {
@@ -99,14 +303,24 @@ impl From<Value> for JSON {
                 json!(arr)
             }
             Value::Null => JSON::Null,
+            Value::Object(o) => {
+                let obj: serde_json::Map<String, JSON> =
+                    o.into_iter().map(|(k, v)| (k, JSON::from(v))).collect();
+                JSON::Object(obj)
+            }
         }
     }
 }
 
+/// Strings and arrays are written out explicitly rather than through the standard library's `Hash` impls, whose
+/// output may change between Rust versions, so that `SecureClock` hashes stay stable.
 impl Hash for Value {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         match &self {
-            Value::String(s) => s.hash(state),
+            Value::String(s) => {
+                state.write(s.as_bytes());
+                state.write_u8(0xff);
+            }
             Value::Int(i) => i.hash(state),
             Value::Float(f) => {
                 if f.is_nan() {
@@ -116,8 +330,19 @@ impl Hash for Value {
                 }
             }
             Value::Bool(b) => b.hash(state),
-            Value::Array(a) => a.hash(state),
+            Value::Array(a) => {
+                state.write_usize(a.len());
+                a.iter().for_each(|value| value.hash(state));
+            }
             Value::Null => state.write_u64(0),
+            Value::Object(o) => {
+                state.write_usize(o.len());
+                o.iter().for_each(|(k, value)| {
+                    state.write(k.as_bytes());
+                    state.write_u8(0xff);
+                    value.hash(state);
+                });
+            }
         }
     }
 }
@@ -128,6 +353,30 @@ impl From<isize> for Value {
     }
 }
 
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Int(value as isize)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value as isize)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Int(value as isize)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_owned())
+    }
+}
+
 impl From<String> for Value {
     fn from(value: String) -> Self {
         Value::String(value)
@@ -151,3 +400,155 @@ impl From<Vec<Value>> for Value {
         Value::Array(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable_hash::stable_hash;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_typed_accessors() {
+        let string: Value = "foo".to_string().into();
+        let int: Value = 3.into();
+        let float: Value = 1.5.into();
+        let boolean: Value = true.into();
+
+        assert_eq!(string.as_str(), Some("foo"));
+        assert_eq!(int.as_f64(), Some(3.0));
+        assert_eq!(float.as_f64(), Some(1.5));
+        assert_eq!(boolean.as_bool(), Some(true));
+
+        // Mismatched types
+        assert_eq!(int.as_str(), None);
+        assert_eq!(string.as_f64(), None);
+        assert_eq!(Value::Null.as_bool(), None);
+        assert_eq!(Value::Array(vec![]).as_f64(), None);
+    }
+
+    #[test]
+    fn test_value_type() {
+        let values = [
+            (Value::from("foo"), ValueType::String, "string"),
+            (Value::from(3), ValueType::Int, "int"),
+            (Value::from(1.5), ValueType::Float, "float"),
+            (Value::from(true), ValueType::Bool, "bool"),
+            (Value::Array(vec![]), ValueType::Array, "array"),
+            (Value::Null, ValueType::Null, "null"),
+            (Value::Object(BTreeMap::new()), ValueType::Object, "object"),
+        ];
+        for (value, value_type, name) in values {
+            assert_eq!(value.value_type(), value_type);
+            assert_eq!(value.type_name(), name);
+            assert_eq!(value_type.to_string(), name);
+        }
+        // Values of different types compare in the order of their types
+        assert!(ValueType::Int > ValueType::Float);
+        assert!(Value::from(1) > Value::from(2.5));
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(Value::from("foo"), Value::String("foo".to_owned()));
+        assert_eq!(Value::from(-3i32), Value::Int(-3));
+        assert_eq!(Value::from(4i64), Value::Int(4));
+        assert_eq!(Value::from(5u32), Value::Int(5));
+        assert_eq!(Value::from(1.5f32), Value::Float(1.5));
+    }
+
+    #[test]
+    fn test_ordered_value() {
+        use std::collections::BTreeSet;
+
+        let values: Vec<Value> = vec![
+            vec![1.into(), "a".to_string().into()].into(),
+            "b".to_string().into(),
+            f32::NAN.into(),
+            Value::Null,
+            2.into(),
+            "a".to_string().into(),
+            true.into(),
+            (-1.5).into(),
+            vec![1.into()].into(),
+            false.into(),
+            1.into(),
+            Value::Null,
+        ];
+        let set: BTreeSet<OrderedValue> = values.into_iter().map(OrderedValue::from).collect();
+        let sorted: Vec<String> = set.into_iter().map(|value| value.0.to_string()).collect();
+        assert_eq!(
+            sorted,
+            [
+                "null",
+                "false",
+                "true",
+                "-1.5",
+                "NaN",
+                "1",
+                "2",
+                "\"a\"",
+                "\"b\"",
+                "[1]",
+                "[1, \"a\"]"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_array_limits() {
+        let limits = ArrayLimits {
+            max_len: 100,
+            max_depth: 8,
+        };
+        let mut nested = json!(1);
+        for _ in 0..limits.max_depth {
+            nested = json!([nested]);
+        }
+        assert!(Value::from_json_with_limits(nested.clone(), &limits).is_ok());
+        assert!(Value::from_json_with_limits(json!([nested]), &limits).is_err());
+
+        let oversized = JSON::Array(vec![json!(0); limits.max_len + 1]);
+        assert!(Value::from_json_with_limits(oversized, &limits).is_err());
+
+        // The default limits also apply to plain conversions
+        let mut nested = json!(1);
+        for _ in 0..ArrayLimits::default().max_depth + 1 {
+            nested = json!([nested]);
+        }
+        assert!(Value::try_from(nested).is_err());
+    }
+
+    #[test]
+    fn test_objects_ignore_key_order() {
+        let object = |json: JSON| match Value::try_from(json!([json])).unwrap() {
+            Value::Array(mut array) => array.remove(0),
+            value => panic!("Expected an array, got {value}"),
+        };
+        let mut reordered = serde_json::Map::new();
+        reordered.insert("b".to_owned(), json!([1.5, "x"]));
+        reordered.insert("a".to_owned(), json!(1));
+        let first = object(json!({"a": 1, "b": [1.5, "x"]}));
+        let second = object(JSON::Object(reordered));
+
+        assert_eq!(first, second);
+        assert_eq!(first.partial_cmp(&second), Some(Ordering::Equal));
+        assert_eq!(OrderedValue(first.clone()), OrderedValue(second.clone()));
+        assert_eq!(stable_hash(&first), stable_hash(&second));
+        assert_eq!(first.to_string(), "{\"a\": 1, \"b\": [1.5, \"x\"]}");
+        assert!(object(json!({"a": 2})) > object(json!({"a": 1, "b": 0})));
+
+        assert_eq!(JSON::from(first), json!({"a": 1, "b": [1.5, "x"]}));
+        assert!(Value::try_from(json!({"a": 1})).is_err());
+    }
+
+    #[test]
+    fn test_default() {
+        assert_eq!(Value::default(), Value::Null);
+
+        let mut values: HashMap<String, Value> = HashMap::new();
+        let value = values.entry("missing".to_owned()).or_default();
+        assert_eq!(*value, Value::Null);
+        *value = 1.into();
+        assert_eq!(values["missing"], Value::Int(1));
+    }
+}
@@ -1,9 +1,49 @@
 pub mod clock;
 mod dot_kernel;
+pub mod encoding;
+#[cfg(feature = "interning")]
+pub mod interned;
 pub mod json;
+pub mod merge_strategy;
+pub mod merkle;
+pub mod op_log;
+#[cfg(test)]
 mod security_sim;
 pub mod shelf_fuzzer;
+pub mod stable_hash;
 pub mod state_vector;
 pub mod temporal;
+pub mod tombstone;
 pub mod traits;
 pub mod wrap_crdt;
+
+/// Compile-time checks that the CRDT types can be shared across threads, as the security simulation does.
+/// A regression such as an `Rc` inside one of these types fails the build instead of surfacing at a call site.
+#[cfg(test)]
+mod thread_safety {
+    use crate::clock::{DotClock, LamportTimestamp, SecureClock};
+    use crate::json::Value;
+    use crate::merge_strategy::MergeStrategyMap;
+    use crate::state_vector::{StateVector, StateVectorContext};
+    use crate::tombstone::TombstoneShelf;
+    use crate::wrap_crdt::{Awareness, Shelf};
+
+    type TestAwareness = Awareness<Value, LamportTimestamp, LamportTimestamp, StateVectorContext>;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<Value>();
+        assert_send_sync::<LamportTimestamp>();
+        assert_send_sync::<DotClock>();
+        assert_send_sync::<SecureClock>();
+        assert_send_sync::<Shelf<Value, LamportTimestamp, DotClock>>();
+        assert_send_sync::<Shelf<Value, LamportTimestamp, SecureClock>>();
+        assert_send_sync::<StateVector<LamportTimestamp, DotClock>>();
+        assert_send_sync::<StateVector<LamportTimestamp, SecureClock>>();
+        assert_send_sync::<TombstoneShelf<Value, LamportTimestamp>>();
+        assert_send_sync::<MergeStrategyMap>();
+        assert_send_sync::<TestAwareness>();
+    }
+}
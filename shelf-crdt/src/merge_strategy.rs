@@ -0,0 +1,333 @@
+use crate::json::{OrderedValue, Value};
+use crate::traits::Mergeable;
+use crate::wrap_crdt::{max_clock, Shelf};
+use std::cmp::Ordering;
+
+/// How two concurrent versions of a leaf are combined.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum MergeStrategy {
+    /// The regular Shelf merge: the value with the highest clock wins.
+    #[default]
+    LastWriterWins,
+    /// Keeps the larger of two numbers. Grow-only counters can be built from one `Max` entry per client that
+    /// only that client increments, with the total being the sum of the entries.
+    Max,
+    /// Keeps the smaller of two numbers, e.g. for low-water marks.
+    /// There is no sum strategy: merging the same state twice would count it twice. Sum `Max` entries instead.
+    Min,
+    /// Keeps the union of two arrays, sorted so that every replica ends up with the same order. Values are sorted and
+    /// deduplicated with the total order of `OrderedValue`, so floats like `NaN` do not break either.
+    Union,
+}
+
+impl MergeStrategy {
+    /// Combines two leaf values. Returns `None` if the strategy does not apply to these value types, in which
+    /// case the leaf falls back to last writer wins.
+    fn combine(&self, this: &Value, other: &Value) -> Option<Value> {
+        match (self, this, other) {
+            (MergeStrategy::LastWriterWins, ..) => None,
+            (MergeStrategy::Max, this, other) => {
+                match this.as_f64()?.partial_cmp(&other.as_f64()?)? {
+                    Ordering::Less => Some(other.clone()),
+                    Ordering::Greater => Some(this.clone()),
+                    // 1 and 1.0 compare equal, so use the value order to pick the same one everywhere.
+                    Ordering::Equal => match this.partial_cmp(other)? {
+                        Ordering::Less => Some(other.clone()),
+                        _ => Some(this.clone()),
+                    },
+                }
+            }
+            (MergeStrategy::Min, this, other) => {
+                match this.as_f64()?.partial_cmp(&other.as_f64()?)? {
+                    Ordering::Less => Some(this.clone()),
+                    Ordering::Greater => Some(other.clone()),
+                    Ordering::Equal => match this.partial_cmp(other)? {
+                        Ordering::Greater => Some(other.clone()),
+                        _ => Some(this.clone()),
+                    },
+                }
+            }
+            (MergeStrategy::Union, Value::Array(this), Value::Array(other)) => {
+                let mut union: Vec<OrderedValue> = this
+                    .iter()
+                    .chain(other.iter())
+                    .cloned()
+                    .map(OrderedValue)
+                    .collect();
+                union.sort();
+                union.dedup();
+                let union: Vec<Value> = union.into_iter().map(|value| value.0).collect();
+                Some(union.into())
+            }
+            (MergeStrategy::Union, ..) => None,
+        }
+    }
+}
+
+/// Merge strategies registered by path pattern, turning a Shelf into a container of different CRDTs.
+/// Patterns are `/` separated keys where `*` matches any single key, e.g. `counters/*`.
+/// Leaves that match no pattern use `MergeStrategy::LastWriterWins`.
+///
+/// All replicas must register the same strategies, otherwise they will not converge.
+/// Strategies that combine values need both versions of a leaf, but a state vector delta skips leaves whose clock
+/// is behind the peer's, so replicas should exchange full state for paths with a non-default strategy.
+#[derive(Clone, Debug, Default)]
+pub struct MergeStrategyMap {
+    strategies: Vec<(Vec<String>, MergeStrategy)>,
+}
+
+impl MergeStrategyMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `strategy` for every path matching `pattern`. Patterns registered earlier take precedence.
+    pub fn register(&mut self, pattern: &str, strategy: MergeStrategy) -> &mut Self {
+        let pattern = pattern.split('/').map(str::to_owned).collect();
+        self.strategies.push((pattern, strategy));
+        self
+    }
+
+    /// The strategy used for the leaf at `path`.
+    pub fn strategy_for(&self, path: &[String]) -> MergeStrategy {
+        self.strategies
+            .iter()
+            .find(|(pattern, _)| {
+                pattern.len() == path.len()
+                    && pattern
+                        .iter()
+                        .zip(path)
+                        .all(|(segment, key)| segment == "*" || segment == key)
+            })
+            .map(|(_, strategy)| *strategy)
+            .unwrap_or_default()
+    }
+}
+
+impl<MapClock, ValueClock> Shelf<Value, MapClock, ValueClock>
+where
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    /// Merges like `merge`, but combines leaves using the strategy registered for their path.
+    /// The combined leaf keeps the clock that a regular merge would have picked.
+    pub fn merge_with_strategies(self, other: Self, strategies: &MergeStrategyMap) -> Self {
+        self.merge_at_path(other, strategies, &mut Vec::new())
+    }
+
+    fn merge_at_path(
+        self,
+        other: Self,
+        strategies: &MergeStrategyMap,
+        path: &mut Vec<String>,
+    ) -> Self {
+        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
+        match (self, other, clock_order) {
+            (
+                Shelf::Map {
+                    shelves: mut these_shelves,
+                    clock: this_clock,
+                },
+                Shelf::Map {
+                    shelves: other_shelves,
+                    clock: other_clock,
+                },
+                Some(Ordering::Equal) | None,
+            ) => {
+                for (key, val) in other_shelves.into_iter() {
+                    let updated_value = match these_shelves.remove(&key) {
+                        Some(sub_shelf) => {
+                            path.push(key.clone());
+                            let merged = sub_shelf.merge_at_path(val, strategies, path);
+                            path.pop();
+                            merged
+                        }
+                        None => val,
+                    };
+                    these_shelves.insert(key, updated_value);
+                }
+                Shelf::Map {
+                    shelves: these_shelves,
+                    clock: max_clock(this_clock, other_clock, clock_order),
+                }
+            }
+            (this @ Shelf::Value { .. }, other @ Shelf::Value { .. }, _) => {
+                let combined = match (&this, &other) {
+                    (Shelf::Value { value: a, .. }, Shelf::Value { value: b, .. }) => {
+                        strategies.strategy_for(path).combine(a, b)
+                    }
+                    _ => None,
+                };
+                match (this.merge(other), combined) {
+                    (Shelf::Value { clock, .. }, Some(value)) => Shelf::Value { value, clock },
+                    (merged, _) => merged,
+                }
+            }
+            (this, other, _) => this.merge(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::{DotClock, LamportTimestamp};
+
+    type TestShelf = Shelf<Value, LamportTimestamp, DotClock>;
+
+    fn strategies() -> MergeStrategyMap {
+        let mut strategies = MergeStrategyMap::new();
+        strategies
+            .register("counters/*/*", MergeStrategy::Max)
+            .register("tags/*", MergeStrategy::Union)
+            .register("gauges/max/*", MergeStrategy::Max)
+            .register("gauges/min/*", MergeStrategy::Min);
+        strategies
+    }
+
+    fn sync(a: TestShelf, b: TestShelf, strategies: &MergeStrategyMap) -> (TestShelf, TestShelf) {
+        let merged_a = a.clone().merge_with_strategies(b.clone(), strategies);
+        let merged_b = b.merge_with_strategies(a, strategies);
+        (merged_a, merged_b)
+    }
+
+    fn total(shelf: &TestShelf, counter: &str) -> f64 {
+        match shelf.get_path(&["counters", counter]).unwrap() {
+            Shelf::Map { shelves, .. } => shelves
+                .values()
+                .filter_map(|shelf| match shelf {
+                    Shelf::Value { value, .. } => value.as_f64(),
+                    _ => None,
+                })
+                .sum(),
+            _ => panic!("Counter should be a map of client counts"),
+        }
+    }
+
+    #[test]
+    fn test_strategy_lookup() {
+        let strategies = strategies();
+        let path = |keys: &[&str]| -> Vec<String> { keys.iter().map(|k| k.to_string()).collect() };
+        assert_eq!(
+            strategies.strategy_for(&path(&["counters", "likes", "1"])),
+            MergeStrategy::Max
+        );
+        assert_eq!(
+            strategies.strategy_for(&path(&["tags", "post"])),
+            MergeStrategy::Union
+        );
+        assert_eq!(
+            strategies.strategy_for(&path(&["tags", "post", "extra"])),
+            MergeStrategy::LastWriterWins
+        );
+        assert_eq!(
+            strategies.strategy_for(&path(&["names", "1"])),
+            MergeStrategy::LastWriterWins
+        );
+    }
+
+    #[test]
+    fn test_additive_counter() {
+        let strategies = strategies();
+        // Client 1 incremented its count to 3 while client 2 reached 5. Client 1's stale copy of client 2's
+        // count has a higher clock but a lower value.
+        let a: TestShelf = json!([{"counters": [{"likes": [{
+            "1": [3, [1, 3]],
+            "2": [1, [1, 4]]
+        }, 0]}, 0]}, 0])
+        .try_into()
+        .unwrap();
+        let b: TestShelf = json!([{"counters": [{"likes": [{
+            "1": [1, [2, 1]],
+            "2": [5, [2, 2]]
+        }, 0]}, 0]}, 0])
+        .try_into()
+        .unwrap();
+
+        // Without the strategy the higher clocks win and increments are lost.
+        let merged = a
+            .clone()
+            .merge_with_strategies(b.clone(), &MergeStrategyMap::new());
+        assert_eq!(total(&merged, "likes"), 4.0);
+
+        let (a, b) = sync(a, b, &strategies);
+        assert_eq!(a, b);
+        assert_eq!(total(&a, "likes"), 8.0);
+    }
+
+    #[test]
+    fn test_numeric_strategies_ignore_clocks() {
+        let strategies = strategies();
+        let replica = |max_views, min_latency, clock: [usize; 2]| -> TestShelf {
+            json!([{"gauges": [{
+                "max": [{"views": [max_views, clock]}, 0],
+                "min": [{"latency": [min_latency, clock]}, 0]
+            }, 0]}, 0])
+            .try_into()
+            .unwrap()
+        };
+        let gauges = |shelf: &TestShelf| {
+            let value = |path: &[&str]| match shelf.get_path(path).unwrap() {
+                Shelf::Value { value, .. } => value.as_f64().unwrap(),
+                _ => panic!("Expected a gauge value"),
+            };
+            (
+                value(&["gauges", "max", "views"]),
+                value(&["gauges", "min", "latency"]),
+            )
+        };
+
+        // The replica with the larger max and smaller min has the higher clock, the lower one, or a concurrent one
+        for clock in [[1, 1], [1, 5], [2, 3]] {
+            let a = replica(9, 2, clock);
+            let b = replica(4, 7, [2, 1]);
+            let (a, b) = sync(a, b, &strategies);
+            assert_eq!(a, b);
+            assert_eq!(gauges(&a), (9.0, 2.0));
+        }
+    }
+
+    #[test]
+    fn test_set_union() {
+        let strategies = strategies();
+        let a: TestShelf = json!([{
+            "tags": [{"post": [["crdt", "rust"], [1, 1]]}, 0],
+            "names": [{"1": ["a", [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let b: TestShelf = json!([{
+            "tags": [{"post": [["wasm", "crdt"], [2, 2]]}, 0],
+            "names": [{"1": ["b", [2, 2]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+
+        let (a, b) = sync(a, b, &strategies);
+        assert_eq!(a, b);
+        let expected: TestShelf = json!([{
+            "tags": [{"post": [["crdt", "rust", "wasm"], [2, 2]]}, 0],
+            "names": [{"1": ["b", [2, 2]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        assert_eq!(a, expected);
+
+        // NaN neither orders nor equals itself, but the union is still commutative and idempotent
+        let nan = || Value::Float(f32::NAN);
+        let this = Value::Array(vec![nan(), 2.0.into()]);
+        let other = Value::Array(vec![1.0.into(), nan()]);
+        let union = MergeStrategy::Union.combine(&this, &other).unwrap();
+        let expected = Value::Array(vec![1.0.into(), 2.0.into(), nan()]);
+        assert_eq!(OrderedValue(union.clone()), OrderedValue(expected));
+        let reversed = MergeStrategy::Union.combine(&other, &this).unwrap();
+        assert_eq!(OrderedValue(reversed), OrderedValue(union.clone()));
+        let again = MergeStrategy::Union.combine(&union, &union).unwrap();
+        assert_eq!(OrderedValue(again), OrderedValue(union));
+        let nans = Value::Array(vec![nan()]);
+        let union = MergeStrategy::Union.combine(&nans, &nans).unwrap();
+        assert_eq!(OrderedValue(union), OrderedValue(nans));
+    }
+}
@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::stable_hash::stable_hash;
+use crate::wrap_crdt::Shelf;
+
+/// Fingerprints of a shelf and each of its subtrees. A reconnecting peer compares summaries with `diff_merkle` to
+/// find the subtrees that diverged, so that state vectors only need to be exchanged for those.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct MerkleNode<K: Eq + Hash = String> {
+    /// Covers the clocks and values of the whole subtree.
+    pub hash: u64,
+    /// Covers only this shelf: the clock of a map, or the value and clock of a Shelf Value.
+    pub own_hash: u64,
+    /// Summaries of the entries of a map. Empty for a Shelf Value.
+    pub children: HashMap<K, MerkleNode<K>>,
+}
+
+impl<T, MapClock, ValueClock, K> Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialEq + PartialOrd + Serialize,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Serialize,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + Serialize,
+    K: Eq + Hash + Clone,
+{
+    /// Summarizes the shelf for `diff_merkle`. Values and clocks are hashed through their binary encoding, so
+    /// summaries agree across peers and platforms like the fingerprints of `stable_hash` do.
+    pub fn merkle_summary(&self) -> MerkleNode<K> {
+        match self {
+            Shelf::Value { value, clock } => {
+                let bytes = bincode::serialize(&(value, clock))
+                    .expect("Shelf values and clocks must serialize");
+                let own_hash = stable_hash(&(0u8, bytes));
+                MerkleNode {
+                    hash: own_hash,
+                    own_hash,
+                    children: HashMap::new(),
+                }
+            }
+            Shelf::Map { shelves, clock } => {
+                let children: HashMap<K, MerkleNode<K>> = shelves
+                    .iter()
+                    .map(|(k, shelf)| (k.clone(), shelf.merkle_summary()))
+                    .collect();
+                // Summing the entries keeps the hash independent of the map's iteration order
+                let entries = children
+                    .iter()
+                    .map(|(k, child)| stable_hash(&(k, child.hash)))
+                    .fold(0u64, u64::wrapping_add);
+                let clock = bincode::serialize(clock).expect("Shelf clocks must serialize");
+                let own_hash = stable_hash(&(1u8, clock));
+                MerkleNode {
+                    hash: stable_hash(&(own_hash, entries)),
+                    own_hash,
+                    children,
+                }
+            }
+        }
+    }
+}
+
+/// Paths of the subtrees whose summaries differ. Entries that only one side has and maps whose own clock differs
+/// are reported once at their root, since the whole subtree has to be synced.
+pub fn diff_merkle<K: Eq + Hash + Clone>(
+    local: &MerkleNode<K>,
+    remote: &MerkleNode<K>,
+) -> Vec<Vec<K>> {
+    let mut diverged = Vec::new();
+    collect_diverged(local, remote, &mut Vec::new(), &mut diverged);
+    diverged
+}
+
+fn collect_diverged<K: Eq + Hash + Clone>(
+    local: &MerkleNode<K>,
+    remote: &MerkleNode<K>,
+    path: &mut Vec<K>,
+    diverged: &mut Vec<Vec<K>>,
+) {
+    if local.hash == remote.hash {
+        return;
+    }
+    if local.own_hash != remote.own_hash {
+        diverged.push(path.clone());
+        return;
+    }
+    for (key, child) in &local.children {
+        path.push(key.clone());
+        match remote.children.get(key) {
+            Some(remote_child) => collect_diverged(child, remote_child, path, diverged),
+            None => diverged.push(path.clone()),
+        }
+        path.pop();
+    }
+    for key in remote
+        .children
+        .keys()
+        .filter(|key| !local.children.contains_key(*key))
+    {
+        path.push(key.clone());
+        diverged.push(path.clone());
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::{DotClock, LamportTimestamp};
+    use crate::json::Value;
+
+    type TestShelf = Shelf<Value, LamportTimestamp, DotClock>;
+
+    fn shelf(json: serde_json::Value) -> TestShelf {
+        json.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_diff_merkle() {
+        let local = shelf(json!([{
+            "user": [{"name": ["a", [1, 1]], "age": [30, [1, 1]]}, 0],
+            "settings": [{"dark": [true, [1, 1]]}, 0]
+        }, 0]));
+        let mut remote = local.clone();
+        *remote.get_path_mut(&["user", "name"]).unwrap() = shelf(json!(["b", [2, 2]]));
+
+        let diverged = diff_merkle(&local.merkle_summary(), &remote.merkle_summary());
+        assert_eq!(diverged, [vec!["user", "name"]]);
+        assert!(diff_merkle(&local.merkle_summary(), &local.clone().merkle_summary()).is_empty());
+
+        // An entry only one side has, and a map that was replaced
+        if let Some(Shelf::Map { shelves, clock }) = remote.get_mut("settings") {
+            shelves.clear();
+            *clock = 1.into();
+        }
+        if let Some(Shelf::Map { shelves, .. }) = remote.get_mut("user") {
+            shelves.remove("age");
+        }
+        let mut diverged = diff_merkle(&local.merkle_summary(), &remote.merkle_summary());
+        diverged.sort();
+        assert_eq!(
+            diverged,
+            [vec!["settings"], vec!["user", "age"], vec!["user", "name"]]
+        );
+    }
+}
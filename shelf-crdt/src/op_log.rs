@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+
+use crate::clock::ClientId;
+use crate::traits::Mergeable;
+use crate::wrap_crdt::Shelf;
+use std::clone::Clone;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+/// What produced a logged operation.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum OperationKind {
+    /// The shelf the log started from.
+    Init,
+    /// A local write at `path`.
+    Set { path: Vec<String> },
+    /// A delta merged in from a peer.
+    Merge,
+}
+
+/// An operation applied to a `LoggedShelf`. Each one is stored as the delta that it merged into the shelf, with
+/// its clocks, so replaying the log does not depend on the order of the operations.
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+pub struct Operation<T, MapClock, ValueClock = MapClock>
+where
+    T: PartialEq + PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    pub kind: OperationKind,
+    /// The client that made the change.
+    pub source: ClientId,
+    pub delta: Shelf<T, MapClock, ValueClock>,
+}
+
+/// An append-only history of the operations applied to a shelf.
+pub type OpLog<T, MapClock, ValueClock = MapClock> = Vec<Operation<T, MapClock, ValueClock>>;
+
+/// Reconstructs a shelf by merging every logged delta into an empty map. Merges commute, so shuffled logs and logs
+/// collected from different replicas of the same history give the same shelf.
+pub fn replay<T, MapClock, ValueClock>(
+    ops: impl IntoIterator<Item = Operation<T, MapClock, ValueClock>>,
+) -> Shelf<T, MapClock, ValueClock>
+where
+    T: PartialEq + PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Default,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    let empty = Shelf::Map {
+        shelves: HashMap::new(),
+        clock: MapClock::default(),
+    };
+    ops.into_iter()
+        .fold(empty, |shelf, op| shelf.merge(op.delta))
+}
+
+/// A Shelf that can record the operations applied to it, for auditing and time-travel debugging.
+/// Operations are only recorded when `log` is set, so that shelves which don't need a history don't pay for it.
+pub struct LoggedShelf<T, MapClock, ValueClock = MapClock>
+where
+    T: PartialEq + PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    pub shelf: Shelf<T, MapClock, ValueClock>,
+    pub log: Option<OpLog<T, MapClock, ValueClock>>,
+}
+
+impl<T, MapClock, ValueClock> LoggedShelf<T, MapClock, ValueClock>
+where
+    T: PartialEq + PartialOrd + Clone,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Clone,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + Clone,
+{
+    /// Wraps `shelf` without recording anything.
+    pub fn new(shelf: Shelf<T, MapClock, ValueClock>) -> Self {
+        LoggedShelf { shelf, log: None }
+    }
+
+    /// Wraps `shelf` and records every following operation. The log starts with `shelf` itself, attributed to
+    /// `source`, so that replaying it yields the current state.
+    pub fn with_log(shelf: Shelf<T, MapClock, ValueClock>, source: ClientId) -> Self {
+        let init = Operation {
+            kind: OperationKind::Init,
+            source,
+            delta: shelf.clone(),
+        };
+        LoggedShelf {
+            shelf,
+            log: Some(vec![init]),
+        }
+    }
+
+    /// Sets `value` at `path`, replacing whatever was there. An empty path replaces the root.
+    /// The clock of `value` must be higher than that of the shelf it replaces, otherwise replaying the log
+    /// keeps the old shelf.
+    pub fn set(
+        &mut self,
+        path: &[&str],
+        value: Shelf<T, MapClock, ValueClock>,
+        source: ClientId,
+    ) -> Result<(), String> {
+        if let Some(log) = self.log.as_mut() {
+            let delta = Self::wrap_in_path(&self.shelf, path, value.clone())?;
+            let path = path.iter().map(|key| key.to_string()).collect();
+            log.push(Operation {
+                kind: OperationKind::Set { path },
+                source,
+                delta,
+            });
+        }
+        if path.is_empty() {
+            self.shelf = value;
+            return Ok(());
+        }
+        let (entry, _) = self
+            .shelf
+            .entry_from_path(path.iter().map(|key| key.to_string()))?;
+        match entry {
+            Entry::Occupied(mut o) => {
+                o.insert(value);
+            }
+            Entry::Vacant(v) => {
+                v.insert(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges a delta from `source` into the shelf.
+    pub fn merge(self, delta: Shelf<T, MapClock, ValueClock>, source: ClientId) -> Self {
+        let LoggedShelf { shelf, mut log } = self;
+        if let Some(log) = log.as_mut() {
+            log.push(Operation {
+                kind: OperationKind::Merge,
+                source,
+                delta: delta.clone(),
+            });
+        }
+        LoggedShelf {
+            shelf: shelf.merge(delta),
+            log,
+        }
+    }
+
+    /// Nests `value` in copies of the maps along `path`, keeping their clocks so that merging the result only
+    /// touches `path`.
+    fn wrap_in_path(
+        shelf: &Shelf<T, MapClock, ValueClock>,
+        path: &[&str],
+        value: Shelf<T, MapClock, ValueClock>,
+    ) -> Result<Shelf<T, MapClock, ValueClock>, String> {
+        let mut delta = value;
+        for (depth, key) in path.iter().enumerate().rev() {
+            let clock = match shelf.get_path(&path[..depth])? {
+                Shelf::Map { clock, .. } => clock.clone(),
+                Shelf::Value { .. } => {
+                    return Err(format!("Cannot set the key '{key}' on a Shelf Value"))
+                }
+            };
+            delta = Shelf::Map {
+                shelves: HashMap::from([(key.to_string(), delta)]),
+                clock,
+            };
+        }
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{prelude::SliceRandom, rngs::StdRng, SeedableRng};
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::{DotClock, LamportTimestamp};
+    use crate::json::Value;
+    use crate::traits::DeltaCRDT;
+
+    type TestShelf = Shelf<Value, LamportTimestamp, DotClock>;
+
+    fn shelf(json: serde_json::Value) -> TestShelf {
+        json.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_shuffled_replay() {
+        let initial = shelf(json!([{"user": [{"name": ["a", [1, 1]]}, 1]}, 0]));
+        let mut live = LoggedShelf::with_log(initial.clone(), 1);
+        live.set(&["user", "name"], shelf(json!(["b", [1, 2]])), 1)
+            .unwrap();
+        live.set(&["user", "age"], shelf(json!([30, [1, 3]])), 1)
+            .unwrap();
+
+        // A peer that started from the same shelf made its own edits
+        let mut peer = initial;
+        if let Some(Shelf::Map { shelves, .. }) = peer.get_mut("user") {
+            shelves.insert("city".to_owned(), shelf(json!(["Graz", [2, 2]])));
+        }
+        let delta = peer
+            .get_state_delta(&live.shelf.get_state_vector())
+            .unwrap();
+        let mut live = live.merge(delta, 2);
+        live.set(
+            &["settings"],
+            shelf(json!([{"dark": [true, [1, 4]]}, 4])),
+            1,
+        )
+        .unwrap();
+
+        let log = live.log.clone().unwrap();
+        assert_eq!(log.len(), 5);
+        assert_eq!(replay(log.clone()), live.shelf);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..20 {
+            let mut shuffled = log.clone();
+            shuffled.shuffle(&mut rng);
+            assert_eq!(replay(shuffled), live.shelf);
+        }
+    }
+
+    #[test]
+    fn test_logging_is_opt_in() {
+        let mut unlogged = LoggedShelf::new(shelf(json!([{}, 0])));
+        unlogged.set(&["a"], shelf(json!([1, [1, 1]])), 1).unwrap();
+        assert!(unlogged.log.is_none());
+
+        let mut logged = LoggedShelf::with_log(shelf(json!([{}, 0])), 1);
+        assert!(logged
+            .set(&["a", "b"], shelf(json!([1, [1, 1]])), 1)
+            .is_err());
+        assert_eq!(logged.log.unwrap().len(), 1);
+    }
+}
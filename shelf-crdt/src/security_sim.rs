@@ -4,12 +4,14 @@ use crate::state_vector::StateVector;
 use crate::traits::DeltaCRDT;
 use crate::wrap_crdt::Shelf;
 
+use rand::prelude::StdRng;
 use rand::seq::{IteratorRandom, SliceRandom};
-use rand::{self, Rng};
+use rand::{self, Rng, SeedableRng};
 use random_word;
 use std::collections::HashMap;
-use std::sync::mpsc::{self, channel};
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{self, channel, sync_channel};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, SystemTime};
 
@@ -63,15 +65,29 @@ struct Message {
 }
 
 impl Message {
-    pub fn new(from: String, payload: Payload) -> Self {
+    pub fn new(from: String, payload: Payload, timestamp: SystemTime) -> Self {
         Self {
             from,
             payload,
-            timestamp: SystemTime::now(),
+            timestamp,
         }
     }
 }
 
+/// Where clients read the time from, so that tests can drive the simulation with virtual time.
+trait TimeSource: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock.
+struct RealTime;
+
+impl TimeSource for RealTime {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
 struct ClientConfig {
     update_interval: Duration,
     set_interval: Duration,
@@ -91,21 +107,96 @@ struct SimulationConfig {
     pub n_nodes: usize,
     pub p_byzantine: f64,
     pub duration: Duration,
+    /// Seeds every random choice in the network so a run can be replayed.
+    pub seed: u64,
+    pub channel: ChannelConfig,
+}
+
+/// The inboxes that clients send each other messages through.
+#[derive(Clone, Copy)]
+enum ChannelConfig {
+    /// Inboxes grow without bound, so a slow client never slows down the ones sending to it.
+    Unbounded,
+    /// Inboxes hold at most `capacity` messages, `when_full` decides what a sender does with the rest.
+    Bounded {
+        capacity: usize,
+        when_full: FullPolicy,
+    },
+}
+
+impl ChannelConfig {
+    fn open(&self) -> (Outbox, Receiver<Message>) {
+        match *self {
+            ChannelConfig::Unbounded => {
+                let (tx, rx) = channel();
+                (Outbox::Unbounded(tx), rx)
+            }
+            ChannelConfig::Bounded {
+                capacity,
+                when_full,
+            } => {
+                let (tx, rx) = sync_channel(capacity);
+                (Outbox::Bounded(tx, when_full), rx)
+            }
+        }
+    }
+}
+
+/// What a sender does when the receiver's inbox is full.
+#[derive(Clone, Copy)]
+enum FullPolicy {
+    /// Give up on the message, like a congested network would.
+    Drop,
+    /// Wait until the receiver makes room. Only use this when clients run on their own threads: a client stepped on
+    /// the same thread as a peer it is blocked on never gets to empty its inbox.
+    Block,
+}
+
+/// The sending end of a client's inbox.
+#[derive(Clone)]
+enum Outbox {
+    Unbounded(Sender<Message>),
+    Bounded(SyncSender<Message>, FullPolicy),
+}
+
+impl Outbox {
+    /// Sends `message`, returning whether it was delivered. It is not if the inbox is full under `FullPolicy::Drop`
+    /// or the receiving client has stopped.
+    fn send(&self, message: Message) -> bool {
+        match self {
+            Outbox::Unbounded(tx) => tx.send(message).is_ok(),
+            Outbox::Bounded(tx, FullPolicy::Block) => tx.send(message).is_ok(),
+            Outbox::Bounded(tx, FullPolicy::Drop) => match tx.try_send(message) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => false,
+            },
+        }
+    }
 }
 
 struct Client {
     uid: String,
-    peers: HashMap<String, Sender<Message>>,
+    peers: HashMap<String, Outbox>,
     inbox: Receiver<Message>,
     actions: Vec<ClientAction>,
     shelf: SecureShelf,
+    rng: StdRng,
+    time: Arc<dyn TimeSource>,
+    /// Messages this client sent that were not delivered.
+    dropped: usize,
 }
 
 impl Client {
     fn new_network(config: &SimulationConfig) -> Vec<Self> {
-        let (outboxes, inboxes): (Vec<Sender<Message>>, Vec<Receiver<Message>>) =
+        Self::new_network_with_time(config, Arc::new(RealTime))
+    }
+
+    /// Creates a network whose clients all read the time from `time`.
+    fn new_network_with_time(config: &SimulationConfig, time: Arc<dyn TimeSource>) -> Vec<Self> {
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let (outboxes, inboxes): (Vec<Outbox>, Vec<Receiver<Message>>) =
             (0..config.n_nodes).fold((vec![], vec![]), |(mut outboxes, mut inboxes), _| {
-                let (tx, rx) = channel();
+                let (tx, rx) = config.channel.open();
                 inboxes.push(rx);
                 outboxes.push(tx);
                 (outboxes, inboxes)
@@ -125,25 +216,28 @@ impl Client {
                         (peer_uid, address)
                     })
                     .collect();
+                let client_rng = StdRng::seed_from_u64(rng.gen());
+                let time = time.clone();
                 if uid < byzantine_split {
-                    Self::new_byzantine(uid.to_string(), inbox, peers)
+                    Self::new_byzantine(uid.to_string(), inbox, peers, client_rng, time)
                 } else {
-                    Self::new(uid.to_string(), inbox, peers)
+                    Self::new(uid.to_string(), inbox, peers, client_rng, time)
                 }
             })
             .collect();
 
         // Randomize order
-        clients.shuffle(&mut rand::thread_rng());
+        clients.shuffle(&mut rng);
         clients
     }
 
     fn new_byzantine(
         uid: String,
         inbox: Receiver<Message>,
-        peers: HashMap<String, Sender<Message>>,
+        peers: HashMap<String, Outbox>,
+        mut rng: StdRng,
+        time: Arc<dyn TimeSource>,
     ) -> Self {
-        let mut rng = rand::thread_rng();
         let actions = [
             ClientAction::new(
                 Action::CheckInbox,
@@ -166,11 +260,16 @@ impl Client {
                 Duration::from_millis(rng.gen_range(5..10)),
             ),
         ];
-        Self::from_actions(uid, inbox, peers, actions)
+        Self::from_actions(uid, inbox, peers, actions, rng, time)
     }
 
-    fn new(uid: String, inbox: Receiver<Message>, peers: HashMap<String, Sender<Message>>) -> Self {
-        let mut rng = rand::thread_rng();
+    fn new(
+        uid: String,
+        inbox: Receiver<Message>,
+        peers: HashMap<String, Outbox>,
+        mut rng: StdRng,
+        time: Arc<dyn TimeSource>,
+    ) -> Self {
         let actions = [
             ClientAction::new(
                 Action::CheckInbox,
@@ -186,14 +285,16 @@ impl Client {
             ),
             ClientAction::new(Action::CheckForCorruption, Duration::from_millis(0)),
         ];
-        Self::from_actions(uid, inbox, peers, actions)
+        Self::from_actions(uid, inbox, peers, actions, rng, time)
     }
 
     fn from_actions(
         uid: String,
         inbox: Receiver<Message>,
-        peers: HashMap<String, Sender<Message>>,
+        peers: HashMap<String, Outbox>,
         actions: impl IntoIterator<Item = ClientAction>,
+        rng: StdRng,
+        time: Arc<dyn TimeSource>,
     ) -> Self {
         Self {
             uid,
@@ -204,20 +305,38 @@ impl Client {
                 shelves: HashMap::new(),
                 clock: 0.into(),
             },
+            rng,
+            time,
+            dropped: 0,
         }
     }
 
+    /// Runs the actions whose interval has elapsed.
     pub fn step(&mut self) -> Option<SecureShelf> {
+        self.run_actions(false)
+    }
+
+    /// Runs every action once regardless of its interval.
+    /// Stepping all clients in lockstep from one thread makes a seeded run reproducible, since nothing depends on timing.
+    pub fn step_all(&mut self) -> Option<SecureShelf> {
+        self.run_actions(true)
+    }
+
+    fn run_actions(&mut self, ignore_intervals: bool) -> Option<SecureShelf> {
         let actions = &mut self.actions;
         let mut context: ActionContext = ActionContext {
             uid: self.uid.as_str(),
             peers: &self.peers,
             inbox: &mut self.inbox,
             shelf: &mut self.shelf,
+            rng: &mut self.rng,
+            time: self.time.as_ref(),
+            dropped: &mut self.dropped,
         };
+        let now = self.time.now();
         let follow_ups: Vec<Action> = actions
             .iter_mut()
-            .filter(|action| action.should_run())
+            .filter(|action| ignore_intervals || action.should_run(now))
             .filter_map(|action| action.act(&mut context))
             .collect();
         for action in follow_ups {
@@ -249,15 +368,14 @@ impl ClientAction {
             last_performed: std::time::UNIX_EPOCH,
         }
     }
-    pub fn should_run(&self) -> bool {
-        SystemTime::now()
-            .duration_since(self.last_performed)
+    pub fn should_run(&self, now: SystemTime) -> bool {
+        now.duration_since(self.last_performed)
             .map(|dur| dur > self.interval)
             .unwrap_or(false)
     }
     pub fn act(&mut self, context: &mut ActionContext) -> Option<Action> {
         let follow_up_action = self.action.act(context);
-        self.last_performed = SystemTime::now();
+        self.last_performed = context.time.now();
         follow_up_action
     }
 }
@@ -317,26 +435,24 @@ impl Action {
                 if let Some(delta) = context.shelf.get_state_delta(&sv) {
                     let outbox = context.peers.get(&message.from).unwrap();
                     let payload = Payload::Delta(delta);
-                    let response = Message::new(context.uid.to_owned(), payload);
-                    let send_res = outbox.send(response);
+                    let response =
+                        Message::new(context.uid.to_owned(), payload, context.time.now());
+                    if !outbox.send(response) {
+                        *context.dropped += 1;
+                    }
                 }
                 None
             }
             Payload::Delta(delta) => {
-                let mut tmp = Shelf::Map {
-                    shelves: HashMap::new(),
-                    clock: 0.into(),
-                };
-                std::mem::swap(&mut tmp, context.shelf);
-                *context.shelf = tmp.secure_merge(delta);
+                context.shelf.secure_merge_assign(delta);
                 None
             }
             Payload::Terminate => Some(Action::Terminate),
         }
     }
     fn random_edit(&self, context: &mut ActionContext) {
-        let key = random_word::gen().to_owned();
-        let value: usize = rand::thread_rng().gen();
+        let key = random_word::all().choose(context.rng).unwrap().to_string();
+        let value: usize = context.rng.gen();
         const SHELF_SIZE_LIMIT: usize = 200_000;
         match context.shelf {
             Shelf::Map {
@@ -350,11 +466,9 @@ impl Action {
                     shelves.insert(key, shelf);
                 } else {
                     // remove some values
-                    let keys = shelves
-                        .keys()
+                    let keys = sorted_keys(shelves)
                         .into_iter()
-                        .cloned()
-                        .choose_multiple(&mut rand::thread_rng(), SHELF_SIZE_LIMIT / 2);
+                        .choose_multiple(context.rng, SHELF_SIZE_LIMIT / 2);
                     for key in keys {
                         shelves.remove(&key);
                     }
@@ -376,15 +490,18 @@ impl Action {
         // Create state vector
         let sv = context.shelf.get_state_vector();
         // Multicast to all peers
-        context
+        let peers = context
             .peers
             .iter()
-            .filter(|(peer_id, _)| peer_id.as_str() != context.uid)
-            .for_each(|(_, outbox)| {
-                let payload = Payload::StateVector(sv.clone());
+            .filter(|(peer_id, _)| peer_id.as_str() != context.uid);
+        for (_, outbox) in peers {
+            let payload = Payload::StateVector(sv.clone());
 
-                let send_res = outbox.send(Message::new(context.uid.to_owned(), payload));
-            })
+            let message = Message::new(context.uid.to_owned(), payload, context.time.now());
+            if !outbox.send(message) {
+                *context.dropped += 1;
+            }
+        }
     }
 
     fn corrupt_clock(&self, context: &mut ActionContext) {
@@ -395,10 +512,10 @@ impl Action {
                     return;
                 }
 
-                if let Some((key, Shelf::Value { clock, .. })) =
-                    shelves.iter_mut().choose(&mut rand::thread_rng())
+                let key = sorted_keys(shelves).into_iter().choose(context.rng);
+                if let Some(Shelf::Value { clock, .. }) = key.and_then(|key| shelves.get_mut(&key))
                 {
-                    clock.clock = rand::random();
+                    clock.clock = context.rng.gen();
                 } else {
                     unreachable!("Shelf should be flat")
                 }
@@ -414,10 +531,10 @@ impl Action {
                     return;
                 }
 
-                if let Some((key, Shelf::Value { value, .. })) =
-                    shelves.iter_mut().choose(&mut rand::thread_rng())
+                let key = sorted_keys(shelves).into_iter().choose(context.rng);
+                if let Some(Shelf::Value { value, .. }) = key.and_then(|key| shelves.get_mut(&key))
                 {
-                    *value = rand::random();
+                    *value = context.rng.gen();
                 } else {
                     unreachable!("Shelf should be flat")
                 }
@@ -443,11 +560,21 @@ impl Action {
     }
 }
 
+/// HashMap iteration order differs between maps, so random picks go through sorted keys to stay reproducible.
+fn sorted_keys(shelves: &HashMap<String, SecureShelf>) -> Vec<String> {
+    let mut keys: Vec<String> = shelves.keys().cloned().collect();
+    keys.sort();
+    keys
+}
+
 struct ActionContext<'a> {
     uid: &'a str,
-    peers: &'a HashMap<String, Sender<Message>>,
+    peers: &'a HashMap<String, Outbox>,
     inbox: &'a mut Receiver<Message>,
     shelf: &'a mut SecureShelf,
+    rng: &'a mut StdRng,
+    time: &'a dyn TimeSource,
+    dropped: &'a mut usize,
 }
 
 #[cfg(test)]
@@ -459,6 +586,8 @@ mod tests {
             n_nodes: 10,
             p_byzantine: 0.4,
             duration: Duration::from_secs(10),
+            seed: 0,
+            channel: ChannelConfig::Unbounded,
         };
         let network = Client::new_network(&config);
         let mailboxes = network[0].peers.clone();
@@ -474,9 +603,11 @@ mod tests {
             .collect();
         thread::sleep(config.duration);
         mailboxes.values().for_each(|mailbox| {
-            mailbox
-                .send(Message::new("".to_owned(), Payload::Terminate))
-                .unwrap()
+            assert!(mailbox.send(Message::new(
+                "".to_owned(),
+                Payload::Terminate,
+                SystemTime::now(),
+            )))
         });
         let shelf_results = handles.into_iter().map(|h| h.join().unwrap());
         let valid_shelves = shelf_results
@@ -504,6 +635,8 @@ mod tests {
             n_nodes: 4,
             p_byzantine: 0.5,
             duration: Duration::from_secs(5),
+            seed: 0,
+            channel: ChannelConfig::Unbounded,
         };
         let mut network = Client::new_network(&config);
         for i in 0..STEPS {
@@ -528,4 +661,133 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn simulate_network_replay() {
+        const STEPS: usize = 10;
+        let config = SimulationConfig {
+            n_nodes: 4,
+            p_byzantine: 0.5,
+            duration: Duration::from_secs(5),
+            seed: 42,
+            channel: ChannelConfig::Unbounded,
+        };
+        let run = || {
+            let mut network = Client::new_network(&config);
+            for _ in 0..STEPS {
+                network.iter_mut().for_each(|client| {
+                    client.step_all();
+                })
+            }
+            let mut shelves: Vec<(String, SecureShelf)> = network
+                .into_iter()
+                .map(|client| (client.uid, client.shelf))
+                .collect();
+            shelves.sort_by(|(a, _), (b, _)| a.cmp(b));
+            shelves
+        };
+        let first = run();
+        assert!(first
+            .iter()
+            .any(|(_, shelf)| matches!(shelf, Shelf::Map { shelves, .. } if !shelves.is_empty())));
+        assert!(first == run());
+    }
+
+    /// A clock that only moves when advanced.
+    struct MockTime(std::sync::Mutex<SystemTime>);
+
+    impl MockTime {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl TimeSource for MockTime {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn simulate_with_virtual_time() {
+        let config = SimulationConfig {
+            n_nodes: 2,
+            p_byzantine: 0.0,
+            duration: Duration::from_secs(1),
+            seed: 0,
+            channel: ChannelConfig::Unbounded,
+        };
+        let start = std::time::UNIX_EPOCH + Duration::from_secs(1000);
+        let time = Arc::new(MockTime(std::sync::Mutex::new(start)));
+        let mut network = Client::new_network_with_time(&config, time.clone());
+        network.sort_by(|a, b| a.uid.cmp(&b.uid));
+        let (sender, receiver) = match network.as_mut_slice() {
+            [sender, receiver] => (sender, receiver),
+            _ => unreachable!("The network has two clients"),
+        };
+
+        sender.step();
+        let message = receiver.inbox.try_recv().unwrap();
+        assert_eq!(message.from, "0");
+        assert_eq!(message.timestamp, start);
+
+        // No time passed, so no action is due
+        sender.step();
+        assert!(receiver.inbox.try_recv().is_err());
+
+        time.advance(Duration::from_secs(1));
+        sender.step();
+        let message = receiver.inbox.try_recv().unwrap();
+        assert_eq!(message.timestamp, start + Duration::from_secs(1));
+    }
+
+    /// Two clients where only the first, uid "0", is stepped, so everything it sends piles up in the second's inbox.
+    fn fast_sender(when_full: FullPolicy) -> (Client, Client) {
+        let config = SimulationConfig {
+            n_nodes: 2,
+            p_byzantine: 0.0,
+            duration: Duration::from_secs(1),
+            seed: 0,
+            channel: ChannelConfig::Bounded {
+                capacity: 2,
+                when_full,
+            },
+        };
+        let mut network = Client::new_network(&config);
+        network.sort_by(|a, b| b.uid.cmp(&a.uid));
+        let sender = network.pop().unwrap();
+        let receiver = network.pop().unwrap();
+        (sender, receiver)
+    }
+
+    #[test]
+    fn simulate_full_inbox_drops() {
+        let (mut sender, receiver) = fast_sender(FullPolicy::Drop);
+        for _ in 0..5 {
+            sender.step_all();
+        }
+        assert_eq!(receiver.inbox.try_iter().count(), 2);
+        assert_eq!(sender.dropped, 3);
+    }
+
+    #[test]
+    fn simulate_full_inbox_blocks() {
+        let (mut sender, receiver) = fast_sender(FullPolicy::Block);
+        let handle = thread::spawn(move || {
+            for _ in 0..5 {
+                sender.step_all();
+            }
+            sender
+        });
+        thread::sleep(Duration::from_millis(200));
+        // The sender waits for room instead of dropping its third message
+        assert!(!handle.is_finished());
+
+        let received = (0..5)
+            .map(|_| receiver.inbox.recv_timeout(Duration::from_secs(5)))
+            .filter(Result::is_ok)
+            .count();
+        assert_eq!(received, 5);
+        assert_eq!(handle.join().unwrap().dropped, 0);
+    }
 }
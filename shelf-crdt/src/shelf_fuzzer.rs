@@ -3,6 +3,8 @@ use random_word;
 use serde_json::{self, json, Map, Number, Value as JSON};
 use std::{collections::HashSet, ops::Range};
 
+use crate::clock::ClientId;
+
 pub struct ShelfFuzzer {
     pub rng: StdRng,
     pub depth_range: Range<usize>,
@@ -25,45 +27,79 @@ impl ShelfFuzzer {
             value_range: 0..1,
         }
     }
-    pub fn generate_json_shelf(&mut self, client_id: usize) -> JSON {
-        return self.generate_children(1, true, client_id);
+    pub fn generate_json_shelf(&mut self, client_id: ClientId) -> JSON {
+        let mut rng = self.rng.clone();
+        let json = self.generate_json_shelf_with(&mut rng, client_id);
+        self.rng = rng;
+        json
     }
 
     pub fn generate_json_values(&mut self) -> JSON {
-        self.generate_children(1, false, 0) // Clock value not used
+        let mut rng = self.rng.clone();
+        let json = self.generate_json_values_with(&mut rng);
+        self.rng = rng;
+        json
+    }
+
+    /// Generates a shelf like `generate_json_shelf`, drawing from `rng` instead of the fuzzer's own RNG.
+    /// Draws happen in the same order, so a fuzzer and an external RNG with the same seed generate the same shelves,
+    /// and generators that share an RNG continue each other's sequence.
+    pub fn generate_json_shelf_with<R: Rng>(&self, rng: &mut R, client_id: ClientId) -> JSON {
+        self.generate_children(rng, 1, true, client_id)
+    }
+
+    /// Generates values like `generate_json_values`, drawing from `rng` instead of the fuzzer's own RNG.
+    pub fn generate_json_values_with<R: Rng>(&self, rng: &mut R) -> JSON {
+        self.generate_children(rng, 1, false, 0) // Clock value not used
+    }
+
+    /// Wraps copies of `base`, plain values like those from `generate_json_values`, in random clocks, one copy per
+    /// client in `client_ids`. The copies share every key and value, so merging them only exercises how clocks are
+    /// resolved and pruned rather than how different structures combine.
+    pub fn generate_clock_variants(&mut self, base: JSON, client_ids: &[ClientId]) -> Vec<JSON> {
+        client_ids
+            .iter()
+            .map(|client_id| Self::wrap_in_clocks(&mut self.rng, &base, 1, *client_id))
+            .collect()
     }
 
     pub fn set_seed(&mut self, seed: u64) {
         self.rng = StdRng::seed_from_u64(seed);
     }
 
-    fn gen_keys(&mut self, n_keys: usize) -> Vec<String> {
+    fn gen_keys<R: Rng>(rng: &mut R, n_keys: usize) -> Vec<String> {
         let mut keys = random_word::all()[..10000]
             .iter()
-            .choose_multiple(&mut self.rng, n_keys);
-        keys.shuffle(&mut self.rng);
+            .choose_multiple(rng, n_keys);
+        keys.shuffle(rng);
         keys.into_iter().map(|s| s.to_string()).collect()
     }
 
-    fn generate_children(&mut self, depth: usize, include_clocks: bool, client_id: usize) -> JSON {
+    fn generate_children<R: Rng>(
+        &self,
+        rng: &mut R,
+        depth: usize,
+        include_clocks: bool,
+        client_id: ClientId,
+    ) -> JSON {
         let mut children: Map<String, JSON> = Map::new();
-        if depth <= self.rng.gen_range(self.depth_range.clone()) {
-            let num_branches = self.rng.gen_range(self.branch_range.clone());
-            let keys = self.gen_keys(num_branches);
+        if depth <= rng.gen_range(self.depth_range.clone()) {
+            let num_branches = rng.gen_range(self.branch_range.clone());
+            let keys = Self::gen_keys(rng, num_branches);
             let branches = keys.into_iter().map(|key| {
                 (
                     key,
-                    self.generate_children(depth + 1, include_clocks, client_id),
+                    self.generate_children(rng, depth + 1, include_clocks, client_id),
                 )
             });
             children.extend(branches);
         } else {
-            let num_items = self.rng.gen_range(self.value_range.clone());
-            let keys = self.gen_keys(num_items);
+            let num_items = rng.gen_range(self.value_range.clone());
+            let keys = Self::gen_keys(rng, num_items);
             let items = keys.into_iter().map(|key| {
-                let mut value = self.sample_value_recursive(depth);
+                let mut value = self.sample_value_recursive(rng, depth);
                 if include_clocks {
-                    value = self.wrap_in_value_clock(value, depth, client_id)
+                    value = Self::wrap_in_value_clock(rng, value, depth, client_id)
                 }
                 (key, value)
             });
@@ -72,55 +108,76 @@ impl ShelfFuzzer {
 
         let children = JSON::Object(children);
         if include_clocks {
-            self.wrap_in_map_clock(children, depth)
+            Self::wrap_in_map_clock(rng, children, depth)
         } else {
             children
         }
     }
-    fn wrap_in_value_clock(&mut self, value: JSON, depth: usize, client_id: usize) -> JSON {
-        let clock = self
-            .rng
-            .gen_range((depth.checked_sub(2).unwrap_or(0))..(depth + 2)) as u16;
+
+    /// Adds clocks to plain values, drawing them like `generate_children` does for the same depth.
+    fn wrap_in_clocks<R: Rng>(rng: &mut R, json: &JSON, depth: usize, client_id: ClientId) -> JSON {
+        let children = match json {
+            JSON::Object(children) => children,
+            value => return Self::wrap_in_value_clock(rng, value.clone(), depth, client_id),
+        };
+        let children = children
+            .iter()
+            .map(|(key, child)| {
+                let child = match child {
+                    JSON::Object(_) => Self::wrap_in_clocks(rng, child, depth + 1, client_id),
+                    value => Self::wrap_in_value_clock(rng, value.clone(), depth, client_id),
+                };
+                (key.clone(), child)
+            })
+            .collect();
+        Self::wrap_in_map_clock(rng, JSON::Object(children), depth)
+    }
+
+    fn wrap_in_value_clock<R: Rng>(
+        rng: &mut R,
+        value: JSON,
+        depth: usize,
+        client_id: ClientId,
+    ) -> JSON {
+        let clock = rng.gen_range((depth.checked_sub(2).unwrap_or(0))..(depth + 2)) as u16;
 
         json!([value, [client_id, clock]])
     }
 
-    fn wrap_in_map_clock(&mut self, value: JSON, depth: usize) -> JSON {
-        let clock = self
-            .rng
-            .gen_range((depth.checked_sub(2).unwrap_or(0))..(depth + 2)) as u16;
+    fn wrap_in_map_clock<R: Rng>(rng: &mut R, value: JSON, depth: usize) -> JSON {
+        let clock = rng.gen_range((depth.checked_sub(2).unwrap_or(0))..(depth + 2)) as u16;
 
         json!([value, clock])
     }
 
-    fn sample_value(&mut self) -> JSON {
-        let pick: usize = self.rng.gen_range(0..2);
+    fn sample_value<R: Rng>(rng: &mut R) -> JSON {
+        let pick: usize = rng.gen_range(0..2);
 
         match pick {
-            0 => JSON::String(random_word::gen().to_string()),
-            1 => JSON::Number(Number::from_f64(self.rng.gen()).unwrap()),
-            _ => JSON::Bool(self.rng.gen()),
+            0 => JSON::String(random_word::all().choose(rng).unwrap().to_string()),
+            1 => JSON::Number(Number::from_f64(rng.gen()).unwrap()),
+            _ => JSON::Bool(rng.gen()),
         }
     }
 
-    fn sample_value_recursive(&mut self, depth: usize) -> JSON {
-        let pick: usize = self.rng.gen_range(0..4);
+    fn sample_value_recursive<R: Rng>(&self, rng: &mut R, depth: usize) -> JSON {
+        let pick: usize = rng.gen_range(0..4);
         let array_size_range = 0..5;
 
         let value = match pick {
             0 => {
-                let size: usize = self.rng.gen_range(array_size_range);
-                let arr = if depth <= self.rng.gen_range(self.depth_range.clone()) {
+                let size: usize = rng.gen_range(array_size_range);
+                let arr = if depth <= rng.gen_range(self.depth_range.clone()) {
                     (0..size)
-                        .map(|_| self.sample_value_recursive(depth + 1))
+                        .map(|_| self.sample_value_recursive(rng, depth + 1))
                         .collect()
                 } else {
-                    (0..size).map(|_| self.sample_value()).collect()
+                    (0..size).map(|_| Self::sample_value(rng)).collect()
                 };
 
                 JSON::Array(arr)
             }
-            _ => self.sample_value(),
+            _ => Self::sample_value(rng),
         };
         return value;
     }
@@ -129,6 +186,9 @@ impl ShelfFuzzer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::{DotClock, LamportTimestamp};
+    use crate::json::Value;
+    use crate::wrap_crdt::Shelf;
     use std::collections::HashSet;
 
     fn has_difference(this: &Map<String, JSON>, other: &Map<String, JSON>) -> bool {
@@ -187,4 +247,81 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_shared_rng() {
+        let fuzzer = |depth_range| ShelfFuzzer {
+            rng: StdRng::seed_from_u64(7),
+            depth_range,
+            branch_range: 1..3,
+            value_range: 1..4,
+        };
+        let mut shallow = fuzzer(0..1);
+        let deep = fuzzer(1..4);
+
+        // An external RNG with the fuzzer's seed reproduces the fuzzer's own sequence
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..10 {
+            assert_eq!(
+                shallow.generate_json_shelf_with(&mut rng, 1),
+                shallow.generate_json_shelf(1)
+            );
+        }
+
+        // Generators sharing an RNG continue each other's sequence deterministically
+        let layered = |rng: &mut StdRng| {
+            let base = shallow.generate_json_values_with(rng);
+            let mutation = deep.generate_json_shelf_with(rng, 2);
+            (base, mutation)
+        };
+        let (base, mutation) = layered(&mut StdRng::seed_from_u64(1));
+        assert_eq!(
+            layered(&mut StdRng::seed_from_u64(1)),
+            (base.clone(), mutation.clone())
+        );
+        assert_eq!(
+            base,
+            shallow.generate_json_values_with(&mut StdRng::seed_from_u64(1))
+        );
+        assert_ne!(
+            mutation,
+            deep.generate_json_shelf_with(&mut StdRng::seed_from_u64(1), 2)
+        );
+    }
+
+    #[test]
+    fn test_clock_variants_converge() {
+        let mut fuzzer = ShelfFuzzer {
+            rng: StdRng::seed_from_u64(3),
+            depth_range: 1..4,
+            branch_range: 1..4,
+            value_range: 1..5,
+        };
+        for _ in 0..50 {
+            let base = fuzzer.generate_json_values();
+            let variants: Vec<Shelf<Value, LamportTimestamp, DotClock>> = fuzzer
+                .generate_clock_variants(base, &[1, 2, 3])
+                .into_iter()
+                .map(|json| json.try_into().unwrap())
+                .collect();
+            // Only the clocks differ
+            let values = variants[0].clone().to_json_values();
+            for variant in &variants[1..] {
+                assert_eq!(variant.clone().to_json_values(), values);
+            }
+
+            // Equal values under concurrent dots are ordered by client, plain merges keep whichever clock they saw first
+            let merge_all = |order: [usize; 3]| {
+                order
+                    .into_iter()
+                    .map(|i| variants[i].clone())
+                    .reduce(Shelf::merge_with_client_tiebreak)
+                    .unwrap()
+            };
+            let expected = merge_all([0, 1, 2]);
+            for order in [[0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]] {
+                assert_eq!(merge_all(order), expected);
+            }
+        }
+    }
 }
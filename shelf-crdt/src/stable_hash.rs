@@ -0,0 +1,92 @@
+use fnv::FnvHasher;
+use std::hash::{Hash, Hasher};
+
+/// Hasher for content fingerprints, like the hash in a `SecureClock`, that peers compute independently and compare.
+/// Unlike `DefaultHasher`, whose algorithm may change between Rust versions, this is FNV-1a 64 and stays the same.
+/// Integers are written as fixed-width little endian, so `usize` and `isize` hash the same on 32 and 64 bit targets,
+/// such as the wasm build of shelf-js and a native peer.
+///
+/// Changing how content is hashed breaks fingerprints exchanged with other versions of the crate.
+#[derive(Default)]
+pub struct StableHasher(FnvHasher);
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write(&[i]);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64);
+    }
+}
+
+/// Hashes `value` with a `StableHasher`.
+pub fn stable_hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = StableHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{LamportTimestamp, SecureClock};
+    use crate::json::Value;
+    use crate::wrap_crdt::Shelf;
+    use serde_json::json;
+
+    /// Catches changes to how content is hashed, which would make peers reject each other's `SecureClock`s.
+    #[test]
+    fn test_pinned_fingerprints() {
+        let shelf = Shelf::<Value, LamportTimestamp, SecureClock>::secure_from_json_values(json!({
+            "name": "a",
+            "tags": ["x", 1, true],
+            "ratio": 1.5,
+            "empty": null
+        }))
+        .unwrap();
+        let hash = |key| match shelf.get(key) {
+            Some(Shelf::Value { clock, .. }) => clock.hash,
+            _ => panic!("Expected a value at '{key}'"),
+        };
+        assert_eq!(hash("name"), 7593782332001554441);
+        assert_eq!(hash("tags"), 8138318840348603245);
+        assert_eq!(hash("ratio"), 6020822156875118392);
+        assert_eq!(hash("empty"), 9808874869469701221);
+    }
+
+    #[test]
+    fn test_fixed_width_integers() {
+        assert_eq!(stable_hash(&7usize), stable_hash(&7u64));
+        assert_eq!(stable_hash(&-7isize), stable_hash(&-7i64));
+        assert_ne!(stable_hash(&7u32), stable_hash(&7u64));
+    }
+}
@@ -1,26 +1,35 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JSON};
 
-use crate::clock::{ShelfClock};
+use crate::clock::ShelfClock;
+use crate::encoding;
 use crate::wrap_crdt::Shelf;
 use std::clone::Clone;
 use std::cmp::Ordering;
-use std::{collections::HashMap, fmt::Debug};
+use std::hash::Hash;
+use std::io::Write;
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+};
 
-use crate::traits::{DeltaCRDT};
+use crate::traits::{DeltaCRDT, Mergeable};
 
 #[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
-pub enum StateVector<NodeClock: PartialEq + PartialOrd, LeafClock: PartialEq + PartialOrd> {
-    Node(
-        HashMap<String, StateVector<NodeClock, LeafClock>>,
-        NodeClock,
-    ),
+pub enum StateVector<
+    NodeClock: PartialEq + PartialOrd,
+    LeafClock: PartialEq + PartialOrd,
+    K: Eq + Hash = String,
+> {
+    Node(HashMap<K, StateVector<NodeClock, LeafClock, K>>, NodeClock),
     Leaf(LeafClock),
 }
 
-impl<NodeClock, LeafClock> StateVector<NodeClock, LeafClock>
+impl<NodeClock, LeafClock, K> StateVector<NodeClock, LeafClock, K>
 where
     NodeClock: PartialEq + PartialOrd,
     LeafClock: PartialEq + PartialOrd,
+    K: Eq + Hash,
 {
     fn get_clock(&self) -> ShelfClock<NodeClock, LeafClock> {
         match self {
@@ -28,18 +37,103 @@ where
             StateVector::Leaf(clock) => ShelfClock::ValueClock(clock),
         }
     }
+
+    /// Rebuilds the state vector with different clock types, like `Shelf::convert_clocks`. During a rolling upgrade
+    /// this lets a replica that already converted its shelf compute deltas for a peer that has not.
+    ///
+    /// The conversion must be monotone: clocks that were ordered must keep their order once converted, otherwise
+    /// the delta can leave out entries that the peer is missing. Leaf clocks are converted without the values they
+    /// belong to, so parts of a clock derived from the value, like the hash of a `SecureClock`, cannot be
+    /// reproduced. Such leaves compare as concurrent to the shelf's and are sent even if the peer has them.
+    pub fn migrate<M2, V2>(
+        self,
+        map_fn: &mut impl FnMut(NodeClock) -> M2,
+        val_fn: &mut impl FnMut(LeafClock) -> V2,
+    ) -> StateVector<M2, V2, K>
+    where
+        M2: PartialEq + PartialOrd,
+        V2: PartialEq + PartialOrd,
+    {
+        match self {
+            StateVector::Node(children, clock) => StateVector::Node(
+                children
+                    .into_iter()
+                    .map(|(k, sv)| (k, sv.migrate(map_fn, val_fn)))
+                    .collect(),
+                map_fn(clock),
+            ),
+            StateVector::Leaf(clock) => StateVector::Leaf(val_fn(clock)),
+        }
+    }
+}
+
+impl<NodeClock, LeafClock, K> StateVector<NodeClock, LeafClock, K>
+where
+    NodeClock: PartialEq + PartialOrd + PartialOrd<LeafClock> + PartialEq<LeafClock>,
+    LeafClock: PartialEq + PartialOrd + PartialOrd<NodeClock> + PartialEq<NodeClock>,
+    K: Eq + Hash,
+{
+    /// Whether a replica at this state vector has seen everything a replica at `other` has, so that the other
+    /// replica's `get_state_delta` for this state vector is empty. Follows the same rules as the delta: entries
+    /// missing here are covered if their clock is below this node's, and a leaf is covered by a node.
+    pub fn covers(&self, other: &Self) -> bool {
+        let clock_ordering = other.get_clock().partial_cmp(&self.get_clock());
+        match (other, self, clock_ordering) {
+            (_, _, Some(Ordering::Less)) => true,
+            (_, _, Some(Ordering::Greater)) => false,
+            (StateVector::Node(other_children, _), StateVector::Node(children, clock), _) => {
+                let clock = ShelfClock::MapClock(clock);
+                other_children
+                    .iter()
+                    .all(|(k, other_child)| match children.get(k) {
+                        Some(child) => child.covers(other_child),
+                        None => other_child.get_clock().partial_cmp(&clock) == Some(Ordering::Less),
+                    })
+            }
+            (StateVector::Node(..), StateVector::Leaf(_), _) => false,
+            (StateVector::Leaf(_), StateVector::Node(..), _) => true,
+            (StateVector::Leaf(_), StateVector::Leaf(_), clock_ordering) => {
+                clock_ordering == Some(Ordering::Equal)
+            }
+        }
+    }
 }
 
-impl<N: PartialEq + PartialOrd, L: Default + PartialEq + PartialOrd> Default for StateVector<N, L> {
+/// Orders state vectors by sync progress: `local >= remote` if the local replica has seen everything the remote one
+/// has, `local < remote` if the remote one is ahead, and neither if both saw updates the other did not. See `covers`.
+/// State vectors that cover each other but differ, e.g. because one lists an entry that is older than the node
+/// holding it, do not compare as equal, so that `==` stays consistent. Use `covers` to check them.
+impl<NodeClock, LeafClock, K> PartialOrd for StateVector<NodeClock, LeafClock, K>
+where
+    NodeClock: PartialEq + PartialOrd + PartialOrd<LeafClock> + PartialEq<LeafClock>,
+    LeafClock: PartialEq + PartialOrd + PartialOrd<NodeClock> + PartialEq<NodeClock>,
+    K: Eq + Hash,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self == other {
+            return Some(Ordering::Equal);
+        }
+        match (self.covers(other), other.covers(self)) {
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            _ => None,
+        }
+    }
+}
+
+impl<N: PartialEq + PartialOrd, L: Default + PartialEq + PartialOrd, K: Eq + Hash> Default
+    for StateVector<N, L, K>
+{
     fn default() -> Self {
         StateVector::Leaf(L::default())
     }
 }
 
-impl<N,L> Debug for StateVector<N, L>
-    where
+impl<N, L, K> Debug for StateVector<N, L, K>
+where
     N: PartialEq + PartialOrd + Debug,
-    L: PartialEq + PartialOrd + Debug
+    L: PartialEq + PartialOrd + Debug,
+    K: Eq + Hash + Display,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let repr = match self {
@@ -57,14 +151,66 @@ impl<N,L> Debug for StateVector<N, L>
     }
 }
 
-impl<T, MapClock, ValueClock> DeltaCRDT for Shelf<T, MapClock, ValueClock>
+impl<N, L> StateVector<N, L>
+where
+    N: PartialEq + PartialOrd + Clone + Into<JSON>,
+    L: PartialEq + PartialOrd + Clone + Into<JSON>,
+{
+    /// Converts the state vector to JSON, mirroring the shelf format: nodes are `[{key: child}, clock]`
+    /// and leaves are just their clock.
+    pub fn to_json(&self) -> JSON {
+        match self {
+            StateVector::Node(children, clock) => {
+                let children: serde_json::Map<String, JSON> = children
+                    .iter()
+                    .map(|(k, sv)| (k.clone(), sv.to_json()))
+                    .collect();
+                let clock: JSON = clock.clone().into();
+                json!([children, clock])
+            }
+            StateVector::Leaf(clock) => clock.clone().into(),
+        }
+    }
+}
+
+impl<N, L> TryFrom<JSON> for StateVector<N, L>
+where
+    N: PartialEq + PartialOrd + TryFrom<JSON>,
+    L: PartialEq + PartialOrd + TryFrom<JSON>,
+{
+    type Error = String;
+
+    fn try_from(json: JSON) -> Result<Self, Self::Error> {
+        match json {
+            JSON::Array(mut array) if array.len() == 2 && array[0].is_object() => {
+                let (children, clock) = (array.remove(0), array.remove(0));
+                let mut nodes = HashMap::new();
+                if let JSON::Object(children) = children {
+                    nodes.reserve(children.len());
+                    for (k, v) in children {
+                        nodes.insert(k, v.try_into()?);
+                    }
+                }
+                let clock = N::try_from(clock).map_err(|_| "Could not parse NodeClock")?;
+                Ok(StateVector::Node(nodes, clock))
+            }
+            clock => {
+                let clock = L::try_from(clock).map_err(|_| "Could not parse LeafClock")?;
+                Ok(StateVector::Leaf(clock))
+            }
+        }
+    }
+}
+
+impl<T, MapClock, ValueClock, K> DeltaCRDT for Shelf<T, MapClock, ValueClock, K>
 where
     T: PartialOrd + Clone,
     MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Clone,
     ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + Clone,
+    K: Eq + Hash + Clone,
 {
-    type Delta = Shelf<T, MapClock, ValueClock>;
-    type StateVector = StateVector<MapClock, ValueClock>;
+    type Delta = Shelf<T, MapClock, ValueClock, K>;
+    type StateVector = StateVector<MapClock, ValueClock, K>;
     fn get_state_vector(&self) -> Self::StateVector {
         match &self {
             Shelf::Value { clock, .. } => StateVector::Leaf(clock.clone()),
@@ -97,17 +243,238 @@ where
      */
 
     fn get_state_delta(&self, state_vector: &Self::StateVector) -> Option<Self::Delta> {
+        self.state_delta_at(state_vector, &mut None)
+    }
+}
+
+/// A delta that leaves out subtrees the receiver already has. See `Shelf::get_compact_state_delta`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompactDelta<T, MapClock, ValueClock = MapClock, K = String>
+where
+    T: PartialEq + PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
+{
+    pub shelf: Shelf<T, MapClock, ValueClock, K>,
+    /// Paths of the subtrees left out of `shelf`, which the receiver copies from its own state before merging.
+    pub kept: Vec<Vec<K>>,
+}
+
+/// A delta that also lists the keys the sender deleted. See `Shelf::get_state_delta_with_deletions`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeletionDelta<T, MapClock, ValueClock = MapClock, K = String>
+where
+    T: PartialEq + PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
+{
+    pub shelf: Option<Shelf<T, MapClock, ValueClock, K>>,
+    /// Paths of the deleted keys, with the clock of the map that deleted them.
+    pub deleted: Vec<(Vec<K>, MapClock)>,
+}
+
+/// Subtrees left out of a compact delta, collected while it is built.
+struct Compaction<K> {
+    path: Vec<K>,
+    kept: Vec<Vec<K>>,
+}
+
+impl<T, MapClock, ValueClock, K> Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialOrd + Clone,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Clone,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + Clone,
+    K: Eq + Hash + Clone,
+{
+    /// Like `get_state_delta`, but a map whose clock is ahead of the peer's is sent without the children the peer
+    /// already has. A regular delta has to resend such a map whole, since the receiver replaces its copy of the map
+    /// instead of merging into it. The receiver must apply the delta with `merge_compact`.
+    pub fn get_compact_state_delta(
+        &self,
+        state_vector: &StateVector<MapClock, ValueClock, K>,
+    ) -> Option<CompactDelta<T, MapClock, ValueClock, K>> {
+        let mut compaction = Some(Compaction {
+            path: Vec::new(),
+            kept: Vec::new(),
+        });
+        let shelf = self.state_delta_at(state_vector, &mut compaction)?;
+        let kept = compaction
+            .map(|compaction| compaction.kept)
+            .unwrap_or_default();
+        Some(CompactDelta { shelf, kept })
+    }
+
+    /// Merges a delta from `get_compact_state_delta`, filling the subtrees it left out in from this shelf.
+    pub fn merge_compact(self, delta: CompactDelta<T, MapClock, ValueClock, K>) -> Self {
+        let CompactDelta { mut shelf, kept } = delta;
+        for path in kept {
+            let (key, parent_path) = match path.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+            let own = path.iter().try_fold(&self, |shelf, key| shelf.get(key));
+            let parent = parent_path
+                .iter()
+                .try_fold(&mut shelf, |shelf, key| shelf.get_mut(key));
+            if let (Some(own), Some(Shelf::Map { shelves, .. })) = (own, parent) {
+                shelves.insert(key.clone(), own.clone());
+            }
+        }
+        self.merge(shelf)
+    }
+
+    /// Like `get_state_delta`, but also lists the keys the peer still has that this shelf deleted.
+    /// A deletion bumps the clock of the map, which only drops the key on a peer whose copy of the map is older.
+    /// If the peer bumped the map to the same clock concurrently, e.g. to delete a key of its own, the two maps are
+    /// merged and the deleted key survives there until the peer prunes. The receiver must apply the delta with
+    /// `merge_with_deletions`.
+    pub fn get_state_delta_with_deletions(
+        &self,
+        state_vector: &StateVector<MapClock, ValueClock, K>,
+    ) -> Option<DeletionDelta<T, MapClock, ValueClock, K>> {
+        let mut deleted = Vec::new();
+        self.collect_deletions(state_vector, &mut Vec::new(), &mut deleted);
+        let shelf = self.state_delta_at(state_vector, &mut None);
+        if shelf.is_none() && deleted.is_empty() {
+            return None;
+        }
+        Some(DeletionDelta { shelf, deleted })
+    }
+
+    /// Merges a delta from `get_state_delta_with_deletions`, then removes the deleted keys that were not edited
+    /// after they were deleted.
+    pub fn merge_with_deletions(self, delta: DeletionDelta<T, MapClock, ValueClock, K>) -> Self {
+        let DeletionDelta { shelf, deleted } = delta;
+        let mut merged = match shelf {
+            Some(shelf) => self.merge(shelf),
+            None => self,
+        };
+        for (path, clock) in deleted {
+            let (key, parent_path) = match path.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+            let parent = parent_path
+                .iter()
+                .try_fold(&mut merged, |shelf, key| shelf.get_mut(key));
+            if let Some(Shelf::Map { shelves, .. }) = parent {
+                if shelves
+                    .get(key)
+                    .is_some_and(|shelf| shelf.get_clock() < ShelfClock::MapClock(&clock))
+                {
+                    shelves.remove(key);
+                }
+            }
+        }
+        merged
+    }
+
+    /// Walks the maps that a delta merges into the peer's rather than replacing them, collecting the peer's keys
+    /// that this shelf no longer has and whose clock is below the map's, i.e. that `prune` would remove.
+    fn collect_deletions(
+        &self,
+        state_vector: &StateVector<MapClock, ValueClock, K>,
+        path: &mut Vec<K>,
+        deleted: &mut Vec<(Vec<K>, MapClock)>,
+    ) {
+        let clock_ordering = self.get_clock().partial_cmp(&state_vector.get_clock());
+        match (self, state_vector, clock_ordering) {
+            // A newer map replaces the peer's along with its deleted keys, an older one is replaced
+            (_, _, Some(Ordering::Less | Ordering::Greater)) => {}
+            (Shelf::Map { shelves, clock }, StateVector::Node(sv_children, _), _) => {
+                let map_clock = ShelfClock::MapClock(clock);
+                for (k, sv_child) in sv_children {
+                    path.push(k.clone());
+                    match shelves.get(k) {
+                        Some(shelf) => shelf.collect_deletions(sv_child, path, deleted),
+                        None if sv_child.get_clock() < map_clock => {
+                            deleted.push((path.clone(), clock.clone()))
+                        }
+                        None => {} // Added by the peer after this shelf last saw the map
+                    }
+                    path.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Paths of the subtrees that `get_state_delta` would send to a peer with `state_vector`, found without cloning
+    /// them. Cheaper than building the delta when only its extent is needed, e.g. to show how far behind a peer is.
+    /// A subtree that is sent whole is reported once at its root.
+    pub fn missing_paths(
+        &self,
+        state_vector: &StateVector<MapClock, ValueClock, K>,
+    ) -> Vec<Vec<K>> {
+        let mut missing = Vec::new();
+        self.visit_missing(state_vector, &mut Vec::new(), &mut |path, _| {
+            missing.push(path.clone())
+        });
+        missing
+    }
+
+    /// The leaves that `get_state_delta` would send to a peer with `state_vector`, with their paths, found without
+    /// building the delta. Subtrees the peer already has are skipped, e.g. to only re-index what changed since the
+    /// last export.
+    pub fn changed_leaves_since(
+        &self,
+        state_vector: &StateVector<MapClock, ValueClock, K>,
+    ) -> impl Iterator<Item = (Vec<K>, &T)> {
+        let mut leaves = Vec::new();
+        self.visit_missing(state_vector, &mut Vec::new(), &mut |path, subtree| {
+            subtree.collect_leaves(path, &mut leaves)
+        });
+        leaves.into_iter()
+    }
+
+    fn collect_leaves<'a>(&'a self, path: &mut Vec<K>, leaves: &mut Vec<(Vec<K>, &'a T)>) {
+        match self {
+            Shelf::Value { value, .. } => leaves.push((path.clone(), value)),
+            Shelf::Map { shelves, .. } => {
+                for (k, v) in shelves {
+                    path.push(k.clone());
+                    v.collect_leaves(path, leaves);
+                    path.pop();
+                }
+            }
+        }
+    }
+
+    fn state_delta_at(
+        &self,
+        state_vector: &StateVector<MapClock, ValueClock, K>,
+        compaction: &mut Option<Compaction<K>>,
+    ) -> Option<Self> {
         let clock_ordering = self.get_clock().partial_cmp(&state_vector.get_clock());
         match (self, state_vector, clock_ordering) {
             (_, _, Some(Ordering::Less)) => None, // No new information to share due to clock Some(ordering or lack) of data
-            (_, _, Some(Ordering::Greater)) => Some(self.clone()), // This content more prevalent than peer.
-            (Shelf::Map { shelves, clock: map_clock }, StateVector::Node(sv_children, sv_clock), _) => {
+            (_, _, Some(Ordering::Greater)) => match compaction {
+                Some(compaction) => Some(self.without_kept_subtrees(state_vector, compaction)),
+                None => Some(self.clone()), // This content more prevalent than peer.
+            },
+            (
+                Shelf::Map {
+                    shelves,
+                    clock: map_clock,
+                },
+                StateVector::Node(sv_children, sv_clock),
+                _,
+            ) => {
                 let sv_clock = ShelfClock::MapClock(sv_clock);
-                let updated_shelf_map: HashMap<String, _> = shelves
+                let updated_shelf_map: HashMap<K, _> = shelves
                     .iter()
                     .filter_map(|(k, v)| {
                         let delta = if let Some(sv_child) = sv_children.get(k) {
-                            v.get_state_delta(sv_child)
+                            if let Some(compaction) = compaction.as_mut() {
+                                compaction.path.push(k.clone());
+                            }
+                            let delta = v.state_delta_at(sv_child, compaction);
+                            if let Some(compaction) = compaction.as_mut() {
+                                compaction.path.pop();
+                            }
+                            delta
                         } else if v.get_clock() < sv_clock {
                             // Values less than parent clock have been overwritten
                             None
@@ -115,7 +482,7 @@ where
                             // Send the shelf if the values cannot be compared (ie different clients) or the parent clock is >= to value
                             Some(v.clone())
                         };
-                        Some((k.to_owned(), delta?))
+                        Some((k.clone(), delta?))
                     })
                     .collect();
                 let has_elements = !updated_shelf_map.is_empty(); // Even if empty, it is an update if clocks don't match.
@@ -124,11 +491,206 @@ where
                     clock: map_clock.clone(),
                 })
             } // if maps, merge recursively
-            (_, _, Some(Ordering::Equal)) => None, // If the clocks equal, no need to send anything over.
-            (Shelf::Value { .. }, StateVector::Node(..), None) => None, // Type order wins: Map > anything else
-            (_, _, None) => Some(self.clone()), // No partial ordering? Values must be compared directly
+            // The structures differ and neither clock is newer, so the merge falls back on type order: Map > anything else
+            (Shelf::Map { .. }, StateVector::Leaf(_), _) => Some(self.clone()),
+            (Shelf::Value { .. }, StateVector::Node(..), _) => None,
+            (Shelf::Value { .. }, StateVector::Leaf(_), Some(Ordering::Equal)) => None, // If the clocks equal, no need to send anything over.
+            (Shelf::Value { .. }, StateVector::Leaf(_), None) => Some(self.clone()), // No partial ordering? Values must be compared directly
+        }
+    }
+
+    /// Walks the shelf like `state_delta_at`, visiting every subtree it would clone with its path.
+    fn visit_missing<'a>(
+        &'a self,
+        state_vector: &StateVector<MapClock, ValueClock, K>,
+        path: &mut Vec<K>,
+        visit: &mut impl FnMut(&mut Vec<K>, &'a Self),
+    ) {
+        let clock_ordering = self.get_clock().partial_cmp(&state_vector.get_clock());
+        match (self, state_vector, clock_ordering) {
+            (_, _, Some(Ordering::Less)) => {}
+            (_, _, Some(Ordering::Greater)) => visit(path, self),
+            (Shelf::Map { shelves, .. }, StateVector::Node(sv_children, sv_clock), _) => {
+                let sv_clock = ShelfClock::MapClock(sv_clock);
+                for (k, v) in shelves {
+                    path.push(k.clone());
+                    match sv_children.get(k) {
+                        Some(sv_child) => v.visit_missing(sv_child, path, visit),
+                        None if v.get_clock() < sv_clock => {} // Overwritten by the peer's map
+                        None => visit(path, v),
+                    }
+                    path.pop();
+                }
+            }
+            (Shelf::Map { .. }, StateVector::Leaf(_), _) => visit(path, self),
+            (Shelf::Value { .. }, StateVector::Node(..), _) => {}
+            (Shelf::Value { .. }, StateVector::Leaf(_), Some(Ordering::Equal)) => {}
+            (Shelf::Value { .. }, StateVector::Leaf(_), None) => visit(path, self),
+        }
+    }
+
+    /// Clones the shelf, leaving out the children that `state_vector` shows the peer already has and recording
+    /// their paths instead.
+    fn without_kept_subtrees(
+        &self,
+        state_vector: &StateVector<MapClock, ValueClock, K>,
+        compaction: &mut Compaction<K>,
+    ) -> Self {
+        match (self, state_vector) {
+            (Shelf::Map { shelves, clock }, StateVector::Node(sv_children, _)) => {
+                let mut sent = HashMap::with_capacity(shelves.len());
+                for (k, v) in shelves {
+                    compaction.path.push(k.clone());
+                    match sv_children.get(k) {
+                        Some(sv_child) if v.matches_state_vector(sv_child) => {
+                            compaction.kept.push(compaction.path.clone())
+                        }
+                        Some(sv_child) => {
+                            sent.insert(k.clone(), v.without_kept_subtrees(sv_child, compaction));
+                        }
+                        None => {
+                            sent.insert(k.clone(), v.clone());
+                        }
+                    }
+                    compaction.path.pop();
+                }
+                Shelf::Map {
+                    shelves: sent,
+                    clock: clock.clone(),
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// Whether `state_vector` describes exactly this shelf.
+    fn matches_state_vector(&self, state_vector: &StateVector<MapClock, ValueClock, K>) -> bool {
+        match (self, state_vector) {
+            (Shelf::Value { clock, .. }, StateVector::Leaf(sv_clock)) => clock == sv_clock,
+            (Shelf::Map { shelves, clock }, StateVector::Node(sv_children, sv_clock)) => {
+                clock == sv_clock
+                    && shelves.len() == sv_children.len()
+                    && shelves.iter().all(|(k, v)| {
+                        sv_children
+                            .get(k)
+                            .is_some_and(|sv_child| v.matches_state_vector(sv_child))
+                    })
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A delta borrowed from the shelf it was computed from. It derives `Serialize` with the variants and fields of
+/// `Shelf`, so it is encoded exactly like the delta shelf that `get_state_delta` would build.
+#[derive(Serialize)]
+enum DeltaView<'a, T, MapClock, ValueClock, K: Eq + Hash> {
+    Value {
+        value: &'a T,
+        clock: &'a ValueClock,
+    },
+    Map {
+        shelves: HashMap<&'a K, DeltaView<'a, T, MapClock, ValueClock, K>>,
+        clock: &'a MapClock,
+    },
+}
+
+impl<'a, T, MapClock, ValueClock, K> DeltaView<'a, T, MapClock, ValueClock, K>
+where
+    T: PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
+{
+    /// Borrows all of `shelf`.
+    fn whole(shelf: &'a Shelf<T, MapClock, ValueClock, K>) -> Self {
+        match shelf {
+            Shelf::Value { value, clock } => DeltaView::Value { value, clock },
+            Shelf::Map { shelves, clock } => DeltaView::Map {
+                shelves: shelves
+                    .iter()
+                    .map(|(k, v)| (k, DeltaView::whole(v)))
+                    .collect(),
+                clock,
+            },
         }
     }
+
+    /// Borrows the clock of the map `shelf`, without any of its entries.
+    fn empty_map(shelf: &'a Shelf<T, MapClock, ValueClock, K>) -> Self {
+        match shelf {
+            Shelf::Map { clock, .. } => DeltaView::Map {
+                shelves: HashMap::new(),
+                clock,
+            },
+            Shelf::Value { .. } => unreachable!("Only maps have entries in a delta"),
+        }
+    }
+
+    /// Adds `subtree`, found at `path` below `shelf`, whose map this view borrows.
+    fn insert(
+        &mut self,
+        shelf: &'a Shelf<T, MapClock, ValueClock, K>,
+        path: &[K],
+        subtree: &'a Shelf<T, MapClock, ValueClock, K>,
+    ) {
+        let (key, (child, rest)) = match (shelf, path.split_first()) {
+            (Shelf::Map { shelves, .. }, Some((key, rest))) => {
+                let (key, child) = shelves
+                    .get_key_value(key)
+                    .expect("Visited paths exist in the shelf");
+                (key, (child, rest))
+            }
+            _ => unreachable!("Only maps have entries in a delta"),
+        };
+        let DeltaView::Map { shelves, .. } = self else {
+            unreachable!("Only maps have entries in a delta")
+        };
+        if rest.is_empty() {
+            shelves.insert(key, DeltaView::whole(subtree));
+        } else {
+            shelves
+                .entry(key)
+                .or_insert_with(|| DeltaView::empty_map(child))
+                .insert(child, rest, subtree);
+        }
+    }
+}
+
+impl<T, MapClock, ValueClock, K> Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialOrd + Clone + Serialize,
+    MapClock:
+        PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Clone + Serialize,
+    ValueClock:
+        PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + Clone + Serialize,
+    K: Eq + Hash + Clone + Serialize,
+{
+    /// Streams the delta that `get_state_delta` would return to `writer`, encoded like `encoding::encode` encodes it,
+    /// without cloning the values of the delta. The subtrees `visit_missing` finds are borrowed from this shelf along
+    /// with the maps leading to them. Returns whether there was a delta; nothing is written if there was not.
+    pub fn serialize_delta_to<W: Write>(
+        &self,
+        state_vector: &StateVector<MapClock, ValueClock, K>,
+        mut writer: W,
+    ) -> Result<bool, String> {
+        let mut delta = None;
+        self.visit_missing(state_vector, &mut Vec::new(), &mut |path, subtree| {
+            if path.is_empty() {
+                delta = Some(DeltaView::whole(subtree));
+            } else {
+                delta
+                    .get_or_insert_with(|| DeltaView::empty_map(self))
+                    .insert(self, path, subtree);
+            }
+        });
+        let Some(delta) = delta else {
+            return Ok(false);
+        };
+        encoding::write_header(&mut writer)?;
+        bincode::serialize_into(writer, &delta).map_err(|err| err.to_string())?;
+        Ok(true)
+    }
 }
 
 pub struct StateVectorContext;
@@ -137,10 +699,14 @@ pub struct StateVectorContext;
 mod test {
     use serde_json::json;
 
-    use crate::{clock::{DotClock, LamportTimestamp}, traits::Mergeable};
+    use crate::{
+        clock::{ClientId, DotClock, LamportTimestamp, SecureClock},
+        json::Value,
+        traits::Mergeable,
+    };
 
     use super::*;
-    type TestShelf = Shelf<crate::json::Value, LamportTimestamp, DotClock>;
+    type TestShelf = Shelf<Value, LamportTimestamp, DotClock>;
 
     fn clock(c: usize) -> DotClock {
         DotClock {
@@ -151,11 +717,11 @@ mod test {
 
     #[test]
     fn test_state_vector() {
-        let shelf: TestShelf = json!([{ 
+        let shelf: TestShelf = json!([{
             "user": [{
             "mouse_position": [[0, 1], [0,0]],
             "cursor": [{"left": ["a", [0,0]], "right": ["b", [0,0]]}, 0]
-        },0]  
+        },0]
     }, 0])
         .try_into()
         .unwrap();
@@ -196,6 +762,76 @@ mod test {
             StateVector::Leaf(_) => panic!("Top level should be a map"),
         }
     }
+    #[test]
+    fn test_json_round_trip() {
+        let shelf: TestShelf = json!([{
+            "user": [{
+                "mouse_position": [[0, 1], [3, 2]],
+                "cursor": [{"left": ["a", [1, 4]]}, 2]
+            }, 1]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let sv = shelf.get_state_vector();
+        let json = sv.to_json();
+        assert_eq!(
+            json,
+            json!([{"user": [{"mouse_position": [3, 2], "cursor": [{"left": [1, 4]}, 2]}, 1]}, 0])
+        );
+        let parsed: StateVector<LamportTimestamp, DotClock> = json.try_into().unwrap();
+        assert_eq!(parsed, sv);
+
+        let invalid: Result<StateVector<LamportTimestamp, DotClock>, _> =
+            json!([{"a": "b"}, 0]).try_into();
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let shelf: TestShelf = json!([{
+            "a": ["x", [1, 1]],
+            "b": ["y", [1, 2]],
+            "c": ["w", [1, 4]]
+        }, 0])
+        .try_into()
+        .unwrap();
+
+        // The sender switched to secure clocks and edited "a"
+        let mut sender = shelf
+            .clone()
+            .convert_clocks(&mut |clock| clock, &mut |value, clock| {
+                SecureClock::new(value, clock.clock)
+            });
+        if let Shelf::Map { shelves, .. } = &mut sender {
+            let value = Value::from("z");
+            let clock = SecureClock::new(&value, 3);
+            shelves.insert("a".to_owned(), Shelf::Value { value, clock });
+        }
+
+        // The receiver still uses dot clocks and edited "c"
+        let mut receiver = shelf;
+        if let Some(Shelf::Value { value, clock }) = receiver.get_mut("c") {
+            *value = "v".into();
+            clock.clock = 5;
+        }
+
+        let sv: StateVector<LamportTimestamp, SecureClock> =
+            receiver
+                .get_state_vector()
+                .migrate(&mut |clock| clock, &mut |clock| SecureClock {
+                    clock: clock.clock,
+                    hash: 0,
+                });
+        let delta = sender.get_state_delta(&sv).unwrap();
+        match delta.get("a") {
+            Some(Shelf::Value { value, .. }) => assert_eq!(*value, Value::from("z")),
+            _ => panic!("Expected the edit to be sent"),
+        }
+        assert!(delta.get("c").is_none(), "The receiver's edit is newer");
+        // The hash of "b" could not be migrated, so it is sent again
+        assert!(delta.get("b").is_some());
+    }
+
     #[test]
     fn test_delta_update() {
         let shelf1: TestShelf = json!([{
@@ -206,10 +842,9 @@ mod test {
         .try_into()
         .unwrap();
         // number ,string, List*, Map<string, Shelf>
-        let mut shelf2: TestShelf =
-            json!([{ "user2": [{"username": ["jwaidhof", [1,0]]}, 0] }, 0])
-                .try_into()
-                .unwrap();
+        let mut shelf2: TestShelf = json!([{ "user2": [{"username": ["jwaidhof", [1,0]]}, 0] }, 0])
+            .try_into()
+            .unwrap();
         let state_vec = shelf2.get_state_vector();
         let diff = shelf1.get_state_delta(&state_vec).unwrap();
 
@@ -225,4 +860,409 @@ mod test {
         .unwrap();
         assert_eq!(shelf2, expected);
     }
+
+    /// Sends `sender` to `receiver` as a delta and checks that the receiver ends up where a full merge in either
+    /// order would.
+    fn assert_delta_converges<M, V>(receiver: Shelf<Value, M, V>, sender: Shelf<Value, M, V>)
+    where
+        M: PartialEq + PartialOrd + PartialOrd<V> + PartialEq<V> + Clone + Display,
+        V: PartialEq + PartialOrd + PartialOrd<M> + PartialEq<M> + Clone + Display,
+    {
+        let expected = sender.clone().merge(receiver.clone());
+        assert_eq!(
+            receiver.clone().merge(sender.clone()),
+            expected,
+            "Merge is not commutative"
+        );
+        let synced = match sender.get_state_delta(&receiver.get_state_vector()) {
+            Some(delta) => receiver.merge(delta),
+            None => receiver,
+        };
+        assert_eq!(synced, expected);
+    }
+
+    #[test]
+    fn test_structure_mismatch() {
+        type LamportShelf = Shelf<Value, LamportTimestamp>;
+        let map = |clock: usize| json!([{"k": [{"a": [1, clock]}, clock]}, 0]);
+        let leaf = |clock: usize| json!([{"k": [2, clock]}, 0]);
+        // Sender clock lower, equal and higher than the receiver's
+        for sender_clock in [1, 2, 3] {
+            let sender: LamportShelf = map(sender_clock).try_into().unwrap();
+            let receiver: LamportShelf = leaf(2).try_into().unwrap();
+            assert_delta_converges(receiver, sender);
+
+            let sender: LamportShelf = leaf(sender_clock).try_into().unwrap();
+            let receiver: LamportShelf = map(2).try_into().unwrap();
+            assert_delta_converges(receiver, sender);
+        }
+
+        // Map and value clocks of different types cannot be ordered when their counts match
+        let map: TestShelf = json!([{"k": [{"a": [1, [1, 2]]}, 2]}, 0])
+            .try_into()
+            .unwrap();
+        let leaf: TestShelf = json!([{"k": [2, [2, 2]]}, 0]).try_into().unwrap();
+        assert_delta_converges(leaf.clone(), map.clone());
+        assert_delta_converges(map, leaf);
+    }
+
+    #[test]
+    fn test_secure_structure_mismatch() {
+        type SecureShelf = Shelf<Value, LamportTimestamp, SecureClock>;
+        let value = |value: Value, clock: usize| {
+            let clock = SecureClock::new(&value, clock);
+            Shelf::Value { value, clock }
+        };
+        let root = |child: SecureShelf| Shelf::Map {
+            shelves: HashMap::from([("k".to_owned(), child)]),
+            clock: LamportTimestamp(0),
+        };
+        let map = |clock: usize| {
+            root(Shelf::Map {
+                shelves: HashMap::from([("a".to_owned(), value(1.into(), clock))]),
+                clock: LamportTimestamp(clock),
+            })
+        };
+        let leaf = |clock: usize| root(value(2.into(), clock));
+        // Sender clock lower, equal and higher than the receiver's
+        for sender_clock in [1, 2, 3] {
+            for (receiver, sender) in [(leaf(2), map(sender_clock)), (map(2), leaf(sender_clock))] {
+                let expected = sender.clone().secure_merge(receiver.clone());
+                assert_eq!(
+                    receiver.clone().secure_merge(sender.clone()),
+                    expected,
+                    "Merge is not commutative"
+                );
+                let synced = match sender.get_state_delta(&receiver.get_state_vector()) {
+                    Some(delta) => receiver.secure_merge(delta),
+                    None => receiver,
+                };
+                assert_eq!(synced, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reconnect_resync() {
+        /// What peers send each other, encoded like on the wire.
+        #[derive(Serialize, Deserialize)]
+        enum SyncMessage {
+            StateVector(StateVector<LamportTimestamp, DotClock>),
+            Delta(TestShelf),
+        }
+
+        /// Writes `value` at `key` like a local edit, returning the update to broadcast.
+        fn edit(shelf: &mut TestShelf, client_id: ClientId, key: String, value: usize) -> Vec<u8> {
+            let clock = match shelf.value_and_clock(&[&key]) {
+                Some((_, clock)) => clock.increment(client_id),
+                None => DotClock {
+                    client_id,
+                    clock: 1,
+                },
+            };
+            let mut entries = serde_json::Map::new();
+            entries.insert(key, json!([value, [clock.client_id, clock.clock]]));
+            let update: TestShelf = json!([entries, 0]).try_into().unwrap();
+            shelf.merge_assign(update.clone());
+            encoding::encode(&SyncMessage::Delta(update)).unwrap()
+        }
+
+        /// Applies a message from the other peer, returning the reply to send back, if any.
+        fn receive(shelf: &mut TestShelf, message: &[u8]) -> Option<Vec<u8>> {
+            match encoding::decode(message).unwrap() {
+                SyncMessage::StateVector(sv) => shelf
+                    .get_state_delta(&sv)
+                    .map(|delta| encoding::encode(&SyncMessage::Delta(delta)).unwrap()),
+                SyncMessage::Delta(delta) => {
+                    shelf.merge_assign(delta);
+                    None
+                }
+            }
+        }
+
+        let state_vector = |shelf: &TestShelf| {
+            encoding::encode(&SyncMessage::StateVector(shelf.get_state_vector())).unwrap()
+        };
+        let mut a: TestShelf = json!([{"title": ["draft", [1, 1]]}, 0]).try_into().unwrap();
+        let mut b = a.clone();
+
+        // Connected, updates are delivered as they happen
+        let update = edit(&mut a, 1, "title".to_owned(), 1);
+        assert!(receive(&mut b, &update).is_none());
+        assert_eq!(a, b);
+
+        // B is offline, so the updates in between are lost. Both keep editing.
+        for i in 0..100 {
+            let _lost = edit(&mut a, 1, format!("key{}", i % 30), i);
+        }
+        for i in 0..5 {
+            let _lost = edit(&mut b, 2, format!("offline{i}"), i);
+        }
+        let _lost = edit(&mut b, 2, "title".to_owned(), 2);
+        assert!(a != b);
+
+        // On reconnect each peer sends its state vector and answers the other's with a delta
+        let catch_up_b = receive(&mut a, &state_vector(&b)).unwrap();
+        let catch_up_a = receive(&mut b, &state_vector(&a)).unwrap();
+        assert!(receive(&mut b, &catch_up_b).is_none());
+        assert!(receive(&mut a, &catch_up_a).is_none());
+        assert_eq!(a, b);
+        assert_eq!(a.keys_at_path(&[]).unwrap().len(), 1 + 30 + 5);
+        assert!(receive(&mut a, &state_vector(&b)).is_none());
+
+        // Catch-up deltas that arrive twice change nothing
+        assert!(receive(&mut b, &catch_up_b).is_none());
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_state_vector_order() {
+        let base: TestShelf = json!([{
+            "a": [1, [1, 1]],
+            "user": [{"name": ["a", [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let mut ahead = base.clone();
+        *ahead.get_path_mut(&["user", "name"]).unwrap() = json!(["b", [1, 2]]).try_into().unwrap();
+        if let Shelf::Map { shelves, .. } = &mut ahead {
+            shelves.insert("b".to_owned(), json!([true, [1, 1]]).try_into().unwrap());
+        }
+        let mut concurrent = base.clone();
+        *concurrent.get_mut("a").unwrap() = json!([2, [2, 2]]).try_into().unwrap();
+
+        let (base_sv, ahead_sv) = (base.get_state_vector(), ahead.get_state_vector());
+        let concurrent_sv = concurrent.get_state_vector();
+        assert!(ahead_sv > base_sv && ahead_sv >= base_sv);
+        assert!(base_sv < ahead_sv);
+        assert_eq!(
+            base_sv.partial_cmp(&base.clone().get_state_vector()),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(ahead_sv.partial_cmp(&concurrent_sv), None);
+        assert!(!ahead_sv.covers(&concurrent_sv) && !concurrent_sv.covers(&ahead_sv));
+
+        // Consistent with the deltas: a replica has nothing to send to one whose state vector covers its own
+        let shelves = [&base, &ahead, &concurrent];
+        for local in shelves {
+            for remote in shelves {
+                assert_eq!(
+                    local.get_state_vector().covers(&remote.get_state_vector()),
+                    remote.get_state_delta(&local.get_state_vector()).is_none()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_changed_leaves_since() {
+        let mut shelf: TestShelf = json!([{
+            "a": [1, [1, 1]],
+            "user": [{"name": ["a", [1, 1]], "age": [30, [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let sv = shelf.get_state_vector();
+        assert_eq!(shelf.changed_leaves_since(&sv).count(), 0);
+
+        *shelf.get_path_mut(&["user", "age"]).unwrap() = json!([31, [1, 2]]).try_into().unwrap();
+        let changed: Vec<_> = shelf.changed_leaves_since(&sv).collect();
+        assert_eq!(
+            changed,
+            [(vec!["user".to_owned(), "age".to_owned()], &Value::Int(31))]
+        );
+
+        // Every leaf of a subtree the peer lacks is yielded
+        let empty: TestShelf = json!([{}, 0]).try_into().unwrap();
+        let mut changed: Vec<_> = shelf
+            .changed_leaves_since(&empty.get_state_vector())
+            .map(|(path, _)| path.join("/"))
+            .collect();
+        changed.sort();
+        assert_eq!(changed, ["a", "user/age", "user/name"]);
+    }
+
+    #[test]
+    fn test_missing_paths() {
+        let receiver: TestShelf = json!([{
+            "a": [1, [1, 1]],
+            "b": [2, [1, 1]],
+            "user": [{"name": ["a", [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let mut sender = receiver.clone();
+        *sender.get_mut("a").unwrap() = json!([3, [1, 2]]).try_into().unwrap();
+        if let Some(Shelf::Map { shelves, .. }) = sender.get_mut("user") {
+            shelves.insert(
+                "city".to_owned(),
+                json!(["Graz", [2, 1]]).try_into().unwrap(),
+            );
+        }
+
+        let sv = receiver.get_state_vector();
+        let mut missing = sender.missing_paths(&sv);
+        missing.sort();
+        assert_eq!(missing, [vec!["a"], vec!["user", "city"]]);
+
+        // Every missing path leads to a leaf of the delta
+        let delta = sender.get_state_delta(&sv).unwrap();
+        let mut delta_leaves = Vec::new();
+        for (key, shelf) in &delta {
+            match shelf {
+                Shelf::Map { .. } => delta_leaves.extend(
+                    shelf
+                        .into_iter()
+                        .map(|(child, _)| vec![key.clone(), child.clone()]),
+                ),
+                Shelf::Value { .. } => delta_leaves.push(vec![key.clone()]),
+            }
+        }
+        delta_leaves.sort();
+        assert_eq!(missing, delta_leaves);
+
+        assert!(receiver
+            .missing_paths(&receiver.get_state_vector())
+            .is_empty());
+        // A peer with an empty map is missing every entry, which are reported at their roots
+        let empty: TestShelf = json!([{}, 0]).try_into().unwrap();
+        let mut missing = sender.missing_paths(&empty.get_state_vector());
+        missing.sort();
+        assert_eq!(missing, [vec!["a"], vec!["b"], vec!["user"]]);
+    }
+
+    #[test]
+    fn test_serialize_delta_to() {
+        let receiver: TestShelf = json!([{
+            "a": [1, [1, 1]],
+            "b": [2, [1, 1]],
+            "user": [{"name": ["a", [1, 1]], "age": [30, [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let mut sender = receiver.clone();
+        *sender.get_mut("a").unwrap() = json!([3, [1, 2]]).try_into().unwrap();
+        if let Some(Shelf::Map { shelves, .. }) = sender.get_mut("user") {
+            shelves.insert(
+                "city".to_owned(),
+                json!(["Graz", [2, 1]]).try_into().unwrap(),
+            );
+        }
+
+        let sv = receiver.get_state_vector();
+        let mut bytes = Vec::new();
+        assert!(sender.serialize_delta_to(&sv, &mut bytes).unwrap());
+        let streamed: TestShelf = encoding::decode(&bytes).unwrap();
+        assert!(streamed == sender.get_state_delta(&sv).unwrap());
+
+        // With one entry per map the order of the entries is fixed, so the bytes match those of the built delta
+        let sv = sender.get_state_vector();
+        *sender.get_mut("b").unwrap() = json!([4, [1, 3]]).try_into().unwrap();
+        let mut bytes = Vec::new();
+        assert!(sender.serialize_delta_to(&sv, &mut bytes).unwrap());
+        assert_eq!(
+            bytes,
+            encoding::encode(&sender.get_state_delta(&sv).unwrap()).unwrap()
+        );
+
+        // Nothing is written when the peer is up to date
+        let mut bytes = Vec::new();
+        assert!(!sender
+            .serialize_delta_to(&sender.get_state_vector(), &mut bytes)
+            .unwrap());
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_compact_delta() {
+        let leaves: serde_json::Map<String, serde_json::Value> = (0..1000)
+            .map(|i| (format!("key{i}"), json!([i, [1, 2]])))
+            .collect();
+        let receiver: TestShelf = json!([{"doc": [leaves, 1]}, 0]).try_into().unwrap();
+
+        // The sender replaced the map, which bumped its clock, but only changed one of its leaves
+        let mut sender = receiver.clone();
+        if let Some(Shelf::Map { shelves, clock }) = sender.get_mut("doc") {
+            *clock = 2.into();
+            shelves.insert(
+                "key7".to_owned(),
+                json!(["changed", [2, 3]]).try_into().unwrap(),
+            );
+        }
+        let full = sender
+            .get_state_delta(&receiver.get_state_vector())
+            .unwrap();
+        assert_eq!(
+            full.get("doc").map(|doc| doc.into_iter().count()),
+            Some(1000)
+        );
+
+        let compact = sender
+            .get_compact_state_delta(&receiver.get_state_vector())
+            .unwrap();
+        let doc = compact.shelf.get("doc").unwrap();
+        assert_eq!(
+            doc.into_iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>(),
+            ["key7"]
+        );
+        assert_eq!(compact.kept.len(), 999);
+        assert_eq!(
+            receiver.clone().merge_compact(compact),
+            receiver.merge(full)
+        );
+
+        // Without a replaced map there is nothing to leave out
+        let mut sender: TestShelf = json!([{"a": [1, [1, 1]], "b": [2, [1, 1]]}, 0])
+            .try_into()
+            .unwrap();
+        let receiver = sender.clone();
+        *sender.get_mut("a").unwrap() = json!([3, [1, 2]]).try_into().unwrap();
+        let compact = sender
+            .get_compact_state_delta(&receiver.get_state_vector())
+            .unwrap();
+        assert!(compact.kept.is_empty());
+        assert_eq!(receiver.merge_compact(compact), sender);
+    }
+
+    #[test]
+    fn test_delta_with_deletions() {
+        let base: TestShelf = json!([{"x": [1, [1, 1]], "y": [2, [1, 1]], "z": [3, [1, 1]]}, 1])
+            .try_into()
+            .unwrap();
+        let delete = |key: &str| {
+            let mut shelf = base.clone();
+            if let Shelf::Map { shelves, clock } = &mut shelf {
+                shelves.remove(key);
+                *clock = 2.into();
+            }
+            shelf
+        };
+        // Both peers delete a different key at the same time, which bumps their maps to the same clock
+        let (a, b) = (delete("x"), delete("z"));
+
+        // A regular delta has nothing to send, so B would keep the key that A deleted
+        assert!(a.get_state_delta(&b.get_state_vector()).is_none());
+        assert!(b.clone().merge(a.clone()).get("x").is_some());
+
+        let to_b = a
+            .get_state_delta_with_deletions(&b.get_state_vector())
+            .unwrap();
+        assert_eq!(to_b.deleted, [(vec!["x".to_owned()], LamportTimestamp(2))]);
+        let to_a = b
+            .get_state_delta_with_deletions(&a.get_state_vector())
+            .unwrap();
+        let (a, b) = (
+            a.merge_with_deletions(to_a),
+            b.clone().merge_with_deletions(to_b.clone()),
+        );
+        assert_eq!(a, b);
+        assert!(a.get("x").is_none() && a.get("z").is_none() && a.get("y").is_some());
+
+        // A key that was set again after the deletion is kept
+        let mut readded = delete("z");
+        if let Shelf::Map { shelves, .. } = &mut readded {
+            shelves.insert("x".to_owned(), json!([4, [1, 3]]).try_into().unwrap());
+        }
+        assert!(readded.merge_with_deletions(to_b).get("x").is_some());
+    }
 }
@@ -0,0 +1,324 @@
+use serde::{Deserialize, Serialize};
+
+use crate::clock::LogicalClock;
+use crate::state_vector::StateVector;
+use crate::traits::{DeltaCRDT, Mergeable};
+use crate::wrap_crdt::Shelf;
+use std::clone::Clone;
+use std::collections::HashMap;
+
+/// Decides what happens when a deleted key is concurrently edited by a peer that has not seen the delete yet.
+/// All replicas of a document must use the same policy, otherwise they will not converge.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum DeletePolicy {
+    /// Observed-remove semantics: a delete only removes the version of the entry that the deleting client saw.
+    /// Edits that the deleting client had not seen yet (and later re-adds) survive.
+    #[default]
+    AddWins,
+    /// Two-phase semantics: once a key is deleted it stays deleted, no matter how high the clock of a concurrent edit is.
+    /// Deleted keys should not be reused.
+    RemoveWins,
+}
+
+/// Deleted paths mapped to the logical clock of the entry that was deleted.
+pub type Tombstones = HashMap<Vec<String>, usize>;
+
+/// A Shelf that records deletions as tombstones so that a stale edit from an offline peer cannot resurrect a deleted key.
+/// Tombstones are never garbage collected.
+pub struct TombstoneShelf<T, MapClock, ValueClock = MapClock>
+where
+    T: PartialEq + PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    pub shelf: Shelf<T, MapClock, ValueClock>,
+    pub tombstones: Tombstones,
+    pub policy: DeletePolicy,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct TombstoneStateVector<
+    MapClock: PartialEq + PartialOrd,
+    ValueClock: PartialEq + PartialOrd,
+> {
+    pub shelf: StateVector<MapClock, ValueClock>,
+    pub tombstones: Tombstones,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TombstoneDelta<T, MapClock, ValueClock = MapClock>
+where
+    T: PartialEq + PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    pub shelf: Option<Shelf<T, MapClock, ValueClock>>,
+    pub tombstones: Tombstones,
+}
+
+impl<T, MapClock, ValueClock> TombstoneShelf<T, MapClock, ValueClock>
+where
+    T: PartialEq + PartialOrd,
+    MapClock:
+        PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + LogicalClock,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + LogicalClock,
+{
+    pub fn new(shelf: Shelf<T, MapClock, ValueClock>, policy: DeletePolicy) -> Self {
+        TombstoneShelf {
+            shelf,
+            tombstones: HashMap::new(),
+            policy,
+        }
+    }
+
+    /// Removes the shelf at `path` and records a tombstone so the deletion propagates to peers.
+    pub fn remove(
+        &mut self,
+        path: &[&str],
+    ) -> Result<Option<Shelf<T, MapClock, ValueClock>>, String> {
+        let (key, parent_path) = path
+            .split_last()
+            .ok_or_else(|| "Path must have at least one key.".to_owned())?;
+        let removed = match self.get_parent_mut(parent_path)? {
+            Shelf::Map { shelves, .. } => shelves.remove(*key),
+            Shelf::Value { .. } => {
+                return Err(format!("Cannot remove the key '{key}' from a Shelf Value"))
+            }
+        };
+        if let Some(removed) = removed.as_ref() {
+            let path: Vec<String> = path.iter().map(|key| key.to_string()).collect();
+            let clock = removed.get_clock().get_logical_clock();
+            Self::insert_tombstone(&mut self.tombstones, path, clock);
+        }
+        Ok(removed)
+    }
+
+    /// Moves the subtree at `from` to `to` like `Shelf::rename`, recording a tombstone for `from` so peers
+    /// drop the old key. Under `DeletePolicy::AddWins`, edits that peers made under `from` before seeing the rename
+    /// survive at the old key instead of following the move.
+    pub fn rename(&mut self, from: &[&str], to: &[&str]) -> Result<(), String> {
+        self.shelf.rename(from, to)?;
+        let clock = self.shelf.get_path(to)?.get_clock().get_logical_clock();
+        let from: Vec<String> = from.iter().map(|key| key.to_string()).collect();
+        Self::insert_tombstone(&mut self.tombstones, from, clock);
+        Ok(())
+    }
+
+    fn get_parent_mut(
+        &mut self,
+        path: &[&str],
+    ) -> Result<&mut Shelf<T, MapClock, ValueClock>, String> {
+        let mut cur = &mut self.shelf;
+        for key in path {
+            cur = cur
+                .get_mut(*key)
+                .ok_or_else(|| format!("Key error: {key}"))?;
+        }
+        Ok(cur)
+    }
+
+    fn insert_tombstone(tombstones: &mut Tombstones, path: Vec<String>, clock: usize) {
+        tombstones
+            .entry(path)
+            .and_modify(|c| *c = (*c).max(clock))
+            .or_insert(clock);
+    }
+
+    /// Removes every entry that is dominated by a tombstone under the current policy.
+    fn apply_tombstones(&mut self) {
+        for (path, deleted_clock) in self.tombstones.iter() {
+            let (key, parent_path) = match path.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+            let mut parent = Some(&mut self.shelf);
+            for segment in parent_path {
+                parent = parent.and_then(|shelf| shelf.get_mut(segment));
+            }
+            if let Some(Shelf::Map { shelves, .. }) = parent {
+                let is_deleted = shelves.get(key).map(|shelf| match self.policy {
+                    DeletePolicy::RemoveWins => true,
+                    DeletePolicy::AddWins => {
+                        shelf.get_clock().get_logical_clock() <= *deleted_clock
+                    }
+                });
+                if let Some(true) = is_deleted {
+                    shelves.remove(key);
+                }
+            }
+        }
+    }
+}
+
+impl<T, MapClock, ValueClock> Mergeable<TombstoneDelta<T, MapClock, ValueClock>>
+    for TombstoneShelf<T, MapClock, ValueClock>
+where
+    T: PartialEq + PartialOrd,
+    MapClock:
+        PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + LogicalClock,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + LogicalClock,
+{
+    fn merge(self, other: TombstoneDelta<T, MapClock, ValueClock>) -> Self {
+        let TombstoneShelf {
+            shelf,
+            mut tombstones,
+            policy,
+        } = self;
+        let shelf = match other.shelf {
+            Some(delta) => shelf.merge(delta),
+            None => shelf,
+        };
+        for (path, clock) in other.tombstones {
+            Self::insert_tombstone(&mut tombstones, path, clock);
+        }
+        let mut merged = TombstoneShelf {
+            shelf,
+            tombstones,
+            policy,
+        };
+        merged.apply_tombstones();
+        merged
+    }
+}
+
+impl<T, MapClock, ValueClock> DeltaCRDT for TombstoneShelf<T, MapClock, ValueClock>
+where
+    T: PartialOrd + Clone,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Clone,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + Clone,
+{
+    type Delta = TombstoneDelta<T, MapClock, ValueClock>;
+    type StateVector = TombstoneStateVector<MapClock, ValueClock>;
+
+    fn get_state_vector(&self) -> Self::StateVector {
+        TombstoneStateVector {
+            shelf: self.shelf.get_state_vector(),
+            tombstones: self.tombstones.clone(),
+        }
+    }
+
+    fn get_state_delta(&self, sv: &Self::StateVector) -> Option<Self::Delta> {
+        let tombstones: Tombstones = self
+            .tombstones
+            .iter()
+            .filter(|(path, clock)| match sv.tombstones.get(*path) {
+                Some(peer_clock) => peer_clock < clock,
+                None => true,
+            })
+            .map(|(path, clock)| (path.clone(), *clock))
+            .collect();
+        let shelf = self.shelf.get_state_delta(&sv.shelf);
+        if shelf.is_none() && tombstones.is_empty() {
+            return None;
+        }
+        Some(TombstoneDelta { shelf, tombstones })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::LamportTimestamp;
+    use crate::json::Value;
+
+    type TestShelf = TombstoneShelf<Value, LamportTimestamp>;
+
+    fn sync(receiver: TestShelf, sender: &TestShelf) -> TestShelf {
+        let sv = receiver.get_state_vector();
+        match sender.get_state_delta(&sv) {
+            Some(delta) => receiver.merge(delta),
+            None => receiver,
+        }
+    }
+
+    fn edit(shelf: &mut TestShelf, key: &str, value: isize, clock: usize) {
+        if let Shelf::Map { shelves, .. } = &mut shelf.shelf {
+            shelves.insert(
+                key.to_owned(),
+                Shelf::Value {
+                    value: value.into(),
+                    clock: clock.into(),
+                },
+            );
+        }
+    }
+
+    /// A deletes "doc" while B is offline and keeps editing it. Returns both replicas after reconnecting.
+    fn offline_edit_during_delete(policy: DeletePolicy) -> (TestShelf, TestShelf) {
+        let base: Shelf<Value, LamportTimestamp> = json!([{"doc": [1, 1], "other": [2, 1]}, 0])
+            .try_into()
+            .unwrap();
+        let mut a = TestShelf::new(base.clone(), policy);
+        let mut b = TestShelf::new(base, policy);
+
+        a.remove(&["doc"]).unwrap();
+        // B's edits push its clock well past anything A has seen
+        edit(&mut b, "doc", 10, 2);
+        edit(&mut b, "doc", 11, 5);
+
+        let a = sync(a, &b);
+        let b = sync(b, &a);
+        (a, b)
+    }
+
+    #[test]
+    fn test_remove_wins() {
+        let (a, b) = offline_edit_during_delete(DeletePolicy::RemoveWins);
+        assert!(a.shelf.get("doc").is_none());
+        assert_eq!(a.shelf, b.shelf);
+        assert!(a.shelf.get("other").is_some());
+    }
+
+    #[test]
+    fn test_add_wins() {
+        let (a, b) = offline_edit_during_delete(DeletePolicy::AddWins);
+        let expected: Shelf<Value, LamportTimestamp> = json!([11, 5]).try_into().unwrap();
+        assert_eq!(a.shelf.get("doc"), Some(&expected));
+        assert_eq!(a.shelf, b.shelf);
+    }
+
+    #[test]
+    fn test_rename_survives_sync() {
+        for policy in [DeletePolicy::AddWins, DeletePolicy::RemoveWins] {
+            let base: Shelf<Value, LamportTimestamp> = json!([{
+                "old": [{"x": [1, 1], "y": [2, 1]}, 1],
+                "other": [3, 1]
+            }, 0])
+            .try_into()
+            .unwrap();
+            let mut a = TestShelf::new(base.clone(), policy);
+            let mut b = TestShelf::new(base, policy);
+            let moved = a.shelf.get("old").unwrap().clone();
+
+            a.rename(&["old"], &["new"]).unwrap();
+            edit(&mut b, "other", 4, 2);
+
+            let a = sync(a, &b);
+            let b = sync(b, &a);
+            for shelf in [&a, &b] {
+                assert!(shelf.shelf.get("old").is_none(), "{policy:?}");
+                assert_eq!(shelf.shelf.get("new"), Some(&moved), "{policy:?}");
+            }
+            assert_eq!(a.shelf, b.shelf);
+        }
+    }
+
+    #[test]
+    fn test_stale_value_does_not_resurrect() {
+        for policy in [DeletePolicy::AddWins, DeletePolicy::RemoveWins] {
+            let base: Shelf<Value, LamportTimestamp> =
+                json!([{"doc": [1, 1]}, 0]).try_into().unwrap();
+            let mut a = TestShelf::new(base.clone(), policy);
+            let b = TestShelf::new(base, policy);
+            a.remove(&["doc"]).unwrap();
+
+            // B never edited the key, so its copy is exactly what A deleted.
+            let a = sync(a, &b);
+            let b = sync(b, &a);
+            assert!(a.shelf.get("doc").is_none(), "{policy:?}");
+            assert!(b.shelf.get("doc").is_none(), "{policy:?}");
+        }
+    }
+}
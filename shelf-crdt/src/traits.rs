@@ -1,3 +1,4 @@
+use crate::clock::ClientId;
 use crate::wrap_crdt::Shelf;
 use bincode::{self, ErrorKind};
 // pub mod temporal;
@@ -50,3 +51,9 @@ pub trait ClockGenerator {
 
     fn next_clock(&mut self, clock: Self::Clock) -> Self::Clock;
 }
+
+/// Hands out ids for new clients, e.g. at random, as UUIDs or sequentially from a coordinator that guarantees
+/// they are unique.
+pub trait IdGenerator {
+    fn next_id(&mut self) -> ClientId;
+}
@@ -1,32 +1,38 @@
-use crate::traits::{ClockGenerator, Mergeable};
+use crate::traits::{ClockGenerator, IdGenerator, Mergeable};
 use serde::{Deserialize, Serialize};
 use serde_json::{self, json, Value as JSON};
 
 use crate::clock::{
-    LamportTimestamp, LamportTimestampGenerator, LogicalClock, SecureClock, ShelfClock,
+    ClientId, DotClock, LamportTimestamp, LamportTimestampGenerator, LogicalClock, OwnedShelfClock,
+    RandomIdGenerator, SecureClock, ShelfClock,
 };
 use crate::json::Value;
 use crate::state_vector::StateVectorContext;
+use std::borrow::Borrow;
 use std::clone::Clone;
 use std::cmp::Ordering;
-use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::hash_map::{self, Entry};
 use std::fmt::Display;
 use std::hash::Hash;
-use std::mem::swap;
+use std::iter::Flatten;
+use std::option;
 use std::{collections::HashMap, fmt::Debug};
+/// Map keys are `String`s unless `K` is given, e.g. to key entities by integer ids without stringifying them.
+/// Conversions to and from JSON and the path helpers that take `&str` keys are only available for `String` keys.
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
-pub enum Shelf<T, MapClock, ValueClock = MapClock>
+pub enum Shelf<T, MapClock, ValueClock = MapClock, K = String>
 where
     T: PartialEq + PartialOrd,
     MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
     ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
 {
     Value {
         value: T,
         clock: ValueClock,
     },
     Map {
-        shelves: HashMap<String, Shelf<T, MapClock, ValueClock>>,
+        shelves: HashMap<K, Shelf<T, MapClock, ValueClock, K>>,
         clock: MapClock,
     },
 }
@@ -36,6 +42,9 @@ where
     MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
     ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
 {
+    /// Imports plain JSON data, giving every node a fresh clock from the generators. Objects become maps and
+    /// everything else, arrays included, becomes a value, so `[]` is an empty array value.
+    /// To load a shelf that was exported with its clocks, use `TryFrom<JSON>` instead.
     pub fn from_json_values<'a, MGen, VGen>(
         json: JSON,
         map_context: &mut MGen,
@@ -47,8 +56,9 @@ where
     {
         match json {
             JSON::Object(obj) => {
+                // Reserving up front avoids rehashing the map as it grows on large imports.
                 let mut shelves: HashMap<String, Shelf<Value, MapClock, ValueClock>> =
-                    HashMap::new();
+                    HashMap::with_capacity(obj.len());
                 for (k, v) in obj {
                     shelves.insert(k, Shelf::from_json_values(v, map_context, value_context)?);
                 }
@@ -76,13 +86,80 @@ where
             }
         }
     }
+
+    /// Appends `value` to the array at `path` and moves its clock forward with `clock_gen`.
+    /// Arrays are still a single leaf: concurrent edits to the same array are not combined, the one with the higher
+    /// clock replaces the whole array.
+    pub fn array_push<G>(
+        &mut self,
+        path: &[&str],
+        value: Value,
+        clock_gen: &mut G,
+    ) -> Result<(), String>
+    where
+        G: ClockGenerator<Clock = ValueClock>,
+        ValueClock: Clone,
+    {
+        self.edit_array(path, clock_gen, |items| {
+            items.push(value);
+            Ok(())
+        })
+    }
+
+    /// Replaces the item at `index` of the array at `path` and moves its clock forward with `clock_gen`.
+    /// Like `array_push`, concurrent edits replace the whole array.
+    pub fn array_set<G>(
+        &mut self,
+        path: &[&str],
+        index: usize,
+        value: Value,
+        clock_gen: &mut G,
+    ) -> Result<(), String>
+    where
+        G: ClockGenerator<Clock = ValueClock>,
+        ValueClock: Clone,
+    {
+        self.edit_array(path, clock_gen, |items| {
+            let len = items.len();
+            let item = items.get_mut(index).ok_or_else(|| {
+                format!("Index {index} is out of bounds for an array of length {len}")
+            })?;
+            *item = value;
+            Ok(())
+        })
+    }
+
+    /// Applies `edit` to the array at `path`. The clock only moves forward if the edit succeeds.
+    fn edit_array<G>(
+        &mut self,
+        path: &[&str],
+        clock_gen: &mut G,
+        edit: impl FnOnce(&mut Vec<Value>) -> Result<(), String>,
+    ) -> Result<(), String>
+    where
+        G: ClockGenerator<Clock = ValueClock>,
+        ValueClock: Clone,
+    {
+        match self.get_path_mut(path)? {
+            Shelf::Value {
+                value: Value::Array(items),
+                clock,
+            } => {
+                edit(items)?;
+                *clock = clock_gen.next_clock(clock.clone());
+                Ok(())
+            }
+            _ => Err(format!("Expected an array at '{}'", path.join("/"))),
+        }
+    }
 }
 
-impl<T, MapClock, ValueClock> Display for Shelf<T, MapClock, ValueClock>
+impl<T, MapClock, ValueClock, K> Display for Shelf<T, MapClock, ValueClock, K>
 where
     T: Display + PartialOrd + PartialEq + Clone,
     MapClock: Display + PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
     ValueClock: Display + PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Display + Eq + Hash,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let repr = match self {
@@ -99,64 +176,121 @@ where
     }
 }
 
-impl<T, MapClock, ValueClock> Debug for Shelf<T, MapClock, ValueClock>
+impl<T, MapClock, ValueClock, K> Debug for Shelf<T, MapClock, ValueClock, K>
 where
     T: Display + PartialOrd + Clone,
     MapClock: Display + PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
     ValueClock: Display + PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Display + Eq + Hash,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         return std::fmt::Display::fmt(&self, f);
     }
 }
 
-impl<T, MapClock, ValueClock> PartialOrd for Shelf<T, MapClock, ValueClock>
+impl<T, MapClock, ValueClock, K> PartialOrd for Shelf<T, MapClock, ValueClock, K>
 where
     T: PartialOrd,
     MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
     ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
 {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
+        self.partial_cmp_by_clock_order(other, clock_order)
+    }
+}
+
+/// An empty Shelf Map with the default map clock. New documents and elements start out as maps so that entries can be
+/// merged into them; a default Shelf Value would be replaced wholesale by the first update. Since the default clock is
+/// the lowest one, any update merged in wins over it.
+impl<T, MapClock, ValueClock, K> Default for Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Default,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Shelf::Map {
+            shelves: HashMap::new(),
+            clock: MapClock::default(),
+        }
+    }
+}
+
+/// Iterates over the entries of a Shelf Map. A Shelf Value has no children and yields nothing.
+impl<'a, T, MapClock, ValueClock, K> IntoIterator for &'a Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
+{
+    type Item = (&'a K, &'a Shelf<T, MapClock, ValueClock, K>);
+    type IntoIter =
+        Flatten<option::IntoIter<hash_map::Iter<'a, K, Shelf<T, MapClock, ValueClock, K>>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let shelves = match self {
+            Shelf::Map { shelves, .. } => Some(shelves.iter()),
+            Shelf::Value { .. } => None,
+        };
+        shelves.into_iter().flatten()
+    }
+}
+
+/// Consumes a Shelf Map, yielding its entries. A Shelf Value has no children and yields nothing.
+impl<T, MapClock, ValueClock, K> IntoIterator for Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
+{
+    type Item = (K, Shelf<T, MapClock, ValueClock, K>);
+    type IntoIter =
+        Flatten<option::IntoIter<hash_map::IntoIter<K, Shelf<T, MapClock, ValueClock, K>>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let shelves = match self {
+            Shelf::Map { shelves, .. } => Some(shelves.into_iter()),
+            Shelf::Value { .. } => None,
+        };
+        shelves.into_iter().flatten()
+    }
+}
+
+impl<T, MapClock, ValueClock, K> Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
+{
+    /// Orders two shelves given the already computed order of their clocks.
+    /// Lets merges reuse a clock comparison instead of redoing it, which matters for expensive clocks like vector clocks.
+    fn partial_cmp_by_clock_order(
+        &self,
+        other: &Self,
+        clock_order: Option<Ordering>,
+    ) -> Option<Ordering> {
         match (self, other) {
-            (
-                Shelf::Map {
-                    clock: this_clock, ..
-                },
-                Shelf::Map {
-                    clock: other_clock, ..
-                },
-            ) => {
-                let clock_order = this_clock.partial_cmp(other_clock);
+            (Shelf::Map { .. }, Shelf::Map { .. }) => {
                 if let Some(Ordering::Equal) = clock_order {
                     None // Cannot order two maps with the same clock value
                 } else {
                     clock_order
                 }
             } // Cannot order 2 shelf maps.
-            (
-                Shelf::Map {
-                    clock: this_clock, ..
-                },
-                Shelf::Value {
-                    clock: other_clock, ..
-                },
-            ) => {
-                let clock_order = this_clock.partial_cmp(other_clock);
+            (Shelf::Map { .. }, Shelf::Value { .. }) => {
                 if let Some(Ordering::Equal) | None = clock_order {
                     Some(Ordering::Greater)
                 } else {
                     clock_order
                 }
             }
-            (
-                Shelf::Value {
-                    clock: this_clock, ..
-                },
-                Shelf::Map {
-                    clock: other_clock, ..
-                },
-            ) => {
-                let clock_order = this_clock.partial_cmp(other_clock);
+            (Shelf::Value { .. }, Shelf::Map { .. }) => {
                 if let Some(Ordering::Equal) | None = clock_order {
                     Some(Ordering::Less)
                 } else {
@@ -165,15 +299,12 @@ where
             }
             (
                 Shelf::Value {
-                    value: this_value,
-                    clock: this_clock,
+                    value: this_value, ..
                 },
                 Shelf::Value {
-                    value: other_value,
-                    clock: other_clock,
+                    value: other_value, ..
                 },
             ) => {
-                let clock_order = this_clock.partial_cmp(other_clock);
                 if let Some(Ordering::Equal) | None = clock_order {
                     this_value.partial_cmp(other_value)
                 } else {
@@ -182,8 +313,81 @@ where
             }
         }
     }
+
+    /// Merges `other` into this shelf in place, producing the same result as `merge`.
+    /// Saves swapping a shelf out of a struct field to merge it by value.
+    pub fn merge_assign(&mut self, other: Self) {
+        self.merge_in_place(other);
+    }
+
+    /// Merges `other` into this shelf in place, producing the same result as `merge`.
+    /// Returns `true` only if the content of this shelf changed, so callers can skip re-broadcasting redundant deltas.
+    pub fn merge_changed(&mut self, other: Self) -> bool {
+        self.merge_in_place(other)
+    }
+
+    /// The merge behind `merge`, `merge_assign` and `merge_changed`, returning whether this shelf changed.
+    /// Debug builds also check that merging `other` a second time would change nothing.
+    fn merge_in_place(&mut self, other: Self) -> bool {
+        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
+        #[cfg(debug_assertions)]
+        self.assert_idempotent(&other, clock_order);
+        self.merge_with_order(other, clock_order)
+    }
+
+    /// `merge_in_place` with the clock order of the two roots already computed, so every pair of nodes is compared
+    /// once. Resolves each node with `merge_decision`.
+    fn merge_with_order(&mut self, other: Self, clock_order: Option<Ordering>) -> bool {
+        match self.merge_decision(&other, clock_order) {
+            MergeDecision::SelfWins | MergeDecision::ValueTiebreak { self_wins: true } => false,
+            MergeDecision::OtherWins | MergeDecision::ValueTiebreak { self_wins: false } => {
+                *self = other;
+                true
+            }
+            MergeDecision::RecursiveMerge => match (self, other) {
+                (
+                    Self::Map { shelves, clock },
+                    Self::Map {
+                        shelves: other_shelves,
+                        clock: other_clock,
+                    },
+                ) => {
+                    let mut changed = false;
+                    if shelves.is_empty() {
+                        // Nothing to merge into, so the other map's entries are taken over without rehashing them.
+                        changed = !other_shelves.is_empty();
+                        *shelves = other_shelves;
+                    } else {
+                        for (key, val) in other_shelves.into_iter() {
+                            // Entries are resolved in place without rehashing the key.
+                            match shelves.get_mut(&key) {
+                                Some(sub_shelf) => {
+                                    let sub_order =
+                                        sub_shelf.get_clock().partial_cmp(&val.get_clock());
+                                    changed |= sub_shelf.merge_with_order(val, sub_order);
+                                }
+                                None => {
+                                    shelves.insert(key, val);
+                                    changed = true;
+                                }
+                            }
+                        }
+                    }
+                    // Mirrors `max_clock`. Taking over an equal clock changes nothing.
+                    if clock_order != Some(Ordering::Greater) {
+                        changed |= clock_order != Some(Ordering::Equal);
+                        *clock = other_clock;
+                    }
+                    changed
+                }
+                _ => unreachable!("Only maps are merged recursively"),
+            },
+        }
+    }
 }
 
+/// Parses the encoded format, in which every node is a `[contents, clock]` pair. Plain JSON data, e.g. `[]` or
+/// `[1, 2, 3]`, is not in this format and is rejected, import it with `from_json_values` instead.
 impl<MapClock, ValueClock> TryFrom<JSON> for Shelf<Value, MapClock, ValueClock>
 where
     MapClock: PartialEq + PartialOrd + TryFrom<JSON> + PartialOrd<ValueClock>,
@@ -202,7 +406,7 @@ where
                 let shelf = match value {
                     JSON::Object(obj) => {
                         let mut shelves: HashMap<String, Shelf<Value, MapClock, ValueClock>> =
-                            HashMap::new();
+                            HashMap::with_capacity(obj.len());
                         for (k, v) in obj {
                             shelves.insert(k, v.try_into()?);
                         }
@@ -251,11 +455,79 @@ where
     }
 }
 
-impl<T, MapClock, ValueClock> Shelf<T, MapClock, ValueClock>
+const TAGGED_VALUE_KEY: &str = "$v";
+const TAGGED_CLOCK_KEY: &str = "$c";
+
+impl<MapClock, ValueClock> Shelf<Value, MapClock, ValueClock>
+where
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    /// Converts to a self-describing format where every shelf is an object `{"$v": value, "$c": clock}` and the
+    /// value of a map is an object of tagged shelves. Unlike the positional `[value, clock]` format, a shelf
+    /// cannot be mistaken for a two element array of user data.
+    pub fn to_json_tagged(self) -> JSON
+    where
+        MapClock: Into<JSON>,
+        ValueClock: Into<JSON>,
+    {
+        let (value, clock) = match self {
+            Shelf::Value { value, clock } => (value.into(), clock.into()),
+            Shelf::Map { shelves, clock } => {
+                let shelves: serde_json::Map<String, JSON> = shelves
+                    .into_iter()
+                    .map(|(k, shelf)| (k, shelf.to_json_tagged()))
+                    .collect();
+                (JSON::Object(shelves), clock.into())
+            }
+        };
+        json!({ TAGGED_VALUE_KEY: value, TAGGED_CLOCK_KEY: clock })
+    }
+
+    /// Parses the format produced by `to_json_tagged`. Anything other than a tagged shelf is rejected rather
+    /// than guessed at, including JSON in the positional `[value, clock]` format.
+    pub fn from_json_tagged(json: JSON) -> Result<Self, String>
+    where
+        MapClock: TryFrom<JSON>,
+        ValueClock: TryFrom<JSON>,
+    {
+        let mut obj = match json {
+            JSON::Object(obj) if obj.len() == 2 => obj,
+            val => return Err(format!("Expected a tagged shelf, found {val}")),
+        };
+        let (value, clock) = match (obj.remove(TAGGED_VALUE_KEY), obj.remove(TAGGED_CLOCK_KEY)) {
+            (Some(value), Some(clock)) => (value, clock),
+            _ => {
+                return Err(format!(
+                    "Tagged shelf must have the keys '{TAGGED_VALUE_KEY}' and '{TAGGED_CLOCK_KEY}'"
+                ))
+            }
+        };
+        match value {
+            JSON::Object(obj) => {
+                let mut shelves: HashMap<String, Self> = HashMap::with_capacity(obj.len());
+                for (k, v) in obj {
+                    shelves.insert(k, Shelf::from_json_tagged(v)?);
+                }
+                let clock = MapClock::try_from(clock).map_err(|_| "Could not parse MapClock")?;
+                Ok(Shelf::Map { shelves, clock })
+            }
+            value => {
+                let value: Value = value.try_into()?;
+                let clock =
+                    ValueClock::try_from(clock).map_err(|_| "Could not parse ValueClock")?;
+                Ok(Shelf::Value { value, clock })
+            }
+        }
+    }
+}
+
+impl<T, MapClock, ValueClock, K> Shelf<T, MapClock, ValueClock, K>
 where
     T: PartialEq + PartialOrd,
     MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
     ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
 {
     pub fn get_clock(&self) -> ShelfClock<MapClock, ValueClock> {
         match &self {
@@ -272,30 +544,32 @@ where
         }
     }
     ///  Gets a Value out of the Shelf
-    pub fn get(&self, key: &str) -> Option<&Self> {
+    pub fn get<Q>(&self, key: &Q) -> Option<&Self>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
         match &self {
             Self::Map { shelves, .. } => shelves.get(key),
             _ => None,
         }
     }
 
-    pub fn get_path(&self, path: &[&str]) -> Result<&Self, String> {
-        let mut cur = self;
-        for key in path {
-            cur = cur.get(key).ok_or_else(|| format!("Key error: {key}"))?;
-        }
-        Ok(cur)
-    }
-
+    /// Returns the map entry addressed by `path` along with the clock of the map that holds it.
+    /// The root is not held by any map, so an empty path is an error. Callers that accept an empty path
+    /// should replace the root shelf directly instead.
     pub fn entry_from_path(
         &mut self,
-        path: impl IntoIterator<Item = String>,
-    ) -> Result<(Entry<String, Self>, &MapClock), String> {
+        path: impl IntoIterator<Item = K>,
+    ) -> Result<(Entry<K, Self>, &MapClock), String>
+    where
+        K: Display,
+    {
         let mut path_iter = path.into_iter();
         let mut update_shelf = self;
-        let mut prev_key: String = path_iter
+        let mut prev_key: K = path_iter
             .next()
-            .ok_or_else(|| "Path must have at least one key.".to_owned())?; // we are sure that at one key exists
+            .ok_or_else(|| "Path must have at least one key. The root has no entry and must be replaced directly.".to_owned())?; // we are sure that at one key exists
         for key in path_iter {
             if let Some(shelf) = update_shelf.get_mut(&prev_key) {
                 update_shelf = shelf;
@@ -307,11 +581,15 @@ where
 
         match update_shelf {
             Shelf::Value { .. } => Err(format!("Cannot set the key '{prev_key}' on a Shelf Value")),
-            Shelf::Map { shelves, clock } => Ok((shelves.entry(prev_key.to_owned()), clock)),
+            Shelf::Map { shelves, clock } => Ok((shelves.entry(prev_key), clock)),
         }
     }
 
-    pub fn get_mut(&mut self, key: &str) -> Option<&mut Self> {
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut Self>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
         match self {
             Self::Map { shelves, .. } => shelves.get_mut(key),
             _ => None,
@@ -342,651 +620,2669 @@ where
             shelf.garbage_collect();
         });
     }
-    /// Convenience method for testing. Calculates the size of the shelf recursively using Rust's  std::mem::size_of.
-    /// Assumes that we are calculating the total size of the instantiated objects.
-    pub fn get_total_bytes(&self) -> usize {
-        match &self {
-            Shelf::Value { .. } => std::mem::size_of::<T>() + std::mem::size_of::<ValueClock>(),
-            Shelf::Map { shelves, clock } => {
-                shelves
-                    .iter()
-                    .map(|(k, s)| std::mem::size_of_val(k) + s.get_total_bytes())
-                    .sum::<usize>()
-                    + std::mem::size_of::<MapClock>()
+
+    /// Keeps the `n` children with the highest clocks and drops the rest, e.g. to bound a feed stored as a map.
+    /// Children with the same logical clock are ordered by key. The map's clock moves up to the newest dropped
+    /// child, so peers replace their copy of the map instead of merging the dropped children back in.
+    ///
+    /// Every replica must prune the map with the same `n`. Replicas that keep a different number of children
+    /// end up with different maps at the same clock and do not converge.
+    pub fn prune_to_recent(&mut self, n: usize)
+    where
+        MapClock: LogicalClock + From<usize>,
+        ValueClock: LogicalClock,
+        K: Ord + Clone,
+    {
+        let (shelves, clock) = match self {
+            Self::Map { shelves, clock } if shelves.len() > n => (shelves, clock),
+            _ => return,
+        };
+        let mut by_recency: Vec<(usize, K)> = shelves
+            .iter()
+            .map(|(k, shelf)| (shelf.get_clock().get_logical_clock(), k.clone()))
+            .collect();
+        by_recency.sort_unstable_by(|a, b| b.cmp(a));
+        let dropped = by_recency.split_off(n);
+        let newest_dropped = dropped[0].0;
+        for (_, key) in dropped {
+            shelves.remove(&key);
+        }
+        if newest_dropped > clock.get_logical_clock() {
+            *clock = newest_dropped.into();
+        }
+    }
+
+    /// Removes entries with a lower clock than the map holding them, returning whether anything was removed.
+    /// A merge treats such entries as overwritten and `get_state_delta` never sends them, so in a malformed or
+    /// untrusted shelf they would linger on this replica only. Like `garbage_collect`, but reports its changes
+    /// so callers know whether an imported shelf needed repairing.
+    pub fn repair(&mut self) -> bool {
+        let (shelves, clock) = match self {
+            Self::Map { shelves, clock } => (shelves, ShelfClock::MapClock(&*clock)),
+            Self::Value { .. } => return false,
+        };
+        let len = shelves.len();
+        shelves.retain(|_, shelf| {
+            !matches!(shelf.get_clock().partial_cmp(&clock), Some(Ordering::Less))
+        });
+        let mut changed = shelves.len() != len;
+        for shelf in shelves.values_mut() {
+            changed |= shelf.repair();
+        }
+        changed
+    }
+
+    /// Checks that the shelf upholds `invariants`, describing the first violation found.
+    /// Meant for tests and fuzzing, so that a malformed shelf is caught by the operation that produced it.
+    pub fn validate(&self, invariants: &Invariants) -> Result<(), String>
+    where
+        K: Display,
+    {
+        if invariants.map_rooted && !self.contains_shelves() {
+            return Err("Root must be a Shelf Map".to_owned());
+        }
+        self.validate_at_path(invariants, &mut Vec::new())
+    }
+
+    fn validate_at_path(
+        &self,
+        invariants: &Invariants,
+        path: &mut Vec<String>,
+    ) -> Result<(), String>
+    where
+        K: Display,
+    {
+        let (shelves, clock) = match self {
+            Self::Map { shelves, clock } => (shelves, ShelfClock::MapClock(clock)),
+            Self::Value { .. } => return Ok(()),
+        };
+        for (key, shelf) in shelves {
+            path.push(key.to_string());
+            if invariants.flat && shelf.contains_shelves() {
+                return Err(format!(
+                    "Nested Shelf Map at '{}' in a flat shelf",
+                    path.join("/")
+                ));
+            }
+            if invariants.monotonic_clocks
+                && shelf.get_clock().partial_cmp(&clock) == Some(Ordering::Less)
+            {
+                return Err(format!(
+                    "Clock at '{}' is lower than the clock of the map holding it",
+                    path.join("/")
+                ));
             }
+            shelf.validate_at_path(invariants, path)?;
+            path.pop();
         }
+        Ok(())
     }
 }
 
-/*
-Currently (x, 1) cmp (x,1) -> x must be in delta
-    Because we don't know if clock was set by current client or another
-We want (x, (1,1)) cmp (x, (1,1))
-    Because we can prune this
-
-*/
+/// Structural invariants checked by `Shelf::validate`. Which ones hold depends on how the shelf is used,
+/// so none are checked by default.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Invariants {
+    /// The root is a map, as in awareness and secure shelves.
+    pub map_rooted: bool,
+    /// The root only holds values, with no nested maps.
+    pub flat: bool,
+    /// No entry has a lower clock than the map holding it. `garbage_collect` removes entries that do.
+    pub monotonic_clocks: bool,
+}
 
-impl<T, MapClock, ValueClock> Mergeable<Self> for Shelf<T, MapClock, ValueClock>
+impl<T, MapClock, ValueClock> Shelf<T, MapClock, ValueClock>
 where
-    T: PartialOrd,
+    T: PartialEq + PartialOrd,
     MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
     ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
 {
-    /// Merges another shelf into the current one, returning the resulting union.
-    fn merge(self, other: Self) -> Self {
-        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
-        // Merging two shelf maps
+    /// Rebuilds the shelf with different clock types, e.g. to upgrade a document to `SecureClock`s.
+    /// `map_fn` converts each map clock and `value_fn` each value clock, given the value it belongs to.
+    /// Every replica must convert before syncing again since shelves with different clocks cannot merge.
+    pub fn convert_clocks<M2, V2>(
+        self,
+        map_fn: &mut impl FnMut(MapClock) -> M2,
+        value_fn: &mut impl FnMut(&T, ValueClock) -> V2,
+    ) -> Shelf<T, M2, V2>
+    where
+        M2: PartialEq + PartialOrd + PartialOrd<V2> + PartialEq<V2>,
+        V2: PartialEq + PartialOrd + PartialOrd<M2> + PartialEq<M2>,
+    {
+        match self {
+            Shelf::Value { value, clock } => {
+                let clock = value_fn(&value, clock);
+                Shelf::Value { value, clock }
+            }
+            Shelf::Map { shelves, clock } => Shelf::Map {
+                shelves: shelves
+                    .into_iter()
+                    .map(|(k, shelf)| (k, shelf.convert_clocks(map_fn, value_fn)))
+                    .collect(),
+                clock: map_fn(clock),
+            },
+        }
+    }
 
-        match (self, other, clock_order) {
-            (_, other, Some(Ordering::Less)) => other, // Update is greater so take on that value
-            (this, _, Some(Ordering::Greater)) => this, // Self is greater so keep value
+    /// Whether both shelves hold the same values under the same keys, whatever their clocks are.
+    /// E.g. a write that stores the value a path already had touches its clock, but not its content.
+    pub fn same_content(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Value { value, .. }, Self::Value { value: other, .. }) => value == other,
             (
-                Self::Map {
-                    shelves: mut these_shelves,
-                    clock: this_clock,
-                },
+                Self::Map { shelves, .. },
                 Self::Map {
                     shelves: other_shelves,
-                    clock: other_clock,
+                    ..
                 },
-                _,
             ) => {
-                for (key, val) in other_shelves.into_iter() {
-                    let updated_value = if let Some(sub_shelf) = these_shelves.remove(&key) {
-                        sub_shelf.merge(val)
-                    } else {
-                        val
-                    };
-                    these_shelves.insert(key, updated_value);
-                }
-                let clock = if this_clock > other_clock {
-                    this_clock
-                } else {
-                    other_clock
-                };
+                shelves.len() == other_shelves.len()
+                    && shelves.iter().all(|(key, shelf)| {
+                        other_shelves
+                            .get(key)
+                            .is_some_and(|other| shelf.same_content(other))
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// Paths of every shelf that differs between `self` and `before`, e.g. the result of a merge and the shelf it was
+    /// merged into. A replaced subtree is reported once at its root rather than leaf by leaf.
+    pub fn changed_paths(&self, before: &Self) -> Vec<Vec<String>> {
+        let mut changes = Vec::new();
+        self.collect_changed_paths(before, &mut Vec::new(), &mut changes);
+        changes
+    }
+
+    fn collect_changed_paths(
+        &self,
+        before: &Self,
+        path: &mut Vec<String>,
+        changes: &mut Vec<Vec<String>>,
+    ) {
+        match (self, before) {
+            (
+                Self::Map { shelves, clock },
                 Self::Map {
-                    shelves: these_shelves,
-                    clock,
+                    shelves: old_shelves,
+                    clock: old_clock,
+                },
+            ) if clock == old_clock => {
+                for (key, shelf) in shelves {
+                    path.push(key.clone());
+                    match old_shelves.get(key) {
+                        Some(old_shelf) => shelf.collect_changed_paths(old_shelf, path, changes),
+                        None => changes.push(path.clone()),
+                    }
+                    path.pop();
                 }
-            } // If there is no priority between maps, they should be merged recursively.
-            (this, _, Some(Ordering::Equal)) => this, // Ruling out recursive map merges ^, if clocks are the same, then the value is unchanged.
-            (this, other, None) => {
-                // Try partial comparison of content and default to client_ids if this fails. Type compare will fail for things like floats that equal NaN.
-                match this.partial_cmp(&other) {
-                    Some(Ordering::Greater | Ordering::Equal) => this,
-                    Some(Ordering::Less) => other,
-                    None => panic!("Could not determine order of elements"),
+                for key in old_shelves.keys().filter(|key| !shelves.contains_key(*key)) {
+                    path.push(key.clone());
+                    changes.push(path.clone());
+                    path.pop();
                 }
-            } // In the case that both are different shelf content types, just take the type max.
+            }
+            _ if self == before => {}
+            _ => changes.push(path.clone()),
         }
     }
-}
 
-impl<T> Shelf<T, LamportTimestamp, SecureClock>
-where
-    T: PartialOrd + Hash,
-{
-    fn prune_corrupt_content(self) -> Option<Self> {
-        match self {
-            Shelf::Value { value, clock } if clock.verify(&value) => {
-                Some(Shelf::Value { value, clock })
-            }
-            Shelf::Map { shelves, clock } => {
-                let shelves: HashMap<String, Self> = shelves
-                    .into_iter()
-                    .filter_map(|(k, shelf)| shelf.prune_corrupt_content().map(|shelf| (k, shelf)))
-                    .collect();
-                let has_elements = !shelves.is_empty();
-                has_elements.then(|| Shelf::Map { shelves, clock })
-            }
-            _ => None,
+    /// Follows `path` down the tree. An empty path addresses the root.
+    pub fn get_path(&self, path: &[&str]) -> Result<&Self, String> {
+        let mut cur = self;
+        for key in path {
+            cur = cur.get(*key).ok_or_else(|| format!("Key error: {key}"))?;
         }
+        Ok(cur)
     }
-    /// Merges another shelf into the current one, returning the resulting union. If the other contents does not match the passed hash, it will keep the local value
-    pub fn secure_merge(self, other: Self) -> Self {
-        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
-        match (self, other, clock_order) {
-            (this, other, Some(Ordering::Less)) => other.prune_corrupt_content().unwrap_or(this), // Update is greater so take on that value
-            (this, _, Some(Ordering::Greater)) => this, // Self is greater so keep value
-            (
-                Self::Map {
-                    shelves: mut these_shelves,
-                    clock: this_clock,
-                },
-                Self::Map {
-                    shelves: other_shelves,
-                    ..
-                },
-                _,
-            ) => {
-                for (key, val) in other_shelves.into_iter() {
-                    if let Some(sub_shelf) = these_shelves.remove(&key) {
-                        these_shelves.insert(key, sub_shelf.secure_merge(val));
-                    } else if let Some(val) = val.prune_corrupt_content() {
-                        these_shelves.insert(key, val);
-                    }
-                }
 
-                Self::Map {
-                    shelves: these_shelves,
-                    clock: this_clock, // Assumes clocks are equal because of previous two branches
-                }
-            } // If there is no priority between maps, they should be merged recursively.
-            (this, _, Some(Ordering::Equal)) => this, // Ruling out recursive map merges ^, if clocks are the same, then the value is unchanged.
-            (this, other, None) => {
-                // Try partial comparison of content and default to client_ids if this fails. Type compare will fail for things like floats that equal NaN.
-                match this.partial_cmp(&other) {
-                    Some(Ordering::Greater | Ordering::Equal) => this,
-                    Some(Ordering::Less) => other.prune_corrupt_content().unwrap_or(this),
-                    None => panic!("Could not determine order of elements"),
-                }
-            } // In the case that both are different shelf content types, just take the type max.
+    /// Mutable version of `get_path`.
+    pub fn get_path_mut(&mut self, path: &[&str]) -> Result<&mut Self, String> {
+        let mut cur = self;
+        for key in path {
+            cur = cur
+                .get_mut(*key)
+                .ok_or_else(|| format!("Key error: {key}"))?;
         }
+        Ok(cur)
     }
-}
 
-impl Shelf<Value, LamportTimestamp, SecureClock> {
-    pub fn secure_from_json_values(json: JSON) -> Result<Self, String> {
-        match json {
-            JSON::Object(obj) => {
-                let mut shelves: HashMap<String, Self> = HashMap::new();
-                for (k, v) in obj {
-                    shelves.insert(k, Shelf::secure_from_json_values(v)?);
-                }
-                Ok(Shelf::Map {
-                    shelves,
-                    clock: 0.into(),
-                })
+    /// Checks whether `path` leads to a shelf like `get_path` does, without allocating an error on a miss.
+    pub fn path_exists(&self, path: &[&str]) -> bool {
+        let mut cur = self;
+        for key in path {
+            match cur.get(*key) {
+                Some(next) => cur = next,
+                None => return false,
             }
-            json => {
-                let value: Value = json.try_into()?;
-                let clock = SecureClock::new(&value, 0);
-                Ok(Shelf::Value { value, clock })
+        }
+        true
+    }
+
+    /// The keys of the map at `path`, without touching the shelves below it. Lets a tree view expand one level
+    /// at a time. Errors if there is no shelf at `path` or it is a Shelf Value.
+    pub fn keys_at_path(&self, path: &[&str]) -> Result<Vec<&String>, String> {
+        match self.get_path(path)? {
+            Shelf::Map { shelves, .. } => Ok(shelves.keys().collect()),
+            Shelf::Value { .. } => Err(format!(
+                "Cannot list the keys of the Shelf Value at '{}'",
+                path.join("/")
+            )),
+        }
+    }
+
+    /// The value that currently wins at `path` and the clock it was written with, e.g. to show when it was last
+    /// edited. Missing paths and maps return `None`.
+    pub fn value_and_clock(&self, path: &[&str]) -> Option<(&T, &ValueClock)> {
+        match self.get_path(path).ok()? {
+            Shelf::Value { value, clock } => Some((value, clock)),
+            Shelf::Map { .. } => None,
+        }
+    }
+
+    /// The client whose write currently wins at the Shelf Value at `path`, e.g. to show who wrote it.
+    /// Only clocks that record their writer, like `DotClock`, can tell. Other clocks, missing paths and maps
+    /// return `None`.
+    pub fn provenance(&self, path: &[&str]) -> Option<ClientId>
+    where
+        ValueClock: LogicalClock,
+    {
+        match self.get_path(path).ok()? {
+            Shelf::Value { clock, .. } => clock.get_client_id(),
+            Shelf::Map { .. } => None,
+        }
+    }
+
+    /// Resets the clock of the map at `path` to its default, e.g. for a session map that is cleared every day, so that
+    /// replacing it does not have to outbid every clock it has had. Its entries keep their clocks.
+    /// Errors if there is no map at `path`.
+    ///
+    /// Map clocks only grow because a replica that still has the higher clock wins every merge. A reset is only safe
+    /// if every replica resets the same map from the same state with no edits in flight, e.g. coordinated by a
+    /// server. Otherwise the copies that were not reset replace the reset one on merge, dropping every entry that was
+    /// added to it since.
+    pub fn reset_map_clock(&mut self, path: &[&str]) -> Result<(), String>
+    where
+        MapClock: Default,
+    {
+        match self.get_path_mut(path)? {
+            Shelf::Map { clock, .. } => {
+                *clock = MapClock::default();
+                Ok(())
+            }
+            Shelf::Value { .. } => Err(format!(
+                "Cannot reset the clock of the Shelf Value at '{}'",
+                path.join("/")
+            )),
+        }
+    }
+
+    /// Merges `delta` only if the clock of the shelf at `path` still equals `expected_clock`, e.g. the clock read before
+    /// preparing the delta. Returns whether the delta was merged. Errors if there is no shelf at `path`.
+    ///
+    /// This is advisory, not a lock: a replica that has not yet seen a concurrent write will still merge, and the
+    /// merged delta is not checked again by peers.
+    pub fn merge_if(
+        &mut self,
+        path: &[&str],
+        expected_clock: &OwnedShelfClock<MapClock, ValueClock>,
+        delta: Self,
+    ) -> Result<bool, String>
+    where
+        T: Clone,
+        MapClock: Clone,
+        ValueClock: Clone,
+    {
+        if self.get_path(path)?.get_clock() != expected_clock.as_shelf_clock() {
+            return Ok(false);
+        }
+        self.merge_assign(delta);
+        Ok(true)
+    }
+
+    /// Returns the child at `key`, inserting the shelf built by `f` if there is none yet.
+    /// Errors if this is a Shelf Value, since values cannot hold children.
+    ///
+    /// The map's clock is left as is: a higher map clock replaces the whole map on peers and would wipe their
+    /// concurrent entries. The inserted shelf keeps the clock `f` gives it, so it should be at least the map's clock
+    /// or `prune` will drop it.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: &str,
+        f: impl FnOnce() -> Self,
+    ) -> Result<&mut Self, String> {
+        match self {
+            Self::Map { shelves, .. } => Ok(shelves.entry(key.to_owned()).or_insert_with(f)),
+            Self::Value { .. } => Err(format!("Cannot insert the key '{key}' into a Shelf Value")),
+        }
+    }
+
+    /// Moves the subtree at `from` to `to`, keeping its clocks so it still merges with concurrent edits made at
+    /// its new location. The parent of `to` must be an existing map without an entry at `to`.
+    ///
+    /// A Shelf cannot express deletions, so peers that still hold `from` will bring it back on the next merge.
+    /// Use `TombstoneShelf::rename` to propagate the removal. Edits that peers make under `from` before they
+    /// see the rename stay at `from`, and if two clients concurrently rename the same subtree to different keys,
+    /// both copies remain. A moved subtree whose clock is older than its new parent's may be dropped by `prune`.
+    pub fn rename(&mut self, from: &[&str], to: &[&str]) -> Result<(), String> {
+        let (from_key, from_parent) = from
+            .split_last()
+            .ok_or_else(|| "Cannot rename the root".to_owned())?;
+        let (to_key, to_parent) = to
+            .split_last()
+            .ok_or_else(|| "Cannot rename to the root".to_owned())?;
+        if to.starts_with(from) {
+            return Err(format!("Cannot move {from:?} into itself"));
+        }
+        match self.get_path(to_parent)? {
+            Shelf::Map { shelves, .. } if shelves.contains_key(*to_key) => {
+                return Err(format!("Key '{to_key}' already exists"))
+            }
+            Shelf::Map { .. } => {}
+            Shelf::Value { .. } => {
+                return Err(format!("Cannot set the key '{to_key}' on a Shelf Value"))
+            }
+        }
+        let moved = match self.get_path_mut(from_parent)? {
+            Shelf::Map { shelves, .. } => shelves.remove(*from_key),
+            Shelf::Value { .. } => None,
+        }
+        .ok_or_else(|| format!("Key error: {from_key}"))?;
+        if let Shelf::Map { shelves, .. } = self.get_path_mut(to_parent)? {
+            shelves.insert(to_key.to_string(), moved);
+        }
+        Ok(())
+    }
+
+    /// Convenience method for testing. Calculates the size of the shelf recursively using Rust's  std::mem::size_of.
+    /// Assumes that we are calculating the total size of the instantiated objects.
+    pub fn get_total_bytes(&self) -> usize {
+        match &self {
+            Shelf::Value { .. } => std::mem::size_of::<T>() + std::mem::size_of::<ValueClock>(),
+            Shelf::Map { shelves, clock } => {
+                shelves
+                    .iter()
+                    .map(|(k, s)| std::mem::size_of_val(k) + s.get_total_bytes())
+                    .sum::<usize>()
+                    + std::mem::size_of::<MapClock>()
             }
         }
     }
 }
 
-pub struct Awareness<T, MapClock, ValueClock, UpdateContext>
+/*
+Currently (x, 1) cmp (x,1) -> x must be in delta
+    Because we don't know if clock was set by current client or another
+We want (x, (1,1)) cmp (x, (1,1))
+    Because we can prune this
+
+*/
+
+/// How a merge resolves two shelves at the same path.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MergeDecision {
+    /// This shelf's clock is higher, or both are the same write, so it is kept.
+    SelfWins,
+    /// The other shelf's clock is higher, so it replaces this one.
+    OtherWins,
+    /// Two maps with equal or concurrent clocks, whose entries are merged one by one.
+    RecursiveMerge,
+    /// Concurrent clocks, or a map and a value with equal clocks, so the shelf with the higher value or type wins.
+    ValueTiebreak { self_wins: bool },
+}
+
+/// The decisions a merge took, mirroring the structure of the shelves. See `Shelf::merge_explained`.
+#[derive(Clone, Debug)]
+pub struct MergeTrace<MapClock, ValueClock, K = String> {
+    pub decision: MergeDecision,
+    /// The clocks that were compared, this shelf's first.
+    pub clocks: (
+        OwnedShelfClock<MapClock, ValueClock>,
+        OwnedShelfClock<MapClock, ValueClock>,
+    ),
+    /// Traces of the entries both maps have, for a `RecursiveMerge`. Entries only one of them has are taken over
+    /// as they are.
+    pub children: HashMap<K, MergeTrace<MapClock, ValueClock, K>>,
+}
+
+/// Picks the higher of two map clocks given the already computed order of `this` relative to `other`.
+/// Maps only merge recursively when their clocks are equal or concurrent, but the merged map should never
+/// end up with a lower clock than either side even if that stops being the case.
+pub(crate) fn max_clock<C>(this: C, other: C, clock_order: Option<Ordering>) -> C {
+    match clock_order {
+        Some(Ordering::Greater) => this,
+        _ => other,
+    }
+}
+
+impl<T, MapClock, ValueClock, K> Mergeable<Self> for Shelf<T, MapClock, ValueClock, K>
 where
     T: PartialOrd,
     MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
     ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
 {
-    pub clients: Shelf<T, MapClock, ValueClock>,
-    pub update_context: UpdateContext,
-    pub client_id: usize,
+    /// Merges another shelf into the current one, returning the resulting union.
+    /// Debug builds also check that merging `other` a second time would change nothing.
+    fn merge(self, other: Self) -> Self {
+        let mut merged = self;
+        merged.merge_in_place(other);
+        merged
+    }
 }
 
-impl<T, MapClock, ValueClock, UpdateContext> Awareness<T, MapClock, ValueClock, UpdateContext>
+impl<T, MapClock, ValueClock, K> Shelf<T, MapClock, ValueClock, K>
 where
-    T: PartialOrd + Debug,
-    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Default,
+    T: PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
     ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
 {
-    pub fn new(update_context: UpdateContext) -> Self {
-        let client_id = rand::random();
-        Awareness {
-            clients: Shelf::Map {
-                shelves: HashMap::new(),
-                clock: MapClock::default(),
+    /// Merges several deltas, e.g. ones a relay collected from different peers. Since merging is associative and
+    /// commutative, the order of the deltas does not matter.
+    ///
+    /// A merge only walks the entries of the delta, not the whole shelf, so the deltas are merged in one by one.
+    /// Combining the deltas with each other first does not save a walk of this shelf and was slower in the
+    /// `merge_all` benchmark, as the combined delta is rebuilt along the way.
+    pub fn merge_all(self, deltas: impl IntoIterator<Item = Self>) -> Self {
+        deltas.into_iter().fold(self, Mergeable::merge)
+    }
+}
+
+impl<T, MapClock, ValueClock, K> Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+    K: Eq + Hash,
+{
+    /// Panics if merging `other` into the result of merging it into this shelf would change anything. Idempotency
+    /// only breaks when clocks compare inconsistently, which the fuzz tests would otherwise catch far from the
+    /// faulty merge. Only the nodes of `other` are visited, and values are never compared for equality, so a NaN
+    /// is not mistaken for a change.
+    #[cfg(debug_assertions)]
+    fn assert_idempotent(&self, other: &Self, clock_order: Option<Ordering>) {
+        if let Some(depth) = self.remerge_changes(other, clock_order, 0) {
+            panic!(
+                "Merge is not idempotent: merging the same delta again changed a node at depth {}",
+                depth
+            );
+        }
+    }
+
+    /// The depth of the first node that merging `other` would change in the result of merging `other` into this
+    /// shelf, where the root is at depth 0.
+    #[cfg(debug_assertions)]
+    fn remerge_changes(
+        &self,
+        other: &Self,
+        clock_order: Option<Ordering>,
+        depth: usize,
+    ) -> Option<usize> {
+        match self.merge_decision(other, clock_order) {
+            MergeDecision::SelfWins | MergeDecision::ValueTiebreak { self_wins: true } => {
+                self.merge_changes(other, depth)
+            }
+            MergeDecision::OtherWins | MergeDecision::ValueTiebreak { self_wins: false } => {
+                other.merge_changes(other, depth)
+            }
+            MergeDecision::RecursiveMerge => match (self, other) {
+                (
+                    Self::Map {
+                        shelves: these_shelves,
+                        clock: this_clock,
+                    },
+                    Self::Map {
+                        shelves: other_shelves,
+                        clock: other_clock,
+                    },
+                ) => {
+                    // Maps are resolved by their clocks alone, and the merged map has the clock `max_clock` picks
+                    let merged_clock = max_clock(this_clock, other_clock, clock_order);
+                    match merged_clock.partial_cmp(other_clock) {
+                        Some(Ordering::Greater) => None,
+                        Some(Ordering::Equal) => other_shelves.iter().find_map(|(key, val)| {
+                            match these_shelves.get(key) {
+                                Some(sub_shelf) => {
+                                    let sub_order =
+                                        sub_shelf.get_clock().partial_cmp(&val.get_clock());
+                                    sub_shelf.remerge_changes(val, sub_order, depth + 1)
+                                }
+                                None => val.merge_changes(val, depth + 1),
+                            }
+                        }),
+                        Some(Ordering::Less) | None => Some(depth),
+                    }
+                }
+                _ => unreachable!("Only maps are merged recursively"),
             },
-            client_id,
-            update_context,
         }
     }
 
-    pub fn new_for_client(client_id: usize, update_context: UpdateContext) -> Self {
-        Awareness {
-            clients: Shelf::Map {
-                shelves: HashMap::new(),
-                clock: MapClock::default(),
+    /// The depth of the first node that merging `other` into this shelf would change, like `merge_changed` but
+    /// without merging.
+    #[cfg(debug_assertions)]
+    fn merge_changes(&self, other: &Self, depth: usize) -> Option<usize> {
+        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
+        match self.merge_decision(other, clock_order) {
+            MergeDecision::SelfWins | MergeDecision::ValueTiebreak { self_wins: true } => None,
+            MergeDecision::OtherWins | MergeDecision::ValueTiebreak { self_wins: false } => {
+                Some(depth)
+            }
+            MergeDecision::RecursiveMerge => match (self, other) {
+                (
+                    Self::Map {
+                        shelves: these_shelves,
+                        ..
+                    },
+                    Self::Map {
+                        shelves: other_shelves,
+                        ..
+                    },
+                ) if clock_order == Some(Ordering::Equal) => {
+                    other_shelves
+                        .iter()
+                        .find_map(|(key, val)| match these_shelves.get(key) {
+                            Some(sub_shelf) => sub_shelf.merge_changes(val, depth + 1),
+                            None => Some(depth + 1),
+                        })
+                }
+                // The map takes over the concurrent clock
+                _ => Some(depth),
             },
-            client_id,
-            update_context,
         }
     }
 
-    pub fn get_peer_state(&self, key: &str) -> Option<&Shelf<T, MapClock, ValueClock>> {
-        self.clients.get(key)
+    /// How `merge` resolves this shelf and `other`, given the order of their clocks. Every merge goes through this,
+    /// so that merging, previewing and explaining a merge cannot disagree.
+    fn merge_decision(&self, other: &Self, clock_order: Option<Ordering>) -> MergeDecision {
+        match (self, other, clock_order) {
+            (_, _, Some(Ordering::Less)) => MergeDecision::OtherWins, // Update is greater so take on that value
+            (_, _, Some(Ordering::Greater)) => MergeDecision::SelfWins, // Self is greater so keep value
+            // If there is no priority between maps, they should be merged recursively.
+            (Self::Map { .. }, Self::Map { .. }, _) => MergeDecision::RecursiveMerge,
+            // Ruling out recursive map merges, if clocks are the same, then the value is unchanged.
+            (Self::Value { .. }, Self::Value { .. }, Some(Ordering::Equal)) => {
+                MergeDecision::SelfWins
+            }
+            (this, other, _) => {
+                // Try partial comparison of content and default to client_ids if this fails. Type compare will fail for things like floats that equal NaN.
+                // A map and a value with equal clocks are ordered by type as well, otherwise each side would keep its own.
+                match this.partial_cmp_by_clock_order(other, None) {
+                    Some(Ordering::Greater | Ordering::Equal) => {
+                        MergeDecision::ValueTiebreak { self_wins: true }
+                    }
+                    Some(Ordering::Less) => MergeDecision::ValueTiebreak { self_wins: false },
+                    None => panic!("Could not determine order of elements"),
+                }
+            }
+        }
     }
+}
 
-    pub fn get_own_state_mut(&mut self) -> Option<&mut Shelf<T, MapClock, ValueClock>> {
-        self.clients.get_mut(&self.client_id.to_string())
+impl<T, MapClock, ValueClock, K> Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialOrd + Clone,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Clone,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + Clone,
+    K: Eq + Hash + Clone,
+{
+    /// Computes what `merge` would return without consuming either shelf.
+    /// Only the parts of each shelf that end up in the result are cloned, so losing subtrees are never copied.
+    pub fn preview_merge(&self, other: &Self) -> Self {
+        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
+        match self.merge_decision(other, clock_order) {
+            MergeDecision::SelfWins | MergeDecision::ValueTiebreak { self_wins: true } => {
+                return self.clone()
+            }
+            MergeDecision::OtherWins | MergeDecision::ValueTiebreak { self_wins: false } => {
+                return other.clone()
+            }
+            MergeDecision::RecursiveMerge => {}
+        }
+        match (self, other) {
+            (
+                Self::Map {
+                    shelves: these_shelves,
+                    clock: this_clock,
+                },
+                Self::Map {
+                    shelves: other_shelves,
+                    clock: other_clock,
+                },
+            ) => {
+                let mut shelves: HashMap<K, Self> = these_shelves
+                    .iter()
+                    .filter(|(key, _)| !other_shelves.contains_key(*key))
+                    .map(|(key, shelf)| (key.clone(), shelf.clone()))
+                    .collect();
+                for (key, val) in other_shelves {
+                    let updated_value = match these_shelves.get(key) {
+                        Some(sub_shelf) => sub_shelf.preview_merge(val),
+                        None => val.clone(),
+                    };
+                    shelves.insert(key.clone(), updated_value);
+                }
+                Self::Map {
+                    shelves,
+                    clock: max_clock(this_clock.clone(), other_clock.clone(), clock_order),
+                }
+            }
+            _ => unreachable!("Only maps are merged recursively"),
+        }
     }
 
-    pub fn get_own_state(&self) -> Option<&Shelf<T, MapClock, ValueClock>> {
-        self.clients.get(&self.client_id.to_string())
+    /// Merges like `merge`, also returning the decision taken at every node that was compared, e.g. to illustrate
+    /// how a conflict was resolved.
+    pub fn merge_explained(self, other: Self) -> (Self, MergeTrace<MapClock, ValueClock, K>) {
+        let trace = self.trace_merge(&other);
+        (self.merge(other), trace)
     }
 
-    pub fn iter_clients(&self) -> impl Iterator + '_ {
-        let iterator = match &self.clients {
-            Shelf::Value { .. } => unreachable!("Client mapping must be a Shelf Map."),
-            Shelf::Map { shelves, .. } => shelves.iter(),
+    fn trace_merge(&self, other: &Self) -> MergeTrace<MapClock, ValueClock, K> {
+        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
+        let decision = self.merge_decision(other, clock_order);
+        let children = match (self, other, decision) {
+            (
+                Self::Map {
+                    shelves: these_shelves,
+                    ..
+                },
+                Self::Map {
+                    shelves: other_shelves,
+                    ..
+                },
+                MergeDecision::RecursiveMerge,
+            ) => other_shelves
+                .iter()
+                .filter_map(|(key, val)| {
+                    let sub_shelf = these_shelves.get(key)?;
+                    Some((key.clone(), sub_shelf.trace_merge(val)))
+                })
+                .collect(),
+            _ => HashMap::new(),
         };
-        iterator
+        MergeTrace {
+            decision,
+            clocks: (self.get_clock().into(), other.get_clock().into()),
+            children,
+        }
     }
 
-    pub fn get_total_bytes(&self) -> usize {
-        return std::mem::size_of::<UpdateContext>()
-            + std::mem::size_of::<usize>()
-            + self.clients.get_total_bytes();
+    /// Makes this shelf an exact copy of `authoritative`, values and clocks included, e.g. for a replica that mirrors
+    /// a primary. Unlike `merge`, this is not a CRDT operation: entries missing from `authoritative` are removed and
+    /// newer local writes are overwritten, whatever their clocks. Mirroring in both directions does not converge.
+    /// Subtrees that are already equal are kept as they are instead of being copied again.
+    pub fn mirror(&mut self, authoritative: &Self) {
+        match (&mut *self, authoritative) {
+            (
+                Shelf::Map { shelves, clock },
+                Shelf::Map {
+                    shelves: authoritative_shelves,
+                    clock: authoritative_clock,
+                },
+            ) => {
+                shelves.retain(|key, _| authoritative_shelves.contains_key(key));
+                for (key, authoritative_shelf) in authoritative_shelves {
+                    match shelves.get_mut(key) {
+                        Some(shelf) => shelf.mirror(authoritative_shelf),
+                        None => {
+                            shelves.insert(key.clone(), authoritative_shelf.clone());
+                        }
+                    }
+                }
+                *clock = authoritative_clock.clone();
+            }
+            (
+                Shelf::Value { value, clock },
+                Shelf::Value {
+                    value: authoritative_value,
+                    clock: authoritative_clock,
+                },
+            ) if value == authoritative_value && clock == authoritative_clock => {}
+            _ => *self = authoritative.clone(),
+        }
     }
 }
 
-impl Awareness<Value, LamportTimestamp, LamportTimestamp, StateVectorContext> {
-    pub fn set_state(
-        &mut self,
-        path: impl IntoIterator<Item = String>,
-        value: Shelf<Value, LamportTimestamp>,
-    ) -> Result<Option<Shelf<Value, LamportTimestamp>>, String> {
-        let (entry, parent_clock) = {
-            let client_id = self.client_id.to_string();
-            let pa = path.into_iter();
-            let p = std::iter::once(client_id).chain(pa);
-            self.clients.entry_from_path(p)
-        }?;
-        let parent_clock = parent_clock.0;
-        let new_ts = match &entry {
-            Entry::Occupied(occupied_entry) => {
-                let old_value = occupied_entry.get();
-                match old_value {
-                    Shelf::Value { clock, .. } => Some(clock.0.max(parent_clock) + 1), // New clock must be
-                    Shelf::Map {
+impl<T, MapClock, ValueClock, K> Shelf<T, MapClock, ValueClock, K>
+where
+    T: PartialOrd,
+    MapClock:
+        PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + LogicalClock,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock> + LogicalClock,
+    K: Eq + Hash,
+{
+    /// The highest logical clock anywhere in the shelf.
+    pub fn max_logical_clock(&self) -> usize {
+        let clock = self.get_clock().get_logical_clock();
+        match self {
+            Shelf::Value { .. } => clock,
+            Shelf::Map { shelves, .. } => shelves
+                .values()
+                .map(|shelf| shelf.max_logical_clock())
+                .fold(clock, usize::max),
+        }
+    }
+
+    fn prune_skewed_content(self, bound: usize) -> Option<Self> {
+        if self.get_clock().get_logical_clock() > bound {
+            return None;
+        }
+        match self {
+            Shelf::Map { shelves, clock } => {
+                let len = shelves.len();
+                let shelves: HashMap<K, Self> = shelves
+                    .into_iter()
+                    .filter_map(|(k, shelf)| {
+                        shelf.prune_skewed_content(bound).map(|shelf| (k, shelf))
+                    })
+                    .collect();
+                // A map that only held rejected entries would otherwise clear the receiver's copy.
+                let keep = !shelves.is_empty() || len == 0;
+                keep.then(|| Shelf::Map { shelves, clock })
+            }
+            value => Some(value),
+        }
+    }
+
+    /// Merges like `merge`, but first drops every entry of `other` whose clock is more than `max_skew` ahead of the
+    /// highest clock in this shelf. A peer with a drifting or buggy clock could otherwise claim a key with a clock
+    /// that local edits never catch up to, freezing it.
+    ///
+    /// This is an opt-in safety valve: replicas that have seen different clocks use different bounds and may reject
+    /// different entries, so strict convergence is no longer guaranteed.
+    pub fn merge_bounded(self, other: Self, max_skew: usize) -> Self {
+        let bound = self.max_logical_clock().saturating_add(max_skew);
+        match other.prune_skewed_content(bound) {
+            Some(other) => self.merge(other),
+            None => self,
+        }
+    }
+}
+
+impl<MapClock> Shelf<Value, MapClock, DotClock>
+where
+    MapClock: PartialEq + PartialOrd + PartialOrd<DotClock> + PartialEq<DotClock>,
+    DotClock: PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    /// Converts to JSON for debugging, with each value as `{"value": value, "client": client_id, "clock": clock}`
+    /// so it is easy to trace which client wrote what. Maps are plain objects like in `to_json_values`.
+    pub fn to_annotated_json(&self) -> JSON {
+        match self {
+            Shelf::Value { value, clock } => json!({
+                "value": JSON::from(value.clone()),
+                "client": clock.client_id,
+                "clock": clock.clock,
+            }),
+            Shelf::Map { shelves, .. } => {
+                let json_map: serde_json::Map<String, JSON> = shelves
+                    .iter()
+                    .map(|(k, shelf)| (k.clone(), shelf.to_annotated_json()))
+                    .collect();
+                JSON::Object(json_map)
+            }
+        }
+    }
+
+    /// Merges like `merge`, but breaks the ties that `merge` cannot: concurrent leaves from different clients whose
+    /// values cannot be ordered either, such as two `NaN`s, which `merge` panics on, and equal values, where `merge`
+    /// keeps whichever clock it had. The leaf written by the higher client id wins.
+    /// All replicas must merge this way, otherwise they will not converge.
+    pub fn merge_with_client_tiebreak(self, other: Self) -> Self {
+        self.merge_with_tiebreak(other, None)
+    }
+
+    /// Merges like `merge_with_client_tiebreak`, but also treats concurrent floats that round to the same multiple of
+    /// `tolerance` as tied, so the client id picks the winner instead of rounding noise. Replicas that compute the
+    /// "same" float slightly differently, e.g. a wasm and a native build, then still agree on which leaf wins.
+    /// Floats are compared by bucket rather than by their distance, since "close to" is not transitive and merging
+    /// three replicas would then depend on the order. Noise that crosses the edge of a bucket still orders by value.
+    /// All replicas must merge with the same tolerance, otherwise they will not converge.
+    pub fn merge_with_float_tolerance(self, other: Self, tolerance: f32) -> Self {
+        self.merge_with_tiebreak(other, Some(tolerance))
+    }
+
+    fn merge_with_tiebreak(self, other: Self, float_tolerance: Option<f32>) -> Self {
+        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
+        match (self, other, clock_order) {
+            (
+                Shelf::Map {
+                    shelves: mut these_shelves,
+                    clock: this_clock,
+                },
+                Shelf::Map {
+                    shelves: other_shelves,
+                    clock: other_clock,
+                },
+                Some(Ordering::Equal) | None,
+            ) => {
+                for (key, val) in other_shelves.into_iter() {
+                    let updated_value = match these_shelves.remove(&key) {
+                        Some(sub_shelf) => sub_shelf.merge_with_tiebreak(val, float_tolerance),
+                        None => val,
+                    };
+                    these_shelves.insert(key, updated_value);
+                }
+                Shelf::Map {
+                    shelves: these_shelves,
+                    clock: max_clock(this_clock, other_clock, clock_order),
+                }
+            }
+            (
+                this @ Shelf::Value {
+                    clock: this_clock, ..
+                },
+                other @ Shelf::Value {
+                    clock: other_clock, ..
+                },
+                None,
+            ) if matches!(
+                this.partial_cmp_by_clock_order(&other, None),
+                None | Some(Ordering::Equal)
+            ) || this.floats_tied(&other, float_tolerance) =>
+            {
+                if other_clock.client_id > this_clock.client_id {
+                    other
+                } else {
+                    this
+                }
+            }
+            (this, other, _) => this.merge(other),
+        }
+    }
+
+    /// Whether both shelves are floats in the same bucket of width `tolerance`.
+    fn floats_tied(&self, other: &Self, tolerance: Option<f32>) -> bool {
+        match (self, other, tolerance) {
+            (
+                Shelf::Value {
+                    value: Value::Float(a),
+                    ..
+                },
+                Shelf::Value {
+                    value: Value::Float(b),
+                    ..
+                },
+                Some(tolerance),
+            ) => (a / tolerance).round() == (b / tolerance).round(),
+            _ => false,
+        }
+    }
+}
+
+/// A value that `secure_merge_audited` dropped because it did not match the hash in its clock.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RejectedEntry<T> {
+    /// Keys from the root of the merged shelf down to the value.
+    pub path: Vec<String>,
+    /// The value the sender claimed.
+    pub value: T,
+    /// The clock it came with, including the hash that failed to verify.
+    pub clock: SecureClock,
+}
+
+impl<T> Shelf<T, LamportTimestamp, SecureClock>
+where
+    T: PartialOrd + Hash,
+{
+    fn prune_corrupt_content(
+        self,
+        path: &mut Vec<String>,
+        sink: &mut dyn FnMut(RejectedEntry<T>),
+    ) -> Option<Self> {
+        match self {
+            Shelf::Value { value, clock } if clock.verify(&value) => {
+                Some(Shelf::Value { value, clock })
+            }
+            Shelf::Value { value, clock } => {
+                sink(RejectedEntry {
+                    path: path.clone(),
+                    value,
+                    clock,
+                });
+                None
+            }
+            Shelf::Map { shelves, clock } => {
+                let shelves: HashMap<String, Self> = shelves
+                    .into_iter()
+                    .filter_map(|(k, shelf)| {
+                        path.push(k);
+                        let shelf = shelf.prune_corrupt_content(path, sink);
+                        let k = path.pop().unwrap();
+                        shelf.map(|shelf| (k, shelf))
+                    })
+                    .collect();
+                let has_elements = !shelves.is_empty();
+                has_elements.then(|| Shelf::Map { shelves, clock })
+            }
+        }
+    }
+    /// Merges another shelf into the current one, returning the resulting union. If the other contents does not match the passed hash, it will keep the local value
+    pub fn secure_merge(self, other: Self) -> Self {
+        self.secure_merge_audited(other, &mut |_| {})
+    }
+
+    /// `secure_merge` in place. Saves swapping a shelf out of a struct field to merge it by value.
+    pub fn secure_merge_assign(&mut self, other: Self) {
+        self.secure_merge_at(other, &mut Vec::new(), &mut |_| {});
+    }
+
+    /// Same as `secure_merge`, but reports every value it drops for failing its hash check to `sink`.
+    /// The shelf does not know who sent `other`, so attach the source in `sink` if it is needed.
+    pub fn secure_merge_audited(
+        self,
+        other: Self,
+        sink: &mut impl FnMut(RejectedEntry<T>),
+    ) -> Self {
+        let mut merged = self;
+        merged.secure_merge_at(other, &mut Vec::new(), sink);
+        merged
+    }
+
+    fn secure_merge_at(
+        &mut self,
+        other: Self,
+        path: &mut Vec<String>,
+        sink: &mut dyn FnMut(RejectedEntry<T>),
+    ) {
+        let clock_order = self.get_clock().partial_cmp(&other.get_clock());
+        // Resolved like `merge`, except that the other side only wins with content that matches its hash
+        match self.merge_decision(&other, clock_order) {
+            MergeDecision::SelfWins | MergeDecision::ValueTiebreak { self_wins: true } => {}
+            MergeDecision::OtherWins | MergeDecision::ValueTiebreak { self_wins: false } => {
+                if let Some(other) = other.prune_corrupt_content(path, sink) {
+                    *self = other;
+                }
+            }
+            MergeDecision::RecursiveMerge => match (self, other) {
+                (
+                    Self::Map { shelves, clock },
+                    Self::Map {
+                        shelves: other_shelves,
+                        clock: other_clock,
+                    },
+                ) => {
+                    for (key, val) in other_shelves.into_iter() {
+                        path.push(key);
+                        match shelves.get_mut(path.last().unwrap()) {
+                            Some(sub_shelf) => {
+                                sub_shelf.secure_merge_at(val, path, sink);
+                                path.pop();
+                            }
+                            None => {
+                                let val = val.prune_corrupt_content(path, sink);
+                                let key = path.pop().unwrap();
+                                if let Some(val) = val {
+                                    shelves.insert(key, val);
+                                }
+                            }
+                        }
+                    }
+                    if clock_order != Some(Ordering::Greater) {
+                        *clock = other_clock;
+                    }
+                }
+                _ => unreachable!("Only maps are merged recursively"),
+            },
+        }
+    }
+}
+
+impl Shelf<Value, LamportTimestamp, SecureClock> {
+    pub fn secure_from_json_values(json: JSON) -> Result<Self, String> {
+        match json {
+            JSON::Object(obj) => {
+                let mut shelves: HashMap<String, Self> = HashMap::with_capacity(obj.len());
+                for (k, v) in obj {
+                    shelves.insert(k, Shelf::secure_from_json_values(v)?);
+                }
+                Ok(Shelf::Map {
+                    shelves,
+                    clock: 0.into(),
+                })
+            }
+            json => {
+                let value: Value = json.try_into()?;
+                let clock = SecureClock::new(&value, 0);
+                Ok(Shelf::Value { value, clock })
+            }
+        }
+    }
+}
+
+pub struct Awareness<T, MapClock, ValueClock, UpdateContext>
+where
+    T: PartialOrd,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock>,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    pub clients: Shelf<T, MapClock, ValueClock>,
+    pub update_context: UpdateContext,
+    pub client_id: ClientId,
+}
+
+impl<T, MapClock, ValueClock, UpdateContext> Awareness<T, MapClock, ValueClock, UpdateContext>
+where
+    T: PartialOrd + Debug,
+    MapClock: PartialEq + PartialOrd + PartialOrd<ValueClock> + PartialEq<ValueClock> + Default,
+    ValueClock: PartialEq + PartialOrd + PartialOrd<MapClock> + PartialEq<MapClock>,
+{
+    pub fn new(update_context: UpdateContext) -> Self {
+        Self::with_id_generator(&mut RandomIdGenerator, update_context)
+    }
+
+    /// Creates an awareness whose client id is taken from `ids`.
+    pub fn with_id_generator(ids: &mut impl IdGenerator, update_context: UpdateContext) -> Self {
+        Self::new_for_client(ids.next_id(), update_context)
+    }
+
+    pub fn new_for_client(client_id: ClientId, update_context: UpdateContext) -> Self {
+        Awareness {
+            clients: Shelf::Map {
+                shelves: HashMap::new(),
+                clock: MapClock::default(),
+            },
+            client_id,
+            update_context,
+        }
+    }
+
+    pub fn get_peer_state(&self, key: &str) -> Option<&Shelf<T, MapClock, ValueClock>> {
+        self.clients.get(key)
+    }
+
+    pub fn get_own_state_mut(&mut self) -> Option<&mut Shelf<T, MapClock, ValueClock>> {
+        self.clients.get_mut(&self.client_id.to_string())
+    }
+
+    pub fn get_own_state(&self) -> Option<&Shelf<T, MapClock, ValueClock>> {
+        self.clients.get(&self.client_id.to_string())
+    }
+
+    /// Drops the state of the peer `client_id`, e.g. once it is known to have disconnected. Returns whether the
+    /// peer was known. This only affects this replica, so the peer reappears if a delta with its state is merged.
+    pub fn remove_peer(&mut self, client_id: &str) -> bool {
+        match &mut self.clients {
+            Shelf::Value { .. } => unreachable!("Client mapping must be a Shelf Map."),
+            Shelf::Map { shelves, .. } => shelves.remove(client_id).is_some(),
+        }
+    }
+
+    pub fn iter_clients(&self) -> impl Iterator + '_ {
+        let iterator = match &self.clients {
+            Shelf::Value { .. } => unreachable!("Client mapping must be a Shelf Map."),
+            Shelf::Map { shelves, .. } => shelves.iter(),
+        };
+        iterator
+    }
+
+    pub fn get_total_bytes(&self) -> usize {
+        return std::mem::size_of::<UpdateContext>()
+            + std::mem::size_of::<usize>()
+            + self.clients.get_total_bytes();
+    }
+}
+
+impl Awareness<Value, LamportTimestamp, LamportTimestamp, StateVectorContext> {
+    /// Sets `value` at `path` within this client's state. An empty path replaces the client's whole state.
+    pub fn set_state(
+        &mut self,
+        path: impl IntoIterator<Item = String>,
+        value: Shelf<Value, LamportTimestamp>,
+    ) -> Result<Option<Shelf<Value, LamportTimestamp>>, String> {
+        let (entry, parent_clock) = {
+            let client_id = self.client_id.to_string();
+            let pa = path.into_iter();
+            let p = std::iter::once(client_id).chain(pa);
+            self.clients.entry_from_path(p)
+        }?;
+        let parent_clock = parent_clock.0;
+        let new_ts = match &entry {
+            Entry::Occupied(occupied_entry) => {
+                let old_value = occupied_entry.get();
+                match old_value {
+                    Shelf::Value { clock, .. } => Some(clock.0.max(parent_clock) + 1), // New clock must be
+                    Shelf::Map {
+                        shelves,
+                        clock: LamportTimestamp(old_clock),
+                    } => {
+                        let highest_child_timestamp = shelves
+                            .values()
+                            .map(|shelf| shelf.get_clock().get_logical_clock())
+                            .max();
+                        highest_child_timestamp.map(|ts| ts.max(parent_clock).max(*old_clock) + 1)
+                    }
+                }
+            }
+            Entry::Vacant(_) => None,
+        };
+        let new_ts = new_ts.unwrap_or(parent_clock + 1);
+        let value = match value {
+            Shelf::Value { value, .. } => Shelf::Value {
+                value,
+                clock: new_ts.into(),
+            },
+            Shelf::Map { shelves, .. } => Shelf::Map {
+                shelves,
+                clock: new_ts.into(),
+            },
+        };
+        let old_value = match entry {
+            Entry::Occupied(mut o) => Some(o.insert(value)),
+            Entry::Vacant(v) => {
+                v.insert(value);
+                None
+            }
+        };
+        Ok(old_value)
+    }
+
+    /// Replaces this client's state with an empty map, e.g. when it goes idle. The map gets a newer clock than the
+    /// old state so that peers replace their copy instead of merging into it.
+    pub fn clear_own_state(&mut self) {
+        let empty = Shelf::Map {
+            shelves: HashMap::new(),
+            clock: LamportTimestamp::default(),
+        };
+        self.set_state(Vec::<String>::new(), empty)
+            .expect("Client mapping must be a Shelf Map.");
+    }
+
+    /// Creates an awareness holding `json` as the state of `client_id`. The state must be an object.
+    pub fn from_json_values(json: JSON, client_id: ClientId) -> Result<Self, String> {
+        if !json.is_object() {
+            return Err(format!("Awareness state must be an object, got {json}"));
+        }
+        let mut map_clock_generator = LamportTimestampGenerator {};
+        let mut val_clock_generator = LamportTimestampGenerator {};
+        let shelf: Shelf<Value, LamportTimestamp, LamportTimestamp> =
+            Shelf::from_json_values(json, &mut map_clock_generator, &mut val_clock_generator)?;
+        let mut client_map = HashMap::new();
+        client_map.insert(client_id.to_string(), shelf);
+        let clients = Shelf::Map {
+            shelves: client_map,
+            clock: LamportTimestamp::default(),
+        };
+
+        Ok(Awareness {
+            client_id,
+            clients,
+            update_context: StateVectorContext,
+        })
+    }
+
+    /// Returns `true` if the delta changed the awareness state.
+    pub fn merge(&mut self, delta: Shelf<Value, LamportTimestamp>) -> bool {
+        self.clients.merge_changed(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{DotClock, DotClockGenerator, LamportTimestamp};
+    use crate::stable_hash::stable_hash;
+    use crate::traits::DeltaCRDT;
+
+    type TestShelf = Shelf<Value, LamportTimestamp, DotClock>;
+
+    use rand::{prelude::StdRng, SeedableRng};
+
+    use crate::{shelf_fuzzer::ShelfFuzzer, wrap_crdt::*};
+
+    fn merge(branch: TestShelf, main: TestShelf) -> TestShelf {
+        let sv = main.get_state_vector();
+        if let Some(delta) = branch.get_state_delta(&sv) {
+            return main.merge(delta);
+        }
+        return main;
+    }
+
+    fn val<T: Into<Value>>(value: T, c: impl Into<LamportTimestamp>) -> TestShelf {
+        Shelf::Value {
+            value: value.into(),
+            clock: clock(c.into().0),
+        }
+    }
+
+    fn array<T: Into<Value>>(arr: Vec<T>, c: impl Into<LamportTimestamp>) -> TestShelf {
+        let arr: Vec<Value> = arr.into_iter().map(|v| v.into()).collect();
+        Shelf::Value {
+            value: arr.into(),
+            clock: clock(c.into().0),
+        }
+    }
+
+    fn shelf_map(
+        contents: impl Iterator<Item = (String, TestShelf)>,
+        c: impl Into<LamportTimestamp>,
+    ) -> TestShelf {
+        let shelves = HashMap::from_iter(contents);
+        Shelf::Map {
+            shelves,
+            clock: c.into(),
+        }
+    }
+
+    fn clock(c: usize) -> DotClock {
+        DotClock {
+            client_id: 0,
+            clock: c,
+        }
+    }
+
+    fn validate_crdt_properties(shelf: TestShelf, shelf2: TestShelf) -> TestShelf {
+        // Forwards
+        let mut receiver = shelf.clone();
+        let sender = shelf2.clone();
+        let sv = receiver.get_state_vector();
+
+        let delta = sender.get_state_delta(&sv);
+        let cached_delta = delta.clone();
+        if let Some(delta) = delta {
+            receiver = receiver.merge(delta);
+        }
+
+        // Backwards
+        let mut receiver_back = shelf2.clone();
+        let sender_back = shelf.clone();
+        let sv_back = receiver_back.get_state_vector();
+        let delta_back = sender_back.get_state_delta(&sv_back);
+        let cached_delta_back = delta_back.clone();
+        if let Some(delta) = delta_back {
+            receiver_back = receiver_back.merge(delta);
+        }
+        let id_delta = cached_delta.clone();
+        let report = || {
+            format!("\nReceiver: {shelf}, \nSender {shelf2}, \nStateVector {sv:?}, \nDelta: {id_delta:?} \nStateVectorBACK: {sv_back:?}, \nDeltaBACK {cached_delta_back:?}")
+        };
+
+        // Ensure both forwards and backwards match
+        assert_eq!(receiver, receiver_back, "Not commutative {}", report());
+        if let Some(delta) = cached_delta {
+            receiver = receiver.merge(delta);
+            assert_eq!(receiver, receiver_back, "Not idempotent {}", report());
+        }
+        return receiver;
+    }
+
+    #[test]
+    fn test_init() {
+        let v: TestShelf = Shelf::Value {
+            value: Value::Bool(true),
+            clock: clock(0),
+        };
+        let m: TestShelf = Shelf::Map {
+            shelves: HashMap::new(),
+            clock: 1.into(),
+        };
+    }
+
+    #[test]
+    fn test_clock() {
+        let shelf = Shelf::Value {
+            value: 1.into(),
+            clock: clock(1),
+        };
+        let shelf2 = val(2, 2);
+        let result = shelf.merge(shelf2);
+
+        if let Shelf::Value { value, clock: c } = result {
+            assert!(c == clock(2));
+            assert_eq!(value, Value::Int(2))
+        } else {
+            panic!("Should be a value");
+        };
+    }
+    #[test]
+    fn test_object_override() {
+        let shelf: TestShelf = shelf_map([("foo".to_owned(), val(1, 0))].into_iter(), 0);
+        let y = val(2, 0);
+        let shelf2 = val(2, 0);
+        let shelf = merge(shelf, shelf2);
+
+        if let Shelf::Map { .. } = shelf {
+        } else {
+            panic!("Expected map to override the integer value")
+        }
+    }
+    #[test]
+    fn test_vec_diff() {
+        let shelf: TestShelf = array(vec![1], 0);
+        let shelf2 = array(vec![2], 2);
+        let shelf = merge(shelf2, shelf);
+
+        if let Shelf::Value {
+            value: Value::Array(list),
+            ..
+        } = shelf
+        {
+            if let Value::Int(n) = list[0] {
+                assert_eq!(n, 2)
+            } else {
+                panic!("not an int {:?}", list[0]);
+            }
+        } else {
+            panic!("Didn't find list: {}", shelf)
+        }
+    }
+    #[test]
+    fn test_recursive_diff() {
+        let shelf = shelf_map([("a".to_owned(), val(1, 0))].into_iter(), 0);
+        let shelf2 = shelf_map(
+            [(
+                "b".to_owned(),
+                Shelf::Value {
+                    value: 2.into(),
+                    clock: DotClock {
+                        client_id: 1,
+                        clock: 0,
+                    },
+                },
+            )]
+            .into_iter(),
+            0,
+        );
+
+        let shelf = merge(shelf2, shelf);
+
+        if let Shelf::Map { shelves, .. } = shelf {
+            assert!(shelves.len() == 2)
+        }
+    }
+
+    #[test]
+    fn test_get() {
+        let mut shelf: TestShelf = json!([{ "user": [{
+            "mouse_position": [[0, 1], [0,0]],
+            "cursor": [{"left": ["a",[0,0]], "right": ["b",[0,0]]},0]
+        }, 0]  }, 0])
+        .try_into()
+        .unwrap();
+        let res = shelf
+            .get_mut("user")
+            .unwrap()
+            .get_mut("cursor")
+            .unwrap()
+            .get_mut("left")
+            .unwrap();
+        if let Shelf::Value {
+            value: Value::String(s),
+            ..
+        } = res
+        {
+            assert_eq!(s, "a");
+        } else {
+            panic!("Unexpected value {:?}", res)
+        }
+
+        assert!(shelf.get("BOOM/goes/the/path").is_none())
+    }
+
+    #[test]
+    fn test_integer_keys() {
+        type EntityShelf = Shelf<Value, LamportTimestamp, DotClock, u64>;
+        let entity = |value: isize, client_id: ClientId, c: usize| EntityShelf::Value {
+            value: value.into(),
+            clock: DotClock {
+                client_id,
+                clock: c,
+            },
+        };
+        let entities = |entries: Vec<(u64, EntityShelf)>| EntityShelf::Map {
+            shelves: entries.into_iter().collect(),
+            clock: 0.into(),
+        };
+        let receiver = entities(vec![(1, entity(10, 1, 1)), (2, entity(20, 1, 1))]);
+        let sender = entities(vec![(2, entity(21, 2, 2)), (3, entity(30, 2, 1))]);
+
+        let delta = sender
+            .get_state_delta(&receiver.get_state_vector())
+            .unwrap();
+        assert!(delta.get(&2).is_some() && delta.get(&3).is_some());
+        let merged = receiver.clone().merge(delta);
+        let expected = entities(vec![
+            (1, entity(10, 1, 1)),
+            (2, entity(21, 2, 2)),
+            (3, entity(30, 2, 1)),
+        ]);
+        assert_eq!(merged, expected);
+        assert_eq!(merged, sender.merge(receiver));
+        assert!(merged
+            .get_state_delta(&expected.get_state_vector())
+            .is_none());
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let shelf: TestShelf = json!([{
+            "a": [1, [1, 1]],
+            "b": [{"c": [2, [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let mut keys: Vec<&String> = Vec::new();
+        for (key, child) in &shelf {
+            assert_eq!(shelf.get(key), Some(child));
+            keys.push(key);
+        }
+        keys.sort();
+        assert_eq!(keys, ["a", "b"]);
+
+        let value = shelf.get("a").unwrap();
+        assert_eq!(value.into_iter().count(), 0);
+
+        let mut owned: Vec<(String, TestShelf)> = shelf.clone().into_iter().collect();
+        owned.sort_by(|(a, _), (b, _)| a.cmp(b));
+        assert_eq!(owned[1], ("b".to_owned(), shelf.get("b").unwrap().clone()));
+        assert_eq!(value.clone().into_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut shelf: TestShelf = json!([{
+            "layers": [{
+                "a": [{"color": ["red", [1, 2]], "size": [3, [1, 1]]}, 1],
+                "b": [1, [1, 1]]
+            }, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let layer = shelf.get_path(&["layers", "a"]).unwrap().clone();
+
+        assert!(shelf.rename(&["layers", "a"], &["layers", "b"]).is_err());
+        assert!(shelf
+            .rename(&["layers", "a"], &["layers", "a", "c"])
+            .is_err());
+        assert!(shelf
+            .rename(&["layers", "missing"], &["layers", "c"])
+            .is_err());
+        assert!(shelf
+            .rename(&["layers", "a"], &["layers", "b", "c"])
+            .is_err());
+        assert_eq!(shelf.get_path(&["layers", "a"]), Ok(&layer));
+
+        shelf.rename(&["layers", "a"], &["layers", "c"]).unwrap();
+        assert!(!shelf.path_exists(&["layers", "a"]));
+        assert_eq!(shelf.get_path(&["layers", "c"]), Ok(&layer));
+
+        // A peer that renamed too and then edited the moved layer merges with the preserved clocks.
+        let edit: TestShelf = json!([{"layers": [{
+            "c": [{"color": ["blue", [2, 3]]}, 1]
+        }, 0]}, 0])
+        .try_into()
+        .unwrap();
+        let merged = shelf.merge(edit);
+        let expected: TestShelf = json!([{"color": ["blue", [2, 3]], "size": [3, [1, 1]]}, 1])
+            .try_into()
+            .unwrap();
+        assert_eq!(merged.get_path(&["layers", "c"]), Ok(&expected));
+    }
+
+    #[test]
+    fn test_path_exists() {
+        let shelf: TestShelf = json!([{
+            "user": [{"name": ["a", [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        assert!(shelf.path_exists(&[]));
+        assert!(shelf.path_exists(&["user"]));
+        assert!(shelf.path_exists(&["user", "name"]));
+        assert!(!shelf.path_exists(&["missing"]));
+        assert!(!shelf.path_exists(&["user", "missing"]));
+        // Paths cannot continue through a value
+        assert!(!shelf.path_exists(&["user", "name", "first"]));
+    }
+
+    #[test]
+    fn test_keys_at_path() {
+        let shelf: TestShelf = json!([{
+            "user": [{
+                "name": ["a", [1, 1]],
+                "cursor": [{"x": [1, [1, 1]], "y": [2, [1, 1]]}, 0]
+            }, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let mut keys = shelf.keys_at_path(&["user"]).unwrap();
+        keys.sort();
+        assert_eq!(keys, ["cursor", "name"]);
+        let mut keys = shelf.keys_at_path(&["user", "cursor"]).unwrap();
+        keys.sort();
+        assert_eq!(keys, ["x", "y"]);
+        assert_eq!(shelf.keys_at_path(&[]).unwrap(), ["user"]);
+
+        assert!(shelf.keys_at_path(&["user", "name"]).is_err());
+        assert!(shelf.keys_at_path(&["missing"]).is_err());
+    }
+
+    #[test]
+    fn test_array_edits() {
+        let mut shelf: TestShelf = json!([{"tags": [["a"], [1, 1]], "name": ["a", [1, 1]]}, 0])
+            .try_into()
+            .unwrap();
+        let original = shelf.clone();
+        let mut clock_gen = DotClockGenerator::new(2);
+
+        shelf
+            .array_push(&["tags"], Value::String("b".to_owned()), &mut clock_gen)
+            .unwrap();
+        shelf
+            .array_set(&["tags"], 0, Value::Int(1), &mut clock_gen)
+            .unwrap();
+        let expected: TestShelf = json!([{"tags": [[1, "b"], [2, 3]], "name": ["a", [1, 1]]}, 0])
+            .try_into()
+            .unwrap();
+        assert_eq!(shelf, expected);
+
+        // Failed edits leave the array and its clock alone
+        assert!(shelf
+            .array_set(&["tags"], 2, Value::Null, &mut clock_gen)
+            .is_err());
+        assert!(shelf
+            .array_push(&["name"], Value::Null, &mut clock_gen)
+            .is_err());
+        assert_eq!(shelf, expected);
+
+        // The edited array replaces the original on peers
+        assert_eq!(original.merge(shelf.clone()), shelf);
+    }
+
+    #[test]
+    fn test_reset_map_clock() {
+        let replica: TestShelf = json!([{"session": [{"user": ["a", [1, 5]]}, 5]}, 0])
+            .try_into()
+            .unwrap();
+        let write = |shelf: &mut TestShelf| {
+            if let Ok(Shelf::Map { shelves, .. }) = shelf.get_path_mut(&["session"]) {
+                shelves.insert("x".to_owned(), json!([1, [1, 1]]).try_into().unwrap());
+            }
+        };
+
+        // Coordinated: both replicas reset before anyone writes
+        let (mut a, mut b) = (replica.clone(), replica.clone());
+        a.reset_map_clock(&["session"]).unwrap();
+        b.reset_map_clock(&["session"]).unwrap();
+        write(&mut a);
+        let delta = a.get_state_delta(&b.get_state_vector()).unwrap();
+        b.merge_assign(delta);
+        assert_eq!(b, a);
+        assert!(b.path_exists(&["session", "x"]));
+
+        // Uncoordinated: the replica that did not reset wins and the write is lost
+        let (mut a, b) = (replica.clone(), replica);
+        a.reset_map_clock(&["session"]).unwrap();
+        write(&mut a);
+        let mut a = a.merge(b.clone());
+        assert_eq!(a, b);
+        assert!(!a.path_exists(&["session", "x"]));
+
+        assert!(a.reset_map_clock(&["session", "user"]).is_err());
+    }
+
+    #[test]
+    fn test_default_is_empty_map() {
+        // Like a document creating a missing element with `or_default` before merging an update into it
+        let mut elements: HashMap<String, TestShelf> = HashMap::new();
+        let update: TestShelf = json!([{"name": ["a", [1, 1]]}, 0]).try_into().unwrap();
+        let element = elements.entry("user".to_owned()).or_default();
+        assert!(matches!(element, Shelf::Map { shelves, .. } if shelves.is_empty()));
+        element.merge_assign(update.clone());
+        assert_eq!(element, &update);
+
+        // Entries can be added and merged into the new element
+        if let Shelf::Map { shelves, .. } = element {
+            shelves.insert("age".to_owned(), json!([30, [2, 1]]).try_into().unwrap());
+        }
+        let merged = TestShelf::default().merge(element.clone());
+        assert!(merged.path_exists(&["name"]) && merged.path_exists(&["age"]));
+    }
+
+    #[test]
+    fn test_merge_explained() {
+        let shelf: TestShelf = json!([{
+            "a": [1, [1, 2]],
+            "b": [2, [1, 1]],
+            "c": ["x", [1, 3]],
+            "user": [{"name": ["a", [1, 1]]}, 0],
+            "only_here": [true, [1, 1]]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let other: TestShelf = json!([{
+            "a": [5, [1, 1]],
+            "b": [3, [2, 2]],
+            "c": ["y", [2, 3]],
+            "user": [{"name": ["a", [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+
+        let (merged, trace) = shelf.clone().merge_explained(other.clone());
+        assert_eq!(merged, shelf.merge(other));
+        assert_eq!(trace.decision, MergeDecision::RecursiveMerge);
+        let decision = |path: &[&str]| {
+            path.iter()
+                .fold(&trace, |trace, key| &trace.children[*key])
+                .decision
+        };
+        assert_eq!(decision(&["a"]), MergeDecision::SelfWins);
+        assert_eq!(decision(&["b"]), MergeDecision::OtherWins);
+        assert_eq!(
+            decision(&["c"]),
+            MergeDecision::ValueTiebreak { self_wins: false }
+        );
+        assert_eq!(decision(&["user"]), MergeDecision::RecursiveMerge);
+        assert_eq!(decision(&["user", "name"]), MergeDecision::SelfWins);
+        assert!(!trace.children.contains_key("only_here"));
+
+        let (this_clock, other_clock) = &trace.children["b"].clocks;
+        assert!(this_clock < other_clock);
+    }
+
+    #[test]
+    fn test_mirror() {
+        let mut replica: TestShelf = json!([{
+            "a": [1, [1, 5]],
+            "extra": [true, [1, 1]],
+            "user": [{"name": ["a", [1, 1]], "age": [30, [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let primary: TestShelf = json!([{
+            "a": [2, [2, 1]],
+            "user": [{"name": ["a", [1, 1]]}, 0],
+            "new": [{"x": [0, [2, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+
+        replica.mirror(&primary);
+        assert_eq!(replica, primary);
+        assert!(!replica.path_exists(&["extra"]) && !replica.path_exists(&["user", "age"]));
+        // The replica's newer write to "a" is overwritten, where a merge would have kept it
+        assert_eq!(replica.value_and_clock(&["a"]).unwrap().0, &Value::Int(2));
+
+        // Mirroring a value over a map replaces it
+        let mut replica = primary.clone();
+        replica.mirror(&json!(["flat", [3, 3]]).try_into().unwrap());
+        assert!(!replica.contains_shelves());
+    }
+
+    #[test]
+    fn test_merge_all() {
+        let base: TestShelf = json!([{
+            "a": [1, [1, 1]],
+            "user": [{"name": ["a", [1, 1]], "age": [30, [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let deltas: Vec<TestShelf> = [
+            json!([{"a": [2, [2, 2]], "user": [{"name": ["b", [2, 2]]}, 0]}, 0]),
+            json!([{"a": [3, [3, 2]], "b": [true, [3, 1]]}, 0]),
+            json!([{"user": [{"age": [31, [4, 2]], "city": ["Graz", [4, 1]]}, 0]}, 0]),
+            // Replaces the map written by the other deltas
+            json!([{"b": [{"nested": [1, [2, 3]]}, 1]}, 0]),
+        ]
+        .into_iter()
+        .map(|json| json.try_into().unwrap())
+        .collect();
+
+        let sequential = deltas
+            .iter()
+            .cloned()
+            .fold(base.clone(), |shelf, delta| shelf.merge(delta));
+        assert_eq!(base.clone().merge_all(deltas.clone()), sequential);
+        assert_eq!(base.clone().merge_all(deltas.into_iter().rev()), sequential);
+        assert_eq!(base.clone().merge_all(Vec::new()), base);
+    }
+
+    #[test]
+    fn test_merge_if() {
+        let mut shelf: TestShelf = json!([{"lock": ["free", [1, 1]]}, 0]).try_into().unwrap();
+        let observed = shelf.get_path(&["lock"]).unwrap().get_clock().into();
+        let claim = |client_id: ClientId, clock: usize| -> TestShelf {
+            json!([{"lock": [format!("held by {client_id}"), [client_id, clock]]}, 0])
+                .try_into()
+                .unwrap()
+        };
+
+        // Another client claimed the lock after it was observed
+        shelf.merge_assign(claim(2, 2));
+        assert_eq!(shelf.merge_if(&["lock"], &observed, claim(1, 2)), Ok(false));
+        assert_eq!(shelf, claim(2, 2));
+
+        let observed = shelf.get_path(&["lock"]).unwrap().get_clock().into();
+        assert_eq!(shelf.merge_if(&["lock"], &observed, claim(1, 3)), Ok(true));
+        assert_eq!(shelf.provenance(&["lock"]), Some(1));
+
+        assert!(shelf
+            .merge_if(&["missing"], &observed, claim(1, 4))
+            .is_err());
+    }
+
+    #[test]
+    fn test_value_and_clock() {
+        let shelf: TestShelf = json!([{"user": [{"name": ["a", [1, 3]]}, 0]}, 0])
+            .try_into()
+            .unwrap();
+        let (value, clock) = shelf.value_and_clock(&["user", "name"]).unwrap();
+        match shelf.get_path(&["user", "name"]).unwrap() {
+            Shelf::Value {
+                value: leaf_value,
+                clock: leaf_clock,
+            } => {
+                assert_eq!(value, leaf_value);
+                assert_eq!(clock, leaf_clock);
+            }
+            Shelf::Map { .. } => panic!("Expected a Shelf Value"),
+        }
+        assert_eq!(value, &Value::from("a"));
+        assert_eq!((clock.client_id, clock.clock), (1, 3));
+
+        assert!(shelf.value_and_clock(&["user"]).is_none());
+        assert!(shelf.value_and_clock(&["user", "missing"]).is_none());
+    }
+
+    #[test]
+    fn test_provenance() {
+        let shelf: TestShelf = json!([{"name": ["a", [1, 1]], "age": [30, [1, 1]]}, 0])
+            .try_into()
+            .unwrap();
+        let peer: TestShelf = json!([{"name": ["b", [2, 2]], "age": [31, [2, 0]]}, 0])
+            .try_into()
+            .unwrap();
+        let merged = shelf.merge(peer);
+        assert_eq!(merged.provenance(&["name"]), Some(2));
+        assert_eq!(merged.provenance(&["age"]), Some(1));
+        assert_eq!(merged.provenance(&[]), None);
+        assert_eq!(merged.provenance(&["missing"]), None);
+
+        let shelf =
+            Shelf::<Value, LamportTimestamp>::try_from(json!([{"name": ["a", 1]}, 0])).unwrap();
+        assert_eq!(shelf.provenance(&["name"]), None);
+    }
+
+    #[test]
+    fn test_empty_path() {
+        let mut shelf: TestShelf = json!([{"user": ["a", [0, 0]]}, 0]).try_into().unwrap();
+        assert_eq!(shelf.get_path(&[]).unwrap(), &shelf);
+        assert!(shelf.entry_from_path(Vec::<String>::new()).is_err());
+
+        // An empty path replaces the client's whole state
+        let mut awareness = Awareness::from_json_values(json!({"cursor": 1}), 0).unwrap();
+        let mut peer = Awareness::from_json_values(json!({"other": 1}), 1).unwrap();
+        peer.merge(awareness.clients.clone());
+        let state: Shelf<Value, LamportTimestamp> =
+            json!([{"mouse": [2, 0]}, 0]).try_into().unwrap();
+        let old_state = awareness.set_state(Vec::<String>::new(), state).unwrap();
+        assert!(old_state.unwrap().get("cursor").is_some());
+        let own_state = awareness.clients.get("0").unwrap();
+        assert!(own_state.get("cursor").is_none());
+        assert!(own_state.get("mouse").is_some());
+
+        let delta = awareness
+            .clients
+            .get_state_delta(&peer.clients.get_state_vector())
+            .unwrap();
+        peer.merge(delta);
+        assert_eq!(peer.clients.get("0"), awareness.clients.get("0"));
+    }
+
+    #[test]
+    fn test_clear_own_state() {
+        let mut awareness =
+            Awareness::from_json_values(json!({"cursor": 1, "user": {"name": "a"}}), 0).unwrap();
+        let mut peer = Awareness::from_json_values(json!({"other": 1}), 1).unwrap();
+        peer.merge(awareness.clients.clone());
+        assert!(peer.get_peer_state("0").unwrap().get("cursor").is_some());
+
+        awareness.clear_own_state();
+        let own_state = awareness.get_own_state().unwrap();
+        assert!(own_state.contains_shelves());
+        assert_eq!(own_state.into_iter().count(), 0);
+
+        let delta = awareness
+            .clients
+            .get_state_delta(&peer.clients.get_state_vector())
+            .unwrap();
+        peer.merge(delta);
+        assert_eq!(peer.get_peer_state("0"), awareness.get_own_state());
+    }
+
+    #[test]
+    fn test_awareness_requires_object() {
+        assert!(Awareness::from_json_values(json!(5), 0).is_err());
+        assert!(Awareness::from_json_values(json!(["cursor"]), 0).is_err());
+        assert!(Awareness::from_json_values(json!({}), 0).is_ok());
+    }
+
+    #[test]
+    fn test_prune_to_recent() {
+        let entries: serde_json::Map<String, JSON> = (0..100)
+            .map(|i| (format!("entry{i:02}"), json!([i, [i % 3, i + 1]])))
+            .collect();
+        let feed: TestShelf = json!([entries, 0]).try_into().unwrap();
+
+        let mut a = feed.clone();
+        a.prune_to_recent(10);
+        let mut kept: Vec<&String> = (&a).into_iter().map(|(k, _)| k).collect();
+        kept.sort();
+        let expected: Vec<String> = (90..100).map(|i| format!("entry{i}")).collect();
+        assert_eq!(kept, expected.iter().collect::<Vec<_>>());
+
+        // A replica that prunes on its own keeps the same entries
+        let mut b = feed.clone();
+        b.prune_to_recent(10);
+        assert_eq!(a, b);
+
+        // One that has not pruned takes the pruned map over, and has nothing to send back
+        let synced = feed
+            .clone()
+            .merge(a.get_state_delta(&feed.get_state_vector()).unwrap());
+        assert_eq!(synced, a);
+        assert!(feed.get_state_delta(&a.get_state_vector()).is_none());
+    }
+
+    #[test]
+    fn test_awareness_with_id_generator() {
+        struct SequentialIds(ClientId);
+
+        impl IdGenerator for SequentialIds {
+            fn next_id(&mut self) -> ClientId {
+                self.0 += 1;
+                self.0
+            }
+        }
+
+        type TestAwareness =
+            Awareness<Value, LamportTimestamp, LamportTimestamp, StateVectorContext>;
+
+        let mut ids = SequentialIds(300);
+        let client_ids: Vec<ClientId> = (0..3)
+            .map(|_| TestAwareness::with_id_generator(&mut ids, StateVectorContext).client_id)
+            .collect();
+        assert_eq!(client_ids, [301, 302, 303]);
+    }
+
+    #[test]
+    fn test_remove_peer() {
+        let mut awareness = Awareness::from_json_values(json!({"cursor": 1}), 0).unwrap();
+        for client_id in 1..3 {
+            let peer =
+                Awareness::from_json_values(json!({"cursor": client_id}), client_id).unwrap();
+            awareness.merge(peer.clients);
+        }
+
+        assert!(awareness.remove_peer("1"));
+        assert!(!awareness.remove_peer("1"));
+        assert!(awareness.get_peer_state("1").is_none());
+        assert!(awareness.get_peer_state("2").is_some());
+        assert!(awareness.get_own_state().is_some());
+    }
+
+    #[test]
+
+    fn test_adding_user() {
+        let mut shelf1: TestShelf = json!([{"1": [{"mouse_position": [[1, 2], [0,2]]}, 2]}, 1])
+            .try_into()
+            .unwrap();
+        let shelf2: TestShelf = json!([{"2": [{"mouse_position": [[3, 4], [1,1]]}, 1]}, 1])
+            .try_into()
+            .unwrap();
+
+        let expected = json!([{
+            "1": [{"mouse_position": [[1, 2], [0,2]]}, 2],
+            "2": [{"mouse_position": [[3, 4], [1,1]]}, 1]
+        }, 1])
+        .try_into()
+        .unwrap();
+        let sv = shelf1.get_state_vector();
+        let diff = shelf2.get_state_delta(&sv).unwrap();
+        shelf1 = shelf1.merge(diff); // Mutate in place
+        assert_eq!(shelf1, expected)
+    }
+    /// Uses examples in assets/shelf_tests.json to test merges.
+    /// Ensures that both shelves arrive at the expected state.
+    #[test]
+    fn test_merges() {
+        let data: JSON = serde_json::from_str(include_str!("../assets/shelf_tests.json")).unwrap();
+
+        let tests = match data {
+            JSON::Array(tests) => tests,
+            _ => panic!("Must be array of tests"),
+        };
+
+        let tests = tests.into_iter().enumerate().map(|(i, test)| {
+            let mut test = match test {
+                JSON::Object(obj) => obj,
+                val => panic!("{val:?} is not an object"),
+            };
+            let shelves: Vec<TestShelf> = match test.remove("shelves") {
+                Some(JSON::Array(shelves)) => {
+                    let possible_shelves: Result<_, _> =
+                        shelves.into_iter().map(Shelf::try_from).collect();
+                    possible_shelves.unwrap()
+                }
+                val => panic!("{val:?} cannot be turned into shelves"),
+            };
+            let expected = test
+                .remove("expected")
+                .map(Shelf::try_from)
+                .and_then(|v| v.ok());
+            let description = test
+                .remove("description")
+                .and_then(|v| match v {
+                    JSON::String(s) => Some(format!("Test {}: {}", i + 1, s)),
+                    _ => None,
+                })
+                .unwrap_or_default();
+
+            (shelves, expected, description)
+        });
+        for (mut shelves, expected, description) in tests {
+            for i in 0..shelves.len() {
+                for j in (i + 1)..shelves.len() {
+                    // Forwards
+                    let mut receiver = shelves[i].clone();
+                    let sender = shelves[j].clone();
+                    let sv = receiver.get_state_vector();
+                    let delta = sender.get_state_delta(&sv).unwrap();
+                    let cached_delta = delta.clone();
+                    receiver = receiver.merge(delta);
+
+                    // Backwards
+                    let mut receiver_back = shelves[j].clone();
+                    let sender_back = shelves[i].clone();
+                    let sv = receiver_back.get_state_vector();
+
+                    if let Some(delta) = sender_back.get_state_delta(&sv) {
+                        receiver_back = receiver_back.merge(delta)
+                    }
+
+                    // Ensure both forwards and backwards match
+                    assert_eq!(receiver, receiver_back, "Not commutative\n {description}");
+
+                    // Ensure duplicate application of deltas has no effect
+                    receiver = receiver.merge(cached_delta);
+                    assert_eq!(receiver, receiver_back, "Not idempotent\n {description}");
+                    shelves[i] = receiver;
+                    shelves[j] = receiver_back;
+                }
+                // Since the first CRDT has now received updates from all others, it should have the expected value.
+                if let Some(expected) = expected.as_ref() {
+                    assert_eq!(
+                        &shelves[i], expected,
+                        "Did not match expected\n {description}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    /// Test setting an empty object over a full object and ensure propagation of erasure.
+    fn test_empty_replacement() {
+        let shelf_map_with_value = shelf_map([("value".to_owned(), val(1, 0))].into_iter(), 0);
+
+        let empty_shelf_map = shelf_map([].into_iter(), 0);
+
+        let shelf_with_value = val(1, 0);
+
+        let shelves = vec![shelf_with_value, empty_shelf_map, shelf_map_with_value];
+        // test all combinations
+        for i in 0..shelves.len() {
+            for j in (i + 1)..shelves.len() {
+                let shelf1 = shelves[i].clone();
+                let shelf2 = shelves[j].clone();
+                let shelf2 = match shelf2 {
+                    Shelf::Value { value, clock } => Shelf::Value {
+                        value,
+                        clock: clock.increment(1),
+                    },
+                    Shelf::Map { shelves, clock } => Shelf::Map {
                         shelves,
-                        clock: LamportTimestamp(old_clock),
-                    } => {
-                        let highest_child_timestamp = shelves
-                            .values()
-                            .map(|shelf| shelf.get_clock().get_logical_clock())
-                            .max();
-                        highest_child_timestamp.map(|ts| ts.max(parent_clock).max(*old_clock) + 1)
-                    }
-                }
+                        clock: clock.increment(),
+                    },
+                };
+
+                let res = validate_crdt_properties(shelf2, shelf1);
             }
-            Entry::Vacant(_) => None,
-        };
-        let new_ts = new_ts.unwrap_or(parent_clock + 1);
-        let value = match value {
-            Shelf::Value { value, .. } => Shelf::Value {
-                value,
-                clock: new_ts.into(),
-            },
-            Shelf::Map { shelves, .. } => Shelf::Map {
+        }
+    }
+
+    #[test]
+    fn test_merge_empty_map() {
+        let full = shelf_map([("a".to_owned(), val(1, 1))].into_iter(), 1);
+        let empty = shelf_map([].into_iter(), 1);
+        assert_eq!(full.clone().merge(empty.clone()), full);
+        assert_eq!(empty.clone().merge(full.clone()), full);
+
+        // An empty map with a newer clock clears the older one, in either order
+        let cleared = shelf_map([].into_iter(), 2);
+        assert_eq!(full.clone().merge(cleared.clone()), cleared);
+        assert_eq!(cleared.clone().merge(full.clone()), cleared);
+
+        // Concurrent map clocks end up with the clock of the map merged in, like non-empty maps do
+        let concurrent_map =
+            |shelves: HashMap<String, Shelf<Value, DotClock>>, client_id| Shelf::Map {
                 shelves,
-                clock: new_ts.into(),
-            },
+                clock: DotClock {
+                    client_id,
+                    clock: 1,
+                },
+            };
+        let leaf = Shelf::Value {
+            value: 1.into(),
+            clock: DotClock::new(1),
         };
-        let old_value = match entry {
-            Entry::Occupied(mut o) => Some(o.insert(value)),
-            Entry::Vacant(v) => {
-                v.insert(value);
-                None
+        let full = concurrent_map(HashMap::from([("a".to_owned(), leaf)]), 1);
+        let empty = concurrent_map(HashMap::new(), 2);
+        assert_eq!(empty.clone().merge(full.clone()), full);
+        match full.clone().merge(empty) {
+            Shelf::Map { shelves, clock } => {
+                assert_eq!(clock.client_id, 2);
+                assert!(shelves.contains_key("a"));
             }
-        };
-        Ok(old_value)
+            _ => panic!("Expected a map"),
+        }
     }
 
-    pub fn from_json_values(json: JSON, client_id: usize) -> Result<Self, String> {
-        let mut map_clock_generator = LamportTimestampGenerator {};
-        let mut val_clock_generator = LamportTimestampGenerator {};
-        let shelf: Shelf<Value, LamportTimestamp, LamportTimestamp> =
-            Shelf::from_json_values(json, &mut map_clock_generator, &mut val_clock_generator)?;
-        let mut client_map = HashMap::new();
-        client_map.insert(client_id.to_string(), shelf);
-        let clients = Shelf::Map {
-            shelves: client_map,
-            clock: LamportTimestamp::default(),
+    #[test]
+    fn test_from_json_values_imports_arrays_as_data() {
+        let import = |json: JSON| {
+            TestShelf::from_json_values(
+                json,
+                &mut LamportTimestampGenerator {},
+                &mut DotClockGenerator::new(0),
+            )
         };
+        match import(json!({})) {
+            Ok(Shelf::Map { shelves, .. }) => assert!(shelves.is_empty()),
+            shelf => panic!("Expected an empty map, got {shelf:?}"),
+        }
+        match import(json!([])) {
+            Ok(Shelf::Value { value, .. }) => assert_eq!(value, Value::Array(vec![])),
+            shelf => panic!("Expected an empty array value, got {shelf:?}"),
+        }
+        assert_eq!(
+            import(json!([1, 2, 3])).unwrap().to_json_values(),
+            json!([1, 2, 3])
+        );
+        assert_eq!(
+            import(json!({"tags": []})).unwrap().to_json_values(),
+            json!({"tags": []})
+        );
 
-        Ok(Awareness {
-            client_id,
-            clients,
-            update_context: StateVectorContext,
-        })
+        // The encoded format reads arrays as [contents, clock] pairs, so plain data is rejected there
+        assert!(TestShelf::try_from(json!([])).is_err());
+        assert!(TestShelf::try_from(json!([1, 2, 3])).is_err());
     }
 
-    pub fn merge(&mut self, delta: Shelf<Value, LamportTimestamp>) {
-        let mut tmp: Shelf<Value, LamportTimestamp> = Shelf::Value {
-            value: 0.into(),
-            clock: 0.into(),
+    #[test]
+    fn test_preview_merge() {
+        let mut fuzzer = ShelfFuzzer {
+            rng: StdRng::seed_from_u64(7),
+            depth_range: 1..4,
+            branch_range: 1..5,
+            value_range: 0..20,
         };
-        swap(&mut tmp, &mut self.clients);
-        let mut result = tmp.merge(delta);
-        swap(&mut result, &mut self.clients);
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::hash::Hasher;
-
-    use crate::clock::{DotClock, LamportTimestamp};
-    use crate::traits::DeltaCRDT;
-
-    type TestShelf = Shelf<Value, LamportTimestamp, DotClock>;
-
-    use rand::{prelude::StdRng, SeedableRng};
-
-    use crate::{shelf_fuzzer::ShelfFuzzer, wrap_crdt::*};
-
-    fn merge(branch: TestShelf, main: TestShelf) -> TestShelf {
-        let sv = main.get_state_vector();
-        if let Some(delta) = branch.get_state_delta(&sv) {
-            return main.merge(delta);
+        for _ in 0..50 {
+            let this: TestShelf = Shelf::try_from(fuzzer.generate_json_shelf(1)).unwrap();
+            let other: TestShelf = Shelf::try_from(fuzzer.generate_json_shelf(2)).unwrap();
+            let (this_before, other_before) = (this.clone(), other.clone());
+
+            let preview = this.preview_merge(&other);
+            assert_eq!(preview, this.clone().merge(other.clone()));
+            assert_eq!(this, this_before);
+            assert_eq!(other, other_before);
         }
-        return main;
     }
 
-    fn val<T: Into<Value>>(value: T, c: impl Into<LamportTimestamp>) -> TestShelf {
-        Shelf::Value {
-            value: value.into(),
-            clock: clock(c.into().0),
+    #[test]
+    fn test_merge_changed() {
+        let mut fuzzer = ShelfFuzzer {
+            rng: StdRng::seed_from_u64(11),
+            depth_range: 1..4,
+            branch_range: 1..5,
+            value_range: 0..20,
+        };
+        for _ in 0..50 {
+            let this: TestShelf = Shelf::try_from(fuzzer.generate_json_shelf(1)).unwrap();
+            let other: TestShelf = Shelf::try_from(fuzzer.generate_json_shelf(2)).unwrap();
+            let expected = this.clone().merge(other.clone());
+
+            let mut merged = this.clone();
+            let changed = merged.merge_changed(other.clone());
+            assert_eq!(merged, expected);
+            assert_eq!(changed, merged != this);
+            // The delta has already been seen
+            assert!(!merged.merge_changed(other));
         }
-    }
 
-    fn array<T: Into<Value>>(arr: Vec<T>, c: impl Into<LamportTimestamp>) -> TestShelf {
-        let arr: Vec<Value> = arr.into_iter().map(|v| v.into()).collect();
-        Shelf::Value {
-            value: arr.into(),
-            clock: clock(c.into().0),
+        let mut shelf: TestShelf = json!([{"a": [1, [0, 1]]}, 0]).try_into().unwrap();
+        let update: TestShelf = json!([{"a": [2, [0, 2]]}, 0]).try_into().unwrap();
+        assert!(shelf.merge_changed(update.clone()));
+        assert!(!shelf.merge_changed(update));
+
+        // A map and a value with concurrent clocks are ordered by type, like `merge` does
+        let map: TestShelf = json!([{"a": [{}, 1]}, 0]).try_into().unwrap();
+        let value: TestShelf = json!([{"a": [5, [0, 1]]}, 0]).try_into().unwrap();
+        for (this, other) in [(&map, &value), (&value, &map)] {
+            let mut merged = this.clone();
+            let changed = merged.merge_changed(other.clone());
+            assert_eq!(merged, this.clone().merge(other.clone()));
+            assert_eq!(changed, merged != *this);
         }
     }
 
-    fn shelf_map(
-        contents: impl Iterator<Item = (String, TestShelf)>,
-        c: impl Into<LamportTimestamp>,
-    ) -> TestShelf {
-        let shelves = HashMap::from_iter(contents);
-        Shelf::Map {
-            shelves,
-            clock: c.into(),
+    #[test]
+    fn test_merge_assign() {
+        let mut fuzzer = ShelfFuzzer {
+            rng: StdRng::seed_from_u64(12),
+            depth_range: 1..4,
+            branch_range: 1..5,
+            value_range: 0..20,
+        };
+        for _ in 0..20 {
+            let this: TestShelf = Shelf::try_from(fuzzer.generate_json_shelf(1)).unwrap();
+            let other: TestShelf = Shelf::try_from(fuzzer.generate_json_shelf(2)).unwrap();
+            let mut merged = this.clone();
+            merged.merge_assign(other.clone());
+            assert_eq!(merged, this.merge(other));
         }
     }
 
-    fn clock(c: usize) -> DotClock {
-        DotClock {
-            client_id: 0,
-            clock: c,
+    #[test]
+    fn test_convert_clocks() {
+        let shelf: TestShelf = json!([{
+            "user": [{"name": ["a", [1, 3]], "tags": [["x", "y"], [2, 1]]}, 2],
+            "count": [4, [1, 5]]
+        }, 1])
+        .try_into()
+        .unwrap();
+        let secure = shelf
+            .clone()
+            .convert_clocks(&mut |clock| clock, &mut |value, clock| {
+                SecureClock::new(value, clock.clock)
+            });
+
+        fn all_verified(shelf: &Shelf<Value, LamportTimestamp, SecureClock>) -> bool {
+            match shelf {
+                Shelf::Value { value, clock } => clock.verify(value),
+                Shelf::Map { shelves, .. } => shelves.values().all(all_verified),
+            }
+        }
+        assert!(all_verified(&secure));
+        assert_eq!(shelf.to_json_values(), secure.clone().to_json_values());
+        match secure.get_path(&["count"]).unwrap() {
+            Shelf::Value { clock, .. } => assert_eq!(clock.clock, 5),
+            _ => panic!("Expected a value"),
+        }
+        match secure.get_path(&["user"]).unwrap() {
+            Shelf::Map { clock, .. } => assert_eq!(*clock, LamportTimestamp(2)),
+            _ => panic!("Expected a map"),
         }
     }
 
-    fn validate_crdt_properties(shelf: TestShelf, shelf2: TestShelf) -> TestShelf {
-        // Forwards
-        let mut receiver = shelf.clone();
-        let sender = shelf2.clone();
-        let sv = receiver.get_state_vector();
+    #[test]
+    fn test_get_or_insert_with() {
+        let mut shelf: TestShelf = json!([{"1": [{"name": ["a", [1, 1]]}, 1]}, 1])
+            .try_into()
+            .unwrap();
 
-        let delta = sender.get_state_delta(&sv);
-        let cached_delta = delta.clone();
-        if let Some(delta) = delta {
-            receiver = receiver.merge(delta);
-        }
+        // Already present: the builder is not called
+        let existing = shelf
+            .get_or_insert_with("1", || panic!("Should not build a new shelf"))
+            .unwrap();
+        assert!(existing.get("name").is_some());
 
-        // Backwards
-        let mut receiver_back = shelf2.clone();
-        let sender_back = shelf.clone();
-        let sv_back = receiver_back.get_state_vector();
-        let delta_back = sender_back.get_state_delta(&sv_back);
-        let cached_delta_back = delta_back.clone();
-        if let Some(delta) = delta_back {
-            receiver_back = receiver_back.merge(delta);
+        // Missing: the new shelf is inserted and can be edited in place
+        let user = shelf
+            .get_or_insert_with("2", || shelf_map([].into_iter(), 1))
+            .unwrap();
+        if let Shelf::Map { shelves, .. } = user {
+            shelves.insert("name".to_owned(), val(2, 2));
         }
-        let id_delta = cached_delta.clone();
-        let report = || {
-            format!("\nReceiver: {shelf}, \nSender {shelf2}, \nStateVector {sv:?}, \nDelta: {id_delta:?} \nStateVectorBACK: {sv_back:?}, \nDeltaBACK {cached_delta_back:?}")
-        };
+        assert_eq!(shelf.get_path(&["2", "name"]).unwrap(), &val(2, 2));
+        assert!(matches!(
+            shelf,
+            Shelf::Map {
+                clock: LamportTimestamp(1),
+                ..
+            }
+        ));
 
-        // Ensure both forwards and backwards match
-        assert_eq!(receiver, receiver_back, "Not commutative {}", report());
-        if let Some(delta) = cached_delta {
-            receiver = receiver.merge(delta);
-            assert_eq!(receiver, receiver_back, "Not idempotent {}", report());
-        }
-        return receiver;
+        let mut value = val(1, 0);
+        assert!(value.get_or_insert_with("key", || val(2, 0)).is_err());
     }
 
     #[test]
-    fn test_init() {
-        let v: TestShelf = Shelf::Value {
-            value: Value::Bool(true),
-            clock: clock(0),
-        };
-        let m: TestShelf = Shelf::Map {
-            shelves: HashMap::new(),
-            clock: 1.into(),
-        };
+    fn test_changed_paths() {
+        let before: TestShelf = json!([{
+            "user": [{"name": ["a", [0, 0]], "age": [1, [0, 0]]}, 0],
+            "other": [2, [0, 0]]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let update: TestShelf = json!([{"user": [{"name": ["b", [0, 1]]}, 0]}, 0])
+            .try_into()
+            .unwrap();
+        let after = before.clone().merge(update);
+        assert_eq!(after.changed_paths(&before), vec![vec!["user", "name"]]);
+        assert!(after.changed_paths(&after).is_empty());
+
+        // Replacing a map is reported at the map, not at each of its leaves
+        let update: TestShelf = json!([{"user": [{"new": [3, [0, 0]]}, 1]}, 0])
+            .try_into()
+            .unwrap();
+        let after = before.clone().merge(update);
+        assert_eq!(after.changed_paths(&before), vec![vec!["user"]]);
     }
 
     #[test]
-    fn test_clock() {
-        let shelf = Shelf::Value {
-            value: 1.into(),
-            clock: clock(1),
-        };
-        let shelf2 = val(2, 2);
-        let result = shelf.merge(shelf2);
-
-        if let Shelf::Value { value, clock: c } = result {
-            assert!(c == clock(2));
-            assert_eq!(value, Value::Int(2))
-        } else {
-            panic!("Should be a value");
-        };
+    fn test_same_content() {
+        let shelf: TestShelf = json!([{"user": [{"name": ["a", [0, 0]]}, 0], "n": [1, [0, 0]]}, 0])
+            .try_into()
+            .unwrap();
+        let touched: TestShelf =
+            json!([{"user": [{"name": ["a", [2, 1]]}, 3], "n": [1, [1, 2]]}, 1])
+                .try_into()
+                .unwrap();
+        assert!(shelf.same_content(&touched));
+
+        for other in [
+            json!([{"user": [{"name": ["b", [0, 0]]}, 0], "n": [1, [0, 0]]}, 0]),
+            json!([{"user": [{"name": ["a", [0, 0]]}, 0]}, 0]),
+            json!([{"user": [{"name": ["a", [0, 0]]}, 0], "n": [{}, 0]}, 0]),
+        ] {
+            let other: TestShelf = other.try_into().unwrap();
+            assert!(!shelf.same_content(&other));
+            assert!(!other.same_content(&shelf));
+        }
     }
-    #[test]
-    fn test_object_override() {
-        let shelf: TestShelf = shelf_map([("foo".to_owned(), val(1, 0))].into_iter(), 0);
-        let y = val(2, 0);
-        let shelf2 = val(2, 0);
-        let shelf = merge(shelf, shelf2);
 
-        if let Shelf::Map { .. } = shelf {
-        } else {
-            panic!("Expected map to override the integer value")
-        }
+    #[test]
+    /// Entries that are replaced or kept in place should resolve the same way as recursively merged ones.
+    fn test_merge_delta_entries() {
+        let receiver: TestShelf = json!([{
+            "newer": [1, [1, 1]],
+            "older": [1, [1, 3]],
+            "same": [1, [1, 2]],
+            "nested": [{"x": [1, [1, 1]], "y": [1, [1, 1]]}, 0]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let delta: TestShelf = json!([{
+            "newer": [2, [2, 2]],
+            "older": [2, [2, 1]],
+            "same": [1, [1, 2]],
+            "nested": [{"x": [2, [2, 2]]}, 0],
+            "added": [2, [2, 1]]
+        }, 0])
+        .try_into()
+        .unwrap();
+        let expected: TestShelf = json!([{
+            "newer": [2, [2, 2]],
+            "older": [1, [1, 3]],
+            "same": [1, [1, 2]],
+            "nested": [{"x": [2, [2, 2]], "y": [1, [1, 1]]}, 0],
+            "added": [2, [2, 1]]
+        }, 0])
+        .try_into()
+        .unwrap();
+        assert_eq!(receiver.clone().merge(delta.clone()), expected);
+        assert_eq!(delta.merge(receiver), expected);
     }
+
     #[test]
-    fn test_vec_diff() {
-        let shelf: TestShelf = array(vec![1], 0);
-        let shelf2 = array(vec![2], 2);
-        let shelf = merge(shelf2, shelf);
+    fn test_merge_bounded() {
+        let local: TestShelf = json!([{
+            "a": [1, [1, 3]],
+            "b": [1, [1, 2]],
+            "c": [{"d": [1, [1, 1]]}, 1]
+        }, 0])
+        .try_into()
+        .unwrap();
+        assert_eq!(local.max_logical_clock(), 3);
+        let delta: TestShelf = json!([{
+            "a": [2, [2, 1000000]],
+            "b": [2, [2, 4]],
+            "c": [{"d": [2, [2, 1000000]]}, 1]
+        }, 0])
+        .try_into()
+        .unwrap();
 
-        if let Shelf::Value {
-            value: Value::Array(list),
-            ..
-        } = shelf
-        {
-            if let Value::Int(n) = list[0] {
-                assert_eq!(n, 2)
-            } else {
-                panic!("not an int {:?}", list[0]);
-            }
-        } else {
-            panic!("Didn't find list: {}", shelf)
-        }
+        let merged = local.clone().merge_bounded(delta.clone(), 10);
+        let expected: TestShelf = json!([{
+            "a": [1, [1, 3]],
+            "b": [2, [2, 4]],
+            "c": [{"d": [1, [1, 1]]}, 1]
+        }, 0])
+        .try_into()
+        .unwrap();
+        assert_eq!(merged, expected);
+
+        // Without the bound the skewed clocks win
+        assert_eq!(
+            local.clone().merge_bounded(delta.clone(), usize::MAX),
+            local.merge(delta)
+        );
     }
+
     #[test]
-    fn test_recursive_diff() {
-        let shelf = shelf_map([("a".to_owned(), val(1, 0))].into_iter(), 0);
-        let shelf2 = shelf_map(
-            [(
-                "b".to_owned(),
-                Shelf::Value {
-                    value: 2.into(),
-                    clock: DotClock {
-                        client_id: 1,
-                        clock: 0,
-                    },
-                },
-            )]
-            .into_iter(),
-            0,
+    fn test_non_finite_floats_to_json() {
+        let leaf = |value: f32| Shelf::Value {
+            value: Value::Float(value),
+            clock: DotClock::new(1),
+        };
+        let shelf: TestShelf = Shelf::Map {
+            shelves: HashMap::from([
+                ("infinity".to_owned(), leaf(f32::INFINITY)),
+                ("nan".to_owned(), leaf(f32::NAN)),
+                ("finite".to_owned(), leaf(1.5)),
+            ]),
+            clock: LamportTimestamp(0),
+        };
+        assert_eq!(
+            shelf.clone().to_json_values(),
+            json!({"infinity": null, "nan": null, "finite": 1.5})
+        );
+        assert_eq!(JSON::from(shelf)[0]["nan"], json!([null, [1, 0]]));
+    }
+
+    #[test]
+    fn test_json_tagged() {
+        let shelf: TestShelf = json!([{
+            "user": [{"name": ["a", [1, 2]], "position": [[3, 4], [1, 1]]}, 2],
+            "empty": [{}, 0]
+        }, 1])
+        .try_into()
+        .unwrap();
+        let tagged = shelf.clone().to_json_tagged();
+        assert_eq!(
+            tagged["$v"]["user"]["$v"]["position"],
+            json!({"$v": [3, 4], "$c": [1, 1]})
         );
+        assert_eq!(TestShelf::from_json_tagged(tagged).unwrap(), shelf);
 
-        let shelf = merge(shelf2, shelf);
+        // A user array shaped like a positional shelf stays a value
+        let pair: TestShelf = json!([{"pair": [["a", [1, 1]], [2, 3]]}, 0])
+            .try_into()
+            .unwrap();
+        let pair = TestShelf::from_json_tagged(pair.to_json_tagged()).unwrap();
+        assert!(!pair.get("pair").unwrap().contains_shelves());
 
-        if let Shelf::Map { shelves, .. } = shelf {
-            assert!(shelves.len() == 2)
-        }
+        // Untagged JSON is rejected instead of guessed at
+        assert!(TestShelf::from_json_tagged(json!([{}, 0])).is_err());
+        assert!(TestShelf::from_json_tagged(json!({"$v": 1})).is_err());
+        assert!(TestShelf::from_json_tagged(json!({"$v": 1, "clock": [1, 1]})).is_err());
     }
 
     #[test]
-    fn test_get() {
-        let mut shelf: TestShelf = json!([{ "user": [{
-            "mouse_position": [[0, 1], [0,0]],
-            "cursor": [{"left": ["a",[0,0]], "right": ["b",[0,0]]},0]
-        }, 0]  }, 0])
+    fn test_annotated_json() {
+        let shelf: TestShelf = json!([{
+            "user": [{"name": ["a", [1, 2]], "position": [[3, 4], [2, 5]]}, 2],
+            "empty": [{}, 0]
+        }, 1])
         .try_into()
         .unwrap();
-        let res = shelf
-            .get_mut("user")
-            .unwrap()
-            .get_mut("cursor")
-            .unwrap()
-            .get_mut("left")
-            .unwrap();
-        if let Shelf::Value {
-            value: Value::String(s),
-            ..
-        } = res
-        {
-            assert_eq!(s, "a");
-        } else {
-            panic!("Unexpected value {:?}", res)
+        assert_eq!(
+            shelf.to_annotated_json(),
+            json!({
+                "user": {
+                    "name": {"value": "a", "client": 1, "clock": 2},
+                    "position": {"value": [3, 4], "client": 2, "clock": 5}
+                },
+                "empty": {}
+            })
+        );
+    }
+
+    #[test]
+    fn test_client_tiebreak() {
+        let nan = |client_id| Shelf::Value {
+            value: f32::NAN.into(),
+            clock: DotClock {
+                client_id,
+                clock: 2,
+            },
+        };
+        let this = shelf_map(
+            [("x".to_owned(), nan(1)), ("y".to_owned(), val(1, 1))].into_iter(),
+            0,
+        );
+        let other = shelf_map(
+            [("x".to_owned(), nan(2)), ("y".to_owned(), val(2, 2))].into_iter(),
+            0,
+        );
+        for merged in [
+            this.clone().merge_with_client_tiebreak(other.clone()),
+            other.merge_with_client_tiebreak(this),
+        ] {
+            match merged.get("x") {
+                Some(Shelf::Value { value, clock }) => {
+                    assert!(value.as_f64().unwrap().is_nan());
+                    assert_eq!(clock.client_id, 2);
+                }
+                x => panic!("Expected a value, found {x:?}"),
+            }
+            assert_eq!(merged.get("y"), Some(&val(2, 2)));
         }
+    }
 
-        assert!(shelf.get("BOOM/goes/the/path").is_none())
+    #[test]
+    fn test_float_tolerance() {
+        let float = |value: f32, client_id| Shelf::Value {
+            value: value.into(),
+            clock: DotClock {
+                client_id,
+                clock: 2,
+            },
+        };
+        // Whichever way the rounding went, the leaf of client 2 wins
+        for noise in [1e-7, -1e-7] {
+            let this = shelf_map([("x".to_owned(), float(0.3, 1))].into_iter(), 0);
+            let other = shelf_map([("x".to_owned(), float(0.3 + noise, 2))].into_iter(), 0);
+            for merged in [
+                this.clone().merge_with_float_tolerance(other.clone(), 1e-6),
+                other.merge_with_float_tolerance(this, 1e-6),
+            ] {
+                assert_eq!(merged.get("x"), Some(&float(0.3 + noise, 2)));
+            }
+        }
+
+        // Floats further apart are still ordered by value
+        let this = shelf_map([("x".to_owned(), float(0.4, 1))].into_iter(), 0);
+        let other = shelf_map([("x".to_owned(), float(0.3, 2))].into_iter(), 0);
+        let merged = this.merge_with_float_tolerance(other, 1e-6);
+        assert_eq!(merged.get("x"), Some(&float(0.4, 1)));
     }
 
     #[test]
+    fn test_float_tolerance_converges() {
+        let float = |value: f32, client_id| {
+            let leaf = Shelf::Value {
+                value: value.into(),
+                clock: DotClock {
+                    client_id,
+                    clock: 2,
+                },
+            };
+            shelf_map([("x".to_owned(), leaf)].into_iter(), 0)
+        };
+        let merge =
+            |a: &TestShelf, b: &TestShelf| a.clone().merge_with_float_tolerance(b.clone(), 0.1);
+        // Each float is within the tolerance of the next, but not of the one after
+        for replicas in [
+            [float(1.0, 3), float(1.08, 2), float(1.16, 1)],
+            [float(1.0, 1), float(1.04, 2), float(1.08, 3)],
+        ] {
+            let mut results = Vec::new();
+            for [a, b, c] in [
+                [0, 1, 2],
+                [0, 2, 1],
+                [1, 0, 2],
+                [1, 2, 0],
+                [2, 0, 1],
+                [2, 1, 0],
+            ] {
+                let (a, b, c) = (&replicas[a], &replicas[b], &replicas[c]);
+                results.push(merge(&merge(a, b), c));
+                results.push(merge(a, &merge(b, c)));
+            }
+            assert!(results.windows(2).all(|pair| pair[0] == pair[1]));
+        }
+    }
 
-    fn test_adding_user() {
-        let mut shelf1: TestShelf = json!([{"1": [{"mouse_position": [[1, 2], [0,2]]}, 2]}, 1])
+    #[test]
+    fn test_validate() {
+        let nested: TestShelf = json!([{"user": [{"name": ["a", [1, 2]]}, 2]}, 1])
             .try_into()
             .unwrap();
-        let shelf2: TestShelf = json!([{"2": [{"mouse_position": [[3, 4], [1,1]]}, 1]}, 1])
+        assert!(nested.validate(&Invariants::default()).is_ok());
+        let err = nested
+            .validate(&Invariants {
+                flat: true,
+                ..Default::default()
+            })
+            .unwrap_err();
+        assert!(err.contains("'user'"), "{err}");
+
+        let value = val(1, 1);
+        let err = value
+            .validate(&Invariants {
+                map_rooted: true,
+                ..Default::default()
+            })
+            .unwrap_err();
+        assert!(err.contains("Root"), "{err}");
+
+        // The leaf is older than the map holding it, which garbage collection would have removed
+        let stale: TestShelf = json!([{"user": [{"name": ["a", [1, 1]]}, 2]}, 1])
             .try_into()
             .unwrap();
+        let monotonic = Invariants {
+            monotonic_clocks: true,
+            ..Default::default()
+        };
+        let err = stale.validate(&monotonic).unwrap_err();
+        assert!(err.contains("'user/name'"), "{err}");
+        assert!(nested.validate(&monotonic).is_ok());
+    }
 
-        let expected = json!([{
-            "1": [{"mouse_position": [[1, 2], [0,2]]}, 2],
-            "2": [{"mouse_position": [[3, 4], [1,1]]}, 1]
+    #[test]
+    fn test_repair() {
+        let mut shelf: TestShelf = json!([{
+            "user": [{"name": ["a", [1, 3]], "tags": [["x"], [1, 1]]}, 2],
+            "count": [4, [1, 5]]
         }, 1])
         .try_into()
         .unwrap();
-        let sv = shelf1.get_state_vector();
-        let diff = shelf2.get_state_delta(&sv).unwrap();
-        shelf1 = shelf1.merge(diff); // Mutate in place
-        assert_eq!(shelf1, expected)
-    }
-    /// Uses examples in assets/shelf_tests.json to test merges.
-    /// Ensures that both shelves arrive at the expected state.
-    #[test]
-    fn test_merges() {
-        let data: JSON = serde_json::from_str(include_str!("../assets/shelf_tests.json")).unwrap();
-
-        let tests = match data {
-            JSON::Array(tests) => tests,
-            _ => panic!("Must be array of tests"),
+        let monotonic = Invariants {
+            monotonic_clocks: true,
+            ..Default::default()
         };
+        assert!(shelf.validate(&monotonic).is_err());
 
-        let tests = tests.into_iter().enumerate().map(|(i, test)| {
-            let mut test = match test {
-                JSON::Object(obj) => obj,
-                val => panic!("{val:?} is not an object"),
-            };
-            let shelves: Vec<TestShelf> = match test.remove("shelves") {
-                Some(JSON::Array(shelves)) => {
-                    let possible_shelves: Result<_, _> =
-                        shelves.into_iter().map(Shelf::try_from).collect();
-                    possible_shelves.unwrap()
-                }
-                val => panic!("{val:?} cannot be turned into shelves"),
-            };
-            let expected = test
-                .remove("expected")
-                .map(Shelf::try_from)
-                .and_then(|v| v.ok());
-            let description = test
-                .remove("description")
-                .and_then(|v| match v {
-                    JSON::String(s) => Some(format!("Test {}: {}", i + 1, s)),
-                    _ => None,
-                })
-                .unwrap_or_default();
-
-            (shelves, expected, description)
-        });
-        for (mut shelves, expected, description) in tests {
-            for i in 0..shelves.len() {
-                for j in (i + 1)..shelves.len() {
-                    // Forwards
-                    let mut receiver = shelves[i].clone();
-                    let sender = shelves[j].clone();
-                    let sv = receiver.get_state_vector();
-                    let delta = sender.get_state_delta(&sv).unwrap();
-                    let cached_delta = delta.clone();
-                    receiver = receiver.merge(delta);
-
-                    // Backwards
-                    let mut receiver_back = shelves[j].clone();
-                    let sender_back = shelves[i].clone();
-                    let sv = receiver_back.get_state_vector();
-
-                    if let Some(delta) = sender_back.get_state_delta(&sv) {
-                        receiver_back = receiver_back.merge(delta)
-                    }
-
-                    // Ensure both forwards and backwards match
-                    assert_eq!(receiver, receiver_back, "Not commutative\n {description}");
+        // A peer that already has the user map never receives the stale tags, so the replicas disagree about them
+        let mut peer: TestShelf = json!([{"user": [{"name": ["a", [1, 3]]}, 2]}, 1])
+            .try_into()
+            .unwrap();
+        let delta = shelf.get_state_delta(&peer.get_state_vector()).unwrap();
+        peer = peer.merge(delta);
+        assert!(shelf.get_path(&["user", "tags"]).is_ok());
+        assert!(peer.get_path(&["user", "tags"]).is_err());
+
+        assert!(shelf.repair());
+        assert!(!shelf.repair());
+        assert!(shelf.validate(&monotonic).is_ok());
+        assert!(shelf.get_path(&["user", "name"]).is_ok());
+        assert_eq!(shelf, peer);
+    }
 
-                    // Ensure duplicate application of deltas has no effect
-                    receiver = receiver.merge(cached_delta);
-                    assert_eq!(receiver, receiver_back, "Not idempotent\n {description}");
-                    shelves[i] = receiver;
-                    shelves[j] = receiver_back;
+    #[test]
+    /// Merged maps should always carry the higher of the two clocks, whichever side it came from.
+    fn test_map_merge_keeps_higher_clock() {
+        assert_eq!(max_clock(1, 2, Some(Ordering::Less)), 2);
+        assert_eq!(max_clock(2, 1, Some(Ordering::Greater)), 2);
+        assert_eq!(max_clock(1, 1, Some(Ordering::Equal)), 1);
+
+        let older = shelf_map([("a".to_owned(), val(1, 1))].into_iter(), 1);
+        let newer = shelf_map([("b".to_owned(), val(2, 3))].into_iter(), 3);
+        for merged in [
+            older.clone().merge(newer.clone()),
+            newer.clone().merge(older.clone()),
+        ] {
+            assert!(matches!(
+                merged,
+                Shelf::Map {
+                    clock: LamportTimestamp(3),
+                    ..
                 }
-                // Since the first CRDT has now received updates from all others, it should have the expected value.
-                if let Some(expected) = expected.as_ref() {
-                    assert_eq!(
-                        &shelves[i], expected,
-                        "Did not match expected\n {description}"
-                    );
+            ));
+        }
+
+        type SecureShelf = Shelf<Value, LamportTimestamp, SecureClock>;
+        let older = SecureShelf::secure_from_json_values(json!({ "a": 1 })).unwrap();
+        let newer = match SecureShelf::secure_from_json_values(json!({ "b": 2 })).unwrap() {
+            Shelf::Map { shelves, .. } => Shelf::Map {
+                shelves,
+                clock: LamportTimestamp(3),
+            },
+            value => value,
+        };
+        for merged in [
+            older.clone().secure_merge(newer.clone()),
+            newer.clone().secure_merge(older.clone()),
+        ] {
+            assert!(matches!(
+                merged,
+                Shelf::Map {
+                    clock: LamportTimestamp(3),
+                    ..
                 }
-            }
+            ));
         }
     }
 
     #[test]
-    /// Test setting an empty object over a full object and ensure propagation of erasure.
-    fn test_empty_replacement() {
-        let shelf_map_with_value = shelf_map([("value".to_owned(), val(1, 0))].into_iter(), 0);
-
-        let empty_shelf_map = shelf_map([].into_iter(), 0);
-
-        let shelf_with_value = val(1, 0);
+    /// Two replicas add different keys under a map with the same clock. Once merged, the result must still hand a
+    /// third replica both additions.
+    fn test_concurrent_additions_reach_third_replica() {
+        let base = json!([{"doc": [{}, 1]}, 0]);
+        let replica_c: TestShelf = base.clone().try_into().unwrap();
+        let mut replica_a = replica_c.clone();
+        let mut replica_b = replica_c.clone();
+        match replica_a.get_mut("doc").unwrap() {
+            Shelf::Map { shelves, .. } => shelves.insert("a".to_owned(), val(1, 2)),
+            _ => unreachable!(),
+        };
+        match replica_b.get_mut("doc").unwrap() {
+            Shelf::Map { shelves, .. } => shelves.insert("b".to_owned(), val(2, 2)),
+            _ => unreachable!(),
+        };
 
-        let shelves = vec![shelf_with_value, empty_shelf_map, shelf_map_with_value];
-        // test all combinations
-        for i in 0..shelves.len() {
-            for j in (i + 1)..shelves.len() {
-                let shelf1 = shelves[i].clone();
-                let shelf2 = shelves[j].clone();
-                let shelf2 = match shelf2 {
-                    Shelf::Value { value, clock } => Shelf::Value {
-                        value,
-                        clock: clock.increment(1),
-                    },
-                    Shelf::Map { shelves, clock } => Shelf::Map {
-                        shelves,
-                        clock: clock.increment(),
-                    },
-                };
+        let merged = replica_a.clone().merge(replica_b.clone());
+        assert_eq!(merged, replica_b.merge(replica_a));
+        let delta = merged
+            .get_state_delta(&replica_c.get_state_vector())
+            .unwrap();
+        assert_eq!(replica_c.merge(delta), merged);
 
-                let res = validate_crdt_properties(shelf2, shelf1);
-            }
-        }
+        type SecureShelf = Shelf<Value, LamportTimestamp, SecureClock>;
+        let replica_c = SecureShelf::secure_from_json_values(json!({ "doc": {} })).unwrap();
+        let replica_a = SecureShelf::secure_from_json_values(json!({ "doc": { "a": 1 } })).unwrap();
+        let replica_b = SecureShelf::secure_from_json_values(json!({ "doc": { "b": 2 } })).unwrap();
+        let merged = replica_a.clone().secure_merge(replica_b.clone());
+        assert_eq!(merged, replica_b.secure_merge(replica_a));
+        assert!(merged.path_exists(&["doc", "a"]) && merged.path_exists(&["doc", "b"]));
+        let delta = merged
+            .get_state_delta(&replica_c.get_state_vector())
+            .unwrap();
+        assert_eq!(replica_c.secure_merge(delta), merged);
     }
 
     #[test]
@@ -1006,20 +3302,14 @@ mod tests {
         assert_eq!(first.secure_merge(second.clone()), second.clone());
         // Clocks don't match
         let value: Value = 1.into();
-        let mut hasher = DefaultHasher::new(); // TODO use a different hasher
-        let pair = (1, value.clone());
-        pair.hash(&mut hasher);
-        let hash = hasher.finish();
+        let hash = stable_hash(&(1usize, value.clone()));
         let clock = SecureClock { clock: 2, hash };
         let first = SecureShelf::Value { value, clock };
         assert_eq!(second.clone().secure_merge(first), second.clone()); // FAILS
 
         // Contents don't match
         let value: Value = 1.into();
-        let mut hasher = DefaultHasher::new(); // TODO use a different hasher
-        let pair = (1, value.clone());
-        pair.hash(&mut hasher);
-        let hash = hasher.finish();
+        let hash = stable_hash(&(1usize, value.clone()));
         let clock = SecureClock { clock: 1, hash };
         let first = SecureShelf::Value {
             value: 5.into(),
@@ -1031,10 +3321,7 @@ mod tests {
         let first = SecureShelf::secure_from_json_values(json!({ "val1": "foo" })).unwrap();
 
         let value: Value = 1.into();
-        let mut hasher = DefaultHasher::new();
-        let pair = (1, value.clone());
-        pair.hash(&mut hasher);
-        let hash = hasher.finish();
+        let hash = stable_hash(&(1usize, value.clone()));
         let clock = SecureClock { clock: 2, hash };
         let inner = SecureShelf::Value { value, clock };
         let second_layer = SecureShelf::Map {
@@ -1061,6 +3348,70 @@ mod tests {
         assert_eq!(expected, result)
     }
 
+    #[test]
+    fn test_secure_merge_audited() {
+        type SecureShelf = Shelf<Value, LamportTimestamp, SecureClock>;
+        let forged = |value: Value, clock: usize| {
+            let hash = stable_hash(&(1usize, Value::from(1)));
+            SecureShelf::Value {
+                value,
+                clock: SecureClock { clock, hash },
+            }
+        };
+        let local =
+            SecureShelf::secure_from_json_values(json!({ "doc": { "title": "a" } })).unwrap();
+        let valid = SecureShelf::secure_from_json_values(json!(1)).unwrap();
+        let remote = SecureShelf::Map {
+            shelves: HashMap::from_iter([
+                (
+                    "doc".to_owned(),
+                    SecureShelf::Map {
+                        shelves: HashMap::from_iter([
+                            ("title".to_owned(), forged("b".into(), 5)),
+                            ("body".to_owned(), forged("c".into(), 1)),
+                        ]),
+                        clock: 0.into(),
+                    },
+                ),
+                ("valid".to_owned(), valid.clone()),
+            ]),
+            clock: 0.into(),
+        };
+
+        let mut rejected = Vec::new();
+        let merged = local
+            .clone()
+            .secure_merge_audited(remote.clone(), &mut |entry| rejected.push(entry));
+        let mut assigned = local.clone();
+        assigned.secure_merge_assign(remote.clone());
+        assert_eq!(assigned, merged);
+        assert_eq!(merged, local.clone().secure_merge(remote));
+        assert_eq!(merged.get("valid"), Some(&valid));
+        rejected.sort_by(|a, b| a.path.cmp(&b.path));
+        let hash = stable_hash(&(1usize, Value::from(1)));
+        assert_eq!(
+            rejected,
+            vec![
+                RejectedEntry {
+                    path: vec!["doc".to_owned(), "body".to_owned()],
+                    value: "c".into(),
+                    clock: SecureClock { clock: 1, hash },
+                },
+                RejectedEntry {
+                    path: vec!["doc".to_owned(), "title".to_owned()],
+                    value: "b".into(),
+                    clock: SecureClock { clock: 5, hash },
+                },
+            ]
+        );
+
+        // A forged root is reported with an empty path
+        let mut rejected = Vec::new();
+        valid.secure_merge_audited(forged(7.into(), 3), &mut |entry| rejected.push(entry));
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].path.is_empty());
+    }
+
     #[test]
     /// Procedurally generates sets shelves and ensures that they all converge.
     fn test_generated_shelves() {
@@ -1070,12 +3421,19 @@ mod tests {
             branch_range: 1..5,
             value_range: 0..20,
         };
+        let invariants = Invariants {
+            map_rooted: true,
+            monotonic_clocks: true,
+            ..Default::default()
+        };
         let num_tests: usize = 2000;
         for i in 0..num_tests {
             let mut shelf: TestShelf = Shelf::try_from(fuzzer.generate_json_shelf(1)).unwrap();
             let mut shelf2: TestShelf = Shelf::try_from(fuzzer.generate_json_shelf(2)).unwrap();
             shelf.garbage_collect();
             shelf2.garbage_collect();
+            shelf.validate(&invariants).unwrap();
+            shelf2.validate(&invariants).unwrap();
 
             if shelf == shelf2 {
                 continue;
@@ -1108,10 +3466,149 @@ mod tests {
 
             // Ensure both forwards and backwards match
             assert_eq!(receiver, receiver_back, "Not commutative {}", report());
+            for merged in [&receiver, &receiver_back] {
+                if let Err(err) = merged.validate(&invariants) {
+                    panic!("Invalid merge result: {err} {}", report());
+                }
+            }
             if let Some(delta) = cached_delta {
                 receiver = receiver.merge(delta);
                 assert_eq!(receiver, receiver_back, "Not idempotent {}", report());
             }
         }
     }
+
+    #[test]
+    /// Each pair of nodes visited by a merge should only compare its clocks once.
+    fn test_merge_clock_comparisons() {
+        use std::cell::Cell;
+
+        thread_local! {
+            static COMPARISONS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        #[derive(PartialEq, Clone, Copy)]
+        struct CountingClock(usize);
+
+        impl PartialOrd for CountingClock {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                COMPARISONS.with(|count| count.set(count.get() + 1));
+                self.0.partial_cmp(&other.0)
+            }
+        }
+
+        type CountingShelf = Shelf<Value, CountingClock>;
+
+        // Builds a tree of maps `depth` levels deep with `width` leaves at the bottom. Returns the number of nodes.
+        fn tree(depth: usize, width: usize, leaf_clock: usize) -> (CountingShelf, usize) {
+            if depth == 0 {
+                let shelves: HashMap<String, CountingShelf> = (0..width)
+                    .map(|i| {
+                        let leaf = Shelf::Value {
+                            value: Value::Int(i as isize),
+                            clock: CountingClock(leaf_clock),
+                        };
+                        (i.to_string(), leaf)
+                    })
+                    .collect();
+                let shelf = Shelf::Map {
+                    shelves,
+                    clock: CountingClock(0),
+                };
+                return (shelf, width + 1);
+            }
+            let (child, child_nodes) = tree(depth - 1, width, leaf_clock);
+            let shelves =
+                HashMap::from_iter([("a".to_owned(), child.clone()), ("b".to_owned(), child)]);
+            let shelf = Shelf::Map {
+                shelves,
+                clock: CountingClock(0),
+            };
+            (shelf, 2 * child_nodes + 1)
+        }
+
+        let (this, nodes) = tree(6, 10, 1);
+        let (other, _) = tree(6, 10, 2);
+        COMPARISONS.with(|count| count.set(0));
+        // Skips the idempotency check that `merge` runs in debug builds, which compares clocks of its own
+        let clock_order = this.get_clock().partial_cmp(&other.get_clock());
+        let mut merged = this;
+        merged.merge_with_order(other, clock_order);
+        assert_eq!(COMPARISONS.with(|count| count.get()), nodes);
+
+        if let Ok(Shelf::Value { clock, .. }) =
+            merged.get_path(&["a", "b", "a", "b", "a", "b", "0"])
+        {
+            assert_eq!(clock.0, 2);
+        } else {
+            panic!("Expected a merged leaf");
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "merging the same delta again changed a node at depth 1")]
+    fn test_merge_idempotency_check() {
+        use std::cell::Cell;
+
+        thread_local! {
+            static COMPARISONS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        // Equal on the first two comparisons and older on every one after, so merging the same delta twice differs.
+        #[derive(PartialEq, Clone, Copy)]
+        struct FlakyClock;
+
+        impl PartialOrd for FlakyClock {
+            fn partial_cmp(&self, _: &Self) -> Option<Ordering> {
+                let count = COMPARISONS.with(|count| count.replace(count.get() + 1));
+                if count < 2 {
+                    Some(Ordering::Equal)
+                } else {
+                    Some(Ordering::Less)
+                }
+            }
+        }
+
+        let leaf = |value: isize| Shelf::Value {
+            value: Value::Int(value),
+            clock: FlakyClock,
+        };
+        let this: Shelf<Value, FlakyClock> = Shelf::Map {
+            shelves: HashMap::from([("x".to_owned(), leaf(1))]),
+            clock: FlakyClock,
+        };
+        let other = Shelf::Map {
+            shelves: HashMap::from([("y".to_owned(), leaf(2))]),
+            clock: FlakyClock,
+        };
+        this.merge(other);
+    }
+
+    #[test]
+    fn test_merge_idempotency_check_allows_nan() {
+        let leaf = |value: f32, client_id| Shelf::Value {
+            value: Value::Float(value),
+            clock: DotClock {
+                client_id,
+                clock: 1,
+            },
+        };
+        let shelf = |key: &str, value: f32, client_id| -> TestShelf {
+            Shelf::Map {
+                shelves: HashMap::from([(key.to_owned(), leaf(value, client_id))]),
+                clock: LamportTimestamp(0),
+            }
+        };
+        let merged = shelf("x", f32::NAN, 1).merge(shelf("y", 1.0, 2));
+        let merged = merged.merge(shelf("x", f32::NAN, 1));
+        match merged.get("x") {
+            Some(Shelf::Value {
+                value: Value::Float(value),
+                ..
+            }) => assert!(value.is_nan()),
+            _ => panic!("Expected the NaN to be kept"),
+        }
+        assert!(merged.get("y").is_some());
+    }
 }
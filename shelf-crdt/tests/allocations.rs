@@ -0,0 +1,84 @@
+//! Allocation counts for bulk imports. This lives in its own test binary, since counting needs a global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use serde_json::{json, Value as JSON};
+use shelf_crdt::clock::{DotClock, DotClockGenerator, LamportTimestamp, LamportTimestampGenerator};
+use shelf_crdt::json::Value;
+use shelf_crdt::wrap_crdt::Shelf;
+
+type TestShelf = Shelf<Value, LamportTimestamp, DotClock>;
+
+/// Allocations at least this large can only be the table of a map with tens of thousands of entries.
+const LARGE_ALLOCATION: usize = 1 << 20;
+
+/// Counts large allocations made on the current thread, so tests running in parallel don't interfere and small
+/// allocations made inside serde_json or std don't matter.
+struct CountingAllocator;
+
+thread_local! {
+    static LARGE_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() >= LARGE_ALLOCATION {
+            LARGE_ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn large_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = LARGE_ALLOCATIONS.with(|count| count.get());
+    let result = f();
+    (result, LARGE_ALLOCATIONS.with(|count| count.get()) - before)
+}
+
+#[test]
+/// Importing a large object should allocate its map once instead of every time the map grows.
+fn bulk_import_allocates_map_once() {
+    let n = 50_000;
+    let values: serde_json::Map<String, JSON> = (0..n).map(|i| (i.to_string(), json!(i))).collect();
+    let plain = JSON::Object(values.clone());
+    let with_clocks: serde_json::Map<String, JSON> = values
+        .into_iter()
+        .map(|(k, v)| (k, json!([v, [0, 0]])))
+        .collect();
+    let with_clocks = json!([with_clocks, 0]);
+
+    // Growing the map one insert at a time would allocate several tables of this size on the way.
+    let (shelf, allocations) = large_allocations(|| {
+        TestShelf::from_json_values(
+            plain,
+            &mut LamportTimestampGenerator {},
+            &mut DotClockGenerator::new(0),
+        )
+        .unwrap()
+    });
+    assert_eq!(allocations, 1);
+
+    let (parsed, allocations) = large_allocations(|| TestShelf::try_from(with_clocks).unwrap());
+    assert_eq!(allocations, 1);
+
+    if let (
+        Shelf::Map { shelves, .. },
+        Shelf::Map {
+            shelves: parsed, ..
+        },
+    ) = (shelf, parsed)
+    {
+        assert_eq!(shelves.len(), n);
+        assert_eq!(parsed.len(), n);
+    } else {
+        panic!("Expected maps");
+    }
+}
@@ -6,11 +6,13 @@ use js_sys::{self, Array, Uint8Array};
 use rand::prelude::StdRng;
 use rand::SeedableRng;
 use serde_json;
-use serde_json::Value as JSON;
+use serde_json::{json, Value as JSON};
 use shelf_crdt::json::Value;
 use shelf_crdt::traits::{DeltaCRDT, Mergeable};
 
-use shelf_crdt::clock::{LamportTimestamp, LamportTimestampGenerator};
+use crate::convert::content_to_json;
+use shelf_crdt::clock::{ClientId, LamportTimestamp, LamportTimestampGenerator};
+use shelf_crdt::encoding;
 use shelf_crdt::state_vector::{StateVector, StateVectorContext};
 use wasm_bindgen::prelude::*;
 
@@ -22,49 +24,74 @@ type ClientShelf = shelf_crdt::wrap_crdt::Shelf<Value, LamportTimestamp, Lamport
 #[wasm_bindgen]
 pub struct Awareness {
     inner: AwarenessClient,
+    version: u64,
+    on_change: Option<js_sys::Function>,
 }
 
 #[wasm_bindgen]
 impl Awareness {
     #[wasm_bindgen(constructor)]
-    pub fn new(content: JsValue, client_id: usize) -> Self {
+    pub fn new(content: JsValue, client_id: ClientId) -> Self {
         let inner = if content.is_undefined() {
             AwarenessClient::new_for_client(client_id, StateVectorContext {})
         } else {
-            let values = content.into_serde().unwrap_throw();
+            let values = content_to_json(&content).unwrap_throw();
             AwarenessClient::from_json_values(values, client_id).unwrap_throw()
         };
-        Self { inner }
+        Self {
+            inner,
+            version: 0,
+            on_change: None,
+        }
     }
     pub fn get(&self, path: Array, client_id: Option<String>) -> JsValue {
-        let mut shelf = client_id
-            .map(|cid| self.inner.get_peer_state(&cid).unwrap_throw())
-            .unwrap_or_else(|| self.inner.get_own_state().unwrap_throw());
-        for key in path.iter() {
-            if let Some(key) = key.as_string() {
-                shelf = shelf
-                    .get(&key)
-                    .ok_or_else(|| format!("Key Error: {}", key))
-                    .unwrap_throw()
-            } else {
-                Err(format!("Invalid key: {:?}", key)).unwrap_throw()
-            }
-        }
-
-        let json = shelf.clone().to_json_values();
+        let json = self.get_shelf(path, client_id).clone().to_json_values();
         JsValue::from_serde(&json).unwrap_throw()
     }
 
+    /// Gets the string at `path`, or `null` if the leaf is not a string.
+    #[wasm_bindgen(js_name = "getString")]
+    pub fn get_string(&self, path: Array, client_id: Option<String>) -> Option<String> {
+        Self::leaf_value(self.get_shelf(path, client_id))
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+    }
+
+    /// Gets the number at `path`, or `null` if the leaf is not a number.
+    #[wasm_bindgen(js_name = "getNumber")]
+    pub fn get_number(&self, path: Array, client_id: Option<String>) -> Option<f64> {
+        Self::leaf_value(self.get_shelf(path, client_id)).and_then(Value::as_f64)
+    }
+
+    /// Gets the boolean at `path`, or `null` if the leaf is not a boolean.
+    #[wasm_bindgen(js_name = "getBool")]
+    pub fn get_bool(&self, path: Array, client_id: Option<String>) -> Option<bool> {
+        Self::leaf_value(self.get_shelf(path, client_id)).and_then(Value::as_bool)
+    }
+
     pub fn set(&mut self, path: Array, contents: JsValue) {
         let path = Self::convert_path(path).unwrap_throw();
-        let json = contents.into_serde().unwrap_throw();
+        let json = content_to_json(&contents).unwrap_throw();
         let shelf = ClientShelf::from_json_values(
             json,
             &mut LamportTimestampGenerator {},
             &mut LamportTimestampGenerator {},
         )
         .unwrap_throw();
-        self.inner.set_state(path, shelf).unwrap_throw();
+        let changed: Vec<String> = std::iter::once(self.inner.client_id.to_string())
+            .chain(path.iter().cloned())
+            .collect();
+        let old = self.inner.set_state(path, shelf).unwrap_throw();
+        // Writing the value a path already has still moves its clock, but is not a change for listeners
+        let keys: Vec<&str> = changed.iter().map(String::as_str).collect();
+        let unchanged = match (old, self.inner.clients.get_path(&keys)) {
+            (Some(old), Ok(new)) => old.same_content(new),
+            _ => false,
+        };
+        if !unchanged {
+            self.version += 1;
+            self.notify(vec![changed]);
+        }
     }
 
     #[wasm_bindgen(js_name = "toString")]
@@ -81,7 +108,7 @@ impl Awareness {
     #[wasm_bindgen(js_name = "getStateVector")]
     pub fn get_state_vector(&self) -> JsValue {
         let sv = self.inner.clients.get_state_vector();
-        let bytes = bincode::serialize(&sv);
+        let bytes = encoding::encode(&sv);
         match bytes {
             Ok(bytes) => Uint8Array::from(&bytes[..]).into(),
             Err(err) => Err(err).unwrap_throw(),
@@ -91,12 +118,12 @@ impl Awareness {
     #[wasm_bindgen(js_name = "getStateDelta")]
     pub fn get_state_delta(&self, sv: Uint8Array) -> JsValue {
         let decoded_sv: StateVector<LamportTimestamp, LamportTimestamp> =
-            bincode::deserialize(&sv.to_vec()[..]).unwrap_throw();
+            encoding::decode(&sv.to_vec()).unwrap_throw();
         let bytes = self
             .inner
             .clients
             .get_state_delta(&decoded_sv)
-            .map(|delta| bincode::serialize(&delta));
+            .map(|delta| encoding::encode(&delta));
 
         match bytes {
             Some(Ok(bytes)) => Uint8Array::from(&bytes[..]).into(),
@@ -106,8 +133,29 @@ impl Awareness {
     }
     #[wasm_bindgen]
     pub fn merge(&mut self, delta: Uint8Array) {
-        let delta: ClientShelf = bincode::deserialize(&delta.to_vec()[..]).unwrap_throw();
-        self.inner.merge(delta);
+        let delta: ClientShelf = encoding::decode(&delta.to_vec()).unwrap_throw();
+        // The state is only cloned when a callback needs to know which paths changed.
+        let before = self.on_change.as_ref().map(|_| self.inner.clients.clone());
+        if self.inner.merge(delta) {
+            self.version += 1;
+        }
+        if let Some(before) = before {
+            self.notify(self.inner.clients.changed_paths(&before));
+        }
+    }
+
+    /// Registers a callback that is invoked after each `set` or `merge` that changes the awareness state.
+    /// It receives an array of `{path, value}` objects, one per changed path, where paths start with the client id
+    /// and `value` is the new content or `null` if the path no longer exists.
+    #[wasm_bindgen(js_name = "setOnChange")]
+    pub fn set_on_change(&mut self, callback: js_sys::Function) {
+        self.on_change = Some(callback);
+    }
+
+    /// Monotonically increasing counter that is bumped whenever a `set` or `merge` alters the awareness state.
+    #[wasm_bindgen]
+    pub fn version(&self) -> u64 {
+        self.version
     }
 
     #[wasm_bindgen(js_name = "getTotalBytes")]
@@ -120,4 +168,49 @@ impl Awareness {
     fn convert_path(list: Array) -> Option<Vec<String>> {
         list.iter().map(|segment| segment.as_string()).collect()
     }
+
+    fn get_shelf(&self, path: Array, client_id: Option<String>) -> &ClientShelf {
+        let mut shelf = client_id
+            .map(|cid| self.inner.get_peer_state(&cid).unwrap_throw())
+            .unwrap_or_else(|| self.inner.get_own_state().unwrap_throw());
+        for key in path.iter() {
+            if let Some(key) = key.as_string() {
+                shelf = shelf
+                    .get(&key)
+                    .ok_or_else(|| format!("Key Error: {}", key))
+                    .unwrap_throw()
+            } else {
+                Err(format!("Invalid key: {:?}", key)).unwrap_throw()
+            }
+        }
+        shelf
+    }
+
+    fn notify(&self, paths: Vec<Vec<String>>) {
+        let callback = match &self.on_change {
+            Some(callback) if !paths.is_empty() => callback,
+            _ => return,
+        };
+        let changes: Vec<JSON> = paths
+            .into_iter()
+            .map(|path| {
+                let keys: Vec<&str> = path.iter().map(String::as_str).collect();
+                let value = match self.inner.clients.get_path(&keys) {
+                    Ok(shelf) => shelf.clone().to_json_values(),
+                    Err(_) => JSON::Null,
+                };
+                json!({ "path": path, "value": value })
+            })
+            .collect();
+        let changes = JsValue::from_serde(&changes).unwrap_throw();
+        callback.call1(&JsValue::NULL, &changes).unwrap_throw();
+    }
+
+    #[inline]
+    fn leaf_value(shelf: &ClientShelf) -> Option<&Value> {
+        match shelf {
+            ClientShelf::Value { value, .. } => Some(value),
+            ClientShelf::Map { .. } => None,
+        }
+    }
 }
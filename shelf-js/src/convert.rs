@@ -0,0 +1,113 @@
+use js_sys::{Array, Function, Object, Reflect};
+use serde_json::{Map, Number, Value as JSON};
+use wasm_bindgen::{JsCast, JsValue};
+
+/// Objects and arrays nested deeper than this are rejected, so deeply nested data cannot overflow the stack.
+const MAX_DEPTH: usize = 512;
+
+/// Converts plain JavaScript data to JSON by walking it directly instead of going through `JSON.stringify` like
+/// `JsValue::into_serde`. Follows the same rules as `JSON.stringify`: `toJSON` methods are called, so a `Date`
+/// becomes its ISO string, `undefined` and functions are dropped from objects and become `null` in arrays, and
+/// non-finite numbers become `null`. Cyclic data is an error, as is data nested deeper than 512 levels.
+pub fn js_to_json(value: &JsValue) -> Result<JSON, String> {
+    let mut ancestors = Vec::new();
+    Ok(convert(value, &JsValue::from_str(""), &mut ancestors)?.unwrap_or(JSON::Null))
+}
+
+/// Converts `value`, which is stored under `key` in its parent. Returns `None` for values that `JSON.stringify`
+/// leaves out, i.e. `undefined` and functions.
+fn convert(
+    value: &JsValue,
+    key: &JsValue,
+    ancestors: &mut Vec<JsValue>,
+) -> Result<Option<JSON>, String> {
+    let value = &apply_to_json(value, key)?;
+    if value.is_undefined() || value.is_function() {
+        return Ok(None);
+    }
+    if value.is_null() {
+        return Ok(Some(JSON::Null));
+    }
+    if let Some(b) = value.as_bool() {
+        return Ok(Some(JSON::Bool(b)));
+    }
+    if let Some(n) = value.as_f64() {
+        return Ok(Some(number_to_json(n)));
+    }
+    if let Some(s) = value.as_string() {
+        return Ok(Some(JSON::String(s)));
+    }
+    if !value.is_object() {
+        return Err(format!("Cannot convert {:?} to JSON", value));
+    }
+    if ancestors.iter().any(|ancestor| Object::is(ancestor, value)) {
+        return Err("Cannot convert cyclic data to JSON".to_owned());
+    }
+    if ancestors.len() >= MAX_DEPTH {
+        return Err(format!(
+            "Data is nested deeper than the limit of {}",
+            MAX_DEPTH
+        ));
+    }
+    ancestors.push(value.clone());
+    let json = if Array::is_array(value) {
+        let array: &Array = value.unchecked_ref();
+        let mut values = Vec::with_capacity(array.length() as usize);
+        for (i, item) in array.iter().enumerate() {
+            let item = convert(&item, &JsValue::from_str(&i.to_string()), ancestors)?;
+            values.push(item.unwrap_or(JSON::Null));
+        }
+        JSON::Array(values)
+    } else {
+        let object: &Object = value.unchecked_ref();
+        let mut map = Map::new();
+        for key in Object::keys(object).iter() {
+            let name = key
+                .as_string()
+                .ok_or_else(|| format!("Invalid key: {:?}", key))?;
+            let item = Reflect::get(object, &key).map_err(|_| format!("Key Error: {}", name))?;
+            if let Some(item) = convert(&item, &key, ancestors)? {
+                map.insert(name, item);
+            }
+        }
+        JSON::Object(map)
+    };
+    ancestors.pop();
+    Ok(Some(json))
+}
+
+/// Replaces `value` with what its `toJSON` method returns, if it has one.
+fn apply_to_json(value: &JsValue, key: &JsValue) -> Result<JsValue, String> {
+    if !value.is_object() {
+        return Ok(value.clone());
+    }
+    let to_json = Reflect::get(value, &JsValue::from_str("toJSON"))
+        .map_err(|_| "Key Error: toJSON".to_owned())?;
+    match to_json.dyn_ref::<Function>() {
+        Some(to_json) => to_json
+            .call1(value, key)
+            .map_err(|err| format!("toJSON failed: {:?}", err)),
+        None => Ok(value.clone()),
+    }
+}
+
+/// JavaScript only has doubles, so integral numbers are turned back into integers like `JSON.parse` would.
+fn number_to_json(n: f64) -> JSON {
+    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+        JSON::from(n as i64)
+    } else {
+        Number::from_f64(n).map(JSON::Number).unwrap_or(JSON::Null)
+    }
+}
+
+/// Converts content passed in from JavaScript. Uses `js_to_json` when the `direct-conversion` feature is enabled.
+pub(crate) fn content_to_json(content: &JsValue) -> Result<JSON, String> {
+    #[cfg(feature = "direct-conversion")]
+    {
+        js_to_json(content)
+    }
+    #[cfg(not(feature = "direct-conversion"))]
+    {
+        content.into_serde().map_err(|err| err.to_string())
+    }
+}
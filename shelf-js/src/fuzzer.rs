@@ -4,12 +4,14 @@ use js_sys::{self, Array, JsString, Uint8Array};
 use rand::prelude::StdRng;
 use rand::{Rng, SeedableRng};
 use serde_json;
-use serde_json::Value as JSON;
-use shelf_crdt::clock::LamportTimestamp;
+use serde_json::{json, Value as JSON};
+use shelf_crdt::clock::{ClientId, LamportTimestamp};
 use shelf_crdt::json::Value;
 use shelf_crdt::shelf_fuzzer::ShelfFuzzer;
 use wasm_bindgen::prelude::*;
 
+use crate::shelf::DotShelf;
+
 #[wasm_bindgen]
 extern "C" {
     // Use `js_namespace` here to bind `console.log(..)` instead of just
@@ -52,6 +54,55 @@ impl Fuzzer {
             .or_else(|_| Err(JsValue::from("Failed to convert shelf to JSON")))
     }
 
+    /// Generates shelves for two clients, syncs them in both directions through `DotShelf` and reports whether
+    /// they converged as `{converged, diff}`. `diff` holds a `{path, left, right}` entry for each path where the
+    /// synced shelves differ, with `null` for a path that is missing on one side.
+    #[wasm_bindgen(js_name = "checkConvergence")]
+    pub fn check_convergence(&mut self) -> Result<JsValue, JsValue> {
+        let left = self.generate_shelf(1)?;
+        let right = self.generate_shelf(2)?;
+        let (left, right) = Self::sync(left, right);
+        let diff: Vec<JSON> = left
+            .inner()
+            .changed_paths(right.inner())
+            .into_iter()
+            .map(|path| {
+                let keys: Vec<&str> = path.iter().map(String::as_str).collect();
+                let value_at = |shelf: &DotShelf| match shelf.inner().get_path(&keys) {
+                    Ok(shelf) => JSON::from(shelf.clone()),
+                    Err(_) => JSON::Null,
+                };
+                json!({ "path": path, "left": value_at(&left), "right": value_at(&right) })
+            })
+            .collect();
+        let report = json!({ "converged": diff.is_empty(), "diff": diff });
+        JsValue::from_serde(&report).map_err(|err| JsValue::from(err.to_string()))
+    }
+
+    fn generate_shelf(&mut self, client_id: ClientId) -> Result<DotShelf, JsValue> {
+        let content = self.generate_content()?;
+        DotShelf::new(content, client_id).map_err(JsValue::from)
+    }
+
+    /// Exchanges deltas between both shelves using their state vectors.
+    fn sync(left: DotShelf, right: DotShelf) -> (DotShelf, DotShelf) {
+        let left_sv: Uint8Array = left.get_state_vector().into();
+        let right_sv: Uint8Array = right.get_state_vector().into();
+        let to_right = left.get_state_delta(right_sv);
+        let to_left = right.get_state_delta(left_sv);
+        let left = if to_left.is_null() {
+            left
+        } else {
+            left.merge(to_left.into())
+        };
+        let right = if to_right.is_null() {
+            right
+        } else {
+            right.merge(to_right.into())
+        };
+        (left, right)
+    }
+
     fn extract_range(array: &JsValue) -> Option<Range<usize>> {
         let start = js_sys::Reflect::get(array, &JsValue::from(0_usize)).ok()?;
         let start: usize = start.as_f64()? as usize;
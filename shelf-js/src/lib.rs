@@ -12,11 +12,13 @@ extern "C" {
 }
 
 mod awareness;
+mod convert;
 mod fuzzer;
 mod secure_shelf;
 mod shelf;
 mod utils;
 pub use awareness::Awareness;
+pub use convert::js_to_json;
 pub use fuzzer::Fuzzer;
 pub use secure_shelf::SecureShelf;
 pub use shelf::DotShelf;
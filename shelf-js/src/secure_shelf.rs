@@ -1,9 +1,11 @@
 use std::collections::hash_map::Entry;
 
 use shelf_crdt::clock::{LamportTimestamp, LogicalClock, SecureClock};
+use shelf_crdt::encoding;
 use shelf_crdt::state_vector::StateVector;
 use shelf_crdt::traits::{DeltaCRDT, Mergeable};
 
+use crate::convert::content_to_json;
 use js_sys::{self, Array, Uint8Array};
 use serde_json;
 use serde_json::Value as JSON;
@@ -23,7 +25,7 @@ impl SecureShelf {
             return Err("Content must be provided".to_owned());
         }
         let inner = {
-            let json: JSON = content.into_serde().unwrap_throw();
+            let json = content_to_json(&content).unwrap_throw();
             SecureShelfCRDT::secure_from_json_values(json).unwrap_throw()
         };
         Ok(Self(inner))
@@ -47,43 +49,26 @@ impl SecureShelf {
         JsValue::from_serde(&json).unwrap_throw()
     }
 
+    /// Sets `contents` at `path`. An empty path replaces the root of the shelf.
     #[wasm_bindgen]
     pub fn set(&mut self, path: Array, contents: JsValue) {
         let path = Self::convert_path(path).unwrap_throw();
+        let json = content_to_json(&contents).unwrap_throw();
+        let contents = SecureShelfCRDT::secure_from_json_values(json).unwrap_throw(); // TODO: Do we need to bound this by parent clock?
+        if path.is_empty() {
+            // The root has no parent, so its own clock is the lower bound for the replacement.
+            let root_clock = self.0.get_clock().get_logical_clock();
+            let new_ts = Self::next_timestamp(Some(&self.0), root_clock);
+            self.0 = Self::with_timestamp(contents, new_ts);
+            return;
+        }
         let (entry, parent_clock) = self.0.entry_from_path(path).unwrap_throw();
-        let parent_clock = parent_clock.0;
-        let new_ts = match &entry {
-            Entry::Occupied(occupied_entry) => {
-                let old_value = occupied_entry.get();
-                match old_value {
-                    SecureShelfCRDT::Value { clock, .. } => Some(clock.clock.max(parent_clock) + 1), // New clock must be
-                    SecureShelfCRDT::Map {
-                        shelves,
-                        clock: LamportTimestamp(old_clock),
-                    } => {
-                        let highest_child_timestamp = shelves
-                            .iter()
-                            .map(|(_, shelf)| shelf.get_clock().get_logical_clock())
-                            .max();
-                        highest_child_timestamp.map(|ts| ts.max(parent_clock).max(*old_clock) + 1)
-                    }
-                }
-            }
+        let old_value = match &entry {
+            Entry::Occupied(occupied_entry) => Some(occupied_entry.get()),
             Entry::Vacant(_) => None,
         };
-        let new_ts = new_ts.unwrap_or(parent_clock + 1);
-        let json = contents.into_serde().unwrap_throw();
-        let contents = SecureShelfCRDT::secure_from_json_values(json).unwrap_throw(); // TODO: Do we need to bound this by parent clock?
-        let value = match contents {
-            SecureShelfCRDT::Value { value, .. } => SecureShelfCRDT::Value {
-                clock: SecureClock::new(&value, new_ts),
-                value,
-            },
-            SecureShelfCRDT::Map { shelves, .. } => SecureShelfCRDT::Map {
-                shelves,
-                clock: new_ts.into(),
-            },
-        };
+        let new_ts = Self::next_timestamp(old_value, parent_clock.0);
+        let value = Self::with_timestamp(contents, new_ts);
         match entry {
             Entry::Occupied(mut o) => Some(o.insert(value)),
             Entry::Vacant(v) => {
@@ -107,18 +92,18 @@ impl SecureShelf {
     #[wasm_bindgen(js_name = "getStateVector")]
     pub fn get_state_vector(&self) -> JsValue {
         let sv = self.0.get_state_vector();
-        let bytes = bincode::serialize(&sv).unwrap_throw();
+        let bytes = encoding::encode(&sv).unwrap_throw();
         Uint8Array::from(&bytes[..]).into()
     }
 
     #[wasm_bindgen(js_name = "getStateDelta")]
     pub fn get_state_delta(&self, sv: Uint8Array) -> JsValue {
         let decoded_sv: StateVector<LamportTimestamp, SecureClock> =
-            bincode::deserialize(&sv.to_vec()[..]).unwrap_throw();
+            encoding::decode(&sv.to_vec()).unwrap_throw();
         let bytes = self
             .0
             .get_state_delta(&decoded_sv)
-            .map(|delta| bincode::serialize(&delta).unwrap_throw());
+            .map(|delta| encoding::encode(&delta).unwrap_throw());
 
         match bytes {
             Some(bytes) => Uint8Array::from(&bytes[..]).into(),
@@ -127,7 +112,7 @@ impl SecureShelf {
     }
     #[wasm_bindgen]
     pub fn merge(self, delta_bytes: Uint8Array) -> Self {
-        let delta: SecureShelfCRDT = bincode::deserialize(&delta_bytes.to_vec()[..]).unwrap_throw();
+        let delta: SecureShelfCRDT = encoding::decode(&delta_bytes.to_vec()).unwrap_throw();
         Self(self.0.secure_merge(delta))
     }
 
@@ -141,6 +126,38 @@ impl SecureShelf {
     fn convert_path(list: Array) -> Option<Vec<String>> {
         list.iter().map(|segment| segment.as_string()).collect()
     }
+
+    /// Timestamp for a value written over `old_value`. It must be higher than anything it replaces.
+    fn next_timestamp(old_value: Option<&SecureShelfCRDT>, parent_clock: usize) -> usize {
+        let new_ts = match old_value {
+            Some(SecureShelfCRDT::Value { clock, .. }) => Some(clock.clock.max(parent_clock) + 1),
+            Some(SecureShelfCRDT::Map {
+                shelves,
+                clock: LamportTimestamp(old_clock),
+            }) => {
+                let highest_child_timestamp = shelves
+                    .iter()
+                    .map(|(_, shelf)| shelf.get_clock().get_logical_clock())
+                    .max();
+                highest_child_timestamp.map(|ts| ts.max(parent_clock).max(*old_clock) + 1)
+            }
+            None => None,
+        };
+        new_ts.unwrap_or(parent_clock + 1)
+    }
+
+    fn with_timestamp(contents: SecureShelfCRDT, new_ts: usize) -> SecureShelfCRDT {
+        match contents {
+            SecureShelfCRDT::Value { value, .. } => SecureShelfCRDT::Value {
+                clock: SecureClock::new(&value, new_ts),
+                value,
+            },
+            SecureShelfCRDT::Map { shelves, .. } => SecureShelfCRDT::Map {
+                shelves,
+                clock: new_ts.into(),
+            },
+        }
+    }
 }
 
 impl From<SecureShelfCRDT> for SecureShelf {
@@ -1,32 +1,64 @@
+use std::borrow::Cow;
+use std::cell::OnceCell;
 use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 
 use shelf_crdt::clock::{
-    DotClock, DotClockGenerator, LamportTimestamp, LamportTimestampGenerator, LogicalClock,
+    ClientId, DotClock, DotClockGenerator, LamportTimestamp, LamportTimestampGenerator,
+    LogicalClock,
 };
+use shelf_crdt::encoding;
 use shelf_crdt::state_vector::StateVector;
-use shelf_crdt::traits::{DeltaCRDT, Mergeable};
+use shelf_crdt::traits::DeltaCRDT;
 
+use crate::convert::content_to_json;
 use js_sys::{self, Array, Uint8Array};
 use serde_json;
-use serde_json::Value as JSON;
+use serde_json::{json, Value as JSON};
 use shelf_crdt::json::Value;
 use shelf_crdt::wrap_crdt::Shelf as GeneralShelfCRDT;
 use wasm_bindgen::prelude::*;
 
 type ShelfCRDT = GeneralShelfCRDT<Value, LamportTimestamp, DotClock>;
 
+/// A top-level entry of a shelf restored with `fromBytesLazy`, kept encoded until it is first read.
+struct LazyEntry {
+    bytes: Vec<u8>,
+    loaded: OnceCell<ShelfCRDT>,
+}
+
+impl LazyEntry {
+    fn get(&self) -> &ShelfCRDT {
+        self.loaded
+            .get_or_init(|| encoding::decode(&self.bytes).unwrap_throw())
+    }
+
+    fn into_shelf(self) -> ShelfCRDT {
+        match self.loaded.into_inner() {
+            Some(shelf) => shelf,
+            None => encoding::decode(&self.bytes).unwrap_throw(),
+        }
+    }
+}
+
 #[wasm_bindgen]
-pub struct DotShelf(ShelfCRDT);
+pub struct DotShelf {
+    inner: ShelfCRDT,
+    /// Top-level entries that are not in `inner` yet. Only shelves restored with `fromBytesLazy` have any.
+    lazy: HashMap<String, LazyEntry>,
+    version: u64,
+    on_change: Option<js_sys::Function>,
+}
 
 #[wasm_bindgen]
 impl DotShelf {
     #[wasm_bindgen(constructor)]
-    pub fn new(content: JsValue, client_id: usize) -> Result<DotShelf, String> {
+    pub fn new(content: JsValue, client_id: ClientId) -> Result<DotShelf, String> {
         if content.is_undefined() {
             return Err("Content must be provided".to_owned());
         }
         let inner = {
-            let json: JSON = content.into_serde().unwrap_throw();
+            let json = content_to_json(&content).unwrap_throw();
             ShelfCRDT::from_json_values(
                 json,
                 &mut LamportTimestampGenerator {},
@@ -34,70 +66,67 @@ impl DotShelf {
             )
             .unwrap_throw()
         };
-        Ok(Self(inner))
+        Ok(Self::from(inner))
     }
     #[wasm_bindgen]
     pub fn get(&self, path: Array) -> JsValue {
-        let mut shelf = &self.0;
-        for key in path.iter() {
-            if let Some(key) = key.as_string() {
-                shelf = shelf
-                    .get(&key)
-                    .ok_or_else(|| format!("Key Error: {}", key))
-                    .unwrap_throw()
-            } else {
-                Err(format!("Invalid key: {:?}", key)).unwrap_throw()
-            }
-        }
-
-        let json = shelf.clone().to_json_values();
+        let json = self.get_shelf(path).into_owned().to_json_values();
         JsValue::from_serde(&json).unwrap_throw()
     }
+
+    /// Gets the string at `path`, or `null` if the leaf is not a string.
+    #[wasm_bindgen(js_name = "getString")]
+    pub fn get_string(&self, path: Array) -> Option<String> {
+        Self::leaf_value(&self.get_shelf(path))
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+    }
+
+    /// Gets the number at `path`, or `null` if the leaf is not a number.
+    #[wasm_bindgen(js_name = "getNumber")]
+    pub fn get_number(&self, path: Array) -> Option<f64> {
+        Self::leaf_value(&self.get_shelf(path)).and_then(Value::as_f64)
+    }
+
+    /// Gets the boolean at `path`, or `null` if the leaf is not a boolean.
+    #[wasm_bindgen(js_name = "getBool")]
+    pub fn get_bool(&self, path: Array) -> Option<bool> {
+        Self::leaf_value(&self.get_shelf(path)).and_then(Value::as_bool)
+    }
+    /// Sets `contents` at `path`. An empty path replaces the root of the shelf.
     #[wasm_bindgen]
-    pub fn set(&mut self, path: Array, contents: JsValue, client_id: usize) {
+    pub fn set(&mut self, path: Array, contents: JsValue, client_id: ClientId) {
         let path = Self::convert_path(path).unwrap_throw();
-        let (entry, parent_clock) = self.0.entry_from_path(path).unwrap_throw();
-        let parent_clock = parent_clock.0;
-        let new_ts = match &entry {
-            Entry::Occupied(occupied_entry) => {
-                let old_value = occupied_entry.get();
-                match old_value {
-                    ShelfCRDT::Value { clock, .. } => Some(clock.clock.max(parent_clock) + 1), // New clock must be
-                    ShelfCRDT::Map {
-                        shelves,
-                        clock: LamportTimestamp(old_clock),
-                    } => {
-                        let highest_child_timestamp = shelves
-                            .iter()
-                            .map(|(_, shelf)| shelf.get_clock().get_logical_clock())
-                            .max();
-                        highest_child_timestamp.map(|ts| ts.max(parent_clock).max(*old_clock) + 1)
-                    }
-                }
-            }
-            Entry::Vacant(_) => None,
-        };
-        let new_ts = new_ts.unwrap_or(parent_clock + 1);
-        let json = contents.into_serde().unwrap_throw();
+        let json = content_to_json(&contents).unwrap_throw();
         let contents = ShelfCRDT::from_json_values(
             json,
             &mut LamportTimestampGenerator {},
             &mut DotClockGenerator::new(client_id),
         )
         .unwrap_throw();
-        let value = match contents {
-            ShelfCRDT::Value { value, .. } => ShelfCRDT::Value {
-                value,
-                clock: DotClock {
-                    client_id,
-                    clock: new_ts,
-                },
-            },
-            ShelfCRDT::Map { shelves, .. } => ShelfCRDT::Map {
-                shelves,
-                clock: new_ts.into(),
-            },
+        self.load_all();
+        let changed = vec![path.clone()];
+        if path.is_empty() {
+            // The root has no parent, so its own clock is the lower bound for the replacement.
+            let root_clock = self.inner.get_clock().get_logical_clock();
+            let new_ts = Self::next_timestamp(Some(&self.inner), root_clock);
+            let unchanged = self.inner.same_content(&contents);
+            self.inner = Self::with_timestamp(contents, new_ts, client_id);
+            if !unchanged {
+                self.version += 1;
+                self.notify(changed);
+            }
+            return;
+        }
+        let (entry, parent_clock) = self.inner.entry_from_path(path).unwrap_throw();
+        let old_value = match &entry {
+            Entry::Occupied(occupied_entry) => Some(occupied_entry.get()),
+            Entry::Vacant(_) => None,
         };
+        let new_ts = Self::next_timestamp(old_value, parent_clock.0);
+        // Writing the value a path already has still moves its clock, but is not a change for listeners
+        let unchanged = old_value.is_some_and(|old| old.same_content(&contents));
+        let value = Self::with_timestamp(contents, new_ts, client_id);
         match entry {
             Entry::Occupied(mut o) => Some(o.insert(value)),
             Entry::Vacant(v) => {
@@ -105,34 +134,124 @@ impl DotShelf {
                 None
             }
         };
+        if !unchanged {
+            self.version += 1;
+            self.notify(changed);
+        }
     }
 
     #[wasm_bindgen(js_name = "toString")]
     pub fn to_string(&self) -> String {
-        format!("Shelf({})", self.0)
+        format!("Shelf({})", self.full())
     }
 
     #[wasm_bindgen(js_name = "toJson")]
     pub fn to_json(&self) -> JsValue {
-        let json: JSON = self.0.clone().into();
+        let json: JSON = self.full().into_owned().into();
         JsValue::from_serde(&json).unwrap()
     }
 
+    /// Serializes the whole shelf with its clocks, e.g. to persist it in IndexedDB.
+    /// Unlike `toJson`, clocks are kept exactly.
+    #[wasm_bindgen(js_name = "toBytes")]
+    pub fn to_bytes(&self) -> Uint8Array {
+        let bytes = encoding::encode(&self.full()).unwrap_throw();
+        Uint8Array::from(&bytes[..])
+    }
+
+    /// Serializes the shelf like `toBytes`, but encodes each top-level entry on its own so that `fromBytesLazy`
+    /// can skip the ones that are not read. The root of the shelf must be a map.
+    #[wasm_bindgen(js_name = "toLazyBytes")]
+    pub fn to_lazy_bytes(&self) -> Result<Uint8Array, String> {
+        let (shelves, clock) = match self.full().into_owned() {
+            ShelfCRDT::Map { shelves, clock } => (shelves, clock),
+            ShelfCRDT::Value { .. } => {
+                return Err("Only shelves with a map at the root can be loaded lazily".to_owned())
+            }
+        };
+        let entries = shelves
+            .into_iter()
+            .map(|(key, shelf)| Ok((key, encoding::encode(&shelf)?)))
+            .collect::<Result<HashMap<String, Vec<u8>>, String>>()?;
+        let bytes = encoding::encode(&(clock, entries))?;
+        Ok(Uint8Array::from(&bytes[..]))
+    }
+
+    /// Restores a shelf serialized with `toLazyBytes`. Top-level entries are only decoded when a path through them
+    /// is first read, so that large shelves open without deserializing regions that are never shown.
+    /// Anything that needs the whole shelf, such as `set`, `merge` or `getStateVector`, decodes the rest first.
+    #[wasm_bindgen(js_name = "fromBytesLazy")]
+    pub fn from_bytes_lazy(bytes: Uint8Array) -> Result<DotShelf, String> {
+        let (clock, entries): (LamportTimestamp, HashMap<String, Vec<u8>>) =
+            encoding::decode(&bytes.to_vec())?;
+        let inner = ShelfCRDT::Map {
+            shelves: HashMap::new(),
+            clock,
+        };
+        let lazy = entries
+            .into_iter()
+            .map(|(key, bytes)| {
+                let entry = LazyEntry {
+                    bytes,
+                    loaded: OnceCell::new(),
+                };
+                (key, entry)
+            })
+            .collect();
+        Ok(Self {
+            lazy,
+            ..Self::from(inner)
+        })
+    }
+
+    /// Decodes every entry that `fromBytesLazy` left encoded. Read-only methods that need the whole shelf,
+    /// like `getStateVector` or `toJson`, assemble a copy of it on each call, so load everything once before
+    /// syncing a lazy shelf.
+    #[wasm_bindgen(js_name = "loadAll")]
+    pub fn load_all(&mut self) {
+        if self.lazy.is_empty() {
+            return;
+        }
+        if let ShelfCRDT::Map { shelves, .. } = &mut self.inner {
+            shelves.extend(
+                self.lazy
+                    .drain()
+                    .map(|(key, entry)| (key, entry.into_shelf())),
+            );
+        }
+    }
+
+    /// Number of top-level entries that are still encoded.
+    #[wasm_bindgen(js_name = "unloadedCount")]
+    pub fn unloaded_count(&self) -> usize {
+        self.lazy
+            .values()
+            .filter(|entry| entry.loaded.get().is_none())
+            .count()
+    }
+
+    /// Restores a shelf serialized with `toBytes`.
+    #[wasm_bindgen(js_name = "fromBytes")]
+    pub fn from_bytes(bytes: Uint8Array) -> Result<DotShelf, String> {
+        let inner: ShelfCRDT = encoding::decode(&bytes.to_vec())?;
+        Ok(Self::from(inner))
+    }
+
     #[wasm_bindgen(js_name = "getStateVector")]
     pub fn get_state_vector(&self) -> JsValue {
-        let sv = self.0.get_state_vector();
-        let bytes = bincode::serialize(&sv).unwrap_throw();
+        let sv = self.full().get_state_vector();
+        let bytes = encoding::encode(&sv).unwrap_throw();
         Uint8Array::from(&bytes[..]).into()
     }
 
     #[wasm_bindgen(js_name = "getStateDelta")]
     pub fn get_state_delta(&self, sv: Uint8Array) -> JsValue {
         let decoded_sv: StateVector<LamportTimestamp, DotClock> =
-            bincode::deserialize(&sv.to_vec()[..]).unwrap_throw();
+            encoding::decode(&sv.to_vec()).unwrap_throw();
         let bytes = self
-            .0
+            .full()
             .get_state_delta(&decoded_sv)
-            .map(|delta| bincode::serialize(&delta).unwrap_throw());
+            .map(|delta| encoding::encode(&delta).unwrap_throw());
 
         match bytes {
             Some(bytes) => Uint8Array::from(&bytes[..]).into(),
@@ -140,14 +259,49 @@ impl DotShelf {
         }
     }
     #[wasm_bindgen]
-    pub fn merge(self, delta_bytes: Uint8Array) -> Self {
-        let delta: ShelfCRDT = bincode::deserialize(&delta_bytes.to_vec()[..]).unwrap_throw();
-        Self(self.0.merge(delta))
+    pub fn merge(mut self, delta_bytes: Uint8Array) -> Self {
+        let delta: ShelfCRDT = encoding::decode(&delta_bytes.to_vec()).unwrap_throw();
+        self.load_all();
+        let Self {
+            mut inner,
+            mut version,
+            on_change,
+            ..
+        } = self;
+        // The shelf is only cloned when a callback needs to know which paths changed.
+        let before = on_change.as_ref().map(|_| inner.clone());
+        if inner.merge_changed(delta) {
+            version += 1;
+        }
+        let shelf = Self {
+            inner,
+            lazy: HashMap::new(),
+            version,
+            on_change,
+        };
+        if let Some(before) = before {
+            shelf.notify(shelf.inner.changed_paths(&before));
+        }
+        shelf
+    }
+
+    /// Registers a callback that is invoked after each `set` or `merge` that changes the shelf.
+    /// It receives an array of `{path, value}` objects, one per changed path, where `value` is the new content
+    /// or `null` if the path no longer exists.
+    #[wasm_bindgen(js_name = "setOnChange")]
+    pub fn set_on_change(&mut self, callback: js_sys::Function) {
+        self.on_change = Some(callback);
+    }
+
+    /// Monotonically increasing counter that is bumped whenever a `set` or `merge` alters the content of the shelf.
+    #[wasm_bindgen]
+    pub fn version(&self) -> u64 {
+        self.version
     }
 
     #[wasm_bindgen(js_name = "getTotalBytes")]
     pub fn get_total_bytes(&self) -> usize {
-        self.0.get_total_bytes()
+        self.full().get_total_bytes()
     }
 
     /// Converts a JavaScript Array to a path of strings. Returns `None` on failure
@@ -155,10 +309,124 @@ impl DotShelf {
     fn convert_path(list: Array) -> Option<Vec<String>> {
         list.iter().map(|segment| segment.as_string()).collect()
     }
+
+    fn get_shelf(&self, path: Array) -> Cow<'_, ShelfCRDT> {
+        if path.length() == 0 {
+            return self.full();
+        }
+        let mut shelf = &self.inner;
+        for (depth, key) in path.iter().enumerate() {
+            if let Some(key) = key.as_string() {
+                let lazy = || match depth {
+                    0 => self.lazy.get(&key).map(LazyEntry::get),
+                    _ => None,
+                };
+                shelf = shelf
+                    .get(&key)
+                    .or_else(lazy)
+                    .ok_or_else(|| format!("Key Error: {}", key))
+                    .unwrap_throw()
+            } else {
+                Err(format!("Invalid key: {:?}", key)).unwrap_throw()
+            }
+        }
+        Cow::Borrowed(shelf)
+    }
+
+    /// The whole shelf, including the entries that `fromBytesLazy` left encoded.
+    fn full(&self) -> Cow<'_, ShelfCRDT> {
+        match &self.inner {
+            ShelfCRDT::Map { shelves, clock } if !self.lazy.is_empty() => {
+                let mut shelves = shelves.clone();
+                shelves.extend(
+                    self.lazy
+                        .iter()
+                        .map(|(key, entry)| (key.clone(), entry.get().clone())),
+                );
+                Cow::Owned(ShelfCRDT::Map {
+                    shelves,
+                    clock: *clock,
+                })
+            }
+            _ => Cow::Borrowed(&self.inner),
+        }
+    }
+
+    /// Timestamp for a value written over `old_value`. It must be higher than anything it replaces.
+    fn next_timestamp(old_value: Option<&ShelfCRDT>, parent_clock: usize) -> usize {
+        let new_ts = match old_value {
+            Some(ShelfCRDT::Value { clock, .. }) => Some(clock.clock.max(parent_clock) + 1),
+            Some(ShelfCRDT::Map {
+                shelves,
+                clock: LamportTimestamp(old_clock),
+            }) => {
+                let highest_child_timestamp = shelves
+                    .iter()
+                    .map(|(_, shelf)| shelf.get_clock().get_logical_clock())
+                    .max();
+                highest_child_timestamp.map(|ts| ts.max(parent_clock).max(*old_clock) + 1)
+            }
+            None => None,
+        };
+        new_ts.unwrap_or(parent_clock + 1)
+    }
+
+    fn with_timestamp(contents: ShelfCRDT, new_ts: usize, client_id: ClientId) -> ShelfCRDT {
+        match contents {
+            ShelfCRDT::Value { value, .. } => ShelfCRDT::Value {
+                value,
+                clock: DotClock {
+                    client_id,
+                    clock: new_ts,
+                },
+            },
+            ShelfCRDT::Map { shelves, .. } => ShelfCRDT::Map {
+                shelves,
+                clock: new_ts.into(),
+            },
+        }
+    }
+
+    fn notify(&self, paths: Vec<Vec<String>>) {
+        let callback = match &self.on_change {
+            Some(callback) if !paths.is_empty() => callback,
+            _ => return,
+        };
+        let changes: Vec<JSON> = paths
+            .into_iter()
+            .map(|path| {
+                let keys: Vec<&str> = path.iter().map(String::as_str).collect();
+                let value = match self.inner.get_path(&keys) {
+                    Ok(shelf) => shelf.clone().to_json_values(),
+                    Err(_) => JSON::Null,
+                };
+                json!({ "path": path, "value": value })
+            })
+            .collect();
+        let changes = JsValue::from_serde(&changes).unwrap_throw();
+        callback.call1(&JsValue::NULL, &changes).unwrap_throw();
+    }
+
+    pub(crate) fn inner(&self) -> &ShelfCRDT {
+        &self.inner
+    }
+
+    #[inline]
+    fn leaf_value(shelf: &ShelfCRDT) -> Option<&Value> {
+        match shelf {
+            ShelfCRDT::Value { value, .. } => Some(value),
+            ShelfCRDT::Map { .. } => None,
+        }
+    }
 }
 
 impl From<ShelfCRDT> for DotShelf {
     fn from(value: ShelfCRDT) -> Self {
-        Self(value)
+        Self {
+            inner: value,
+            lazy: HashMap::new(),
+            version: 0,
+            on_change: None,
+        }
     }
 }
@@ -0,0 +1,49 @@
+//! Times converting content from JavaScript with `js_to_json` against `JsValue::into_serde`.
+//! Run with `wasm-pack test --headless --firefox -- --test conversion_bench` and read the timings from the log.
+
+#![cfg(target_arch = "wasm32")]
+// `into_serde` is deprecated, but it is what `js_to_json` is compared against
+#![allow(deprecated)]
+
+extern crate wasm_bindgen_test;
+use js_sys::Date;
+use serde_json::json;
+use shelf_js::js_to_json;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_test::*;
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+/// Each conversion is timed this many times and the fastest run is reported, to leave out warm-up and GC pauses.
+const RUNS: usize = 5;
+
+fn fastest_ms(mut f: impl FnMut()) -> f64 {
+    (0..RUNS)
+        .map(|_| {
+            let start = Date::now();
+            f();
+            Date::now() - start
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+#[wasm_bindgen_test]
+fn conversion_10k_keys() {
+    let keys: serde_json::Map<String, serde_json::Value> = (0..10_000)
+        .map(|i| {
+            (
+                i.to_string(),
+                json!({"value": i, "label": format!("item {i}")}),
+            )
+        })
+        .collect();
+    let content = serde_json::Value::Object(keys);
+    let js_content = JsValue::from_serde(&content).unwrap();
+
+    let direct_ms = fastest_ms(|| assert_eq!(js_to_json(&js_content).unwrap(), content));
+    let serde_ms = fastest_ms(|| {
+        let serialized: serde_json::Value = js_content.into_serde().unwrap();
+        assert_eq!(serialized, content);
+    });
+    console_log!("10k keys: js_to_json {direct_ms}ms, into_serde {serde_ms}ms");
+}
@@ -3,11 +3,275 @@
 #![cfg(target_arch = "wasm32")]
 
 extern crate wasm_bindgen_test;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::rc::Rc;
+
+use js_sys::{Array, Date, Function, Object, Reflect, Uint8Array};
+use serde_json::json;
+use shelf_crdt::clock::{DotClock, LamportTimestamp, LamportTimestampGenerator};
+use shelf_crdt::json::Value;
+use shelf_crdt::wrap_crdt::Shelf as GeneralShelf;
+use shelf_js::{js_to_json, Awareness, DotShelf, Fuzzer};
+use wasm_bindgen::prelude::*;
 use wasm_bindgen_test::*;
 
 wasm_bindgen_test_configure!(run_in_browser);
 
+fn path(keys: &[&str]) -> Array {
+    keys.iter().map(|key| JsValue::from_str(key)).collect()
+}
+
+fn dot_shelf(content: serde_json::Value, client_id: u64) -> DotShelf {
+    DotShelf::new(JsValue::from_serde(&content).unwrap(), client_id).unwrap()
+}
+
 #[wasm_bindgen_test]
 fn pass() {
     assert_eq!(1 + 1, 2);
 }
+
+#[wasm_bindgen_test]
+fn version_only_bumps_on_change() {
+    let mut sender = dot_shelf(json!({"a": 1}), 1);
+    let receiver = dot_shelf(json!({"a": 1}), 1);
+    let empty = dot_shelf(json!({}), 1);
+
+    // Receiver already has everything in the delta
+    let delta: Uint8Array = sender
+        .get_state_delta(empty.get_state_vector().into())
+        .into();
+    let receiver = receiver.merge(delta);
+    assert_eq!(receiver.version(), 0);
+
+    sender.set(path(&["a"]), JsValue::from(2), 1);
+    assert_eq!(sender.version(), 1);
+    let delta: Uint8Array = sender
+        .get_state_delta(receiver.get_state_vector().into())
+        .into();
+    let receiver = receiver.merge(delta);
+    assert_eq!(receiver.version(), 1);
+
+    // Setting the value a path already has does not alter the content
+    sender.set(path(&["a"]), JsValue::from(2), 1);
+    assert_eq!(sender.version(), 1);
+    let root = Object::new();
+    Reflect::set(&root, &"a".into(), &2.into()).unwrap();
+    sender.set(path(&[]), root.clone().into(), 1);
+    assert_eq!(sender.version(), 1);
+
+    let mut awareness = Awareness::new(root.into(), 1);
+    awareness.set(path(&["a"]), JsValue::from(2));
+    assert_eq!(awareness.version(), 0);
+    awareness.set(path(&["a"]), JsValue::from(3));
+    assert_eq!(awareness.version(), 1);
+}
+
+#[wasm_bindgen_test]
+fn typed_getters() {
+    let shelf = dot_shelf(
+        json!({"name": "shelf", "count": 3, "ratio": 0.5, "online": true, "nested": {}}),
+        1,
+    );
+    assert_eq!(shelf.get_string(path(&["name"])), Some("shelf".to_owned()));
+    assert_eq!(shelf.get_number(path(&["count"])), Some(3.0));
+    assert_eq!(shelf.get_number(path(&["ratio"])), Some(0.5));
+    assert_eq!(shelf.get_bool(path(&["online"])), Some(true));
+
+    // Mismatched types return null
+    assert_eq!(shelf.get_string(path(&["count"])), None);
+    assert_eq!(shelf.get_number(path(&["name"])), None);
+    assert_eq!(shelf.get_bool(path(&["nested"])), None);
+}
+
+#[wasm_bindgen_test]
+fn empty_path_addresses_root() {
+    let mut shelf = dot_shelf(json!({"a": 1}), 1);
+    let peer = dot_shelf(json!({"a": 1}), 2);
+    let root: serde_json::Value = shelf.get(path(&[])).into_serde().unwrap();
+    assert_eq!(root, json!({"a": 1}));
+
+    shelf.set(path(&[]), JsValue::from_serde(&json!({"b": 2})).unwrap(), 1);
+    let root: serde_json::Value = shelf.get(path(&[])).into_serde().unwrap();
+    assert_eq!(root, json!({"b": 2}));
+
+    // The replacement wins over the old root on peers
+    let delta: Uint8Array = shelf.get_state_delta(peer.get_state_vector().into()).into();
+    let peer = peer.merge(delta);
+    let root: serde_json::Value = peer.get(path(&[])).into_serde().unwrap();
+    assert_eq!(root, json!({"b": 2}));
+}
+
+#[wasm_bindgen_test]
+fn on_change_reports_merged_paths() {
+    let mut sender = dot_shelf(json!({"user": {"name": "a", "age": 1}}), 1);
+    let receiver = dot_shelf(json!({"user": {"name": "a", "age": 1}}), 1);
+    let mut receiver = {
+        let delta: Uint8Array = sender
+            .get_state_delta(receiver.get_state_vector().into())
+            .into();
+        receiver.merge(delta)
+    };
+
+    let changes = Rc::new(RefCell::new(Vec::new()));
+    let callback = {
+        let changes = changes.clone();
+        Closure::wrap(Box::new(move |change: JsValue| {
+            changes
+                .borrow_mut()
+                .push(change.into_serde::<serde_json::Value>().unwrap())
+        }) as Box<dyn FnMut(JsValue)>)
+    };
+    receiver.set_on_change(callback.as_ref().unchecked_ref::<Function>().clone());
+
+    sender.set(path(&["user", "name"]), JsValue::from("b"), 1);
+    let delta: Uint8Array = sender
+        .get_state_delta(receiver.get_state_vector().into())
+        .into();
+    let receiver = receiver.merge(delta);
+    assert_eq!(
+        *changes.borrow(),
+        vec![json!([{"path": ["user", "name"], "value": "b"}])]
+    );
+    assert_eq!(receiver.version(), 1);
+}
+
+#[wasm_bindgen_test]
+fn direct_conversion_matches_serde() {
+    let content = json!({
+        "name": "shelf",
+        "count": 3,
+        "ratio": 0.5,
+        "online": true,
+        "tags": ["a", 1, null],
+        "nested": {"empty": {}, "deep": {"value": -2}}
+    });
+    let js_content = JsValue::from_serde(&content).unwrap();
+    assert_eq!(js_to_json(&js_content).unwrap(), content);
+
+    // Large documents should produce identical shelves through either path
+    let keys: serde_json::Map<String, serde_json::Value> = (0..10_000)
+        .map(|i| {
+            (
+                i.to_string(),
+                json!({"value": i, "label": format!("item {i}")}),
+            )
+        })
+        .collect();
+    let js_content = JsValue::from_serde(&serde_json::Value::Object(keys)).unwrap();
+
+    let direct = js_to_json(&js_content).unwrap();
+    let serialized: serde_json::Value = js_content.into_serde().unwrap();
+
+    type Shelf = GeneralShelf<Value, LamportTimestamp>;
+    let from_direct = Shelf::from_json_values(
+        direct,
+        &mut LamportTimestampGenerator {},
+        &mut LamportTimestampGenerator {},
+    )
+    .unwrap();
+    let from_serialized = Shelf::from_json_values(
+        serialized,
+        &mut LamportTimestampGenerator {},
+        &mut LamportTimestampGenerator {},
+    )
+    .unwrap();
+    assert_eq!(from_direct, from_serialized);
+}
+
+#[wasm_bindgen_test]
+fn direct_conversion_follows_json_stringify() {
+    // Dates are converted through their toJSON method
+    let object = Object::new();
+    Reflect::set(&object, &"at".into(), &Date::new(&0.into())).unwrap();
+    assert_eq!(
+        js_to_json(&object).unwrap(),
+        json!({"at": "1970-01-01T00:00:00.000Z"})
+    );
+
+    // Cycles are rejected instead of overflowing the stack
+    let cyclic = Object::new();
+    Reflect::set(&cyclic, &"self".into(), &cyclic).unwrap();
+    assert!(js_to_json(&cyclic).is_err());
+
+    // The same object may appear more than once as long as it does not contain itself
+    let shared = Object::new();
+    let array = Array::of2(&shared, &shared);
+    assert_eq!(js_to_json(&array).unwrap(), json!([{}, {}]));
+}
+
+#[wasm_bindgen_test]
+fn bytes_round_trip_keeps_clocks() {
+    let large = usize::MAX;
+    let shelf: GeneralShelf<Value, LamportTimestamp, DotClock> = json!([{
+        "user": [{"name": ["a", [7, large]], "age": [3, [2, 1]]}, large]
+    }, 0])
+    .try_into()
+    .unwrap();
+    let shelf = DotShelf::from(shelf);
+    let restored = DotShelf::from_bytes(shelf.to_bytes()).unwrap();
+    assert_eq!(
+        restored
+            .to_json()
+            .into_serde::<serde_json::Value>()
+            .unwrap(),
+        shelf.to_json().into_serde::<serde_json::Value>().unwrap()
+    );
+
+    // Clocks survive, so the restored shelf has nothing to send back
+    let delta = shelf.get_state_delta(restored.get_state_vector().into());
+    assert!(delta.is_null());
+
+    assert!(DotShelf::from_bytes(Uint8Array::from(&[1, 2, 3][..])).is_err());
+}
+
+#[wasm_bindgen_test]
+fn lazy_bytes_only_decode_read_entries() {
+    let content: serde_json::Map<String, serde_json::Value> = (0..1_000)
+        .map(|i| {
+            (
+                i.to_string(),
+                json!({"value": i, "label": format!("item {i}")}),
+            )
+        })
+        .collect();
+    let shelf = dot_shelf(serde_json::Value::Object(content), 1);
+    let mut lazy = DotShelf::from_bytes_lazy(shelf.to_lazy_bytes().unwrap()).unwrap();
+    assert_eq!(lazy.unloaded_count(), 1_000);
+
+    assert_eq!(
+        lazy.get_string(path(&["5", "label"])),
+        Some("item 5".to_owned())
+    );
+    assert_eq!(lazy.unloaded_count(), 999);
+
+    // Clocks survive, so the lazy shelf has nothing to send back
+    let delta = shelf.get_state_delta(lazy.get_state_vector().into());
+    assert!(delta.is_null());
+
+    lazy.load_all();
+    assert_eq!(lazy.unloaded_count(), 0);
+    assert_eq!(
+        lazy.to_json().into_serde::<serde_json::Value>().unwrap(),
+        shelf.to_json().into_serde::<serde_json::Value>().unwrap()
+    );
+
+    assert!(dot_shelf(json!(1), 1).to_lazy_bytes().is_err());
+}
+
+#[wasm_bindgen_test]
+fn fuzzer_shelves_converge() {
+    let config = JsValue::from_serde(&json!({
+        "seed": 7,
+        "depthRange": [1, 4],
+        "branchRange": [1, 4],
+        "valueRange": [0, 6]
+    }))
+    .unwrap();
+    let mut fuzzer = Fuzzer::new(&config.into());
+    for _ in 0..20 {
+        let report = js_to_json(&fuzzer.check_convergence().unwrap()).unwrap();
+        assert_eq!(report["converged"], json!(true), "{report}");
+        assert_eq!(report["diff"], json!([]));
+    }
+}